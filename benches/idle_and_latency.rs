@@ -0,0 +1,183 @@
+//! Idle CPU usage and request latency of the server binary.
+//!
+//! Starts the server on a free port, samples its CPU time from `/proc` while its only client is
+//! idle, then measures round trips of `GET` from one connection and from several at once.
+//!
+//! `cargo bench --bench idle_and_latency`, `BENCH_REQUESTS` sets the requests per connection
+//! and `BENCH_POLLER` the poller backend, io_uring needs `--features io-uring`. `BENCH_SERVER`
+//! runs another server binary instead, an older build to compare with for instance.
+//! The bench fails when the idle server uses more than `MAX_IDLE_CPU` of a core.
+//! Connections are opened one at a time and pinged before the next one, they are set up
+//! outside of the measurements.
+
+use std::{
+    env, fs,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+const IDLE_WINDOW: Duration = Duration::from_secs(3);
+/// Share of a core the idle server may use, one tick of `/proc` is 0.3% of the window. A loop
+/// polling with a timeout rounded up to a millisecond uses over 1%.
+const MAX_IDLE_CPU: f64 = 0.005;
+const CLIENTS: usize = 8;
+/// clock ticks per second of the `/proc/<pid>/stat` times, fixed by the kernel ABI
+const USER_HZ: f64 = 100.0;
+
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    port: u16,
+}
+
+impl Server {
+    fn start() -> Server {
+        let port = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .unwrap()
+            .port();
+        let dir = env::temp_dir().join(format!("bench-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let binary = env::var("BENCH_SERVER")
+            .unwrap_or_else(|_| env!("CARGO_BIN_EXE_async-tcp-command-server").to_string());
+        let mut command = Command::new(binary);
+        command
+            .args(["--bind", &format!("127.0.0.1:{}", port)])
+            .args(["--dir", dir.to_str().unwrap()])
+            .args(["--save", ""]);
+        if let Ok(poller) = env::var("BENCH_POLLER") {
+            command.args(["--poller", &poller]);
+        }
+        let child = command
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Server { child, dir, port }
+    }
+
+    /// Connects once the server listens and waits for the connection to be served.
+    fn connect(&self) -> BufReader<TcpStream> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let stream = loop {
+            match TcpStream::connect(("127.0.0.1", self.port)) {
+                Ok(stream) => break stream,
+                Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(20)),
+                Err(err) => panic!("the server did not start: {}", err),
+            }
+        };
+        stream.set_nodelay(true).unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let mut conn = BufReader::new(stream);
+        round_trip(&mut conn, "PING\r\n");
+        conn
+    }
+
+    /// CPU time used by the server so far.
+    fn cpu_time(&self) -> Duration {
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.child.id())).unwrap();
+        // the command name may hold spaces, the fields are counted from its closing paren
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+        let ticks: u64 = fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap();
+        Duration::from_secs_f64(ticks as f64 / USER_HZ)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Sends one inline command and reads the reply, bulk replies span two lines.
+fn round_trip(conn: &mut BufReader<TcpStream>, cmd: &str) {
+    conn.get_mut().write_all(cmd.as_bytes()).unwrap();
+    let mut line = String::new();
+    conn.read_line(&mut line).unwrap();
+    if line.starts_with('$') && !line.starts_with("$-1") {
+        line.clear();
+        conn.read_line(&mut line).unwrap();
+    }
+}
+
+/// Latencies of `requests` sequential `GET`s over one connection.
+fn run_client(mut conn: BufReader<TcpStream>, requests: usize) -> Vec<Duration> {
+    (0..requests)
+        .map(|_| {
+            let start = Instant::now();
+            round_trip(&mut conn, "GET bench:key\r\n");
+            start.elapsed()
+        })
+        .collect()
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>, elapsed: Duration) {
+    latencies.sort();
+    let at = |quantile: f64| latencies[((latencies.len() - 1) as f64 * quantile) as usize];
+    println!(
+        "{:<12} {:>7} requests {:>9.0} req/s  p50 {:>8.1?}  p99 {:>8.1?}  p99.9 {:>8.1?}  max {:>8.1?}",
+        name,
+        latencies.len(),
+        latencies.len() as f64 / elapsed.as_secs_f64(),
+        at(0.5),
+        at(0.99),
+        at(0.999),
+        at(1.0)
+    );
+}
+
+fn main() {
+    let requests: usize = env::var("BENCH_REQUESTS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(10_000);
+    let server = Server::start();
+    let mut conn = server.connect();
+    round_trip(&mut conn, "SET bench:key value\r\n");
+
+    // let the start up settle before sampling, the connection stays open and idle
+    thread::sleep(Duration::from_millis(500));
+    let before = server.cpu_time();
+    thread::sleep(IDLE_WINDOW);
+    let idle = server.cpu_time() - before;
+    let idle_share = idle.as_secs_f64() / IDLE_WINDOW.as_secs_f64();
+    println!(
+        "idle         {:?} of CPU over {:?} ({:.1}% of a core)",
+        idle,
+        IDLE_WINDOW,
+        idle_share * 100.0
+    );
+
+    let start = Instant::now();
+    let latencies = run_client(conn, requests);
+    report("1 client", latencies, start.elapsed());
+
+    let conns: Vec<_> = (0..CLIENTS).map(|_| server.connect()).collect();
+    let start = Instant::now();
+    let latencies = thread::scope(|scope| {
+        let clients: Vec<_> = conns
+            .into_iter()
+            .map(|conn| scope.spawn(move || run_client(conn, requests)))
+            .collect();
+        clients
+            .into_iter()
+            .flat_map(|client| client.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    report(&format!("{} clients", CLIENTS), latencies, start.elapsed());
+
+    assert!(
+        idle_share <= MAX_IDLE_CPU,
+        "the idle server used {:.1}% of a core, more than {:.1}%",
+        idle_share * 100.0,
+        MAX_IDLE_CPU * 100.0
+    );
+}
@@ -0,0 +1,144 @@
+use std::{
+    io::{self, Read, Result, Write},
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use crate::{
+    pipeline::Pipeline,
+    protocol::{decode_reply, encode_command},
+    value::Value,
+};
+
+/// Blocking connection to the server, each call waits for the replies of its commands.
+pub struct Connection {
+    stream: TcpStream,
+    /// received bytes not decoded yet
+    input: Vec<u8>,
+    /// set when a write or read failed, replies may be left over or missing
+    broken: bool,
+}
+
+impl Connection {
+    /// # Errors
+    ///
+    /// This function will return an error if the connection can't be established.
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Connection> {
+        Ok(Connection::new(TcpStream::connect(addr)?))
+    }
+
+    /// Connects within `timeout`, which then bounds every read and write.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the connection can't be established in time.
+    pub fn connect_timeout(addr: &SocketAddr, timeout: Duration) -> Result<Connection> {
+        let conn = Connection::new(TcpStream::connect_timeout(addr, timeout)?);
+        conn.set_timeout(Some(timeout))?;
+        Ok(conn)
+    }
+
+    fn new(stream: TcpStream) -> Connection {
+        // commands are small, they shouldn't wait for the acks of the previous ones
+        let _ = stream.set_nodelay(true);
+        Connection {
+            stream,
+            input: vec![],
+            broken: false,
+        }
+    }
+
+    /// Bounds reads and writes, they fail with `WouldBlock` or `TimedOut` once it expires.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `timeout` is zero.
+    pub fn set_timeout(&self, timeout: Option<Duration>) -> Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        self.stream.set_write_timeout(timeout)
+    }
+
+    /// Sends a command and waits for its reply, error replies are returned as
+    /// [`Value::Error`].
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the command can't be encoded, or if the
+    /// connection fails or the server sends an invalid reply.
+    pub fn command(&mut self, args: &[&str]) -> Result<Value> {
+        let mut out = vec![];
+        encode_command(args, &mut out)?;
+        let mut replies = self.round_trip(&out, 1)?;
+        Ok(replies.remove(0))
+    }
+
+    /// Sends the commands of `pipeline` at once and returns their replies in order.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the connection fails or the server sends an
+    /// invalid reply.
+    pub fn pipeline(&mut self, pipeline: &Pipeline) -> Result<Vec<Value>> {
+        self.round_trip(pipeline.encoded(), pipeline.len())
+    }
+
+    /// Checks the server answers PING.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the connection fails or the reply isn't PONG.
+    pub fn ping(&mut self) -> Result<()> {
+        match self.command(&["PING"])? {
+            Value::Simple(pong) if pong == "PONG" => Ok(()),
+            reply => {
+                self.broken = true;
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unexpected reply to PING: {:?}", reply),
+                ))
+            }
+        }
+    }
+
+    /// Whether a failure left the connection unusable, the replies on it can't be matched with
+    /// the commands anymore.
+    pub fn is_broken(&self) -> bool {
+        self.broken
+    }
+
+    fn round_trip(&mut self, commands: &[u8], count: usize) -> Result<Vec<Value>> {
+        if self.broken {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                "the connection failed earlier",
+            ));
+        }
+        let replies = self
+            .stream
+            .write_all(commands)
+            .and_then(|_| (0..count).map(|_| self.read_reply()).collect());
+        self.broken = replies.is_err();
+        replies
+    }
+
+    fn read_reply(&mut self) -> Result<Value> {
+        let mut buf = [0; 4096];
+        loop {
+            if let Some((value, consumed)) = decode_reply(&self.input)? {
+                self.input.drain(..consumed);
+                return Ok(value);
+            }
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the server closed the connection",
+                    ))
+                }
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
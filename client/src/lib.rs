@@ -0,0 +1,22 @@
+//! Client for the async TCP command server.
+//!
+//! [`Connection`] sends commands and waits for their replies, [`Pipeline`] sends a batch of
+//! commands at once and [`Pool`] shares connections between threads, checking the health of the
+//! ones that sat idle. [`NonBlockingConnection`] never blocks, it is meant to be registered with
+//! a mio poller. Replies are decoded into [`Value`].
+//!
+//! The server reads one command per line with its arguments separated by whitespace, so
+//! arguments can't be empty or hold whitespace.
+
+pub mod connection;
+pub mod nonblocking;
+pub mod pipeline;
+pub mod pool;
+pub mod protocol;
+pub mod value;
+
+pub use connection::Connection;
+pub use nonblocking::NonBlockingConnection;
+pub use pipeline::Pipeline;
+pub use pool::{Pool, PoolConfig, PooledConnection};
+pub use value::Value;
@@ -0,0 +1,162 @@
+use std::{
+    io::{self, Read, Result, Write},
+    net::SocketAddr,
+};
+
+use mio::{event::Source, net::TcpStream, Interest, Registry, Token};
+
+use crate::{
+    pipeline::Pipeline,
+    protocol::{decode_reply, encode_command},
+    value::Value,
+};
+
+/// Connection that never blocks, for callers running their own mio event loop.
+///
+/// Commands are queued by `send` and written by `flush`, `try_recv` returns their replies in
+/// order as they come in. Register the connection for READABLE, and for WRITABLE while
+/// `wants_write`. Events are edge triggered: after an event call `flush`, and `try_recv` until
+/// it returns None.
+pub struct NonBlockingConnection {
+    stream: TcpStream,
+    /// received bytes not decoded yet
+    input: Vec<u8>,
+    /// commands not written yet
+    output: Vec<u8>,
+    /// replies still expected
+    pending: usize,
+}
+
+impl NonBlockingConnection {
+    /// Starts connecting, commands can be queued right away, they are written once the
+    /// connection is established.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the socket can't be created.
+    pub fn connect(addr: SocketAddr) -> Result<NonBlockingConnection> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(NonBlockingConnection {
+            stream,
+            input: vec![],
+            output: vec![],
+            pending: 0,
+        })
+    }
+
+    /// Queues a command.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the command can't be encoded.
+    pub fn send(&mut self, args: &[&str]) -> Result<()> {
+        encode_command(args, &mut self.output)?;
+        self.pending += 1;
+        Ok(())
+    }
+
+    /// Queues the commands of `pipeline`.
+    pub fn send_pipeline(&mut self, pipeline: &Pipeline) {
+        self.output.extend_from_slice(pipeline.encoded());
+        self.pending += pipeline.len();
+    }
+
+    /// Writes queued commands until the socket would block, returns true once they are all
+    /// written.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the connection failed.
+    pub fn flush(&mut self) -> Result<bool> {
+        let mut written = 0;
+        let result = loop {
+            if written == self.output.len() {
+                break Ok(true);
+            }
+            match self.stream.write(&self.output[written..]) {
+                Ok(0) => break Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                // still connecting
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::NotConnected =>
+                {
+                    break Ok(false)
+                }
+                Err(err) => break Err(err),
+            }
+        };
+        self.output.drain(..written);
+        result
+    }
+
+    /// Whether commands are waiting for the socket to become writable.
+    pub fn wants_write(&self) -> bool {
+        !self.output.is_empty()
+    }
+
+    /// Replies still expected for the commands sent.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Returns the next reply, reading what the socket holds when none is buffered. None when
+    /// the reply didn't fully come in yet.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the connection failed, was closed by the server,
+    /// or if the server sent an invalid reply.
+    pub fn try_recv(&mut self) -> Result<Option<Value>> {
+        if let Some(value) = self.decode()? {
+            return Ok(Some(value));
+        }
+        let mut buf = [0; 4096];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the server closed the connection",
+                    ))
+                }
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::NotConnected =>
+                {
+                    break
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        self.decode()
+    }
+
+    fn decode(&mut self) -> Result<Option<Value>> {
+        match decode_reply(&self.input)? {
+            Some((value, consumed)) => {
+                self.input.drain(..consumed);
+                self.pending = self.pending.saturating_sub(1);
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Source for NonBlockingConnection {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        self.stream.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        self.stream.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        self.stream.deregister(registry)
+    }
+}
@@ -0,0 +1,44 @@
+use std::io::Result;
+
+use crate::protocol::encode_command;
+
+/// Commands sent together, the server answers them in order.
+///
+/// Sent with [`Connection::pipeline`](crate::Connection::pipeline), or queued on a
+/// [`NonBlockingConnection`](crate::NonBlockingConnection).
+#[derive(Debug, Clone, Default)]
+pub struct Pipeline {
+    commands: Vec<u8>,
+    len: usize,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Appends a command.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the command can't be encoded, see
+    /// [`encode_command`].
+    pub fn add(&mut self, args: &[&str]) -> Result<&mut Pipeline> {
+        encode_command(args, &mut self.commands)?;
+        self.len += 1;
+        Ok(self)
+    }
+
+    /// Number of commands, and of replies to expect.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub(crate) fn encoded(&self) -> &[u8] {
+        &self.commands
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    io::{self, Result},
+    net::{SocketAddr, ToSocketAddrs},
+    ops::{Deref, DerefMut},
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::connection::Connection;
+
+/// Settings of a [`Pool`].
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// connections open at once, idle or in use
+    pub max_size: usize,
+    /// connections idle for longer are pinged before they are handed out, zero checks them
+    /// every time
+    pub check_after: Duration,
+    /// how long `get` waits for a connection when `max_size` of them are in use
+    pub wait_timeout: Duration,
+    /// bounds connecting, and every read and write
+    pub io_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> PoolConfig {
+        PoolConfig {
+            max_size: 8,
+            check_after: Duration::from_secs(30),
+            wait_timeout: Duration::from_secs(5),
+            io_timeout: Duration::from_secs(5),
+        }
+    }
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// with the time they were returned, the last one returned is handed out first
+    idle: Vec<(Connection, Instant)>,
+    /// idle or in use
+    open: usize,
+}
+
+/// Connections to one server shared between threads.
+///
+/// Connections are opened as needed up to `max_size` and go back to the pool when the
+/// [`PooledConnection`] is dropped, unless a failure broke them. A connection idle for longer
+/// than `check_after` has to answer PING before it is handed out, otherwise it is closed and
+/// another one is tried.
+pub struct Pool {
+    addr: SocketAddr,
+    config: PoolConfig,
+    state: Mutex<PoolState>,
+    /// signaled when a connection is returned or closed
+    returned: Condvar,
+}
+
+impl Pool {
+    /// Creates an empty pool, connections are opened by `get`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if `addr` doesn't resolve.
+    pub fn new(addr: impl ToSocketAddrs, config: PoolConfig) -> Result<Pool> {
+        let addr = addr.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "the address didn't resolve")
+        })?;
+        Ok(Pool {
+            addr,
+            config,
+            state: Mutex::new(PoolState::default()),
+            returned: Condvar::new(),
+        })
+    }
+
+    /// Hands out a healthy connection, opening one if none is idle.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if a connection can't be opened, or of kind
+    /// `TimedOut` if `max_size` connections stay in use for `wait_timeout`.
+    pub fn get(&self) -> Result<PooledConnection<'_>> {
+        let deadline = Instant::now() + self.config.wait_timeout;
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((mut conn, since)) = state.idle.pop() {
+                if since.elapsed() < self.config.check_after {
+                    return Ok(self.hand_out(conn));
+                }
+                // pinged without holding the pool
+                drop(state);
+                if conn.ping().is_ok() {
+                    return Ok(self.hand_out(conn));
+                }
+                state = self.state.lock().unwrap();
+                state.open -= 1;
+                continue;
+            }
+            if state.open < self.config.max_size {
+                state.open += 1;
+                drop(state);
+                return match Connection::connect_timeout(&self.addr, self.config.io_timeout) {
+                    Ok(conn) => Ok(self.hand_out(conn)),
+                    Err(err) => {
+                        self.state.lock().unwrap().open -= 1;
+                        self.returned.notify_one();
+                        Err(err)
+                    }
+                };
+            }
+            let timeout = deadline
+                .checked_duration_since(Instant::now())
+                .filter(|timeout| !timeout.is_zero())
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "no connection of the pool became available in time",
+                    )
+                })?;
+            state = self.returned.wait_timeout(state, timeout).unwrap().0;
+        }
+    }
+
+    /// Connections open, idle or in use, and idle ones.
+    pub fn status(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.open, state.idle.len())
+    }
+
+    fn hand_out(&self, conn: Connection) -> PooledConnection<'_> {
+        PooledConnection {
+            pool: self,
+            conn: Some(conn),
+        }
+    }
+
+    fn put_back(&self, conn: Connection) {
+        let mut state = self.state.lock().unwrap();
+        if conn.is_broken() {
+            state.open -= 1;
+        } else {
+            state.idle.push((conn, Instant::now()));
+        }
+        self.returned.notify_one();
+    }
+}
+
+/// A connection of a [`Pool`], returned to it when dropped.
+pub struct PooledConnection<'a> {
+    pool: &'a Pool,
+    /// only None while dropped
+    conn: Option<Connection>,
+}
+
+impl Deref for PooledConnection<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledConnection<'_> {
+    fn deref_mut(&mut self) -> &mut Connection {
+        self.conn.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            self.pool.put_back(conn);
+        }
+    }
+}
@@ -0,0 +1,205 @@
+use std::io::{self, Result};
+
+use crate::value::Value;
+
+/// Longest bulk string a reply may hold, the server's `proto-max-bulk-len` default.
+const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Most items an array reply may hold.
+const MAX_ARRAY_LEN: usize = i32::MAX as usize;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", msg),
+    )
+}
+
+/// Appends a command to `out` the way the server reads commands: the arguments on one line,
+/// separated by spaces.
+///
+/// # Errors
+///
+/// This function will return an error of kind `InvalidInput` if there is no argument, or if an
+/// argument is empty or holds whitespace, the server couldn't tell the arguments apart.
+pub fn encode_command(args: &[&str], out: &mut Vec<u8>) -> Result<()> {
+    if args.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "a command needs a name",
+        ));
+    }
+    if let Some(arg) = args
+        .iter()
+        .find(|arg| arg.is_empty() || arg.contains(char::is_whitespace))
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("arguments can't be empty or hold whitespace: {:?}", arg),
+        ));
+    }
+    out.extend_from_slice(args.join(" ").as_bytes());
+    out.extend_from_slice(b"\r\n");
+    Ok(())
+}
+
+/// Reads a `\r\n` terminated line starting at `pos`, returning it along with the position right
+/// after the terminator. Returns None when the line is not complete yet.
+fn read_line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let end = buf[pos..].windows(2).position(|window| window == b"\r\n")?;
+    Some((&buf[pos..pos + end], pos + end + 2))
+}
+
+fn read_integer(text: &[u8]) -> Result<i64> {
+    std::str::from_utf8(text)
+        .ok()
+        .and_then(|n| n.parse::<i64>().ok())
+        .ok_or_else(|| invalid("invalid integer"))
+}
+
+/// Reads the length of a bulk string or an array, None for a nil reply.
+fn read_length(text: &[u8], max: usize) -> Result<Option<usize>> {
+    let len = read_integer(text)?;
+    if len < 0 {
+        return Ok(None);
+    }
+    // lengths come from the peer, they can't be trusted to size anything
+    match usize::try_from(len) {
+        Ok(len) if len <= max => Ok(Some(len)),
+        _ => Err(invalid("invalid length")),
+    }
+}
+
+/// Decodes one reply from the start of `buf`.
+///
+/// Returns the reply along with the number of bytes consumed, or None if the buffer does not
+/// hold a complete reply yet.
+///
+/// # Errors
+///
+/// This function will return an error if the bytes are not a valid reply.
+pub fn decode_reply(buf: &[u8]) -> Result<Option<(Value, usize)>> {
+    decode_at(buf, 0)
+}
+
+fn decode_at(buf: &[u8], pos: usize) -> Result<Option<(Value, usize)>> {
+    let (line, next) = match read_line(buf, pos) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let (prefix, rest) = line.split_first().ok_or_else(|| invalid("empty reply"))?;
+    let text = || String::from_utf8_lossy(rest).into_owned();
+    let value = match prefix {
+        b'+' => Value::Simple(text()),
+        b'-' => Value::Error(text()),
+        b':' => Value::Integer(read_integer(rest)?),
+        b'$' => {
+            let len = match read_length(rest, MAX_BULK_LEN)? {
+                Some(len) => len,
+                None => return Ok(Some((Value::Nil, next))),
+            };
+            let end = next
+                .checked_add(len)
+                .ok_or_else(|| invalid("invalid length"))?;
+            let terminator = match buf.get(end..).and_then(|tail| tail.get(..2)) {
+                Some(terminator) => terminator,
+                None => return Ok(None),
+            };
+            if terminator != b"\r\n" {
+                return Err(invalid("bulk string is not terminated"));
+            }
+            let bulk = String::from_utf8_lossy(&buf[next..end]).into_owned();
+            return Ok(Some((Value::Bulk(bulk), end + 2)));
+        }
+        b'*' => {
+            let count = match read_length(rest, MAX_ARRAY_LEN)? {
+                Some(count) => count,
+                None => return Ok(Some((Value::NilArray, next))),
+            };
+            // items are pushed as they are decoded, the count may be a lie
+            let mut items = vec![];
+            let mut pos = next;
+            for _ in 0..count {
+                match decode_at(buf, pos)? {
+                    Some((item, next)) => {
+                        items.push(item);
+                        pos = next;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            return Ok(Some((Value::Array(items), pos)));
+        }
+        prefix => {
+            return Err(invalid(&format!(
+                "unexpected reply type '{}'",
+                *prefix as char
+            )))
+        }
+    };
+    Ok(Some((value, next)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_inline_commands() {
+        let mut out = vec![];
+        encode_command(&["SET", "key", "value"], &mut out).unwrap();
+        encode_command(&["PING"], &mut out).unwrap();
+        assert_eq!(out, b"SET key value\r\nPING\r\n");
+
+        for args in [&[][..], &["SET", "key", "two words"], &["SET", "", "value"]] {
+            let err = encode_command(args, &mut out).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn decodes_every_type() {
+        let buf = b"*7\r\n+OK\r\n-ERR no\r\n:-42\r\n$5\r\na\r\nbc\r\n$-1\r\n*1\r\n*0\r\n*-1\r\n";
+        let (value, consumed) = decode_reply(buf).unwrap().unwrap();
+        assert_eq!(consumed, buf.len());
+        assert_eq!(
+            value,
+            Value::Array(vec![
+                Value::Simple("OK".to_string()),
+                Value::Error("ERR no".to_string()),
+                Value::Integer(-42),
+                Value::Bulk("a\r\nbc".to_string()),
+                Value::Nil,
+                Value::Array(vec![Value::Array(vec![])]),
+                Value::NilArray,
+            ])
+        );
+    }
+
+    #[test]
+    fn waits_for_whole_replies() {
+        let buf = b"*2\r\n$3\r\nabc\r\n:1\r\n+OK\r\n";
+        for end in 0..17 {
+            assert_eq!(decode_reply(&buf[..end]).unwrap(), None, "{} bytes", end);
+        }
+        let (_, consumed) = decode_reply(buf).unwrap().unwrap();
+        assert_eq!(consumed, 17);
+        let (value, _) = decode_reply(&buf[consumed..]).unwrap().unwrap();
+        assert_eq!(value, Value::Simple("OK".to_string()));
+    }
+
+    #[test]
+    fn rejects_invalid_replies() {
+        let huge = [
+            &b"*9223372036854775807\r\n"[..],
+            b"*4294967296\r\n",
+            b"$9223372036854775807\r\n",
+            b"$18446744073709551615\r\n",
+            b"$1073741824\r\n",
+        ];
+        let malformed = [&b"hello\r\n"[..], b"\r\n", b":one\r\n", b"$3\r\nabcd\r\n"];
+        for buf in huge.into_iter().chain(malformed) {
+            let err = decode_reply(buf).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+    }
+}
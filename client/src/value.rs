@@ -0,0 +1,46 @@
+use std::io::{self, Result};
+
+/// A reply of the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Nil,
+    Array(Vec<Value>),
+    NilArray,
+}
+
+impl Value {
+    pub fn is_error(&self) -> bool {
+        matches!(self, Value::Error(_))
+    }
+
+    /// Text of a simple or bulk string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Simple(text) | Value::Bulk(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    /// Turns an error reply into an error carrying its message.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error of kind `Other` if the reply is an error.
+    pub fn into_result(self) -> Result<Value> {
+        match self {
+            Value::Error(msg) => Err(io::Error::other(msg)),
+            value => Ok(value),
+        }
+    }
+}
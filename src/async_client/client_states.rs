@@ -4,6 +4,8 @@ pub enum ClientStates {
     ReadCommand,
     RunningCommand,
     WriteOutput(String),
+    /// the connection is a replica, it only receives the replication stream
+    Replica,
     ToBeClosed,
     Close,
     Closed,
@@ -1,42 +1,101 @@
 use std::{
-    io::{self, BufRead, BufReader, Write},
-    os::fd::{AsFd, AsRawFd},
-    sync::{Arc, Mutex, RwLock},
+    io,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
     thread::JoinHandle,
 };
 
-use mio::net::TcpStream;
+use mio::{net::TcpStream, Interest};
 
 use crate::{
-    command::core::get_and_run_cmd,
-    reactor::{core::Reactor, event_listener::EventListener},
+    async_server::listener::ListenerPolicy,
+    command::core::{find_keyspace_spec, get_and_run_cmd, parse_args, spawn_worker},
+    protocol::reply::Reply,
+    reactor::{core::Reactor, event::Event, event_listener::EventListener},
+    replication::{link::ReplicaLink, primary::psync},
+    store::core::Store,
 };
 
-use super::client_states::ClientStates;
+use super::{
+    client_states::ClientStates,
+    session::Session,
+    stream::ClientStream,
+    transaction::{Transaction, TxAction},
+};
+
+/// Bytes read from a connection per poll, a client flooding its connection can't hold the event
+/// loop for longer. Reading goes on when the buffered commands are done.
+pub const READ_BUDGET: usize = 64 * 1024;
+
+/// Longest command a client may send, a connection buffering more without a line break is
+/// answered with an error and closed.
+pub const MAX_QUERY_BUFFER: usize = 1024 * 1024;
+
+/// Id of the next connection. Tokens are only unique within the reactor of a shard, the store
+/// and the replication state shared by the shards know clients by this id.
+static NEXT_CLIENT_ID: AtomicUsize = AtomicUsize::new(1);
 
-pub struct AsyncClientHandler {
-    client: TcpStream,
+/// Serves the commands of one client connection, plain TCP unless another stream is given.
+pub struct AsyncClientHandler<S: ClientStream = TcpStream> {
+    client: S,
     reactor: Arc<RwLock<Reactor>>,
+    db: Arc<RwLock<Store>>,
     state: Arc<Mutex<Option<ClientStates>>>,
     fd: usize,
+    /// unique in the process, unlike the token of the connection
+    client_id: usize,
     name: String,
     command: Option<JoinHandle<()>>,
-    is_writeable: bool,
+    transaction: Transaction,
+    session: Session,
+    /// policy of the listener that accepted the connection
+    policy: Arc<ListenerPolicy>,
+    /// set once the connection turned into a replica with PSYNC
+    replica: Option<Arc<ReplicaLink>>,
+    /// events the connection is registered for, None once it is deregistered
+    interest: Option<Interest>,
+    /// reply bytes the socket did not take yet, written on the next writable event
+    output: Vec<u8>,
+    /// bytes read from the connection that don't make a whole command yet
+    input: Vec<u8>,
+    /// set by a readable event until a read would block, events are edge triggered
+    readable: bool,
+    /// the client closed its end, the commands left in `input` are still served
+    eof: bool,
 }
 
-impl AsyncClientHandler {
-    pub fn new(client: TcpStream, reactor: Arc<RwLock<Reactor>>) -> AsyncClientHandler {
-        let fd = client.as_fd().as_raw_fd() as usize;
-        let name = client.peer_addr().unwrap().to_string();
+impl<S: ClientStream> AsyncClientHandler<S> {
+    pub fn new(
+        client: S,
+        token: usize,
+        policy: Arc<ListenerPolicy>,
+        reactor: Arc<RwLock<Reactor>>,
+        db: Arc<RwLock<Store>>,
+    ) -> AsyncClientHandler<S> {
+        let name = client.peer_name();
         // let client_rc = Rc::new(RefCell::new(client));
         AsyncClientHandler {
             client,
             reactor,
+            db,
             state: Arc::new(Mutex::new(None)),
-            fd,
+            fd: token,
+            client_id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
             name,
             command: None,
-            is_writeable: false,
+            transaction: Transaction::default(),
+            // authenticated for real in initalize, the store can't be locked here as the
+            // handler is created while the reactor is held
+            session: Session::new(None, policy.stats().clone()),
+            policy,
+            replica: None,
+            interest: None,
+            output: vec![],
+            input: vec![],
+            readable: false,
+            eof: false,
         }
     }
 
@@ -49,11 +108,16 @@ impl AsyncClientHandler {
             "initalize is called for state {:?}",
             self.state.lock().unwrap()
         );
+        let user = self.policy.initial_user(self.db.read().unwrap().auth());
+        self.session = Session::new(user, self.policy.stats().clone());
         self.update_state(ClientStates::Waiting);
         {
             let mut reactor = self.reactor.write().unwrap();
 
-            reactor.register(self.id(), &mut self.client, None).unwrap();
+            reactor
+                .register(self.id(), &self.client, Some(Interest::READABLE))
+                .unwrap();
+            self.interest = Some(Interest::READABLE);
 
             // reactor.schedule(self.id());
         }
@@ -62,26 +126,36 @@ impl AsyncClientHandler {
         let mut schedule_evnt = true;
         println!("read is called for state {:?}", self.state.lock().unwrap());
 
-        let mut buf_reader = BufReader::new(&self.client);
-        let mut raw_cmd = String::new();
-        match buf_reader.read_line(&mut raw_cmd) {
-            Ok(_) => {
-                if let Some(handler) = {
-                    let waker = self.reactor.write().unwrap().get_waker_for_fd().clone();
-                    get_and_run_cmd(
-                        self.id(),
-                        raw_cmd,
-                        self.state.clone(),
-                        waker,
-                        self.reactor.clone(),
-                    )
-                } {
-                    self.command = Some(handler);
-                    self.update_state(ClientStates::RunningCommand);
-                    schedule_evnt = false;
+        let raw_cmd = match self.next_line() {
+            Ok(Some(raw_cmd)) => raw_cmd,
+            Ok(None) => {
+                if self.eof {
+                    self.update_state(ClientStates::ToBeClosed);
+                } else if self.readable {
+                    // the read budget ran out before a whole command came in
+                    self.update_state(ClientStates::ReadCommand);
                 } else {
-                    self.update_state(ClientStates::WriteOutput("invalid command".to_string()));
+                    // the readable event only carried part of a command or protocol data,
+                    // such as a TLS handshake
+                    self.update_state(ClientStates::Waiting);
+                    schedule_evnt = false;
+                }
+                if schedule_evnt {
+                    let mut reactor = self.reactor.write().unwrap();
+                    reactor.schedule(self.id());
                 }
+                return;
+            }
+            Err(err) if err.kind() == io::ErrorKind::InvalidData => {
+                println!("client {} sent {}, closing it", self.name(), err);
+                // nothing more is read, the buffered bytes go with the connection
+                self.input.clear();
+                self.session.quit();
+                let reply = Reply::error(format!("ERR Protocol error: {}", err));
+                self.update_state(ClientStates::WriteOutput(reply.encode()));
+                let mut reactor = self.reactor.write().unwrap();
+                reactor.schedule(self.id());
+                return;
             }
             Err(err) => {
                 println!("client {} exiting due to  {}", self.name(), err);
@@ -90,9 +164,62 @@ impl AsyncClientHandler {
                 }
 
                 self.update_state(ClientStates::ToBeClosed);
+                let mut reactor = self.reactor.write().unwrap();
+                reactor.schedule(self.id());
+                return;
             }
         };
 
+        let args = parse_args(&raw_cmd);
+        if self.answered_by_session(&args) {
+            // the session queued the reply
+        } else if is_psync(&args) {
+            self.start_replica(&args);
+            schedule_evnt = false;
+        } else if self.rejects_write(&args) {
+            let reply = self.transaction.reject(Reply::read_only());
+            self.update_state(ClientStates::WriteOutput(reply.encode()));
+        } else {
+            match self.transaction.intercept(self.client_id, &args) {
+                TxAction::Run => {
+                    // set before the worker starts, it replaces the state once done
+                    self.update_state(ClientStates::RunningCommand);
+                    if let Some(handler) = {
+                        let completions = self.reactor.read().unwrap().completions();
+                        get_and_run_cmd(
+                            self.id(),
+                            raw_cmd,
+                            self.state.clone(),
+                            completions,
+                            self.reactor.clone(),
+                            self.db.clone(),
+                            self.policy.stats().clone(),
+                        )
+                    } {
+                        self.command = Some(handler);
+                        schedule_evnt = false;
+                    } else {
+                        let reply = Reply::error("ERR unknown command");
+                        self.update_state(ClientStates::WriteOutput(reply.encode()));
+                    }
+                }
+                TxAction::Reply(reply) => {
+                    self.update_state(ClientStates::WriteOutput(reply.encode()));
+                }
+                TxAction::Store(work) => {
+                    self.update_state(ClientStates::RunningCommand);
+                    let completions = self.reactor.read().unwrap().completions();
+                    let db = self.db.clone();
+                    let handler =
+                        spawn_worker(self.id(), self.state.clone(), completions, move || {
+                            work(&mut db.write().unwrap()).encode()
+                        });
+                    self.command = Some(handler);
+                    schedule_evnt = false;
+                }
+            }
+        }
+
         if schedule_evnt {
             {
                 let mut reactor = self.reactor.write().unwrap();
@@ -100,28 +227,199 @@ impl AsyncClientHandler {
             }
         }
     }
+
+    /// Takes the next command out of the input, reading from the connection when no whole
+    /// command is buffered. Returns None when the command didn't fully arrive yet, the last
+    /// line is taken as is once the client closed the connection.
+    ///
+    /// Fails with `InvalidData` once more than `MAX_QUERY_BUFFER` bytes are buffered without a
+    /// whole command.
+    fn next_line(&mut self) -> io::Result<Option<String>> {
+        if !self.input.contains(&b'\n') {
+            self.fill_input()?;
+        }
+        let end = match self.input.iter().position(|byte| *byte == b'\n') {
+            Some(end) => end + 1,
+            None if self.eof && !self.input.is_empty() => self.input.len(),
+            None if self.input.len() > MAX_QUERY_BUFFER => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "too big inline request",
+                ))
+            }
+            None => return Ok(None),
+        };
+        let line: Vec<u8> = self.input.drain(..end).collect();
+        Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+    }
+
+    /// Reads from the connection until it would block or `READ_BUDGET` bytes were read.
+    fn fill_input(&mut self) -> io::Result<()> {
+        let mut buf = [0; 4096];
+        let mut read = 0;
+        while self.readable && read < READ_BUDGET {
+            match self.client.read(&mut buf) {
+                Ok(0) => {
+                    self.readable = false;
+                    self.eof = true;
+                }
+                Ok(n) => {
+                    self.input.extend_from_slice(&buf[..n]);
+                    read += n;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => self.readable = false,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves on to the next command once a reply is written, it is read right away when it is
+    /// already buffered or the connection may hold more. Returns true if the handler has to be
+    /// scheduled.
+    fn await_command(&mut self) -> bool {
+        if self.readable || self.eof || self.input.contains(&b'\n') {
+            self.update_state(ClientStates::ReadCommand);
+            true
+        } else {
+            self.update_state(ClientStates::Waiting);
+            false
+        }
+    }
+
+    /// Lets the session answer connection level commands and refuse commands from
+    /// unauthenticated connections, returns true if a reply was queued.
+    fn answered_by_session(&mut self, args: &[String]) -> bool {
+        match self.session.intercept(self.client_id, &self.db, args) {
+            Some(reply) => {
+                // a refused command can't be queued, the transaction is discarded on EXEC
+                let reply = if reply.is_error() {
+                    self.transaction.reject(reply)
+                } else {
+                    reply
+                };
+                self.update_state(ClientStates::WriteOutput(reply.encode()));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns true for a write command sent to a read only replica.
+    fn rejects_write(&self, args: &[String]) -> bool {
+        find_keyspace_spec(args).is_some_and(|spec| spec.is_write()) && {
+            let db = self.db.read().unwrap();
+            db.replication()
+                .is_some_and(|replication| replication.rejects_writes())
+        }
+    }
+
+    /// Turns the connection into a replica following the replication stream.
+    fn start_replica(&mut self, args: &[String]) {
+        let completions = self.reactor.read().unwrap().completions();
+        let output_limit = self
+            .db
+            .read()
+            .unwrap()
+            .replication()
+            .map_or(0, |replication| replication.output_limit());
+        let link = Arc::new(ReplicaLink::new(
+            self.id(),
+            self.client_id,
+            self.name(),
+            output_limit,
+            completions,
+        ));
+        match psync(self.db.clone(), link.clone(), args) {
+            Ok(_) => {
+                // acknowledgements the replica already sent
+                link.receive(&self.input);
+                self.input.clear();
+                self.replica = Some(link);
+                self.update_state(ClientStates::Replica);
+            }
+            Err(reply) => {
+                self.update_state(ClientStates::WriteOutput(reply.encode()));
+                let mut reactor = self.reactor.write().unwrap();
+                reactor.schedule(self.id());
+            }
+        }
+    }
+
+    /// Writes the pending part of the replication stream.
+    pub fn write_replica_stream(&mut self) {
+        let link = match self.replica.clone() {
+            Some(link) => link,
+            None => return,
+        };
+        if link.is_disconnected() {
+            self.update_state(ClientStates::ToBeClosed);
+            let mut reactor = self.reactor.write().unwrap();
+            reactor.schedule(self.id());
+            return;
+        }
+
+        // the stream stays with the link while the socket is full, the link limits it
+        if self.output.is_empty() {
+            self.output = link.take_pending();
+        }
+        if let Err(err) = self.write_output() {
+            println!("replica {} faced error {}", self.name(), err);
+            self.update_state(ClientStates::ToBeClosed);
+            let mut reactor = self.reactor.write().unwrap();
+            reactor.schedule(self.id());
+            return;
+        }
+        // the rest goes out on the next writable event
+        self.update_state(ClientStates::Replica);
+    }
+
+    /// Reads the acknowledgements sent by the replica, returns false once it closed the
+    /// connection or the link disconnected it.
+    fn read_replica_input(&mut self) -> bool {
+        let link = match self.replica.clone() {
+            Some(link) => link,
+            None => return false,
+        };
+        let mut buf = [0; 1024];
+        loop {
+            match self.client.read(&mut buf) {
+                Ok(0) => return false,
+                Ok(n) => {
+                    link.receive(&buf[..n]);
+                    if link.is_disconnected() {
+                        return false;
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
     pub fn write_command(&mut self, output: String) {
         if let Some(handler) = self.command.take() {
             handler.join().unwrap();
         }
         println!("write is called for state {:?}", self.state.lock().unwrap());
 
-        let buff_len = output.len();
+        // sent after what an earlier write left
+        self.output.extend_from_slice(output.as_bytes());
         let mut schedule_evnt = true;
 
-        match self.client.write(&output.to_string().into_bytes()) {
-            Ok(n) if n < buff_len => {
-                self.update_state(ClientStates::WriteOutput(output));
+        match self.write_output() {
+            Ok(_) if !self.output.is_empty() => {
+                // the rest is in `output`, the writable event schedules the handler again
+                self.update_state(ClientStates::WriteOutput(String::new()));
+                schedule_evnt = false;
             }
-            Err(err)
-                if err.kind() == io::ErrorKind::WouldBlock
-                    || err.kind() == io::ErrorKind::Interrupted =>
-            {
-                self.update_state(ClientStates::WriteOutput(output));
+            Ok(_) if self.session.is_quitting() => {
+                self.update_state(ClientStates::ToBeClosed);
             }
             Ok(_) => {
-                self.update_state(ClientStates::Waiting);
-                schedule_evnt = false;
+                schedule_evnt = self.await_command();
             }
             Err(err) => {
                 let name = self.name();
@@ -135,6 +433,49 @@ impl AsyncClientHandler {
             reactor.schedule(self.id());
         }
     }
+
+    /// Writes the pending output until the socket would block.
+    fn write_output(&mut self) -> io::Result<()> {
+        let mut written = 0;
+        while written < self.output.len() {
+            match self.client.write(&self.output[written..]) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => written += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        self.output.drain(..written);
+        Ok(())
+    }
+
+    /// Registers the connection for the events it waits on: writable only while output is
+    /// pending, readable unless the client doesn't read its replies, in which case its commands
+    /// wait until they are written. A replica is always read, its acknowledgements don't wait
+    /// on the stream.
+    fn update_interest(&mut self) {
+        let current = match self.interest {
+            Some(interest) => interest,
+            None => return,
+        };
+        let writable = !self.output.is_empty() || self.client.has_pending_output();
+        let interest = match (writable, self.replica.is_some()) {
+            (false, _) => Interest::READABLE,
+            (true, false) => Interest::WRITABLE,
+            (true, true) => Interest::READABLE.add(Interest::WRITABLE),
+        };
+        if interest == current {
+            return;
+        }
+        // data that came in while reading was paused is reported once readable is back
+        let mut reactor = self.reactor.write().unwrap();
+        match reactor.reregister(self.id(), &self.client, interest) {
+            Ok(_) => self.interest = Some(interest),
+            Err(err) => println!("client {} faced error {}", self.name, err),
+        }
+    }
+
     pub fn to_be_closed(&mut self) {
         if let Some(handler) = self.command.take() {
             handler.join().unwrap();
@@ -156,13 +497,30 @@ impl AsyncClientHandler {
         if let Some(handler) = self.command.take() {
             handler.join().unwrap();
         }
+        {
+            let mut db = self.db.write().unwrap();
+            db.unwatch(self.client_id, self.transaction.watched_keys());
+            if let (Some(_), Some(replication)) = (self.replica.take(), db.replication_mut()) {
+                replication.remove_replica(self.client_id);
+            }
+        }
+
+        self.policy.release();
 
+        self.interest = None;
+        // tasks and events still queued for the handler find it closed, not to be initialized
+        self.update_state(ClientStates::Closed);
         let mut reactor = self.reactor.write().unwrap();
-        reactor.remove_old_connection(self.id(), &mut self.client);
+        reactor.remove_old_connection(self.id(), &self.client);
     }
 }
 
-impl EventListener for AsyncClientHandler {
+fn is_psync(args: &[String]) -> bool {
+    args.first()
+        .is_some_and(|name| name.eq_ignore_ascii_case("PSYNC"))
+}
+
+impl<S: ClientStream> EventListener for AsyncClientHandler<S> {
     fn id(&self) -> usize {
         self.fd
     }
@@ -185,6 +543,9 @@ impl EventListener for AsyncClientHandler {
             Some(ClientStates::WriteOutput(output)) => {
                 self.write_command(output);
             }
+            Some(ClientStates::Replica) => {
+                self.write_replica_stream();
+            }
             Some(ClientStates::ToBeClosed) => {
                 self.to_be_closed();
             }
@@ -195,17 +556,27 @@ impl EventListener for AsyncClientHandler {
                 self.update_state(state);
             }
         };
+        self.update_interest();
         println!("current client state {:?}", self.state.lock().unwrap());
         Ok(())
     }
 
-    fn handle_event(&mut self, event: &mio::event::Event) {
+    fn handle_event(&mut self, event: &Event) {
         println!(
             "current client state {:?} from handle_event client",
             self.state.lock().unwrap()
         );
         if event.is_readable() {
-            let state = self.state.lock().unwrap().take();
+            self.readable = true;
+            // a worker may replace the state of a running command meanwhile, the state is only
+            // taken when the event moves it on
+            let state = {
+                let mut state = self.state.lock().unwrap();
+                match *state {
+                    Some(ClientStates::Waiting) | Some(ClientStates::Replica) => state.take(),
+                    _ => None,
+                }
+            };
             match state {
                 Some(ClientStates::Waiting) => {
                     let name = self.name();
@@ -219,15 +590,34 @@ impl EventListener for AsyncClientHandler {
                         reactor.schedule(self.id());
                     }
                 }
-                Some(state) => self.update_state(state),
+                Some(ClientStates::Replica) => {
+                    if !self.read_replica_input() {
+                        println!("replica {} closed the connection", self.name());
+                        self.update_state(ClientStates::ToBeClosed);
+                    } else {
+                        self.update_state(ClientStates::Replica);
+                    }
+                    let mut reactor = self.reactor.write().unwrap();
+                    reactor.schedule(self.id());
+                }
                 _ => {}
             }
         }
-        if event.is_writable() {
-            self.is_writeable = true;
-        }
-        if event.is_write_closed() {
-            self.is_writeable = false;
+        // a peer that went away shows up as the pending write failing
+        if event.is_writable() || event.is_write_closed() {
+            // sends what the stream could not write earlier, such as pending TLS records
+            if let Err(err) = self.client.flush() {
+                println!("client {} faced error {}", self.name(), err);
+            }
+            let blocked = matches!(
+                *self.state.lock().unwrap(),
+                Some(ClientStates::WriteOutput(_)) | Some(ClientStates::Replica)
+            );
+            if blocked && !self.output.is_empty() {
+                let mut reactor = self.reactor.write().unwrap();
+                reactor.schedule(self.id());
+            }
+            self.update_interest();
         }
     }
 }
@@ -1,2 +1,5 @@
 pub mod client_states;
 pub mod core;
+pub mod session;
+pub mod stream;
+pub mod transaction;
@@ -0,0 +1,202 @@
+use std::sync::{Arc, RwLock};
+
+use crate::{
+    auth::core::Denial,
+    command::{acl, core::CommandSpec},
+    protocol::reply::Reply,
+    stats::core::{self as stats, Stats},
+    store::core::Store,
+};
+
+pub const SPECS: &[CommandSpec] = &[
+    CommandSpec::new("AUTH", -2, 0).no_keys(),
+    CommandSpec::new("HELLO", -1, 0).no_keys(),
+    CommandSpec::new("QUIT", 1, 0).no_keys(),
+];
+
+/// Per connection authentication state along with the connection level commands: AUTH, HELLO
+/// and QUIT.
+///
+/// Every other command is checked against the ACL rules of the authenticated user.
+pub struct Session {
+    /// user the connection is authenticated as
+    user: Option<String>,
+    /// set by QUIT, the connection is closed once the reply is written
+    quitting: bool,
+    stats: Arc<Stats>,
+}
+
+impl Session {
+    pub fn new(user: Option<String>, stats: Arc<Stats>) -> Session {
+        Session {
+            user,
+            quitting: false,
+            stats,
+        }
+    }
+
+    pub fn is_quitting(&self) -> bool {
+        self.quitting
+    }
+
+    /// Closes the connection once the reply queued last is written.
+    pub fn quit(&mut self) {
+        self.quitting = true;
+    }
+
+    /// Handles the connection level commands and ACL, refuses every other command until the
+    /// connection is authenticated and then unless the user is allowed to run it. Returns None
+    /// when the command can run.
+    pub fn intercept(
+        &mut self,
+        client: usize,
+        db: &Arc<RwLock<Store>>,
+        args: &[String],
+    ) -> Option<Reply> {
+        let name = args.first()?.to_uppercase();
+        match name.as_str() {
+            "AUTH" => Some(self.auth(db, args)),
+            "HELLO" => Some(self.hello(client, db, args)),
+            "QUIT" => {
+                self.quit();
+                Some(Reply::ok())
+            }
+            _ => self.check_permissions(db, args),
+        }
+    }
+
+    fn check_permissions(&mut self, db: &Arc<RwLock<Store>>, args: &[String]) -> Option<Reply> {
+        let user = match &self.user {
+            Some(user) => user.to_string(),
+            None => return Some(self.noauth()),
+        };
+        let permitted = db.read().unwrap().auth().permits(&user, args);
+        let denial = match permitted {
+            Ok(_) if args[0].eq_ignore_ascii_case("ACL") => {
+                return Some(acl::execute(&mut db.write().unwrap(), &user, args));
+            }
+            Ok(_) => return None,
+            Err(denial) => denial,
+        };
+
+        let stats = &self.stats;
+        Some(match denial {
+            Denial::User => {
+                // the user was deleted or disabled, the connection has to authenticate again
+                self.user = None;
+                stats::bump(&stats.noauth_rejections);
+                Reply::error("NOAUTH Authentication required.")
+            }
+            Denial::Command => {
+                stats::bump(&stats.acl_denied_commands);
+                Reply::error(format!(
+                    "NOPERM User {} has no permissions to run the '{}' command",
+                    user,
+                    args[0].to_lowercase()
+                ))
+            }
+            Denial::Key => {
+                stats::bump(&stats.acl_denied_keys);
+                Reply::error("NOPERM No permissions to access a key")
+            }
+            Denial::Channel => {
+                stats::bump(&stats.acl_denied_channels);
+                Reply::error("NOPERM No permissions to access a channel")
+            }
+        })
+    }
+
+    fn noauth(&self) -> Reply {
+        stats::bump(&self.stats.noauth_rejections);
+        Reply::error("NOAUTH Authentication required.")
+    }
+
+    /// `AUTH [username] password`
+    fn auth(&mut self, db: &Arc<RwLock<Store>>, args: &[String]) -> Reply {
+        let (username, password) = match args.len() {
+            2 => ("default", args[1].as_str()),
+            3 => (args[1].as_str(), args[2].as_str()),
+            _ => return Reply::wrong_arity("auth"),
+        };
+        if args.len() == 2 && !db.read().unwrap().auth().requires_auth() {
+            return Reply::error(
+                "ERR AUTH <password> called without any password configured for the default \
+                 user. Are you sure your configuration is correct?",
+            );
+        }
+        match self.login(db, username, password) {
+            Ok(_) => Reply::ok(),
+            Err(reply) => reply,
+        }
+    }
+
+    fn login(
+        &mut self,
+        db: &Arc<RwLock<Store>>,
+        username: &str,
+        password: &str,
+    ) -> Result<(), Reply> {
+        if !db.read().unwrap().auth().check(username, password) {
+            stats::bump(&self.stats.auth_failures);
+            return Err(Reply::error(
+                "WRONGPASS invalid username-password pair or user is disabled.",
+            ));
+        }
+        self.user = Some(username.to_string());
+        Ok(())
+    }
+
+    /// `HELLO [protover [AUTH username password]]`, only RESP2 is supported.
+    fn hello(&mut self, client: usize, db: &Arc<RwLock<Store>>, args: &[String]) -> Reply {
+        if let Some(protover) = args.get(1) {
+            match protover.parse::<i64>() {
+                Ok(2) => {}
+                Ok(_) => return Reply::error("NOPROTO unsupported protocol version"),
+                Err(_) => {
+                    return Reply::error("ERR Protocol version is not an integer or out of range")
+                }
+            }
+        }
+        let mut options = args.iter().skip(2);
+        while let Some(option) = options.next() {
+            if !option.eq_ignore_ascii_case("AUTH") {
+                return Reply::syntax_error();
+            }
+            let (username, password) = match (options.next(), options.next()) {
+                (Some(username), Some(password)) => (username, password),
+                _ => return Reply::syntax_error(),
+            };
+            if let Err(reply) = self.login(db, username, password) {
+                return reply;
+            }
+        }
+        if self.user.is_none() {
+            return Reply::error(
+                "NOAUTH HELLO must be called with the client already authenticated, otherwise \
+                 the HELLO <proto> AUTH <user> <pass> option can be used to authenticate the \
+                 client and select the RESP protocol version at the same time",
+            );
+        }
+
+        let role = match db.read().unwrap().replication() {
+            Some(replication) if !replication.is_primary() => "replica",
+            _ => "master",
+        };
+        Reply::Array(vec![
+            Reply::Bulk("server".to_string()),
+            Reply::Bulk(env!("CARGO_PKG_NAME").to_string()),
+            Reply::Bulk("version".to_string()),
+            Reply::Bulk(env!("CARGO_PKG_VERSION").to_string()),
+            Reply::Bulk("proto".to_string()),
+            Reply::Integer(2),
+            Reply::Bulk("id".to_string()),
+            Reply::Integer(client as i64),
+            Reply::Bulk("mode".to_string()),
+            Reply::Bulk("standalone".to_string()),
+            Reply::Bulk("role".to_string()),
+            Reply::Bulk(role.to_string()),
+            Reply::Bulk("modules".to_string()),
+            Reply::Array(vec![]),
+        ])
+    }
+}
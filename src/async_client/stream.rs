@@ -0,0 +1,50 @@
+use std::{
+    io::{Read, Write},
+    os::fd::AsRawFd,
+};
+
+use mio::net::{TcpStream, UnixStream};
+
+use crate::tls::stream::TlsStream;
+
+/// A connection the client handler can serve.
+pub trait ClientStream: Read + Write + AsRawFd + Send + Sync + 'static {
+    /// Address of the peer, used to name the connection.
+    fn peer_name(&self) -> String;
+
+    /// Returns true while the stream holds output it could not hand to the socket yet.
+    fn has_pending_output(&self) -> bool {
+        false
+    }
+}
+
+impl ClientStream for TcpStream {
+    fn peer_name(&self) -> String {
+        self.peer_addr()
+            .map_or_else(|_| "unknown".to_string(), |addr| addr.to_string())
+    }
+}
+
+impl ClientStream for UnixStream {
+    fn peer_name(&self) -> String {
+        // clients rarely bind their end of the socket, the fd tells them apart
+        let path = self
+            .peer_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()));
+        match path {
+            Some(path) => format!("unix:{}", path),
+            None => format!("unix:fd{}", self.as_raw_fd()),
+        }
+    }
+}
+
+impl<S: ClientStream> ClientStream for TlsStream<S> {
+    fn peer_name(&self) -> String {
+        self.get_ref().peer_name()
+    }
+
+    fn has_pending_output(&self) -> bool {
+        self.wants_write()
+    }
+}
@@ -0,0 +1,329 @@
+use crate::{
+    command::core::{execute_keyspace, find_keyspace_spec, CommandSpec, READONLY},
+    protocol::reply::Reply,
+    store::core::Store,
+};
+
+pub const SPECS: &[CommandSpec] = &[
+    CommandSpec::new("MULTI", 1, 0).no_keys(),
+    CommandSpec::new("EXEC", 1, 0).no_keys(),
+    CommandSpec::new("DISCARD", 1, 0).no_keys(),
+    CommandSpec::new("WATCH", -2, READONLY).keys(1, -1),
+    CommandSpec::new("UNWATCH", 1, 0).no_keys(),
+];
+
+/// Work that has to run against the store on a worker thread.
+pub type StoreWork = Box<dyn FnOnce(&mut Store) -> Reply + Send>;
+
+/// What the client handler should do with a command after the transaction state has seen it.
+pub enum TxAction {
+    /// Not a transaction command, run it as usual.
+    Run,
+    /// Reply right away without touching the store.
+    Reply(Reply),
+    /// Run on a worker thread while holding the store lock.
+    Store(StoreWork),
+}
+
+/// Per connection MULTI/EXEC state along with the keys watched by the connection.
+#[derive(Default)]
+pub struct Transaction {
+    /// commands queued since MULTI, None outside of a transaction
+    queued: Option<Vec<Vec<String>>>,
+    /// set when a command could not be queued, EXEC then discards the transaction
+    aborted: bool,
+    watched: Vec<String>,
+}
+
+impl Transaction {
+    pub fn watched_keys(&self) -> &[String] {
+        &self.watched
+    }
+
+    /// Inspects a command, queueing it when inside MULTI and handling the transaction commands
+    /// themselves.
+    pub fn intercept(&mut self, client: usize, args: &[String]) -> TxAction {
+        let name = match args.first() {
+            Some(name) => name.to_uppercase(),
+            None => return TxAction::Run,
+        };
+
+        match name.as_str() {
+            "MULTI" => self.multi(args),
+            "EXEC" => self.exec(client, args),
+            "DISCARD" => self.discard(client, args),
+            "WATCH" => self.watch(client, args),
+            "UNWATCH" => self.unwatch(client, args),
+            _ if self.queued.is_some() => self.queue(args),
+            _ => TxAction::Run,
+        }
+    }
+
+    /// Returns the error for a command rejected before reaching the transaction, a transaction
+    /// being queued is aborted like for any command that could not be queued.
+    pub fn reject(&mut self, reply: Reply) -> Reply {
+        if self.queued.is_some() {
+            self.aborted = true;
+        }
+        reply
+    }
+
+    fn multi(&mut self, args: &[String]) -> TxAction {
+        if args.len() != 1 {
+            return TxAction::Reply(Reply::wrong_arity("multi"));
+        }
+        if self.queued.is_some() {
+            return TxAction::Reply(Reply::error("ERR MULTI calls can not be nested"));
+        }
+        self.queued = Some(vec![]);
+        self.aborted = false;
+        TxAction::Reply(Reply::ok())
+    }
+
+    fn queue(&mut self, args: &[String]) -> TxAction {
+        let error = match find_keyspace_spec(args) {
+            Some(spec) => spec.check_arity(args).err(),
+            None => Some(Reply::error(format!(
+                "ERR '{}' can not be used inside MULTI",
+                args[0].to_lowercase()
+            ))),
+        };
+        if let Some(err) = error {
+            self.aborted = true;
+            return TxAction::Reply(err);
+        }
+        if let Some(queued) = self.queued.as_mut() {
+            queued.push(args.to_vec());
+        }
+        TxAction::Reply(Reply::Simple("QUEUED".to_string()))
+    }
+
+    fn exec(&mut self, client: usize, args: &[String]) -> TxAction {
+        if args.len() != 1 {
+            return TxAction::Reply(Reply::wrong_arity("exec"));
+        }
+        let queued = match self.queued.take() {
+            Some(queued) => queued,
+            None => return TxAction::Reply(Reply::error("ERR EXEC without MULTI")),
+        };
+        let watched = std::mem::take(&mut self.watched);
+
+        if self.aborted {
+            self.aborted = false;
+            return TxAction::Store(Box::new(move |store| {
+                store.unwatch(client, &watched);
+                Reply::error("EXECABORT Transaction discarded because of previous errors.")
+            }));
+        }
+
+        TxAction::Store(Box::new(move |store| {
+            // the store lock is held for the whole batch so no other command can interleave
+            let dirty = store.is_dirty(client);
+            store.unwatch(client, &watched);
+            if dirty {
+                return Reply::NilArray;
+            }
+            let writes = queued
+                .iter()
+                .any(|args| find_keyspace_spec(args).is_some_and(|spec| spec.is_write()));
+            // the server may have become a replica since the commands were queued
+            if writes
+                && store
+                    .replication()
+                    .is_some_and(|replication| replication.rejects_writes())
+            {
+                return Reply::read_only();
+            }
+            // wrap the writes so that replaying the AOF applies the batch as a whole
+            if writes {
+                store.propagate(&["MULTI".to_string()]);
+            }
+            let replies = queued
+                .iter()
+                .map(|args| {
+                    execute_keyspace(store, args)
+                        .unwrap_or_else(|| Reply::error("ERR unknown command"))
+                })
+                .collect();
+            if writes {
+                store.propagate(&["EXEC".to_string()]);
+            }
+            Reply::Array(replies)
+        }))
+    }
+
+    fn discard(&mut self, client: usize, args: &[String]) -> TxAction {
+        if args.len() != 1 {
+            return TxAction::Reply(Reply::wrong_arity("discard"));
+        }
+        if self.queued.take().is_none() {
+            return TxAction::Reply(Reply::error("ERR DISCARD without MULTI"));
+        }
+        self.aborted = false;
+        let watched = std::mem::take(&mut self.watched);
+        TxAction::Store(Box::new(move |store| {
+            store.unwatch(client, &watched);
+            Reply::ok()
+        }))
+    }
+
+    fn watch(&mut self, client: usize, args: &[String]) -> TxAction {
+        if args.len() < 2 {
+            return TxAction::Reply(Reply::wrong_arity("watch"));
+        }
+        if self.queued.is_some() {
+            return TxAction::Reply(Reply::error("ERR WATCH inside MULTI is not allowed"));
+        }
+        let mut keys: Vec<String> = vec![];
+        for key in args[1..].iter() {
+            if !self.watched.contains(key) && !keys.contains(key) {
+                keys.push(key.to_string());
+            }
+        }
+        self.watched.extend(keys.iter().cloned());
+        TxAction::Store(Box::new(move |store| {
+            for key in keys.iter() {
+                store.watch(client, key);
+            }
+            Reply::ok()
+        }))
+    }
+
+    fn unwatch(&mut self, client: usize, args: &[String]) -> TxAction {
+        if args.len() != 1 {
+            return TxAction::Reply(Reply::wrong_arity("unwatch"));
+        }
+        if self.queued.is_some() {
+            return TxAction::Reply(Reply::error("ERR UNWATCH inside MULTI is not allowed"));
+        }
+        let watched = std::mem::take(&mut self.watched);
+        TxAction::Store(Box::new(move |store| {
+            store.unwatch(client, &watched);
+            Reply::ok()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(command: &str) -> Vec<String> {
+        command.split_whitespace().map(str::to_string).collect()
+    }
+
+    /// Runs a command of `client` the way the client handler does.
+    fn run(tx: &mut Transaction, store: &mut Store, client: usize, command: &str) -> Reply {
+        let args = args(command);
+        match tx.intercept(client, &args) {
+            TxAction::Run => execute_keyspace(store, &args).unwrap(),
+            TxAction::Reply(reply) => reply,
+            TxAction::Store(work) => work(store),
+        }
+    }
+
+    fn queued() -> Reply {
+        Reply::Simple("QUEUED".to_string())
+    }
+
+    #[test]
+    fn runs_queued_commands_on_exec() {
+        let (mut tx, mut store) = (Transaction::default(), Store::default());
+        assert_eq!(run(&mut tx, &mut store, 1, "MULTI"), Reply::ok());
+        assert!(run(&mut tx, &mut store, 1, "MULTI").is_error());
+        assert_eq!(run(&mut tx, &mut store, 1, "SET a 1"), queued());
+        assert_eq!(run(&mut tx, &mut store, 1, "INCR a"), queued());
+        assert_eq!(run(&mut tx, &mut store, 1, "SADD a x"), queued());
+        assert_eq!(store.get("a"), None);
+        let reply = run(&mut tx, &mut store, 1, "EXEC");
+        let replies = match reply {
+            Reply::Array(replies) => replies,
+            reply => panic!("EXEC replied {:?}", reply),
+        };
+        assert_eq!(replies[..2], [Reply::ok(), Reply::Integer(2)]);
+        // an error while running does not undo the other commands
+        assert!(replies[2].is_error());
+        assert_eq!(
+            run(&mut tx, &mut store, 1, "GET a"),
+            Reply::Bulk("2".to_string())
+        );
+        assert!(run(&mut tx, &mut store, 1, "EXEC").is_error());
+    }
+
+    #[test]
+    fn discards_queued_commands() {
+        let (mut tx, mut store) = (Transaction::default(), Store::default());
+        assert!(run(&mut tx, &mut store, 1, "DISCARD").is_error());
+        run(&mut tx, &mut store, 1, "MULTI");
+        assert_eq!(run(&mut tx, &mut store, 1, "SET a 1"), queued());
+        assert_eq!(run(&mut tx, &mut store, 1, "DISCARD"), Reply::ok());
+        assert!(run(&mut tx, &mut store, 1, "EXEC").is_error());
+        assert_eq!(store.get("a"), None);
+    }
+
+    #[test]
+    fn aborts_exec_after_a_command_could_not_be_queued() {
+        let (mut tx, mut store) = (Transaction::default(), Store::default());
+        run(&mut tx, &mut store, 1, "MULTI");
+        assert_eq!(run(&mut tx, &mut store, 1, "SET a 1"), queued());
+        assert!(run(&mut tx, &mut store, 1, "SET a").is_error());
+        assert!(run(&mut tx, &mut store, 1, "NOSUCHCOMMAND").is_error());
+        assert_eq!(
+            run(&mut tx, &mut store, 1, "EXEC"),
+            Reply::error("EXECABORT Transaction discarded because of previous errors.")
+        );
+        assert_eq!(store.get("a"), None);
+
+        // the next transaction starts afresh
+        run(&mut tx, &mut store, 1, "MULTI");
+        run(&mut tx, &mut store, 1, "SET a 1");
+        assert_eq!(
+            run(&mut tx, &mut store, 1, "EXEC"),
+            Reply::Array(vec![Reply::ok()])
+        );
+
+        // errors rejected before reaching the transaction abort it too
+        run(&mut tx, &mut store, 1, "MULTI");
+        tx.reject(Reply::error("NOPERM no permissions"));
+        assert!(run(&mut tx, &mut store, 1, "EXEC").is_error());
+    }
+
+    #[test]
+    fn fails_exec_once_a_watched_key_changed() {
+        let (mut tx, mut store) = (Transaction::default(), Store::default());
+        let mut other = Transaction::default();
+        run(&mut other, &mut store, 2, "SET a 1");
+        assert_eq!(run(&mut tx, &mut store, 1, "WATCH a b"), Reply::ok());
+        assert_eq!(tx.watched_keys(), ["a", "b"]);
+        run(&mut other, &mut store, 2, "SET a 2");
+        run(&mut tx, &mut store, 1, "MULTI");
+        assert!(run(&mut tx, &mut store, 1, "WATCH a").is_error());
+        run(&mut tx, &mut store, 1, "SET a 3");
+        assert_eq!(run(&mut tx, &mut store, 1, "EXEC"), Reply::NilArray);
+        assert_eq!(
+            run(&mut tx, &mut store, 1, "GET a"),
+            Reply::Bulk("2".to_string())
+        );
+
+        // EXEC unwatched everything, so the next transaction goes through
+        assert!(tx.watched_keys().is_empty());
+        run(&mut other, &mut store, 2, "SET b 1");
+        run(&mut tx, &mut store, 1, "MULTI");
+        run(&mut tx, &mut store, 1, "SET a 3");
+        assert_eq!(
+            run(&mut tx, &mut store, 1, "EXEC"),
+            Reply::Array(vec![Reply::ok()])
+        );
+
+        // as does one whose keys were unwatched before the write
+        run(&mut tx, &mut store, 1, "WATCH a");
+        assert_eq!(run(&mut tx, &mut store, 1, "UNWATCH"), Reply::ok());
+        run(&mut other, &mut store, 2, "SET a 4");
+        run(&mut tx, &mut store, 1, "MULTI");
+        run(&mut tx, &mut store, 1, "GET a");
+        assert_eq!(
+            run(&mut tx, &mut store, 1, "EXEC"),
+            Reply::Array(vec![Reply::Bulk("4".to_string())])
+        );
+    }
+}
@@ -1,120 +1,325 @@
 use std::{
-    io::{self, Result},
-    os::fd::AsRawFd,
+    io::{self, Result, Write},
     rc::Rc,
-    sync::{Arc, Mutex, RwLock},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
 };
 
-use mio::{
-    net::{TcpListener, TcpStream},
-    Interest,
-};
+use mio::{net::TcpListener, Interest};
+use rustls::ServerConfig;
 
 use crate::{
-    async_client::core::AsyncClientHandler,
-    reactor::{core::Reactor, event_listener::EventListener},
+    async_client::{core::AsyncClientHandler, stream::ClientStream},
+    reactor::{completion::Notifier, core::Reactor, event::Event, event_listener::EventListener},
+    stats::core as stats,
+    store::core::Store,
+    tls::stream::TlsStream,
+};
+
+use super::{
+    listener::{ListenerPolicy, ServerListener},
+    server_states::ServerStates,
 };
 
-use super::server_states::ServerStates;
+/// Connections accepted per poll, the listener is polled again on the next iteration when more
+/// are pending.
+const ACCEPTS_PER_POLL: usize = 64;
 
-pub struct AsyncTcpCommandServer {
+/// How long accepting pauses after it failed, out of file descriptors for instance. Pending
+/// connections wait in the backlog meanwhile.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Accepts client connections on a listener, TCP unless another listener is given.
+pub struct AsyncTcpCommandServer<L: ServerListener = TcpListener> {
     reactor: Arc<RwLock<Reactor>>,
-    listener: Rc<TcpListener>,
+    db: Arc<RwLock<Store>>,
+    listener: Rc<L>,
     fd: usize,
-    state: Option<ServerStates>,
+    state: Option<ServerStates<L>>,
+    /// accepted connections are served over TLS when set
+    tls: Option<Arc<ServerConfig>>,
+    policy: Arc<ListenerPolicy>,
+    /// set by a readable event until accept would block, events are edge triggered
+    readable: bool,
+    /// accepting pauses until then after an error
+    backoff_until: Option<Instant>,
 }
 
-impl AsyncTcpCommandServer {
-    pub fn new(addr: String, reactor: Arc<RwLock<Reactor>>) -> AsyncTcpCommandServer {
-        let mut listener = TcpListener::bind(addr.parse().unwrap()).unwrap();
-        let fd = listener.as_raw_fd() as usize;
-        {
+impl<L: ServerListener> AsyncTcpCommandServer<L> {
+    pub fn new(
+        listener: L,
+        tls: Option<Arc<ServerConfig>>,
+        policy: Arc<ListenerPolicy>,
+        reactor: Arc<RwLock<Reactor>>,
+        db: Arc<RwLock<Store>>,
+    ) -> AsyncTcpCommandServer<L> {
+        let fd = {
             let mut reactor = reactor.write().unwrap();
+            let token = reactor.allocate_token();
             reactor
-                .register(fd, &mut listener, Some(Interest::READABLE))
+                .register(token, &listener, Some(Interest::READABLE))
                 .unwrap();
-        }
+            token
+        };
 
         AsyncTcpCommandServer {
             reactor,
+            db,
             listener: Rc::new(listener),
             fd,
             state: None,
+            tls,
+            policy,
+            readable: false,
+            backoff_until: None,
         }
     }
 
-    fn handle_new_connection(&mut self, client: TcpStream) -> Result<()> {
-        let client_fd = client.as_raw_fd() as usize;
+    fn handle_new_connection(&mut self, client: L::Stream) -> Result<()> {
+        let tls = self.tls.clone();
         // create a new client handler and add it to the reactot add connection method
         let mut reactor = self.reactor.write().unwrap();
-        let client_handler = AsyncClientHandler::new(client, self.reactor.clone());
-        reactor.add_new_connection(client_fd, client_handler);
-        self.state.replace(ServerStates::Waiting);
-        reactor.schedule(self.id());
+        match tls {
+            Some(config) => {
+                let client =
+                    TlsStream::new(client, config).inspect_err(|_| self.policy.release())?;
+                let token = reactor.allocate_token();
+                let client_handler = AsyncClientHandler::new(
+                    client,
+                    token,
+                    self.policy.clone(),
+                    self.reactor.clone(),
+                    self.db.clone(),
+                );
+                reactor.add_new_connection(token, client_handler);
+            }
+            None => {
+                let token = reactor.allocate_token();
+                let client_handler = AsyncClientHandler::new(
+                    client,
+                    token,
+                    self.policy.clone(),
+                    self.reactor.clone(),
+                    self.db.clone(),
+                );
+                reactor.add_new_connection(token, client_handler);
+            }
+        }
+        Ok(())
+    }
+
+    /// Accepts the pending connections until the listener would block, or until
+    /// `ACCEPTS_PER_POLL` of them were accepted in which case the listener is scheduled again.
+    fn accept_connections(&mut self) -> Result<()> {
+        for _ in 0..ACCEPTS_PER_POLL {
+            let client = match self.listener.accept_client() {
+                Ok(client) => client,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.readable = false;
+                    return Ok(());
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                // the peer left before its connection was accepted
+                Err(err) if err.kind() == io::ErrorKind::ConnectionAborted => continue,
+                Err(err) => {
+                    println!(
+                        "failed to accept on {}: {}, retrying in {:?}",
+                        self.listener.local_name(),
+                        err,
+                        ACCEPT_BACKOFF
+                    );
+                    self.back_off();
+                    return Ok(());
+                }
+            };
+            if self.policy.admit() {
+                println!("recieved new connection from {}", client.peer_name());
+                if let Err(err) = self.handle_new_connection(client) {
+                    println!("failed to set up connection: {}", err);
+                }
+            } else {
+                self.reject(client);
+            }
+        }
+        self.reactor.write().unwrap().schedule(self.id());
         Ok(())
     }
 
-    fn close_connection(&mut self, mut listner: TcpListener) -> Result<()> {
+    /// Stops accepting for `ACCEPT_BACKOFF`, the listener stays readable and is polled again
+    /// once the delay is over.
+    fn back_off(&mut self) {
+        self.backoff_until = Some(Instant::now() + ACCEPT_BACKOFF);
+        let notifier = Notifier::new(self.fd, self.reactor.read().unwrap().completions());
+        thread::spawn(move || {
+            thread::sleep(ACCEPT_BACKOFF);
+            let _ = notifier.notify();
+        });
+    }
+
+    /// Turns a connection away once the limits are reached, the error is only written on plain
+    /// connections as TLS ones did not go through the handshake yet.
+    fn reject(&mut self, mut client: L::Stream) {
+        stats::bump(&self.policy.stats().rejected_connections);
+        let (listener_clients, server_clients) = self.policy.clients();
+        println!(
+            "refused connection from {}, serving {} clients on the listener and {} overall",
+            client.peer_name(),
+            listener_clients,
+            server_clients
+        );
+        if self.tls.is_none() {
+            // the socket was just accepted, its send buffer has room for the reply
+            let _ = client.write(b"-ERR max number of clients reached\r\n");
+        }
+        // dropping the stream closes it
+    }
+
+    fn close_connection(&mut self, listner: L) -> Result<()> {
         let fd = self.fd;
         let mut reactor = self.reactor.write().unwrap();
-        reactor.remove_old_connection(fd, &mut listner);
+        reactor.remove_old_connection(fd, &listner);
         Ok(())
     }
 }
 
-impl EventListener for AsyncTcpCommandServer {
+impl<L: ServerListener> EventListener for AsyncTcpCommandServer<L> {
     fn id(&self) -> usize {
         self.fd
     }
 
     fn name(&self) -> String {
-        format!(
-            "AsyncTcpCommandServer tcp://{}",
-            self.listener.local_addr().unwrap()
-        )
+        let name = self.listener.local_name();
+        match self.tls {
+            Some(_) => format!("AsyncTcpCommandServer tls+{}", name),
+            None => format!("AsyncTcpCommandServer {}", name),
+        }
     }
 
     fn poll(&mut self) -> std::io::Result<()> {
         println!("server poll is called");
-        if let Some(state) = self.state.take() {
-            match state {
-                ServerStates::Accepting(client) => {
-                    self.handle_new_connection(client)?;
+        match self.state.take() {
+            Some(ServerStates::Close(listner)) => self.close_connection(listner),
+            state => {
+                self.state = state;
+                if let Some(until) = self.backoff_until {
+                    if Instant::now() < until {
+                        return Ok(());
+                    }
+                    self.backoff_until = None;
                 }
-                ServerStates::Close(listner) => {
-                    self.close_connection(listner)?;
+                if self.readable {
+                    self.accept_connections()?;
                 }
-                _ => {}
+                Ok(())
             }
         }
-        Ok(())
     }
 
-    fn handle_event(&mut self, event: &mio::event::Event) {
+    fn handle_event(&mut self, event: &Event) {
         if event.is_readable() {
-            if let Some((client, addr)) = match self.listener.accept() {
-                Ok((connection, addr)) => Some((connection, addr)),
-                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
-                    self.state.replace(ServerStates::Waiting);
-                    None
-                }
-                Err(err) => {
-                    println!("err in listner: {}", err);
-                    let listner_clone = self.listener.clone();
-                    self.state
-                        .replace(ServerStates::Close(Rc::try_unwrap(listner_clone).unwrap()));
-                    None
-                }
-            } {
-                println!("recieved new connection from {}", addr);
-                self.state.replace(ServerStates::Accepting(client));
-            }
+            self.readable = true;
+            let mut reactor = self.reactor.write().unwrap();
+            reactor.schedule(self.id());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::TcpStream as StdTcpStream,
+        os::fd::{AsRawFd, RawFd},
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    };
+
+    use super::*;
+    use crate::{
+        async_server::listener::{bind_tcp, ClientLimit},
+        config::listener::{ListenAddr, ListenerConfig},
+        event_loop::core::EventLoop,
+        harness::core::TIMEOUT,
+        reactor::poller::{core::Poller, mio_poller::MioPoller},
+        stats::core::Stats,
+    };
+
+    /// A listener whose first accepts fail as if the process ran out of file descriptors.
+    struct Exhausted {
+        listener: TcpListener,
+        failures: AtomicUsize,
+    }
+
+    impl AsRawFd for Exhausted {
+        fn as_raw_fd(&self) -> RawFd {
+            self.listener.as_raw_fd()
+        }
+    }
+
+    impl ServerListener for Exhausted {
+        type Stream = mio::net::TcpStream;
 
+        fn accept_client(&self) -> Result<Self::Stream> {
+            let failures = &self.failures;
+            if failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
             {
-                let fd = self.id();
-                let mut reactor = self.reactor.write().unwrap();
-                reactor.schedule(fd);
+                return Err(io::Error::from_raw_os_error(libc::EMFILE));
             }
+            self.listener.accept_client()
+        }
+
+        fn local_name(&self) -> String {
+            self.listener.local_name()
         }
     }
+
+    #[test]
+    fn backs_off_when_accept_fails() {
+        let listener = bind_tcp("127.0.0.1:0", false).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let poller: Arc<dyn Poller> = Arc::new(MioPoller::new().unwrap());
+        let reactor = Arc::new(RwLock::new(Reactor::new(poller.clone())));
+        let config = ListenerConfig::new(ListenAddr::Tcp(addr.to_string()), 0);
+        let policy = Arc::new(ListenerPolicy::new(
+            &config,
+            Arc::new(ClientLimit::new(0)),
+            Arc::new(Stats::default()),
+        ));
+        let stop = Arc::new(AtomicBool::new(false));
+        let event_loop = {
+            let stop = stop.clone();
+            thread::spawn(move || {
+                let listener = Exhausted {
+                    listener,
+                    failures: AtomicUsize::new(2),
+                };
+                let db = Arc::new(RwLock::new(Store::default()));
+                let server =
+                    AsyncTcpCommandServer::new(listener, None, policy, reactor.clone(), db);
+                let mut event_loop = EventLoop::new(reactor);
+                event_loop
+                    .connection_handler_map
+                    .insert(server.id(), Box::new(server));
+                while !stop.load(Ordering::SeqCst) {
+                    event_loop.run_once().unwrap();
+                }
+            })
+        };
+
+        let started = Instant::now();
+        let mut conn = StdTcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+        conn.write_all(b"PING\r\n").unwrap();
+        let mut reply = [0; 7];
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+        // accepted once each failure was waited out
+        assert!(started.elapsed() >= ACCEPT_BACKOFF * 2);
+
+        stop.store(true, Ordering::SeqCst);
+        poller.wake().unwrap();
+        event_loop.join().unwrap();
+    }
 }
@@ -0,0 +1,307 @@
+use std::{
+    ffi::OsString,
+    fs,
+    io::{self, Result},
+    net::SocketAddr,
+    os::{
+        fd::{AsFd, AsRawFd},
+        unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    },
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use mio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+use socket2::{Domain, Socket, Type};
+
+use crate::{
+    async_client::stream::ClientStream,
+    auth::core::Auth,
+    config::listener::{AuthPolicy, ListenerConfig},
+    stats::core::Stats,
+};
+
+/// A listening socket the server accepts client connections from.
+pub trait ServerListener: AsRawFd + Send + Sync + 'static {
+    type Stream: ClientStream;
+
+    fn accept_client(&self) -> Result<Self::Stream>;
+
+    /// Address the listener is bound to, as an url such as `tcp://127.0.0.1:7878`.
+    fn local_name(&self) -> String;
+}
+
+impl ServerListener for TcpListener {
+    type Stream = TcpStream;
+
+    fn accept_client(&self) -> Result<TcpStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn local_name(&self) -> String {
+        self.local_addr().map_or_else(
+            |_| "tcp://unknown".to_string(),
+            |addr| format!("tcp://{}", addr),
+        )
+    }
+}
+
+impl ServerListener for UnixListener {
+    type Stream = UnixStream;
+
+    fn accept_client(&self) -> Result<UnixStream> {
+        self.accept().map(|(stream, _)| stream)
+    }
+
+    fn local_name(&self) -> String {
+        let path = self
+            .local_addr()
+            .ok()
+            .and_then(|addr| addr.as_pathname().map(|path| path.display().to_string()));
+        format!("unix://{}", path.unwrap_or_default())
+    }
+}
+
+/// Binds a TCP listener on an `ip:port` address. With `reuse_port` several listeners can bind
+/// the same address, the kernel spreads incoming connections between them.
+///
+/// # Errors
+///
+/// This function will return an error if the address is invalid or can not be bound.
+pub fn bind_tcp(addr: &str, reuse_port: bool) -> Result<TcpListener> {
+    let addr: SocketAddr = addr.parse().map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid address '{}'", addr),
+        )
+    })?;
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+/// Binds a Unix socket listener at `path` with the given permissions, a socket file left by a
+/// previous run is replaced.
+///
+/// The socket is bound in a directory only the server can enter and moved to `path` once its
+/// permissions are set, so no client connects while the umask still decides who can.
+///
+/// # Errors
+///
+/// This function will return an error if the path exists and is not a socket, or the socket
+/// can not be bound.
+pub fn bind_unix(path: &Path, perm: u32) -> Result<UnixListener> {
+    if let Ok(metadata) = fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        fs::remove_file(path)?;
+    }
+    let name = path.file_name().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} is not a socket path", path.display()),
+        )
+    })?;
+    let mut staging = OsString::from(".");
+    staging.push(name);
+    staging.push(".d");
+    let staging = path.with_file_name(staging);
+    // left behind by a run that stopped halfway
+    if fs::symlink_metadata(&staging).is_ok() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let staged = staging.join("s");
+    let bound = UnixListener::bind(&staged).and_then(|listener| {
+        fs::set_permissions(&staged, fs::Permissions::from_mode(perm))?;
+        fs::rename(&staged, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+/// File descriptors kept for listeners, persistence and replication links on top of the ones
+/// of the clients.
+const RESERVED_FDS: libc::rlim_t = 32;
+
+/// Raises the open files limit of the process, up to its hard limit, so `maxclients`
+/// connections fit. Returns the number of clients that fit, lower than `maxclients` when the
+/// limit could not be raised that far and never 0, which would mean no limit.
+///
+/// # Errors
+///
+/// This function will return an error if the limit can not be read.
+pub fn fit_open_files(maxclients: usize) -> Result<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let wanted = match maxclients {
+        0 => limit.rlim_max,
+        maxclients => (maxclients as libc::rlim_t).saturating_add(RESERVED_FDS),
+    };
+    if wanted > limit.rlim_cur {
+        let raised = libc::rlimit {
+            rlim_cur: wanted.min(limit.rlim_max),
+            rlim_max: limit.rlim_max,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &raised) } == 0 {
+            limit = raised;
+        }
+    }
+    let fit = limit.rlim_cur.saturating_sub(RESERVED_FDS).max(1);
+    Ok(match maxclients {
+        0 => fit.try_into().unwrap_or(usize::MAX),
+        maxclients => maxclients.min(fit.try_into().unwrap_or(usize::MAX)),
+    })
+}
+
+/// Shares a bound Unix socket listener with another reactor, both accept from the same socket.
+///
+/// # Errors
+///
+/// This function will return an error if the file descriptor can not be duplicated.
+pub fn share_unix(listener: &UnixListener) -> Result<UnixListener> {
+    let fd = listener.as_fd().try_clone_to_owned()?;
+    Ok(UnixListener::from_std(
+        std::os::unix::net::UnixListener::from(fd),
+    ))
+}
+
+/// Bounds the number of connections served at once.
+pub struct ClientLimit {
+    /// 0 for no limit
+    max: usize,
+    clients: AtomicUsize,
+}
+
+impl ClientLimit {
+    pub fn new(max: usize) -> ClientLimit {
+        ClientLimit {
+            max,
+            clients: AtomicUsize::new(0),
+        }
+    }
+
+    /// Counts a new connection, returns false without counting it when the limit is reached.
+    pub fn acquire(&self) -> bool {
+        self.clients
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |clients| {
+                (self.max == 0 || clients < self.max).then_some(clients + 1)
+            })
+            .is_ok()
+    }
+
+    /// Forgets a closed connection.
+    pub fn release(&self) {
+        self.clients.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.load(Ordering::SeqCst)
+    }
+}
+
+/// Limits and auth policy of a listener, shared with the connections it accepted.
+pub struct ListenerPolicy {
+    /// connections of this listener
+    limit: ClientLimit,
+    /// connections of every listener, bounded by `maxclients`
+    server: Arc<ClientLimit>,
+    auth: AuthPolicy,
+    /// counters of the whole server
+    stats: Arc<Stats>,
+}
+
+impl ListenerPolicy {
+    pub fn new(
+        config: &ListenerConfig,
+        server: Arc<ClientLimit>,
+        stats: Arc<Stats>,
+    ) -> ListenerPolicy {
+        ListenerPolicy {
+            limit: ClientLimit::new(config.maxclients),
+            server,
+            auth: config.auth.clone(),
+            stats,
+        }
+    }
+
+    pub fn stats(&self) -> &Arc<Stats> {
+        &self.stats
+    }
+
+    /// Counts a new connection against the listener and server limits, returns false without
+    /// counting it when either is reached.
+    pub fn admit(&self) -> bool {
+        if !self.server.acquire() {
+            return false;
+        }
+        if !self.limit.acquire() {
+            self.server.release();
+            return false;
+        }
+        true
+    }
+
+    /// Forgets a closed connection.
+    pub fn release(&self) {
+        self.limit.release();
+        self.server.release();
+    }
+
+    /// Connections served by the listener and by the whole server.
+    pub fn clients(&self) -> (usize, usize) {
+        (self.limit.clients(), self.server.clients())
+    }
+
+    /// User new connections are authenticated as, None when they have to authenticate.
+    pub fn initial_user(&self, auth: &Auth) -> Option<String> {
+        match &self.auth {
+            AuthPolicy::Default if !auth.requires_auth() => Some("default".to_string()),
+            AuthPolicy::Default | AuthPolicy::Required => None,
+            AuthPolicy::As(user) => Some(user.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_clients_in_the_open_files_limit() {
+        assert_eq!(fit_open_files(1).unwrap(), 1);
+        let fit = fit_open_files(usize::MAX).unwrap();
+        assert!(fit < usize::MAX);
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        assert_eq!(
+            unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+            0
+        );
+        assert_eq!(fit as libc::rlim_t + RESERVED_FDS, limit.rlim_cur);
+        assert_eq!(fit_open_files(0).unwrap(), fit);
+    }
+}
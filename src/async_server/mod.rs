@@ -1,2 +1,3 @@
 pub mod core;
+pub mod listener;
 pub mod server_states;
@@ -1,8 +1,7 @@
-use mio::net::{TcpListener, TcpStream};
+use super::listener::ServerListener;
 
-pub enum ServerStates {
+pub enum ServerStates<L: ServerListener> {
     Waiting,
-    Accepting(TcpStream),
-    Close(TcpListener),
+    Close(L),
     Closed,
 }
@@ -0,0 +1,211 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    io::{self, Result, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{command::core::find_command_spec, config::core::Config};
+
+use super::user::User;
+
+/// Why a command was refused.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Denial {
+    /// the user was deleted or disabled since the connection authenticated
+    User,
+    Command,
+    Key,
+    Channel,
+}
+
+/// Users of the server and their ACL rules.
+///
+/// The `default` user is the one connections start as. Unless `requirepass` sets its password
+/// it authenticates without one, so connections are authenticated right away, and it can run
+/// every command on every key.
+#[derive(Debug)]
+pub struct Auth {
+    users: BTreeMap<String, User>,
+    aclfile: Option<PathBuf>,
+}
+
+impl Default for Auth {
+    fn default() -> Auth {
+        Auth::new(&Config::default())
+    }
+}
+
+fn default_user(config: &Config) -> User {
+    let mut user = User::new("default");
+    for rule in ["on", "allkeys", "allchannels", "allcommands"] {
+        user.apply(rule).unwrap();
+    }
+    match config.requirepass.as_deref() {
+        Some(password) => user.apply(&format!(">{}", password)).unwrap(),
+        None => user.apply("nopass").unwrap(),
+    }
+    user
+}
+
+/// Channel arguments of the pub/sub commands.
+fn channels_of(args: &[String]) -> &[String] {
+    match args[0].to_uppercase().as_str() {
+        "PUBLISH" | "SPUBLISH" => &args[1..args.len().min(2)],
+        "SUBSCRIBE" | "SSUBSCRIBE" | "PSUBSCRIBE" => &args[1..],
+        _ => &[],
+    }
+}
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+impl Auth {
+    pub fn new(config: &Config) -> Auth {
+        let mut users = BTreeMap::new();
+        users.insert("default".to_string(), default_user(config));
+        Auth {
+            users,
+            aclfile: config.aclfile.clone(),
+        }
+    }
+
+    /// Returns true if new connections start unauthenticated.
+    pub fn requires_auth(&self) -> bool {
+        !self
+            .users
+            .get("default")
+            .is_some_and(|user| user.is_enabled() && user.is_nopass())
+    }
+
+    /// Checks credentials, disabled users can't authenticate.
+    pub fn check(&self, username: &str, password: &str) -> bool {
+        self.users
+            .get(username)
+            .is_some_and(|user| user.is_enabled() && user.check_password(password))
+    }
+
+    /// Checks that the user can run the command on its keys and channels.
+    pub fn permits(&self, username: &str, args: &[String]) -> std::result::Result<(), Denial> {
+        let user = match self.users.get(username) {
+            Some(user) if user.is_enabled() => user,
+            _ => return Err(Denial::User),
+        };
+        if !user.can_run(args) {
+            return Err(Denial::Command);
+        }
+        if let Some(spec) = find_command_spec(args) {
+            let write = spec.is_write();
+            if !spec
+                .keys_of(args)
+                .iter()
+                .all(|key| user.can_access_key(key, write))
+            {
+                return Err(Denial::Key);
+            }
+        }
+        if !channels_of(args)
+            .iter()
+            .all(|channel| user.can_access_channel(channel))
+        {
+            return Err(Denial::Channel);
+        }
+        Ok(())
+    }
+
+    pub fn user(&self, name: &str) -> Option<&User> {
+        self.users.get(name)
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = &User> {
+        self.users.values()
+    }
+
+    /// Creates or modifies a user, none of the rules is applied if one of them is invalid.
+    ///
+    /// # Errors
+    ///
+    /// This function will return the invalid rule along with the reason.
+    pub fn set_user(&mut self, name: &str, rules: &[String]) -> std::result::Result<(), String> {
+        let mut user = self
+            .users
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| User::new(name));
+        for rule in rules {
+            user.apply(rule)
+                .map_err(|err| format!("Error in ACL SETUSER modifier '{}': {}", rule, err))?;
+        }
+        self.users.insert(name.to_string(), user);
+        Ok(())
+    }
+
+    /// Deletes a user, returns false if it did not exist.
+    pub fn delete_user(&mut self, name: &str) -> bool {
+        self.users.remove(name).is_some()
+    }
+
+    pub fn aclfile(&self) -> Option<&Path> {
+        self.aclfile.as_deref()
+    }
+
+    /// Replaces the users with the ones of the ACL file, a missing file leaves them untouched.
+    ///
+    /// Each line of the file is `user <name> <rule> ...`, as written by `save`. The `default`
+    /// user keeps its configuration if the file does not define it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can not be read or holds an invalid
+    /// line, the current users are kept in that case.
+    pub fn load(&mut self) -> Result<()> {
+        let path = match self.aclfile.as_deref() {
+            Some(path) if path.exists() => path,
+            _ => return Ok(()),
+        };
+        let mut users = BTreeMap::new();
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let words: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+            match words.as_slice() {
+                [] => continue,
+                [user, name, rules @ ..] if user == "user" => {
+                    let mut user = User::new(name);
+                    for rule in rules {
+                        user.apply(rule)
+                            .map_err(|err| invalid(format!("line {}: {}", n + 1, err)))?;
+                    }
+                    users.insert(name.to_string(), user);
+                }
+                _ => return Err(invalid(format!("line {}: expected 'user'", n + 1))),
+            }
+        }
+        if let Some(default) = self.users.remove("default") {
+            users.entry("default".to_string()).or_insert(default);
+        }
+        self.users = users;
+        Ok(())
+    }
+
+    /// Writes every user to the ACL file, through a temporary file renamed over it.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if no ACL file is configured or it can not be
+    /// written.
+    pub fn save(&self) -> Result<()> {
+        let path = self
+            .aclfile
+            .as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no ACL file configured"))?;
+        let temp = path.with_extension(format!("tmp-{}", std::process::id()));
+        {
+            let mut file = fs::File::create(&temp)?;
+            for user in self.users.values() {
+                writeln!(file, "{}", user.describe())?;
+            }
+            file.sync_all()?;
+        }
+        fs::rename(&temp, path)
+    }
+}
@@ -0,0 +1,151 @@
+/// Matches `text` against a glob style pattern: `*` matches any sequence, `?` any single
+/// character, `[abc]`, `[^abc]` and `[a-z]` a set of characters and `\` escapes the next one.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches(&pattern, &text)
+}
+
+fn matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // position after the last `*` and the text position it is currently matched up to, used to
+    // backtrack when the rest of the pattern fails
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(p + 1),
+            Some('[') => match_class(pattern, p, text[t]),
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(p + 2),
+            Some(c) => (*c == text[t]).then_some(p + 1),
+            None => None,
+        };
+        match (step, star) {
+            (Some(next), _) => {
+                p = next;
+                t += 1;
+            }
+            (None, Some((after_star, matched))) => {
+                p = after_star;
+                t = matched + 1;
+                star = Some((after_star, matched + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches `c` against the class opening at `start`, returns the position after the class.
+fn match_class(pattern: &[char], start: usize, c: char) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&'^');
+    if negate {
+        p += 1;
+    }
+    let mut found = false;
+    while p < pattern.len() && pattern[p] != ']' {
+        if pattern[p] == '\\' && p + 1 < pattern.len() {
+            found |= pattern[p + 1] == c;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == '-' && pattern[p + 2] != ']' {
+            let (low, high) = if pattern[p] <= pattern[p + 2] {
+                (pattern[p], pattern[p + 2])
+            } else {
+                (pattern[p + 2], pattern[p])
+            };
+            found |= low <= c && c <= high;
+            p += 3;
+        } else {
+            found |= pattern[p] == c;
+            p += 1;
+        }
+    }
+    // an unterminated class is matched as if it was closed at the end of the pattern
+    (found != negate).then_some((p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(cases: &[(&str, &str, bool)]) {
+        for (pattern, text, expected) in cases {
+            assert_eq!(
+                glob_match(pattern, text),
+                *expected,
+                "{:?} against {:?}",
+                pattern,
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn matches_wildcards() {
+        check(&[
+            ("", "", true),
+            ("", "a", false),
+            ("*", "", true),
+            ("**", "anything", true),
+            ("abc", "abc", true),
+            ("abc", "abcd", false),
+            ("a?c", "abc", true),
+            ("a?c", "ac", false),
+            ("?", "é", true),
+            ("user:*", "user:", true),
+            ("user:*", "users:1", false),
+        ]);
+    }
+
+    #[test]
+    fn backtracks_over_stars() {
+        check(&[
+            ("*ab", "aab", true),
+            ("a*bc", "abcbc", true),
+            ("*a*b", "xaxxab", true),
+            ("a*a*a", "aaa", true),
+            ("a*a*a", "aa", false),
+            ("*x*y*z", "xyxyxzy", false),
+            ("*?b", "b", false),
+            ("a*b", "axxbc", false),
+        ]);
+    }
+
+    #[test]
+    fn matches_classes() {
+        check(&[
+            ("[abc]", "b", true),
+            ("[abc]", "d", false),
+            ("[^abc]", "b", false),
+            ("[^abc]", "d", true),
+            ("[a-c]x", "bx", true),
+            ("[a-c]x", "dx", false),
+            // a reversed range is the same range
+            ("[c-a]", "b", true),
+            ("[-a]", "-", true),
+            ("[a-]", "-", true),
+            ("[\\]]", "]", true),
+            ("[\\^a]", "^", true),
+            ("[ab", "a", true),
+            ("[ab", "c", false),
+        ]);
+    }
+
+    #[test]
+    fn matches_escapes() {
+        check(&[
+            ("\\*", "*", true),
+            ("\\*", "a", false),
+            ("a\\?", "a?", true),
+            ("a\\?", "ab", false),
+            ("\\[a]", "[a]", true),
+            // a trailing backslash is a backslash
+            ("a\\", "a\\", true),
+        ]);
+    }
+}
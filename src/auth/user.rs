@@ -0,0 +1,461 @@
+use sha2::{Digest, Sha256};
+
+use crate::command::core::{find_command_spec, in_category, CATEGORIES};
+
+use super::pattern::glob_match;
+
+/// Allows or denies a command, a `cmd|subcommand` pair or a `@category`.
+#[derive(Debug, Clone, PartialEq)]
+struct CommandRule {
+    allow: bool,
+    target: String,
+}
+
+impl CommandRule {
+    fn matches(&self, args: &[String]) -> bool {
+        let name = &args[0];
+        if let Some(category) = self.target.strip_prefix('@') {
+            return in_category(name, category);
+        }
+        match self.target.split_once('|') {
+            Some((command, subcommand)) => {
+                command.eq_ignore_ascii_case(name)
+                    && args
+                        .get(1)
+                        .is_some_and(|arg| arg.eq_ignore_ascii_case(subcommand))
+            }
+            None => self.target.eq_ignore_ascii_case(name),
+        }
+    }
+}
+
+/// Keys matching `pattern` can be read and/or written.
+#[derive(Debug, Clone, PartialEq)]
+struct KeyPattern {
+    pattern: String,
+    read: bool,
+    write: bool,
+}
+
+impl KeyPattern {
+    fn describe(&self) -> String {
+        match (self.read, self.write) {
+            (true, false) => format!("%R~{}", self.pattern),
+            (false, true) => format!("%W~{}", self.pattern),
+            _ => format!("~{}", self.pattern),
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of a password, the form passwords are kept and saved in.
+pub fn hash_password(password: &str) -> String {
+    Sha256::digest(password.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Compares two secrets in a time that only depends on their length.
+fn secure_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (x, y)| diff | (x ^ y))
+            == 0
+}
+
+/// A user along with its ACL rules.
+///
+/// Command rules are kept in the order they were given, the last one matching a command
+/// decides whether it is allowed. A new user is disabled and can neither authenticate, run a
+/// command, access a key nor a channel.
+#[derive(Debug, Clone)]
+pub struct User {
+    name: String,
+    enabled: bool,
+    nopass: bool,
+    /// SHA-256 of the accepted passwords
+    passwords: Vec<String>,
+    commands: Vec<CommandRule>,
+    keys: Vec<KeyPattern>,
+    channels: Vec<String>,
+}
+
+impl User {
+    pub fn new(name: &str) -> User {
+        User {
+            name: name.to_string(),
+            enabled: false,
+            nopass: false,
+            passwords: vec![],
+            commands: vec![],
+            keys: vec![],
+            channels: vec![],
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Returns true if the user authenticates with any password.
+    pub fn is_nopass(&self) -> bool {
+        self.nopass
+    }
+
+    /// Applies one ACL rule such as `on`, `>password`, `+@read`, `-del` or `~app:*`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error describing why the rule is invalid.
+    pub fn apply(&mut self, rule: &str) -> Result<(), String> {
+        match rule.to_lowercase().as_str() {
+            "on" => self.enabled = true,
+            "off" => self.enabled = false,
+            "nopass" => {
+                self.nopass = true;
+                self.passwords.clear();
+            }
+            "resetpass" => {
+                self.nopass = false;
+                self.passwords.clear();
+            }
+            "allcommands" => self.apply_command(true, "@all")?,
+            "nocommands" => self.apply_command(false, "@all")?,
+            "allkeys" => self.apply("~*")?,
+            "resetkeys" => self.keys.clear(),
+            "allchannels" => self.apply("&*")?,
+            "resetchannels" => self.channels.clear(),
+            "reset" => {
+                for rule in [
+                    "resetpass",
+                    "resetkeys",
+                    "resetchannels",
+                    "nocommands",
+                    "off",
+                ] {
+                    self.apply(rule)?;
+                }
+            }
+            _ => return self.apply_pattern(rule),
+        }
+        Ok(())
+    }
+
+    fn apply_pattern(&mut self, rule: &str) -> Result<(), String> {
+        if let Some(password) = rule.strip_prefix('>') {
+            self.add_password(hash_password(password));
+        } else if let Some(password) = rule.strip_prefix('<') {
+            self.remove_password(&hash_password(password))?;
+        } else if let Some(hash) = rule.strip_prefix('#') {
+            if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+                return Err(
+                    "The password hash must be exactly 64 characters and contain \
+                            only lowercase hexadecimal characters"
+                        .to_string(),
+                );
+            }
+            self.add_password(hash.to_lowercase());
+        } else if let Some(hash) = rule.strip_prefix('!') {
+            self.remove_password(&hash.to_lowercase())?;
+        } else if let Some(target) = rule.strip_prefix('+') {
+            self.apply_command(true, target)?;
+        } else if let Some(target) = rule.strip_prefix('-') {
+            self.apply_command(false, target)?;
+        } else if let Some(pattern) = rule.strip_prefix('~') {
+            self.add_key_pattern(pattern, true, true);
+        } else if let Some((access, pattern)) =
+            rule.strip_prefix('%').and_then(|rule| rule.split_once('~'))
+        {
+            let access = access.to_uppercase();
+            if access.is_empty() || !access.chars().all(|c| c == 'R' || c == 'W') {
+                return Err("Syntax error".to_string());
+            }
+            self.add_key_pattern(pattern, access.contains('R'), access.contains('W'));
+        } else if let Some(pattern) = rule.strip_prefix('&') {
+            if !self.channels.iter().any(|channel| channel == pattern) {
+                self.channels.push(pattern.to_string());
+            }
+        } else {
+            return Err("Syntax error".to_string());
+        }
+        Ok(())
+    }
+
+    fn add_password(&mut self, hash: String) {
+        self.nopass = false;
+        if !self.passwords.contains(&hash) {
+            self.passwords.push(hash);
+        }
+    }
+
+    fn remove_password(&mut self, hash: &str) -> Result<(), String> {
+        let len = self.passwords.len();
+        self.passwords.retain(|password| password != hash);
+        if self.passwords.len() == len {
+            return Err("no such password".to_string());
+        }
+        Ok(())
+    }
+
+    fn apply_command(&mut self, allow: bool, target: &str) -> Result<(), String> {
+        let target = target.to_lowercase();
+        let known = match target.strip_prefix('@') {
+            Some(category) => CATEGORIES.contains(&category),
+            None => {
+                let command = target
+                    .split_once('|')
+                    .map_or(target.as_str(), |(cmd, _)| cmd);
+                find_command_spec(&[command.to_string()]).is_some()
+            }
+        };
+        if !known {
+            return Err("Unknown command or category name in ACL".to_string());
+        }
+        if target == "@all" {
+            // every earlier rule is overridden
+            self.commands.clear();
+        } else {
+            self.commands.retain(|rule| rule.target != target);
+        }
+        self.commands.push(CommandRule { allow, target });
+        Ok(())
+    }
+
+    fn add_key_pattern(&mut self, pattern: &str, read: bool, write: bool) {
+        let key = KeyPattern {
+            pattern: pattern.to_string(),
+            read,
+            write,
+        };
+        if !self.keys.contains(&key) {
+            self.keys.push(key);
+        }
+    }
+
+    pub fn check_password(&self, password: &str) -> bool {
+        if self.nopass {
+            return true;
+        }
+        let hash = hash_password(password);
+        self.passwords.iter().fold(false, |found, candidate| {
+            found | secure_eq(candidate, &hash)
+        })
+    }
+
+    pub fn can_run(&self, args: &[String]) -> bool {
+        self.commands
+            .iter()
+            .rev()
+            .find(|rule| rule.matches(args))
+            .is_some_and(|rule| rule.allow)
+    }
+
+    pub fn can_access_key(&self, key: &str, write: bool) -> bool {
+        self.keys.iter().any(|pattern| {
+            (if write { pattern.write } else { pattern.read }) && glob_match(&pattern.pattern, key)
+        })
+    }
+
+    pub fn can_access_channel(&self, channel: &str) -> bool {
+        self.channels
+            .iter()
+            .any(|pattern| glob_match(pattern, channel))
+    }
+
+    pub fn flags(&self) -> Vec<&'static str> {
+        let mut flags = vec![if self.enabled { "on" } else { "off" }];
+        if self.nopass {
+            flags.push("nopass");
+        }
+        flags
+    }
+
+    pub fn passwords(&self) -> &[String] {
+        &self.passwords
+    }
+
+    pub fn describe_commands(&self) -> String {
+        let rules: Vec<String> = self
+            .commands
+            .iter()
+            .map(|rule| format!("{}{}", if rule.allow { '+' } else { '-' }, rule.target))
+            .collect();
+        match rules.first() {
+            Some(first) if first == "+@all" || first == "-@all" => rules.join(" "),
+            _ => std::iter::once("-@all".to_string())
+                .chain(rules)
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+
+    pub fn describe_keys(&self) -> String {
+        self.keys
+            .iter()
+            .map(|pattern| pattern.describe())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn describe_channels(&self) -> String {
+        self.channels
+            .iter()
+            .map(|pattern| format!("&{}", pattern))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Describes the user as the rules recreating it, the form used by ACL LIST and the ACL
+    /// file.
+    pub fn describe(&self) -> String {
+        let mut rules: Vec<String> = vec![format!("user {}", self.name)];
+        rules.extend(self.flags().iter().map(|flag| flag.to_string()));
+        rules.extend(self.passwords.iter().map(|hash| format!("#{}", hash)));
+        rules.push(if self.keys.is_empty() {
+            "resetkeys".to_string()
+        } else {
+            self.describe_keys()
+        });
+        rules.push(if self.channels.is_empty() {
+            "resetchannels".to_string()
+        } else {
+            self.describe_channels()
+        });
+        rules.push(self.describe_commands());
+        rules.join(" ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(rules: &[&str]) -> User {
+        let mut user = User::new("alice");
+        for rule in rules {
+            user.apply(rule).unwrap();
+        }
+        user
+    }
+
+    fn args(command: &str) -> Vec<String> {
+        command.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn starts_disabled_without_rights() {
+        let user = User::new("alice");
+        assert!(!user.is_enabled() && !user.is_nopass());
+        assert!(!user.check_password(""));
+        assert!(!user.can_run(&args("GET a")));
+        assert!(!user.can_access_key("a", false));
+        assert!(!user.can_access_channel("news"));
+        assert_eq!(
+            user.describe(),
+            "user alice off resetkeys resetchannels -@all"
+        );
+    }
+
+    #[test]
+    fn manages_passwords() {
+        let mut user = user(&["on", ">secret", ">other"]);
+        assert!(user.check_password("secret") && user.check_password("other"));
+        assert!(!user.check_password("wrong"));
+        user.apply("<other").unwrap();
+        assert!(!user.check_password("other"));
+        assert!(user.apply("<other").is_err());
+
+        user.apply(&format!("#{}", hash_password("hashed").to_uppercase()))
+            .unwrap();
+        assert!(user.check_password("hashed"));
+        assert!(user.apply("#abc").is_err());
+        user.apply(&format!("!{}", hash_password("hashed")))
+            .unwrap();
+        assert!(!user.check_password("hashed"));
+
+        user.apply("nopass").unwrap();
+        assert!(user.check_password("anything") && user.passwords().is_empty());
+        user.apply("resetpass").unwrap();
+        assert!(!user.check_password("anything"));
+    }
+
+    #[test]
+    fn lets_the_last_matching_command_rule_win() {
+        let mut user = user(&["+@all", "-@write"]);
+        assert!(user.can_run(&args("GET a")));
+        assert!(!user.can_run(&args("SET a 1")));
+        user.apply("+set").unwrap();
+        assert!(user.can_run(&args("SET a 1")));
+        assert!(!user.can_run(&args("DEL a")));
+        // a rule given again moves to the end
+        user.apply("-@write").unwrap();
+        assert!(!user.can_run(&args("SET a 1")));
+        assert_eq!(user.describe_commands(), "+@all +set -@write");
+
+        // @all drops every earlier rule
+        user.apply("nocommands").unwrap();
+        assert_eq!(user.describe_commands(), "-@all");
+        user.apply("+acl|whoami").unwrap();
+        assert!(user.can_run(&args("ACL WHOAMI")) && user.can_run(&args("acl whoami")));
+        assert!(!user.can_run(&args("ACL SETUSER bob")) && !user.can_run(&args("ACL")));
+
+        assert!(user.apply("+nosuchcommand").is_err());
+        assert!(user.apply("-@nosuchcategory").is_err());
+        assert!(user.apply("bogus").is_err());
+    }
+
+    #[test]
+    fn limits_keys_and_channels() {
+        let mut user = user(&["%R~read:*", "%W~write:*", "%RW~both:*", "~all:*"]);
+        assert!(user.can_access_key("read:1", false) && !user.can_access_key("read:1", true));
+        assert!(!user.can_access_key("write:1", false) && user.can_access_key("write:1", true));
+        for key in ["both:1", "all:1"] {
+            assert!(user.can_access_key(key, false) && user.can_access_key(key, true));
+        }
+        assert!(!user.can_access_key("other", false));
+        assert_eq!(user.describe_keys(), "%R~read:* %W~write:* ~both:* ~all:*");
+        assert!(user.apply("%X~key").is_err() && user.apply("%~key").is_err());
+
+        user.apply("&news.*").unwrap();
+        assert!(user.can_access_channel("news.sport") && !user.can_access_channel("sport"));
+        user.apply("resetchannels").unwrap();
+        assert!(!user.can_access_channel("news.sport"));
+        user.apply("resetkeys").unwrap();
+        assert!(!user.can_access_key("all:1", false));
+    }
+
+    #[test]
+    fn describes_the_rules_recreating_it() {
+        let original = user(&[
+            "on",
+            ">secret",
+            "%R~read:*",
+            "~app:*",
+            "&news.*",
+            "+@read",
+            "-get",
+            "+acl|whoami",
+        ]);
+        let description = original.describe();
+        let words: Vec<&str> = description.split_whitespace().collect();
+        assert_eq!(&words[..2], ["user", "alice"]);
+        let copy = user(&words[2..]);
+        assert_eq!(copy.describe(), description);
+        assert!(copy.is_enabled() && copy.check_password("secret"));
+        for command in ["SCARD s", "GET a", "ACL WHOAMI", "SET a 1"] {
+            assert_eq!(
+                copy.can_run(&args(command)),
+                original.can_run(&args(command)),
+                "{}",
+                command
+            );
+        }
+        assert!(copy.can_access_key("read:1", false) && !copy.can_access_key("read:1", true));
+        assert!(copy.can_access_channel("news.x"));
+    }
+}
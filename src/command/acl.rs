@@ -0,0 +1,228 @@
+use crate::{
+    command::core::{commands_in_category, CATEGORIES},
+    protocol::reply::Reply,
+    store::core::Store,
+};
+
+use super::core::{CommandSpec, ADMIN};
+
+pub const SPECS: &[CommandSpec] = &[CommandSpec::new("ACL", -2, ADMIN).no_keys()];
+
+/// Runs an ACL subcommand for the connection authenticated as `username`.
+///
+/// ACL is run by the connection session rather than a worker since WHOAMI depends on the
+/// connection.
+pub fn execute(store: &mut Store, username: &str, args: &[String]) -> Reply {
+    let subcommand = match args.get(1) {
+        Some(subcommand) => subcommand.to_uppercase(),
+        None => return Reply::wrong_arity("acl"),
+    };
+    let arity_ok = match subcommand.as_str() {
+        "SETUSER" => args.len() >= 3,
+        "GETUSER" => args.len() == 3,
+        "DELUSER" => args.len() >= 3,
+        "CAT" => args.len() <= 3,
+        "LIST" | "USERS" | "WHOAMI" | "LOAD" | "SAVE" => args.len() == 2,
+        _ => {
+            return Reply::error(format!(
+                "ERR unknown subcommand '{}'. Try ACL HELP.",
+                args[1]
+            ))
+        }
+    };
+    if !arity_ok {
+        return Reply::wrong_arity(&format!("acl|{}", subcommand));
+    }
+
+    match subcommand.as_str() {
+        "SETUSER" => match store.auth_mut().set_user(&args[2], &args[3..]) {
+            Ok(_) => Reply::ok(),
+            Err(err) => Reply::error(format!("ERR {}", err)),
+        },
+        "GETUSER" => getuser(store, &args[2]),
+        "DELUSER" => deluser(store, &args[2..]),
+        "LIST" => Reply::bulk_array(store.auth().users().map(|user| user.describe())),
+        "USERS" => Reply::bulk_array(store.auth().users().map(|user| user.name().to_string())),
+        "WHOAMI" => Reply::Bulk(username.to_string()),
+        "CAT" => match args.get(2) {
+            None => Reply::bulk_array(CATEGORIES.iter().map(|name| name.to_string())),
+            Some(category) if CATEGORIES.contains(&category.to_lowercase().as_str()) => {
+                Reply::bulk_array(
+                    commands_in_category(&category.to_lowercase())
+                        .into_iter()
+                        .map(|name| name.to_lowercase()),
+                )
+            }
+            Some(category) => Reply::error(format!("ERR Unknown category '{}'", category)),
+        },
+        "LOAD" => {
+            if store.auth().aclfile().is_none() {
+                return no_aclfile();
+            }
+            match store.auth_mut().load() {
+                Ok(_) => Reply::ok(),
+                Err(err) => Reply::error(format!("ERR Error loading the ACL file: {}", err)),
+            }
+        }
+        "SAVE" => {
+            if store.auth().aclfile().is_none() {
+                return no_aclfile();
+            }
+            match store.auth().save() {
+                Ok(_) => Reply::ok(),
+                Err(err) => Reply::error(format!(
+                    "ERR There was an error trying to save the ACLs: {}",
+                    err
+                )),
+            }
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn no_aclfile() -> Reply {
+    Reply::error("ERR This instance is not configured to use an ACL file, set 'aclfile' to use it")
+}
+
+fn getuser(store: &Store, name: &str) -> Reply {
+    let user = match store.auth().user(name) {
+        Some(user) => user,
+        None => return Reply::Nil,
+    };
+    Reply::Array(vec![
+        Reply::Bulk("flags".to_string()),
+        Reply::bulk_array(user.flags().into_iter().map(str::to_string)),
+        Reply::Bulk("passwords".to_string()),
+        Reply::bulk_array(user.passwords().iter().cloned()),
+        Reply::Bulk("commands".to_string()),
+        Reply::Bulk(user.describe_commands()),
+        Reply::Bulk("keys".to_string()),
+        Reply::Bulk(user.describe_keys()),
+        Reply::Bulk("channels".to_string()),
+        Reply::Bulk(user.describe_channels()),
+    ])
+}
+
+/// Deletes users, connections authenticated as one of them are refused from then on.
+fn deluser(store: &mut Store, names: &[String]) -> Reply {
+    if names.iter().any(|name| name == "default") {
+        return Reply::error("ERR The 'default' user cannot be removed");
+    }
+    let deleted = names
+        .iter()
+        .filter(|name| store.auth_mut().delete_user(name))
+        .count();
+    Reply::Integer(deleted as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{auth::core::Auth, config::core::Config};
+
+    fn acl(store: &mut Store, args: &[&str]) -> Reply {
+        let args: Vec<String> = std::iter::once("ACL")
+            .chain(args.iter().copied())
+            .map(str::to_string)
+            .collect();
+        execute(store, "default", &args)
+    }
+
+    fn bulk(text: &str) -> Reply {
+        Reply::Bulk(text.to_string())
+    }
+
+    #[test]
+    fn sets_gets_and_deletes_users() {
+        let mut store = Store::default();
+        let reply = acl(
+            &mut store,
+            &[
+                "SETUSER", "alice", "on", "nopass", "~app:*", "&news", "+@read",
+            ],
+        );
+        assert_eq!(reply, Reply::ok());
+        assert_eq!(
+            acl(&mut store, &["GETUSER", "alice"]),
+            Reply::Array(vec![
+                bulk("flags"),
+                Reply::bulk_array(["on", "nopass"].map(str::to_string)),
+                bulk("passwords"),
+                Reply::bulk_array(Vec::<String>::new()),
+                bulk("commands"),
+                bulk("-@all +@read"),
+                bulk("keys"),
+                bulk("~app:*"),
+                bulk("channels"),
+                bulk("&news"),
+            ])
+        );
+        assert!(matches!(
+            acl(&mut store, &["SETUSER", "alice", "+nosuchcommand"]),
+            Reply::Error(_)
+        ));
+        assert_eq!(acl(&mut store, &["GETUSER", "bob"]), Reply::Nil);
+        assert_eq!(
+            acl(&mut store, &["USERS"]),
+            Reply::bulk_array(["alice", "default"].map(str::to_string))
+        );
+        assert_eq!(acl(&mut store, &["WHOAMI"]), bulk("default"));
+
+        assert!(matches!(
+            acl(&mut store, &["DELUSER", "alice", "default"]),
+            Reply::Error(_)
+        ));
+        assert_eq!(
+            acl(&mut store, &["DELUSER", "alice", "bob"]),
+            Reply::Integer(1)
+        );
+        assert_eq!(acl(&mut store, &["GETUSER", "alice"]), Reply::Nil);
+        assert!(matches!(acl(&mut store, &["GETUSER"]), Reply::Error(_)));
+    }
+
+    #[test]
+    fn saves_and_loads_the_aclfile() {
+        let mut store = Store::default();
+        assert!(matches!(acl(&mut store, &["SAVE"]), Reply::Error(_)));
+        assert!(matches!(acl(&mut store, &["LOAD"]), Reply::Error(_)));
+
+        let dir = std::env::temp_dir().join(format!("acl-save-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            aclfile: Some(dir.join("users.acl")),
+            ..Config::default()
+        };
+        store.set_auth(Auth::new(&config));
+        let rules = [
+            "SETUSER",
+            "alice",
+            "on",
+            ">secret",
+            "%R~read:*",
+            "%W~write:*",
+            "&news.*",
+            "+@all",
+            "-@dangerous",
+            "+acl|whoami",
+        ];
+        assert_eq!(acl(&mut store, &rules), Reply::ok());
+        let listed = acl(&mut store, &["LIST"]);
+        assert_eq!(acl(&mut store, &["SAVE"]), Reply::ok());
+
+        let mut loaded = Store::default();
+        loaded.set_auth(Auth::new(&config));
+        assert_eq!(acl(&mut loaded, &["LOAD"]), Reply::ok());
+        assert_eq!(acl(&mut loaded, &["LIST"]), listed);
+        let alice = loaded.auth().user("alice").unwrap();
+        assert!(alice.check_password("secret"));
+        assert!(alice.can_access_key("read:1", false) && !alice.can_access_key("read:1", true));
+
+        // a file with an invalid rule is refused and leaves the users as they were
+        fs::write(dir.join("users.acl"), "user alice on +nosuchcommand\n").unwrap();
+        assert!(matches!(acl(&mut loaded, &["LOAD"]), Reply::Error(_)));
+        assert_eq!(acl(&mut loaded, &["LIST"]), listed);
+    }
+}
@@ -1,14 +1,25 @@
 use std::{
     sync::{Arc, Mutex, RwLock},
-    thread::JoinHandle,
+    thread::{self, JoinHandle},
 };
 
-use mio::Waker;
-
 use crate::{
-    async_client::client_states::ClientStates,
-    command::{echo::Echo, ping::Ping},
-    reactor::core::Reactor,
+    async_client::{client_states::ClientStates, session, transaction},
+    command::{
+        acl,
+        echo::{self, Echo},
+        keys::{self, KeyCommand},
+        ping::{self, Ping},
+        server::{self, ServerCommand},
+        set::{self, SetCommand},
+        string::{self, StringCommand},
+        zset::{self, SortedSetCommand},
+    },
+    protocol::reply::Reply,
+    reactor::{completion::CompletionQueue, core::Reactor},
+    replication::primary,
+    stats::core::Stats,
+    store::core::Store,
 };
 
 pub trait Command {
@@ -18,26 +29,335 @@ pub trait Command {
         raw_cmd: String,
         fd: usize,
         state: Arc<Mutex<Option<ClientStates>>>,
-        waker: Arc<Waker>,
+        completions: Arc<CompletionQueue>,
         reactor: Arc<RwLock<Reactor>>,
     ) -> JoinHandle<()>;
 }
 
-fn registered_commands() -> Vec<Box<dyn Command>> {
-    vec![Box::new(Ping {}), Box::new(Echo {})]
+/// The command modifies the keyspace, it is fed to the AOF.
+pub const WRITE: u32 = 1;
+/// The command only reads the keyspace.
+pub const READONLY: u32 = 1 << 1;
+/// Server administration command.
+pub const ADMIN: u32 = 1 << 2;
+
+/// Static description of a command.
+///
+/// `arity` follows the usual convention: a positive value is the exact number of arguments
+/// including the command name, a negative value is the minimum number of arguments. Keys are
+/// the arguments from `first_key` to `last_key`, a negative `last_key` counts from the end.
+pub struct CommandSpec {
+    pub name: &'static str,
+    pub arity: i32,
+    pub flags: u32,
+    /// 0 when the command takes no key
+    pub first_key: usize,
+    pub last_key: i32,
+}
+
+impl CommandSpec {
+    /// Describes a command whose only key is its first argument.
+    pub const fn new(name: &'static str, arity: i32, flags: u32) -> CommandSpec {
+        CommandSpec {
+            name,
+            arity,
+            flags,
+            first_key: 1,
+            last_key: 1,
+        }
+    }
+
+    pub const fn keys(self, first_key: usize, last_key: i32) -> CommandSpec {
+        CommandSpec {
+            first_key,
+            last_key,
+            ..self
+        }
+    }
+
+    pub const fn no_keys(self) -> CommandSpec {
+        self.keys(0, 0)
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.flags & WRITE != 0
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        self.flags & READONLY != 0
+    }
+
+    /// The key arguments of a call to this command.
+    pub fn keys_of<'a>(&self, args: &'a [String]) -> &'a [String] {
+        if self.first_key == 0 || self.first_key >= args.len() {
+            return &[];
+        }
+        let last = match self.last_key {
+            last if last < 0 => args.len() as i32 + last,
+            last => last,
+        };
+        let last = (last.max(0) as usize).min(args.len() - 1);
+        if last < self.first_key {
+            return &[];
+        }
+        &args[self.first_key..=last]
+    }
+
+    pub fn check_arity(&self, args: &[String]) -> Result<(), Reply> {
+        let len = args.len() as i32;
+        if (self.arity >= 0 && len != self.arity) || (self.arity < 0 && len < -self.arity) {
+            return Err(Reply::wrong_arity(self.name));
+        }
+        Ok(())
+    }
+}
+
+/// Looks up the spec for the command named by the first argument.
+pub fn find_spec<'a>(specs: &'a [CommandSpec], args: &[String]) -> Option<&'a CommandSpec> {
+    let name = args.first()?;
+    specs
+        .iter()
+        .find(|spec| spec.name.eq_ignore_ascii_case(name))
+}
+
+/// Splits a raw command line into its arguments.
+pub fn parse_args(raw_cmd: &str) -> Vec<String> {
+    raw_cmd
+        .split_whitespace()
+        .map(|arg| arg.to_string())
+        .collect()
+}
+
+/// Runs `work` on a worker thread and hands its output back to the client.
+///
+/// Once the output is ready the client state is moved to `WriteOutput` and the fd is handed
+/// back through the completion queue, which wakes the reactor out of its wait. The worker waits
+/// for its turn first when the queue has a
+/// [`WorkerGate`](crate::reactor::completion::WorkerGate).
+pub fn spawn_worker<F>(
+    fd: usize,
+    state: Arc<Mutex<Option<ClientStates>>>,
+    completions: Arc<CompletionQueue>,
+    work: F,
+) -> JoinHandle<()>
+where
+    F: FnOnce() -> String + Send + 'static,
+{
+    let gate = completions.gate();
+    let ticket = gate.as_ref().map(|gate| gate.spawned(fd));
+    thread::spawn(move || {
+        if let (Some(gate), Some(ticket)) = (&gate, ticket) {
+            gate.enter(ticket);
+        }
+        println!(
+            "Worker thread {:?} spawned for Fd {}",
+            thread::current().id(),
+            fd
+        );
+        let output = work();
+        state
+            .lock()
+            .unwrap()
+            .replace(ClientStates::WriteOutput(output));
+        completions.complete(fd).unwrap();
+        if let (Some(gate), Some(ticket)) = (&gate, ticket) {
+            gate.leave(ticket);
+        }
+    })
+}
+
+type Executor = fn(&mut Store, &[String]) -> Reply;
+type Query = fn(&Store, &[String]) -> Option<Reply>;
+
+/// Every command family operating on the keyspace along with its synchronous executor.
+const KEYSPACE_COMMANDS: &[(&[CommandSpec], Executor)] = &[
+    (keys::SPECS, keys::execute),
+    (string::SPECS, string::execute),
+    (set::SPECS, set::execute),
+    (zset::SPECS, zset::execute),
+];
+
+/// The executors of the read-only commands of each keyspace family.
+const KEYSPACE_QUERIES: &[Query] = &[keys::query, string::query, set::query, zset::query];
+
+/// Every command the server knows grouped by the ACL category of its family. Commands also
+/// belong to @read, @write or @admin and @dangerous depending on their flags.
+const COMMAND_FAMILIES: &[(&[CommandSpec], &str)] = &[
+    (keys::SPECS, "keyspace"),
+    (string::SPECS, "string"),
+    (set::SPECS, "set"),
+    (zset::SPECS, "sortedset"),
+    (server::SPECS, "server"),
+    (primary::SPECS, "server"),
+    (acl::SPECS, "server"),
+    (ping::SPECS, "connection"),
+    (echo::SPECS, "connection"),
+    (session::SPECS, "connection"),
+    (transaction::SPECS, "transaction"),
+];
+
+/// ACL categories, @all matches every command including unknown ones.
+pub const CATEGORIES: &[&str] = &[
+    "all",
+    "keyspace",
+    "string",
+    "set",
+    "sortedset",
+    "server",
+    "connection",
+    "transaction",
+    "read",
+    "write",
+    "admin",
+    "dangerous",
+];
+
+/// Looks up the spec of any command.
+pub fn find_command_spec(args: &[String]) -> Option<&'static CommandSpec> {
+    COMMAND_FAMILIES
+        .iter()
+        .find_map(|(specs, _)| find_spec(specs, args))
+}
+
+/// Returns true if the command named `name` belongs to the ACL category.
+pub fn in_category(name: &str, category: &str) -> bool {
+    if category == "all" {
+        return true;
+    }
+    COMMAND_FAMILIES.iter().any(|(specs, family)| {
+        specs.iter().any(|spec| {
+            spec.name.eq_ignore_ascii_case(name)
+                && match category {
+                    "read" => spec.is_readonly(),
+                    "write" => spec.flags & WRITE != 0,
+                    "admin" | "dangerous" => spec.flags & ADMIN != 0,
+                    category => *family == category,
+                }
+        })
+    })
+}
+
+/// Names of the commands in the ACL category.
+pub fn commands_in_category(category: &str) -> Vec<&'static str> {
+    COMMAND_FAMILIES
+        .iter()
+        .flat_map(|(specs, _)| specs.iter())
+        .filter(|spec| in_category(spec.name, category))
+        .map(|spec| spec.name)
+        .collect()
+}
+
+/// Looks up the spec of a keyspace command.
+pub fn find_keyspace_spec(args: &[String]) -> Option<&'static CommandSpec> {
+    KEYSPACE_COMMANDS
+        .iter()
+        .find_map(|(specs, _)| find_spec(specs, args))
+}
+
+/// Executes a keyspace command on the calling thread, the caller is expected to already hold
+/// the store lock. Returns None if the command is not a keyspace command.
+///
+/// Successful write commands are propagated to the AOF and counted towards the snapshot save
+/// rules.
+pub fn execute_keyspace(store: &mut Store, args: &[String]) -> Option<Reply> {
+    let (spec, execute) = KEYSPACE_COMMANDS
+        .iter()
+        .find_map(|(specs, execute)| find_spec(specs, args).map(|spec| (spec, execute)))?;
+    let reply = execute(store, args);
+    if spec.is_write() && !reply.is_error() {
+        let effects = effects_of(store, args, &reply);
+        if !effects.is_empty() {
+            store.record_change();
+        }
+        for effect in effects {
+            store.propagate(&effect);
+        }
+    }
+    Some(reply)
+}
+
+/// Executes a read-only keyspace command under a shared borrow of the store. Returns None if
+/// the command is not a read-only keyspace command.
+pub fn query_keyspace(store: &Store, args: &[String]) -> Option<Reply> {
+    KEYSPACE_QUERIES.iter().find_map(|query| query(store, args))
+}
+
+/// Runs a keyspace command from a worker thread. Read-only commands share the store read lock,
+/// so reads from every shard run at once, the others take the write lock.
+pub fn run_keyspace(db: &RwLock<Store>, args: &[String]) -> Reply {
+    if let Some(reply) = query_keyspace(&db.read().unwrap(), args) {
+        return reply;
+    }
+    let mut store = db.write().unwrap();
+    execute_keyspace(&mut store, args).unwrap_or_else(|| Reply::error("ERR unknown command"))
+}
+
+fn bulk_items(reply: &Reply) -> Vec<String> {
+    match reply {
+        Reply::Bulk(member) => vec![member.to_string()],
+        Reply::Array(items) => items.iter().flat_map(bulk_items).collect(),
+        _ => vec![],
+    }
+}
+
+/// The commands to propagate for a write. Commands whose outcome depends on randomness or on
+/// the current time are replaced by the change they made, so that replaying them at any later
+/// point gives the same keyspace.
+fn effects_of(store: &Store, args: &[String], reply: &Reply) -> Vec<Vec<String>> {
+    let name = args[0].to_uppercase();
+    let key = || args[1].to_string();
+    match name.as_str() {
+        "SPOP" => {
+            let members = bulk_items(reply);
+            if members.is_empty() {
+                return vec![];
+            }
+            let mut effect = vec!["SREM".to_string(), key()];
+            effect.extend(members);
+            vec![effect]
+        }
+        "EXPIRE" | "PEXPIRE" | "EXPIREAT" | "PEXPIREAT" => match (reply, store.expiry(&args[1])) {
+            (Reply::Integer(0), _) => vec![],
+            (_, Some(at)) => vec![vec!["PEXPIREAT".to_string(), key(), at.to_string()]],
+            (_, None) => vec![vec!["DEL".to_string(), key()]],
+        },
+        "SET" if args.len() > 3 => match (reply, store.expiry(&args[1])) {
+            (Reply::Nil, _) => vec![],
+            (_, Some(at)) => vec![
+                vec!["SET".to_string(), key(), args[2].to_string()],
+                vec!["PEXPIREAT".to_string(), key(), at.to_string()],
+            ],
+            (_, None) => vec![vec!["SET".to_string(), key(), args[2].to_string()]],
+        },
+        _ => vec![args.to_vec()],
+    }
+}
+
+fn registered_commands(db: Arc<RwLock<Store>>, stats: Arc<Stats>) -> Vec<Box<dyn Command>> {
+    vec![
+        Box::new(Ping {}),
+        Box::new(Echo {}),
+        Box::new(KeyCommand::new(db.clone())),
+        Box::new(StringCommand::new(db.clone())),
+        Box::new(SetCommand::new(db.clone())),
+        Box::new(SortedSetCommand::new(db.clone())),
+        Box::new(ServerCommand::new(db, stats)),
+    ]
 }
 
 pub fn get_and_run_cmd(
     fd: usize,
     raw_cmd: String,
     state: Arc<Mutex<Option<ClientStates>>>,
-    waker: Arc<Waker>,
+    completions: Arc<CompletionQueue>,
     reactor: Arc<RwLock<Reactor>>,
+    db: Arc<RwLock<Store>>,
+    stats: Arc<Stats>,
 ) -> Option<JoinHandle<()>> {
-    let mut commands = registered_commands();
+    let mut commands = registered_commands(db, stats);
     for cmd in commands.iter_mut() {
         if cmd.as_mut().can_process(raw_cmd.to_string()) {
-            return Some(cmd.run(raw_cmd, fd, state, waker, reactor));
+            return Some(cmd.run(raw_cmd, fd, state, completions, reactor));
         }
     }
     None
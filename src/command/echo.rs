@@ -1,8 +1,8 @@
-use std::thread;
+use crate::protocol::reply::Reply;
 
-use crate::async_client::client_states::ClientStates;
+use super::core::{parse_args, spawn_worker, Command, CommandSpec};
 
-use super::core::Command;
+pub const SPECS: &[CommandSpec] = &[CommandSpec::new("ECHO", -2, 0).no_keys()];
 
 pub struct Echo {}
 
@@ -18,39 +18,21 @@ impl Command for Echo {
         state: std::sync::Arc<
             std::sync::Mutex<Option<crate::async_client::client_states::ClientStates>>,
         >,
-        waker: std::sync::Arc<mio::Waker>,
-        reactor: std::sync::Arc<std::sync::RwLock<crate::reactor::core::Reactor>>,
+        completions: std::sync::Arc<crate::reactor::completion::CompletionQueue>,
+        _reactor: std::sync::Arc<std::sync::RwLock<crate::reactor::core::Reactor>>,
     ) -> std::thread::JoinHandle<()> {
-        let waker = waker.clone();
-        thread::spawn(move || {
-            println!(
-                "Worker thread {:?} spawned for ping command",
-                thread::current().id()
-            );
+        spawn_worker(fd, state, completions, move || {
+            if let Err(reply) = SPECS[0].check_arity(&parse_args(&raw_cmd)) {
+                return reply.encode();
+            }
+            // the message is the rest of the line, spaces included
             let echo_re = regex::Regex::new(r"(?i)^echo(.*)").unwrap();
             let mut echo_val = String::new();
 
             if let Some(val) = echo_re.captures(&raw_cmd) {
-                echo_val = val[1].trim().to_string() + "\n";
+                echo_val = val[1].trim().to_string();
             }
-            state
-                .lock()
-                .unwrap()
-                .replace(ClientStates::WriteOutput(echo_val));
-
-            waker.clone().wake().unwrap();
-            println!("deadlock after this?");
-
-            {
-                let mut reactor = reactor.write().unwrap();
-                reactor.schedule(fd);
-            }
-            waker.clone().wake().unwrap();
-
-            println!(
-                "Worker thread {:?} spawned for ping command finished",
-                thread::current().id()
-            );
+            Reply::Bulk(echo_val).encode()
         })
     }
 }
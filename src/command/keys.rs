@@ -0,0 +1,163 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    thread::JoinHandle,
+};
+
+use crate::{
+    async_client::client_states::ClientStates,
+    protocol::reply::Reply,
+    reactor::{completion::CompletionQueue, core::Reactor},
+    store::core::{now_ms, Store},
+};
+
+use super::core::{
+    find_spec, parse_args, run_keyspace, spawn_worker, Command, CommandSpec, READONLY, WRITE,
+};
+
+pub const SPECS: &[CommandSpec] = &[
+    CommandSpec::new("DEL", -2, WRITE).keys(1, -1),
+    CommandSpec::new("EXISTS", -2, READONLY).keys(1, -1),
+    CommandSpec::new("TYPE", 2, READONLY),
+    CommandSpec::new("EXPIRE", 3, WRITE),
+    CommandSpec::new("PEXPIRE", 3, WRITE),
+    CommandSpec::new("EXPIREAT", 3, WRITE),
+    CommandSpec::new("PEXPIREAT", 3, WRITE),
+    CommandSpec::new("TTL", 2, READONLY),
+    CommandSpec::new("PTTL", 2, READONLY),
+    CommandSpec::new("PERSIST", 2, WRITE),
+];
+
+/// Generic key commands that work on any value type: deletion, existence and expiry.
+pub struct KeyCommand {
+    db: Arc<RwLock<Store>>,
+}
+
+impl KeyCommand {
+    pub fn new(db: Arc<RwLock<Store>>) -> KeyCommand {
+        KeyCommand { db }
+    }
+}
+
+impl Command for KeyCommand {
+    fn can_process(&mut self, raw_cmd: String) -> bool {
+        find_spec(SPECS, &parse_args(&raw_cmd)).is_some()
+    }
+
+    fn run(
+        &mut self,
+        raw_cmd: String,
+        fd: usize,
+        state: Arc<Mutex<Option<ClientStates>>>,
+        completions: Arc<CompletionQueue>,
+        _reactor: Arc<RwLock<Reactor>>,
+    ) -> JoinHandle<()> {
+        let db = self.db.clone();
+        spawn_worker(fd, state, completions, move || {
+            run_keyspace(&db, &parse_args(&raw_cmd)).encode()
+        })
+    }
+}
+
+/// Executes a key command against the store.
+pub fn execute(store: &mut Store, args: &[String]) -> Reply {
+    let spec = match find_spec(SPECS, args) {
+        Some(spec) => spec,
+        None => return Reply::error("ERR unknown key command"),
+    };
+    if let Err(reply) = spec.check_arity(args) {
+        return reply;
+    }
+
+    let result = match spec.name {
+        "DEL" => Ok(del(store, args)),
+        "EXPIRE" => expire(store, args, 1000, false),
+        "PEXPIRE" => expire(store, args, 1, false),
+        "EXPIREAT" => expire(store, args, 1000, true),
+        "PEXPIREAT" => expire(store, args, 1, true),
+        "PERSIST" => Ok(Reply::Integer(store.persist(&args[1]) as i64)),
+        _ => return read(store, spec, args),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+/// Executes a read-only key command, it only needs the store read lock. Returns None for
+/// the other commands.
+pub fn query(store: &Store, args: &[String]) -> Option<Reply> {
+    let spec = find_spec(SPECS, args).filter(|spec| spec.is_readonly())?;
+    Some(match spec.check_arity(args) {
+        Ok(()) => read(store, spec, args),
+        Err(reply) => reply,
+    })
+}
+
+fn read(store: &Store, spec: &CommandSpec, args: &[String]) -> Reply {
+    let result = match spec.name {
+        "EXISTS" => Ok(exists(store, args)),
+        "TYPE" => Ok(key_type(store, args)),
+        "TTL" => Ok(ttl(store, args, 1000)),
+        "PTTL" => Ok(ttl(store, args, 1)),
+        _ => unreachable!(),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+fn del(store: &mut Store, args: &[String]) -> Reply {
+    let removed = args[1..]
+        .iter()
+        .filter(|key| store.contains(key) && store.remove(key).is_some())
+        .count();
+    Reply::Integer(removed as i64)
+}
+
+fn exists(store: &Store, args: &[String]) -> Reply {
+    let found = args[1..].iter().filter(|key| store.contains(key)).count();
+    Reply::Integer(found as i64)
+}
+
+fn key_type(store: &Store, args: &[String]) -> Reply {
+    let name = store
+        .get(&args[1])
+        .map_or("none", |value| value.type_name());
+    Reply::Simple(name.to_string())
+}
+
+/// Sets the expiry of a key, `unit_ms` is the length of the unit of the argument in
+/// milliseconds and `absolute` tells whether it is a unix time or a time to live.
+fn expire(
+    store: &mut Store,
+    args: &[String],
+    unit_ms: i64,
+    absolute: bool,
+) -> Result<Reply, Reply> {
+    let amount = args[2]
+        .parse::<i64>()
+        .map_err(|_| Reply::not_an_integer())?;
+    let invalid = || {
+        Reply::error(format!(
+            "ERR invalid expire time in '{}' command",
+            args[0].to_lowercase()
+        ))
+    };
+    let mut at = amount.checked_mul(unit_ms).ok_or_else(invalid)?;
+    if !absolute {
+        at = at.checked_add(now_ms() as i64).ok_or_else(invalid)?;
+    }
+    // a time in the past deletes the key
+    Ok(Reply::Integer(
+        store.set_expiry(&args[1], at.max(0) as u64) as i64
+    ))
+}
+
+fn ttl(store: &Store, args: &[String], unit_ms: u64) -> Reply {
+    if !store.contains(&args[1]) {
+        return Reply::Integer(-2);
+    }
+    match store.expiry(&args[1]) {
+        Some(at) => {
+            let remaining = at.saturating_sub(now_ms());
+            // round to the closest unit like the ttl was given
+            Reply::Integer(((remaining + unit_ms / 2) / unit_ms) as i64)
+        }
+        None => Reply::Integer(-1),
+    }
+}
@@ -1,3 +1,9 @@
+pub mod acl;
 pub mod core;
 pub mod echo;
+pub mod keys;
 pub mod ping;
+pub mod server;
+pub mod set;
+pub mod string;
+pub mod zset;
@@ -1,13 +1,13 @@
-use std::{
-    sync::{Arc, RwLock},
-    thread,
-};
+use std::sync::{Arc, RwLock};
 
-use mio::Waker;
+use crate::{
+    protocol::reply::Reply,
+    reactor::{completion::CompletionQueue, core::Reactor},
+};
 
-use crate::{async_client::client_states::ClientStates, reactor::core::Reactor};
+use super::core::{spawn_worker, Command, CommandSpec};
 
-use super::core::Command;
+pub const SPECS: &[CommandSpec] = &[CommandSpec::new("PING", -1, 0).no_keys()];
 
 pub struct Ping {}
 
@@ -23,33 +23,11 @@ impl Command for Ping {
         state: std::sync::Arc<
             std::sync::Mutex<Option<crate::async_client::client_states::ClientStates>>,
         >,
-        waker: std::sync::Arc<Waker>,
-        reactor: Arc<RwLock<Reactor>>,
+        completions: Arc<CompletionQueue>,
+        _reactor: Arc<RwLock<Reactor>>,
     ) -> std::thread::JoinHandle<()> {
-        let waker = waker.clone();
-        thread::spawn(move || {
-            println!(
-                "Worker thread {:?} spawned for ping command",
-                thread::current().id()
-            );
-            state
-                .lock()
-                .unwrap()
-                .replace(ClientStates::WriteOutput("+PONG\t\n".to_string()));
-
-            waker.clone().wake().unwrap();
-            println!("deadlock after this?");
-
-            {
-                let mut reactor = reactor.write().unwrap();
-                reactor.schedule(fd);
-            }
-            waker.clone().wake().unwrap();
-
-            println!(
-                "Worker thread {:?} spawned for ping command finished",
-                thread::current().id()
-            );
+        spawn_worker(fd, state, completions, || {
+            Reply::Simple("PONG".to_string()).encode()
         })
     }
 }
@@ -0,0 +1,312 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use crate::{
+    async_client::client_states::ClientStates,
+    persistence::{
+        rewrite::start_rewrite,
+        snapshot::{save_blocking, start_bgsave},
+    },
+    protocol::reply::Reply,
+    reactor::{completion::CompletionQueue, core::Reactor},
+    replication::{
+        core::{LinkStatus, Role},
+        upstream::spawn_connect,
+    },
+    stats::core::{self as stats, Stats},
+    store::core::Store,
+};
+
+use super::core::{find_spec, parse_args, spawn_worker, Command, CommandSpec, ADMIN};
+
+pub const SPECS: &[CommandSpec] = &[
+    CommandSpec::new("BGREWRITEAOF", 1, ADMIN).no_keys(),
+    CommandSpec::new("SAVE", 1, ADMIN).no_keys(),
+    CommandSpec::new("BGSAVE", 1, ADMIN).no_keys(),
+    CommandSpec::new("LASTSAVE", 1, ADMIN).no_keys(),
+    CommandSpec::new("REPLICAOF", 3, ADMIN).no_keys(),
+    CommandSpec::new("ROLE", 1, ADMIN).no_keys(),
+    CommandSpec::new("INFO", -1, ADMIN).no_keys(),
+];
+
+/// Server administration commands, these do not operate on individual keys.
+pub struct ServerCommand {
+    db: Arc<RwLock<Store>>,
+    stats: Arc<Stats>,
+}
+
+impl ServerCommand {
+    pub fn new(db: Arc<RwLock<Store>>, stats: Arc<Stats>) -> ServerCommand {
+        ServerCommand { db, stats }
+    }
+}
+
+impl Command for ServerCommand {
+    fn can_process(&mut self, raw_cmd: String) -> bool {
+        find_spec(SPECS, &parse_args(&raw_cmd)).is_some()
+    }
+
+    fn run(
+        &mut self,
+        raw_cmd: String,
+        fd: usize,
+        state: Arc<Mutex<Option<ClientStates>>>,
+        completions: Arc<CompletionQueue>,
+        reactor: Arc<RwLock<Reactor>>,
+    ) -> JoinHandle<()> {
+        let (db, stats) = (self.db.clone(), self.stats.clone());
+        spawn_worker(fd, state, completions, move || {
+            execute(db, reactor, &stats, &parse_args(&raw_cmd)).encode()
+        })
+    }
+}
+
+fn execute(
+    db: Arc<RwLock<Store>>,
+    reactor: Arc<RwLock<Reactor>>,
+    stats: &Stats,
+    args: &[String],
+) -> Reply {
+    let spec = match find_spec(SPECS, args) {
+        Some(spec) => spec,
+        None => return Reply::error("ERR unknown server command"),
+    };
+    if let Err(reply) = spec.check_arity(args) {
+        return reply;
+    }
+
+    match spec.name {
+        "BGREWRITEAOF" => match start_rewrite(db) {
+            Ok(_) => Reply::Simple("Background append only file rewriting started".to_string()),
+            Err(err) => err,
+        },
+        "SAVE" => match save_blocking(&mut db.write().unwrap()) {
+            Ok(_) => Reply::ok(),
+            Err(err) => err,
+        },
+        "BGSAVE" => match start_bgsave(db) {
+            Ok(_) => Reply::Simple("Background saving started".to_string()),
+            Err(err) => err,
+        },
+        "LASTSAVE" => {
+            let store = db.read().unwrap();
+            let last_save = store.snapshot_state().map_or(0, |state| state.last_save());
+            Reply::Integer(last_save as i64)
+        }
+        "REPLICAOF" => replicaof(db, reactor, args),
+        "ROLE" => role(&db.read().unwrap()),
+        "INFO" => info(&db.read().unwrap(), stats, args),
+        _ => unreachable!(),
+    }
+}
+
+/// `REPLICAOF host port` makes the server follow another instance, the connection is made in
+/// the background and the reply does not wait for the sync. `REPLICAOF NO ONE` turns a replica
+/// back into a primary keeping its data, for manual failover.
+fn replicaof(db: Arc<RwLock<Store>>, reactor: Arc<RwLock<Reactor>>, args: &[String]) -> Reply {
+    if args[1].eq_ignore_ascii_case("NO") && args[2].eq_ignore_ascii_case("ONE") {
+        let previous = {
+            let mut store = db.write().unwrap();
+            match store.replication_mut() {
+                Some(replication) => replication.promote(),
+                None => return Reply::error("ERR replication is not enabled"),
+            }
+        };
+        if let Some(upstream) = previous {
+            println!("promoted to primary, dropping the link with the former primary");
+            let _ = upstream.notify();
+        }
+        return Reply::ok();
+    }
+    let port = match args[2].parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => return Reply::error("ERR Invalid master port"),
+    };
+    let (link_id, previous) = {
+        let mut store = db.write().unwrap();
+        let replication = match store.replication_mut() {
+            Some(replication) => replication,
+            None => return Reply::error("ERR replication is not enabled"),
+        };
+        if replication
+            .upstream()
+            .is_some_and(|upstream| upstream.host == args[1] && upstream.port == port)
+        {
+            return Reply::Simple("OK Already connected to specified master".to_string());
+        }
+        replication.follow(&args[1], port)
+    };
+    // the connection to the former primary notices it is stale once polled and closes
+    if let Some(upstream) = previous {
+        let _ = upstream.notify();
+    }
+    spawn_connect(link_id, Duration::ZERO, reactor, db);
+    Reply::ok()
+}
+
+/// Reports the role of the server along with its replication offset: a primary lists its
+/// replicas with the offset they acknowledged, a replica its primary and the link status.
+fn role(store: &Store) -> Reply {
+    let replication = match store.replication() {
+        Some(replication) => replication,
+        None => return Reply::Array(vec![Reply::Bulk("master".to_string()), Reply::Integer(0)]),
+    };
+    match replication.role() {
+        Role::Primary => Reply::Array(vec![
+            Reply::Bulk("master".to_string()),
+            Reply::Integer(replication.offset() as i64),
+            Reply::Array(
+                replication
+                    .replicas()
+                    .iter()
+                    .map(|replica| {
+                        Reply::bulk_array([
+                            replica.ip().to_string(),
+                            replica.listening_port().to_string(),
+                            replica.ack_offset().to_string(),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]),
+        Role::Replica(upstream) => Reply::Array(vec![
+            Reply::Bulk("slave".to_string()),
+            Reply::Bulk(upstream.host.to_string()),
+            Reply::Integer(upstream.port as i64),
+            Reply::Bulk(upstream.status.as_str().to_string()),
+            Reply::Integer(replication.offset() as i64),
+        ]),
+    }
+}
+
+/// Sections reported by INFO when none is given.
+const INFO_SECTIONS: &[&str] = &["stats", "replication"];
+
+/// `INFO [section ...]` reports server state as `field:value` lines grouped by section.
+fn info(store: &Store, stats: &Stats, args: &[String]) -> Reply {
+    let sections: Vec<String> = match args.len() {
+        1 => INFO_SECTIONS.iter().map(|name| name.to_string()).collect(),
+        _ => args[1..].iter().map(|name| name.to_lowercase()).collect(),
+    };
+    let mut out = String::new();
+    for section in sections.iter() {
+        let fields = match section.as_str() {
+            "all" | "default" | "everything" => {
+                return info(store, stats, &[args[0].to_string()]);
+            }
+            "stats" => stats_info(stats),
+            "replication" => replication_info(store),
+            _ => continue,
+        };
+        if !out.is_empty() {
+            out.push_str("\r\n");
+        }
+        out.push_str(&format!("# {}\r\n", capitalize(section)));
+        for (field, value) in fields {
+            out.push_str(&format!("{}:{}\r\n", field, value));
+        }
+    }
+    Reply::Bulk(out)
+}
+
+fn capitalize(name: &str) -> String {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .map(|first| first.to_uppercase().chain(chars).collect())
+        .unwrap_or_default()
+}
+
+fn stats_info(stats: &Stats) -> Vec<(String, String)> {
+    [
+        ("rejected_connections", &stats.rejected_connections),
+        ("total_auth_failures", &stats.auth_failures),
+        ("total_noauth_rejections", &stats.noauth_rejections),
+        ("acl_access_denied_cmd", &stats.acl_denied_commands),
+        ("acl_access_denied_key", &stats.acl_denied_keys),
+        ("acl_access_denied_channel", &stats.acl_denied_channels),
+    ]
+    .into_iter()
+    .map(|(field, counter)| (field.to_string(), stats::read(counter).to_string()))
+    .collect()
+}
+
+fn replication_info(store: &Store) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = vec![];
+    let mut field = |name: &str, value: String| fields.push((name.to_string(), value));
+    let replication = match store.replication() {
+        Some(replication) => replication,
+        None => {
+            field("role", "master".to_string());
+            return fields;
+        }
+    };
+
+    match replication.role() {
+        Role::Primary => field("role", "master".to_string()),
+        Role::Replica(upstream) => {
+            field("role", "slave".to_string());
+            field("master_host", upstream.host.to_string());
+            field("master_port", upstream.port.to_string());
+            let up = upstream.status == LinkStatus::Connected;
+            field(
+                "master_link_status",
+                if up { "up" } else { "down" }.to_string(),
+            );
+            let last_io = upstream
+                .last_io
+                .map_or(-1, |last_io| last_io.elapsed().as_secs() as i64);
+            field("master_last_io_seconds_ago", last_io.to_string());
+            let syncing = upstream.status == LinkStatus::Sync;
+            field("master_sync_in_progress", (syncing as u8).to_string());
+            field("slave_repl_offset", replication.offset().to_string());
+            field(
+                "slave_read_only",
+                (replication.is_read_only() as u8).to_string(),
+            );
+        }
+    }
+
+    field("connected_slaves", replication.replicas().len().to_string());
+    for (idx, replica) in replication.replicas().iter().enumerate() {
+        let state = if replica.is_syncing() {
+            "wait_bgsave"
+        } else {
+            "online"
+        };
+        field(
+            &format!("slave{}", idx),
+            format!(
+                "ip={},port={},state={},offset={},lag={}",
+                replica.ip(),
+                replica.listening_port(),
+                state,
+                replica.ack_offset(),
+                replica.lag().as_secs()
+            ),
+        );
+    }
+
+    let backlog = replication.backlog();
+    field("master_replid", replication.replid().to_string());
+    field(
+        "master_replid2",
+        replication.replid2().unwrap_or("0").to_string(),
+    );
+    field("master_repl_offset", replication.offset().to_string());
+    let second_offset = match replication.replid2() {
+        Some(_) => replication.second_offset() as i64,
+        None => -1,
+    };
+    field("second_repl_offset", second_offset.to_string());
+    field("repl_backlog_size", replication.backlog_size().to_string());
+    field(
+        "repl_backlog_first_byte_offset",
+        backlog.start_offset().to_string(),
+    );
+    field("repl_backlog_histlen", backlog.len().to_string());
+    fields
+}
@@ -0,0 +1,310 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, RwLock},
+    thread::JoinHandle,
+};
+
+use rand::seq::{IteratorRandom, SliceRandom};
+
+use crate::{
+    async_client::client_states::ClientStates,
+    protocol::reply::Reply,
+    reactor::{completion::CompletionQueue, core::Reactor},
+    store::{core::Store, value::Value},
+};
+
+use super::core::{
+    find_spec, parse_args, run_keyspace, spawn_worker, Command, CommandSpec, READONLY, WRITE,
+};
+
+pub const SPECS: &[CommandSpec] = &[
+    CommandSpec::new("SADD", -3, WRITE),
+    CommandSpec::new("SREM", -3, WRITE),
+    CommandSpec::new("SISMEMBER", 3, READONLY),
+    CommandSpec::new("SCARD", 2, READONLY),
+    CommandSpec::new("SMEMBERS", 2, READONLY),
+    CommandSpec::new("SPOP", -2, WRITE),
+    CommandSpec::new("SRANDMEMBER", -2, READONLY),
+    CommandSpec::new("SINTER", -2, READONLY).keys(1, -1),
+    CommandSpec::new("SUNION", -2, READONLY).keys(1, -1),
+    CommandSpec::new("SDIFF", -2, READONLY).keys(1, -1),
+    CommandSpec::new("SINTERSTORE", -3, WRITE).keys(1, -1),
+    CommandSpec::new("SUNIONSTORE", -3, WRITE).keys(1, -1),
+    CommandSpec::new("SDIFFSTORE", -3, WRITE).keys(1, -1),
+];
+
+/// Largest negative count of SRANDMEMBER, the reply holds that many members.
+const MAX_REPEATED_MEMBERS: u64 = 1 << 20;
+
+/// Unordered set commands (SADD, SREM, SMEMBERS, ...) including the set algebra ones.
+pub struct SetCommand {
+    db: Arc<RwLock<Store>>,
+}
+
+impl SetCommand {
+    pub fn new(db: Arc<RwLock<Store>>) -> SetCommand {
+        SetCommand { db }
+    }
+}
+
+impl Command for SetCommand {
+    fn can_process(&mut self, raw_cmd: String) -> bool {
+        find_spec(SPECS, &parse_args(&raw_cmd)).is_some()
+    }
+
+    fn run(
+        &mut self,
+        raw_cmd: String,
+        fd: usize,
+        state: Arc<Mutex<Option<ClientStates>>>,
+        completions: Arc<CompletionQueue>,
+        _reactor: Arc<RwLock<Reactor>>,
+    ) -> JoinHandle<()> {
+        let db = self.db.clone();
+        spawn_worker(fd, state, completions, move || {
+            run_keyspace(&db, &parse_args(&raw_cmd)).encode()
+        })
+    }
+}
+
+/// Executes a set command against the store.
+pub fn execute(store: &mut Store, args: &[String]) -> Reply {
+    let spec = match find_spec(SPECS, args) {
+        Some(spec) => spec,
+        None => return Reply::error("ERR unknown set command"),
+    };
+    if let Err(reply) = spec.check_arity(args) {
+        return reply;
+    }
+
+    let result = match spec.name {
+        "SADD" => sadd(store, args),
+        "SREM" => srem(store, args),
+        "SPOP" => spop(store, args),
+        "SINTERSTORE" => store_result(store, args, Op::Inter),
+        "SUNIONSTORE" => store_result(store, args, Op::Union),
+        "SDIFFSTORE" => store_result(store, args, Op::Diff),
+        _ => return read(store, spec, args),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+/// Executes a read-only set command, it only needs the store read lock. Returns None for
+/// the other commands.
+pub fn query(store: &Store, args: &[String]) -> Option<Reply> {
+    let spec = find_spec(SPECS, args).filter(|spec| spec.is_readonly())?;
+    Some(match spec.check_arity(args) {
+        Ok(()) => read(store, spec, args),
+        Err(reply) => reply,
+    })
+}
+
+fn read(store: &Store, spec: &CommandSpec, args: &[String]) -> Reply {
+    let result = match spec.name {
+        "SISMEMBER" => sismember(store, args),
+        "SCARD" => scard(store, args),
+        "SMEMBERS" => smembers(store, args),
+        "SRANDMEMBER" => srandmember(store, args),
+        "SINTER" => combine(store, &args[1..], Op::Inter).map(Reply::bulk_array),
+        "SUNION" => combine(store, &args[1..], Op::Union).map(Reply::bulk_array),
+        "SDIFF" => combine(store, &args[1..], Op::Diff).map(Reply::bulk_array),
+        _ => unreachable!(),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+#[derive(Clone, Copy)]
+enum Op {
+    Inter,
+    Union,
+    Diff,
+}
+
+fn read_set<'a>(store: &'a Store, key: &str) -> Result<Option<&'a HashSet<String>>, Reply> {
+    match store.get(key) {
+        Some(value) => value.as_set().map(Some).ok_or_else(Reply::wrong_type),
+        None => Ok(None),
+    }
+}
+
+/// The set at `key`, created empty if missing. Callers report a change with `Store::modified`.
+fn write_set<'a>(store: &'a mut Store, key: &str) -> Result<&'a mut HashSet<String>, Reply> {
+    read_set(store, key)?;
+    store
+        .get_or_insert_with(key, || Value::Set(HashSet::new()))
+        .as_set_mut()
+        .ok_or_else(Reply::wrong_type)
+}
+
+/// Drops the key once the last member has been removed so that empty sets never linger.
+fn remove_if_empty(store: &mut Store, key: &str) {
+    if let Ok(Some(set)) = read_set(store, key) {
+        if set.is_empty() {
+            store.discard(key);
+        }
+    }
+}
+
+fn parse_count(raw: &str) -> Result<i64, Reply> {
+    raw.parse::<i64>().map_err(|_| Reply::not_an_integer())
+}
+
+fn sadd(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let set = write_set(store, &args[1])?;
+    let added = args[2..]
+        .iter()
+        .filter(|member| set.insert(member.to_string()))
+        .count();
+    if added > 0 {
+        store.modified(&args[1]);
+    }
+    Ok(Reply::Integer(added as i64))
+}
+
+fn srem(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    if read_set(store, &args[1])?.is_none() {
+        return Ok(Reply::Integer(0));
+    }
+    let set = write_set(store, &args[1])?;
+    let removed = args[2..]
+        .iter()
+        .filter(|member| set.remove(member.as_str()))
+        .count();
+    if removed > 0 {
+        store.modified(&args[1]);
+    }
+    remove_if_empty(store, &args[1]);
+    Ok(Reply::Integer(removed as i64))
+}
+
+fn sismember(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let found = read_set(store, &args[1])?.is_some_and(|set| set.contains(&args[2]));
+    Ok(Reply::Integer(found as i64))
+}
+
+fn scard(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let len = read_set(store, &args[1])?.map_or(0, |set| set.len());
+    Ok(Reply::Integer(len as i64))
+}
+
+fn smembers(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let members = read_set(store, &args[1])?
+        .map(|set| set.iter().cloned().collect::<Vec<_>>())
+        .unwrap_or_default();
+    Ok(Reply::bulk_array(members))
+}
+
+fn spop(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    if args.len() > 3 {
+        return Err(Reply::syntax_error());
+    }
+    let count = match args.get(2) {
+        Some(raw) => {
+            let count = parse_count(raw)?;
+            if count < 0 {
+                return Err(Reply::error("ERR value is out of range, must be positive"));
+            }
+            Some(count as usize)
+        }
+        None => None,
+    };
+
+    let popped = match read_set(store, &args[1])? {
+        Some(set) => {
+            let mut rng = rand::thread_rng();
+            set.iter()
+                .cloned()
+                .choose_multiple(&mut rng, count.unwrap_or(1).min(set.len()))
+        }
+        None => vec![],
+    };
+    if !popped.is_empty() {
+        let set = write_set(store, &args[1])?;
+        for member in popped.iter() {
+            set.remove(member);
+        }
+        store.modified(&args[1]);
+        remove_if_empty(store, &args[1]);
+    }
+
+    match count {
+        Some(_) => Ok(Reply::bulk_array(popped)),
+        None => Ok(popped.into_iter().next().map_or(Reply::Nil, Reply::Bulk)),
+    }
+}
+
+fn srandmember(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    if args.len() > 3 {
+        return Err(Reply::syntax_error());
+    }
+    let count = args.get(2).map(|raw| parse_count(raw)).transpose()?;
+    let set = read_set(store, &args[1])?;
+    let mut rng = rand::thread_rng();
+
+    match (set, count) {
+        (None, None) => Ok(Reply::Nil),
+        (None, Some(_)) => Ok(Reply::Array(vec![])),
+        (Some(set), None) => Ok(set
+            .iter()
+            .choose(&mut rng)
+            .map_or(Reply::Nil, |member| Reply::Bulk(member.to_string()))),
+        (Some(set), Some(count)) if count >= 0 => Ok(Reply::bulk_array(
+            set.iter()
+                .cloned()
+                .choose_multiple(&mut rng, (count as usize).min(set.len())),
+        )),
+        (Some(_), Some(count)) if count.unsigned_abs() > MAX_REPEATED_MEMBERS => {
+            Err(Reply::error("ERR value is out of range"))
+        }
+        (Some(set), Some(count)) => {
+            // a negative count allows the same member to be returned more than once
+            let members: Vec<&String> = set.iter().collect();
+            let picked = (0..count.unsigned_abs())
+                .filter_map(|_| members.choose(&mut rng).map(|member| member.to_string()));
+            Ok(Reply::bulk_array(picked))
+        }
+    }
+}
+
+fn combine(store: &Store, keys: &[String], op: Op) -> Result<Vec<String>, Reply> {
+    // type check every key up front so that a wrong type is reported even when the result is
+    // already known to be empty
+    let sets = keys
+        .iter()
+        .map(|key| read_set(store, key))
+        .collect::<Result<Vec<_>, Reply>>()?;
+    let empty = HashSet::new();
+    let first = sets[0].unwrap_or(&empty);
+    let others = &sets[1..];
+
+    let result = first.iter().filter(|member| match op {
+        Op::Inter => others
+            .iter()
+            .all(|set| set.is_some_and(|set| set.contains(*member))),
+        Op::Diff => !others
+            .iter()
+            .any(|set| set.is_some_and(|set| set.contains(*member))),
+        Op::Union => true,
+    });
+    let mut result: HashSet<String> = result.cloned().collect();
+    if let Op::Union = op {
+        for set in others.iter().flatten() {
+            result.extend(set.iter().cloned());
+        }
+    }
+    Ok(result.into_iter().collect())
+}
+
+fn store_result(store: &mut Store, args: &[String], op: Op) -> Result<Reply, Reply> {
+    let result = combine(store, &args[2..], op)?;
+    let len = result.len();
+    if result.is_empty() {
+        store.remove(&args[1]);
+    } else {
+        store.insert(
+            args[1].to_string(),
+            Value::Set(result.into_iter().collect()),
+        );
+    }
+    Ok(Reply::Integer(len as i64))
+}
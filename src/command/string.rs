@@ -0,0 +1,317 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    thread::JoinHandle,
+};
+
+use crate::{
+    async_client::client_states::ClientStates,
+    protocol::reply::Reply,
+    reactor::{completion::CompletionQueue, core::Reactor},
+    store::{
+        core::{now_ms, Store},
+        value::Value,
+    },
+};
+
+use super::core::{
+    find_spec, parse_args, run_keyspace, spawn_worker, Command, CommandSpec, READONLY, WRITE,
+};
+
+/// Largest string SETRANGE is allowed to produce.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// Error of GETRANGE and SETRANGE when a bound falls inside a multi-byte character.
+const SPLIT_CHARACTER: &str = "ERR offset splits a multi-byte character";
+
+pub const SPECS: &[CommandSpec] = &[
+    CommandSpec::new("GET", 2, READONLY),
+    CommandSpec::new("SET", -3, WRITE),
+    CommandSpec::new("GETSET", 3, WRITE),
+    CommandSpec::new("GETDEL", 2, WRITE),
+    CommandSpec::new("INCR", 2, WRITE),
+    CommandSpec::new("DECR", 2, WRITE),
+    CommandSpec::new("INCRBY", 3, WRITE),
+    CommandSpec::new("DECRBY", 3, WRITE),
+    CommandSpec::new("INCRBYFLOAT", 3, WRITE),
+    CommandSpec::new("APPEND", 3, WRITE),
+    CommandSpec::new("STRLEN", 2, READONLY),
+    CommandSpec::new("GETRANGE", 4, READONLY),
+    CommandSpec::new("SETRANGE", 4, WRITE),
+];
+
+/// String commands including the atomic counter and substring operations.
+///
+/// Writes run while holding the store write lock, so increments issued from different worker
+/// threads never interleave.
+pub struct StringCommand {
+    db: Arc<RwLock<Store>>,
+}
+
+impl StringCommand {
+    pub fn new(db: Arc<RwLock<Store>>) -> StringCommand {
+        StringCommand { db }
+    }
+}
+
+impl Command for StringCommand {
+    fn can_process(&mut self, raw_cmd: String) -> bool {
+        find_spec(SPECS, &parse_args(&raw_cmd)).is_some()
+    }
+
+    fn run(
+        &mut self,
+        raw_cmd: String,
+        fd: usize,
+        state: Arc<Mutex<Option<ClientStates>>>,
+        completions: Arc<CompletionQueue>,
+        _reactor: Arc<RwLock<Reactor>>,
+    ) -> JoinHandle<()> {
+        let db = self.db.clone();
+        spawn_worker(fd, state, completions, move || {
+            run_keyspace(&db, &parse_args(&raw_cmd)).encode()
+        })
+    }
+}
+
+/// Executes a string command against the store.
+pub fn execute(store: &mut Store, args: &[String]) -> Reply {
+    let spec = match find_spec(SPECS, args) {
+        Some(spec) => spec,
+        None => return Reply::error("ERR unknown string command"),
+    };
+    if let Err(reply) = spec.check_arity(args) {
+        return reply;
+    }
+
+    let result = match spec.name {
+        "SET" => set(store, args),
+        "GETSET" => getset(store, args),
+        "GETDEL" => getdel(store, args),
+        "INCR" => incr_by(store, &args[1], 1),
+        "DECR" => incr_by(store, &args[1], -1),
+        "INCRBY" => parse_int(&args[2]).and_then(|delta| incr_by(store, &args[1], delta)),
+        "DECRBY" => parse_int(&args[2]).and_then(|delta| match delta.checked_neg() {
+            Some(delta) => incr_by(store, &args[1], delta),
+            None => Err(overflow()),
+        }),
+        "INCRBYFLOAT" => incr_by_float(store, args),
+        "APPEND" => append(store, args),
+        "SETRANGE" => setrange(store, args),
+        _ => return read(store, spec, args),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+/// Executes a read-only string command, it only needs the store read lock. Returns None for
+/// the other commands.
+pub fn query(store: &Store, args: &[String]) -> Option<Reply> {
+    let spec = find_spec(SPECS, args).filter(|spec| spec.is_readonly())?;
+    Some(match spec.check_arity(args) {
+        Ok(()) => read(store, spec, args),
+        Err(reply) => reply,
+    })
+}
+
+fn read(store: &Store, spec: &CommandSpec, args: &[String]) -> Reply {
+    let result = match spec.name {
+        "GET" => get(store, args),
+        "STRLEN" => strlen(store, args),
+        "GETRANGE" => getrange(store, args),
+        _ => unreachable!(),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+fn overflow() -> Reply {
+    Reply::error("ERR increment or decrement would overflow")
+}
+
+fn parse_int(raw: &str) -> Result<i64, Reply> {
+    raw.parse::<i64>().map_err(|_| Reply::not_an_integer())
+}
+
+fn read_string<'a>(store: &'a Store, key: &str) -> Result<Option<&'a String>, Reply> {
+    match store.get(key) {
+        Some(value) => value.as_string().map(Some).ok_or_else(Reply::wrong_type),
+        None => Ok(None),
+    }
+}
+
+/// The string at `key`, created empty if missing. Every caller changes it, so it is reported to
+/// watchers right away.
+fn write_string<'a>(store: &'a mut Store, key: &str) -> Result<&'a mut String, Reply> {
+    read_string(store, key)?;
+    store.modified(key);
+    store
+        .get_or_insert_with(key, || Value::String(String::new()))
+        .as_string_mut()
+        .ok_or_else(Reply::wrong_type)
+}
+
+fn bulk_or_nil(val: Option<&String>) -> Reply {
+    val.map_or(Reply::Nil, |val| Reply::Bulk(val.to_string()))
+}
+
+fn get(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    Ok(bulk_or_nil(read_string(store, &args[1])?))
+}
+
+/// Parses the expiry option of SET into a unix time in milliseconds.
+fn parse_expire_option(option: &str, raw: &str) -> Result<u64, Reply> {
+    let amount = raw.parse::<i64>().map_err(|_| Reply::not_an_integer())?;
+    if amount <= 0 {
+        return Err(Reply::error("ERR invalid expire time in 'set' command"));
+    }
+    let ms = match option {
+        "EX" => amount.checked_mul(1000),
+        _ => Some(amount),
+    };
+    ms.and_then(|ms| (now_ms() as i64).checked_add(ms))
+        .map(|at| at as u64)
+        .ok_or_else(|| Reply::error("ERR invalid expire time in 'set' command"))
+}
+
+fn set(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let mut nx = false;
+    let mut xx = false;
+    let mut expiry = None;
+    let mut options = args[3..].iter();
+    while let Some(arg) = options.next() {
+        match arg.to_uppercase().as_str() {
+            "NX" if !xx => nx = true,
+            "XX" if !nx => xx = true,
+            option @ ("EX" | "PX") if expiry.is_none() => {
+                let raw = options.next().ok_or_else(Reply::syntax_error)?;
+                expiry = Some(parse_expire_option(option, raw)?);
+            }
+            _ => return Err(Reply::syntax_error()),
+        }
+    }
+
+    let exists = store.contains(&args[1]);
+    if (nx && exists) || (xx && !exists) {
+        return Ok(Reply::Nil);
+    }
+    store.insert(args[1].to_string(), Value::String(args[2].to_string()));
+    if let Some(at) = expiry {
+        store.set_expiry(&args[1], at);
+    }
+    Ok(Reply::ok())
+}
+
+fn getset(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let old = bulk_or_nil(read_string(store, &args[1])?);
+    store.insert(args[1].to_string(), Value::String(args[2].to_string()));
+    Ok(old)
+}
+
+fn getdel(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let old = bulk_or_nil(read_string(store, &args[1])?);
+    store.remove(&args[1]);
+    Ok(old)
+}
+
+fn incr_by(store: &mut Store, key: &str, delta: i64) -> Result<Reply, Reply> {
+    let current = match read_string(store, key)? {
+        Some(val) => parse_int(val)?,
+        None => 0,
+    };
+    let next = current.checked_add(delta).ok_or_else(overflow)?;
+    *write_string(store, key)? = next.to_string();
+    Ok(Reply::Integer(next))
+}
+
+fn incr_by_float(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let parse = |raw: &str| {
+        raw.parse::<f64>()
+            .ok()
+            .filter(|val| val.is_finite())
+            .ok_or_else(|| Reply::error("ERR value is not a valid float"))
+    };
+    let delta = parse(&args[2])?;
+    let current = match read_string(store, &args[1])? {
+        Some(val) => parse(val)?,
+        None => 0.0,
+    };
+    let next = current + delta;
+    if !next.is_finite() {
+        return Err(Reply::error("ERR increment would produce NaN or Infinity"));
+    }
+    let next = format!("{}", next);
+    *write_string(store, &args[1])? = next.to_string();
+    Ok(Reply::Bulk(next))
+}
+
+fn append(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let val = write_string(store, &args[1])?;
+    val.push_str(&args[2]);
+    Ok(Reply::Integer(val.len() as i64))
+}
+
+fn strlen(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let len = read_string(store, &args[1])?.map_or(0, |val| val.len());
+    Ok(Reply::Integer(len as i64))
+}
+
+fn getrange(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let mut start = parse_int(&args[2])?;
+    let mut end = parse_int(&args[3])?;
+    let val = match read_string(store, &args[1])? {
+        Some(val) => val,
+        None => return Ok(Reply::Bulk(String::new())),
+    };
+
+    let len = val.len() as i64;
+    if start < 0 && end < 0 && start > end {
+        return Ok(Reply::Bulk(String::new()));
+    }
+    if start < 0 {
+        start = (len + start).max(0);
+    }
+    if end < 0 {
+        end = (len + end).max(0);
+    }
+    end = end.min(len - 1);
+    if start > end || len == 0 {
+        return Ok(Reply::Bulk(String::new()));
+    }
+    // values are text, a range cutting a character in two has no text to reply with
+    match val.get(start as usize..=end as usize) {
+        Some(slice) => Ok(Reply::Bulk(slice.to_string())),
+        None => Err(Reply::error(SPLIT_CHARACTER)),
+    }
+}
+
+fn setrange(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let offset = parse_int(&args[2])?;
+    if offset < 0 {
+        return Err(Reply::error("ERR offset is out of range"));
+    }
+    let offset = offset as usize;
+    let patch = args[3].as_bytes();
+    if offset + patch.len() > MAX_STRING_LEN {
+        return Err(Reply::error(
+            "ERR string exceeds maximum allowed size (proto-max-bulk-len)",
+        ));
+    }
+    if read_string(store, &args[1])?.is_none() && patch.is_empty() {
+        return Ok(Reply::Integer(0));
+    }
+
+    // checked before anything is written, the value is left as it was on error
+    if let Some(val) = read_string(store, &args[1])? {
+        let end = (offset + patch.len()).min(val.len());
+        if offset < val.len() && !(val.is_char_boundary(offset) && val.is_char_boundary(end)) {
+            return Err(Reply::error(SPLIT_CHARACTER));
+        }
+    }
+
+    let val = write_string(store, &args[1])?;
+    if val.len() < offset {
+        // padded with zero bytes, which are text too
+        val.extend(std::iter::repeat_n('\0', offset - val.len()));
+    }
+    let end = (offset + patch.len()).min(val.len());
+    val.replace_range(offset..end, &args[3]);
+    Ok(Reply::Integer(val.len() as i64))
+}
@@ -0,0 +1,541 @@
+use std::{
+    sync::{Arc, Mutex, RwLock},
+    thread::JoinHandle,
+};
+
+use crate::{
+    async_client::client_states::ClientStates,
+    protocol::reply::Reply,
+    reactor::{completion::CompletionQueue, core::Reactor},
+    store::{core::Store, sorted_set::SortedSet, value::Value},
+};
+
+use super::core::{
+    find_spec, parse_args, run_keyspace, spawn_worker, Command, CommandSpec, READONLY, WRITE,
+};
+
+pub const SPECS: &[CommandSpec] = &[
+    CommandSpec::new("ZADD", -4, WRITE),
+    CommandSpec::new("ZREM", -3, WRITE),
+    CommandSpec::new("ZSCORE", 3, READONLY),
+    CommandSpec::new("ZCARD", 2, READONLY),
+    CommandSpec::new("ZRANK", 3, READONLY),
+    CommandSpec::new("ZREVRANK", 3, READONLY),
+    CommandSpec::new("ZRANGE", -4, READONLY),
+    CommandSpec::new("ZCOUNT", 4, READONLY),
+    CommandSpec::new("ZPOPMIN", -2, WRITE),
+    CommandSpec::new("ZPOPMAX", -2, WRITE),
+];
+
+/// Sorted set commands, used for leaderboards and time ordered indexes.
+pub struct SortedSetCommand {
+    db: Arc<RwLock<Store>>,
+}
+
+impl SortedSetCommand {
+    pub fn new(db: Arc<RwLock<Store>>) -> SortedSetCommand {
+        SortedSetCommand { db }
+    }
+}
+
+impl Command for SortedSetCommand {
+    fn can_process(&mut self, raw_cmd: String) -> bool {
+        find_spec(SPECS, &parse_args(&raw_cmd)).is_some()
+    }
+
+    fn run(
+        &mut self,
+        raw_cmd: String,
+        fd: usize,
+        state: Arc<Mutex<Option<ClientStates>>>,
+        completions: Arc<CompletionQueue>,
+        _reactor: Arc<RwLock<Reactor>>,
+    ) -> JoinHandle<()> {
+        let db = self.db.clone();
+        spawn_worker(fd, state, completions, move || {
+            run_keyspace(&db, &parse_args(&raw_cmd)).encode()
+        })
+    }
+}
+
+/// Executes a sorted set command against the store.
+pub fn execute(store: &mut Store, args: &[String]) -> Reply {
+    let spec = match find_spec(SPECS, args) {
+        Some(spec) => spec,
+        None => return Reply::error("ERR unknown sorted set command"),
+    };
+    if let Err(reply) = spec.check_arity(args) {
+        return reply;
+    }
+
+    let result = match spec.name {
+        "ZADD" => zadd(store, args),
+        "ZREM" => zrem(store, args),
+        "ZPOPMIN" => zpop(store, args, false),
+        "ZPOPMAX" => zpop(store, args, true),
+        _ => return read(store, spec, args),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+/// Executes a read-only sorted set command, it only needs the store read lock. Returns None for
+/// the other commands.
+pub fn query(store: &Store, args: &[String]) -> Option<Reply> {
+    let spec = find_spec(SPECS, args).filter(|spec| spec.is_readonly())?;
+    Some(match spec.check_arity(args) {
+        Ok(()) => read(store, spec, args),
+        Err(reply) => reply,
+    })
+}
+
+fn read(store: &Store, spec: &CommandSpec, args: &[String]) -> Reply {
+    let result = match spec.name {
+        "ZSCORE" => zscore(store, args),
+        "ZCARD" => zcard(store, args),
+        "ZRANK" => zrank(store, args, false),
+        "ZREVRANK" => zrank(store, args, true),
+        "ZRANGE" => zrange(store, args),
+        "ZCOUNT" => zcount(store, args),
+        _ => unreachable!(),
+    };
+    result.unwrap_or_else(|err| err)
+}
+
+/// Formats a score the same way it is accepted on input, `1` rather than `1.0`.
+pub fn format_score(score: f64) -> String {
+    format!("{}", score)
+}
+
+fn parse_score(raw: &str) -> Result<f64, Reply> {
+    match raw.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => Err(Reply::error("ERR value is not a valid float")),
+    }
+}
+
+fn parse_int(raw: &str) -> Result<i64, Reply> {
+    raw.parse::<i64>().map_err(|_| Reply::not_an_integer())
+}
+
+fn read_zset<'a>(store: &'a Store, key: &str) -> Result<Option<&'a SortedSet>, Reply> {
+    match store.get(key) {
+        Some(value) => value
+            .as_sorted_set()
+            .map(Some)
+            .ok_or_else(Reply::wrong_type),
+        None => Ok(None),
+    }
+}
+
+/// The sorted set at `key`, created empty if missing. Callers report a change with
+/// `Store::modified`.
+fn write_zset<'a>(store: &'a mut Store, key: &str) -> Result<&'a mut SortedSet, Reply> {
+    read_zset(store, key)?;
+    store
+        .get_or_insert_with(key, || Value::SortedSet(SortedSet::default()))
+        .as_sorted_set_mut()
+        .ok_or_else(Reply::wrong_type)
+}
+
+fn remove_if_empty(store: &mut Store, key: &str) {
+    if let Ok(Some(zset)) = read_zset(store, key) {
+        if zset.is_empty() {
+            store.discard(key);
+        }
+    }
+}
+
+fn flatten<'a, I>(items: I, withscores: bool) -> Reply
+where
+    I: IntoIterator<Item = (&'a str, f64)>,
+{
+    let mut out = vec![];
+    for (member, score) in items {
+        out.push(Reply::Bulk(member.to_string()));
+        if withscores {
+            out.push(Reply::Bulk(format_score(score)));
+        }
+    }
+    Reply::Array(out)
+}
+
+#[derive(Default)]
+struct ZaddFlags {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+    ch: bool,
+    incr: bool,
+}
+
+fn zadd(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    let mut flags = ZaddFlags::default();
+    let mut pos = 2;
+    while let Some(arg) = args.get(pos) {
+        match arg.to_uppercase().as_str() {
+            "NX" => flags.nx = true,
+            "XX" => flags.xx = true,
+            "GT" => flags.gt = true,
+            "LT" => flags.lt = true,
+            "CH" => flags.ch = true,
+            "INCR" => flags.incr = true,
+            _ => break,
+        }
+        pos += 1;
+    }
+
+    let pairs = &args[pos..];
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        return Err(Reply::syntax_error());
+    }
+    if flags.nx && flags.xx {
+        return Err(Reply::error(
+            "ERR XX and NX options at the same time are not compatible",
+        ));
+    }
+    if (flags.gt && flags.lt) || ((flags.gt || flags.lt) && flags.nx) {
+        return Err(Reply::error(
+            "ERR GT, LT, and/or NX options at the same time are not compatible",
+        ));
+    }
+    if flags.incr && pairs.len() > 2 {
+        return Err(Reply::error(
+            "ERR INCR option supports a single increment-element pair",
+        ));
+    }
+    // parse every score before touching the set so that a bad pair leaves it unchanged
+    let pairs = pairs
+        .chunks(2)
+        .map(|pair| Ok((parse_score(&pair[0])?, pair[1].to_string())))
+        .collect::<Result<Vec<_>, Reply>>()?;
+
+    let zset = write_zset(store, &args[1])?;
+    let mut added = 0;
+    let mut changed = 0;
+    let mut incr_result = None;
+    for (score, member) in pairs {
+        let current = zset.score(&member);
+        let new_score = match current {
+            None if flags.xx => continue,
+            Some(_) if flags.nx => continue,
+            Some(current) if flags.incr => current + score,
+            _ => score,
+        };
+        if new_score.is_nan() {
+            return Err(Reply::error("ERR resulting score is not a number (NaN)"));
+        }
+        match current {
+            Some(current) if flags.gt && new_score <= current => continue,
+            Some(current) if flags.lt && new_score >= current => continue,
+            Some(current) if current == new_score => {}
+            Some(_) => {
+                zset.insert(member, new_score);
+                changed += 1;
+            }
+            None => {
+                zset.insert(member, new_score);
+                added += 1;
+            }
+        }
+        incr_result = Some(new_score);
+    }
+    if added + changed > 0 {
+        store.modified(&args[1]);
+    }
+    remove_if_empty(store, &args[1]);
+
+    if flags.incr {
+        return Ok(incr_result.map_or(Reply::Nil, |score| Reply::Bulk(format_score(score))));
+    }
+    if flags.ch {
+        added += changed;
+    }
+    Ok(Reply::Integer(added))
+}
+
+fn zrem(store: &mut Store, args: &[String]) -> Result<Reply, Reply> {
+    if read_zset(store, &args[1])?.is_none() {
+        return Ok(Reply::Integer(0));
+    }
+    let zset = write_zset(store, &args[1])?;
+    let removed = args[2..]
+        .iter()
+        .filter(|member| zset.remove(member).is_some())
+        .count();
+    if removed > 0 {
+        store.modified(&args[1]);
+    }
+    remove_if_empty(store, &args[1]);
+    Ok(Reply::Integer(removed as i64))
+}
+
+fn zscore(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let score = read_zset(store, &args[1])?.and_then(|zset| zset.score(&args[2]));
+    Ok(score.map_or(Reply::Nil, |score| Reply::Bulk(format_score(score))))
+}
+
+fn zcard(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let len = read_zset(store, &args[1])?.map_or(0, |zset| zset.len());
+    Ok(Reply::Integer(len as i64))
+}
+
+fn zrank(store: &Store, args: &[String], rev: bool) -> Result<Reply, Reply> {
+    let rank = read_zset(store, &args[1])?.and_then(|zset| {
+        zset.rank(&args[2])
+            .map(|rank| if rev { zset.len() - 1 - rank } else { rank })
+    });
+    Ok(rank.map_or(Reply::Nil, |rank| Reply::Integer(rank as i64)))
+}
+
+#[derive(Clone, Copy)]
+struct ScoreBound {
+    value: f64,
+    exclusive: bool,
+}
+
+impl ScoreBound {
+    fn parse(raw: &str) -> Result<ScoreBound, Reply> {
+        let (exclusive, raw) = match raw.strip_prefix('(') {
+            Some(rest) => (true, rest),
+            None => (false, raw),
+        };
+        match raw.parse::<f64>() {
+            Ok(value) if !value.is_nan() => Ok(ScoreBound { value, exclusive }),
+            _ => Err(Reply::error("ERR min or max is not a float")),
+        }
+    }
+
+    fn below(&self, score: f64) -> bool {
+        score < self.value || (self.exclusive && score == self.value)
+    }
+
+    fn above(&self, score: f64) -> bool {
+        score > self.value || (self.exclusive && score == self.value)
+    }
+}
+
+enum LexBound {
+    Min,
+    Max,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    fn parse(raw: &str) -> Result<LexBound, Reply> {
+        match raw {
+            "-" => Ok(LexBound::Min),
+            "+" => Ok(LexBound::Max),
+            _ if raw.starts_with('[') => Ok(LexBound::Inclusive(raw[1..].to_string())),
+            _ if raw.starts_with('(') => Ok(LexBound::Exclusive(raw[1..].to_string())),
+            _ => Err(Reply::error("ERR min or max not valid string range item")),
+        }
+    }
+
+    fn below(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member < bound.as_str(),
+            LexBound::Exclusive(bound) => member <= bound.as_str(),
+        }
+    }
+
+    fn above(&self, member: &str) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member > bound.as_str(),
+            LexBound::Exclusive(bound) => member >= bound.as_str(),
+        }
+    }
+}
+
+#[derive(PartialEq)]
+enum RangeBy {
+    Index,
+    Score,
+    Lex,
+}
+
+fn zrange(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let mut by = RangeBy::Index;
+    let mut rev = false;
+    let mut withscores = false;
+    let mut limit = None;
+
+    let mut pos = 4;
+    while let Some(arg) = args.get(pos) {
+        match arg.to_uppercase().as_str() {
+            "BYSCORE" => by = RangeBy::Score,
+            "BYLEX" => by = RangeBy::Lex,
+            "REV" => rev = true,
+            "WITHSCORES" => withscores = true,
+            "LIMIT" if pos + 2 < args.len() => {
+                limit = Some((parse_int(&args[pos + 1])?, parse_int(&args[pos + 2])?));
+                pos += 2;
+            }
+            _ => return Err(Reply::syntax_error()),
+        }
+        pos += 1;
+    }
+    if limit.is_some() && by == RangeBy::Index {
+        return Err(Reply::error(
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX",
+        ));
+    }
+    if withscores && by == RangeBy::Lex {
+        return Err(Reply::error(
+            "ERR syntax error, WITHSCORES not supported in combination with BYLEX",
+        ));
+    }
+
+    // with REV the range is given from the highest to the lowest end
+    let (low, high) = if rev && by != RangeBy::Index {
+        (&args[3], &args[2])
+    } else {
+        (&args[2], &args[3])
+    };
+
+    let zset = match read_zset(store, &args[1])? {
+        Some(zset) => zset,
+        None => {
+            // still validate the range so that errors do not depend on the key existing
+            match by {
+                RangeBy::Index => {
+                    parse_int(low)?;
+                    parse_int(high)?;
+                }
+                RangeBy::Score => {
+                    ScoreBound::parse(low)?;
+                    ScoreBound::parse(high)?;
+                }
+                RangeBy::Lex => {
+                    LexBound::parse(low)?;
+                    LexBound::parse(high)?;
+                }
+            }
+            return Ok(Reply::Array(vec![]));
+        }
+    };
+
+    // positions of the range in ascending order, members are only visited once LIMIT narrowed
+    // them down
+    let len = zset.len();
+    let (mut lo, mut hi) = match by {
+        RangeBy::Index => {
+            let len = len as i64;
+            let mut start = parse_int(low)?;
+            let mut stop = parse_int(high)?;
+            if start < 0 {
+                start = (len + start).max(0);
+            }
+            if stop < 0 {
+                stop += len;
+            }
+            stop = stop.min(len - 1);
+            if start > stop || start >= len {
+                (0, 0)
+            } else if rev {
+                ((len - 1 - stop) as usize, (len - start) as usize)
+            } else {
+                (start as usize, stop as usize + 1)
+            }
+        }
+        RangeBy::Score => {
+            let min = ScoreBound::parse(low)?;
+            let max = ScoreBound::parse(high)?;
+            (
+                zset.count_below(|_, score| min.below(score)),
+                zset.count_below(|_, score| !max.above(score)),
+            )
+        }
+        RangeBy::Lex => {
+            let min = LexBound::parse(low)?;
+            let max = LexBound::parse(high)?;
+            (
+                zset.count_below(|member, _| min.below(member)),
+                zset.count_below(|member, _| !max.above(member)),
+            )
+        }
+    };
+    hi = hi.max(lo);
+
+    match limit {
+        Some((offset, _)) if offset < 0 => hi = lo,
+        Some((offset, count)) => {
+            let skip = (offset as u64).min((hi - lo) as u64) as usize;
+            let take = match count {
+                count if count < 0 => hi - lo - skip,
+                count => (count as u64).min((hi - lo - skip) as u64) as usize,
+            };
+            // the offset counts from the end the range is read from
+            if rev {
+                hi -= skip;
+                lo = hi - take;
+            } else {
+                lo += skip;
+                hi = lo + take;
+            }
+        }
+        None => {}
+    }
+
+    let items: Vec<(&str, f64)> = if rev {
+        zset.range(lo..hi).rev().collect()
+    } else {
+        zset.range(lo..hi).collect()
+    };
+    Ok(flatten(items, withscores))
+}
+
+fn zcount(store: &Store, args: &[String]) -> Result<Reply, Reply> {
+    let min = ScoreBound::parse(&args[2])?;
+    let max = ScoreBound::parse(&args[3])?;
+    let count = read_zset(store, &args[1])?.map_or(0, |zset| {
+        let lo = zset.count_below(|_, score| min.below(score));
+        let hi = zset.count_below(|_, score| !max.above(score));
+        hi.saturating_sub(lo)
+    });
+    Ok(Reply::Integer(count as i64))
+}
+
+fn zpop(store: &mut Store, args: &[String], max: bool) -> Result<Reply, Reply> {
+    if args.len() > 3 {
+        return Err(Reply::syntax_error());
+    }
+    let count = match args.get(2) {
+        Some(raw) => {
+            let count = parse_int(raw)?;
+            if count < 0 {
+                return Err(Reply::error("ERR value is out of range, must be positive"));
+            }
+            count as usize
+        }
+        None => 1,
+    };
+    if read_zset(store, &args[1])?.is_none() {
+        return Ok(Reply::Array(vec![]));
+    }
+
+    let zset = write_zset(store, &args[1])?;
+    let mut popped = vec![];
+    for _ in 0..count {
+        let item = if max { zset.pop_max() } else { zset.pop_min() };
+        match item {
+            Some(item) => popped.push(item),
+            None => break,
+        }
+    }
+    if !popped.is_empty() {
+        store.modified(&args[1]);
+    }
+    remove_if_empty(store, &args[1]);
+    Ok(flatten(
+        popped
+            .iter()
+            .map(|(member, score)| (member.as_str(), *score)),
+        true,
+    ))
+}
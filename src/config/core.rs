@@ -0,0 +1,393 @@
+use std::{
+    fs,
+    io::{self, Result},
+    path::PathBuf,
+};
+
+use super::listener::{ListenAddr, ListenerConfig};
+
+/// When the append only file is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FsyncPolicy {
+    /// fsync after every write command, slowest but nothing is lost.
+    Always,
+    /// fsync once per second from a background thread.
+    EverySec,
+    /// leave flushing to the operating system.
+    No,
+}
+
+impl FsyncPolicy {
+    pub fn parse(raw: &str) -> Option<FsyncPolicy> {
+        match raw.to_lowercase().as_str() {
+            "always" => Some(FsyncPolicy::Always),
+            "everysec" => Some(FsyncPolicy::EverySec),
+            "no" => Some(FsyncPolicy::No),
+            _ => None,
+        }
+    }
+}
+
+/// Whether TLS clients have to present a certificate signed by `tls-ca-cert-file`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TlsAuthClients {
+    No,
+    /// a certificate is verified when presented but not required
+    Optional,
+    Yes,
+}
+
+impl TlsAuthClients {
+    pub fn parse(raw: &str) -> Option<TlsAuthClients> {
+        match raw.to_lowercase().as_str() {
+            "no" => Some(TlsAuthClients::No),
+            "optional" => Some(TlsAuthClients::Optional),
+            "yes" => Some(TlsAuthClients::Yes),
+            _ => None,
+        }
+    }
+}
+
+/// What the reactors poll their sources with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PollerBackend {
+    /// epoll on Linux, kqueue on the BSDs
+    Mio,
+    /// Linux only, built with the `io-uring` feature
+    IoUring,
+}
+
+impl PollerBackend {
+    pub fn parse(raw: &str) -> Option<PollerBackend> {
+        match raw.to_lowercase().as_str() {
+            "mio" => Some(PollerBackend::Mio),
+            "io_uring" | "io-uring" => Some(PollerBackend::IoUring),
+            _ => None,
+        }
+    }
+}
+
+/// Server configuration.
+///
+/// It is read from an optional config file made of `key value` lines, after which
+/// `--key value` command line arguments override individual settings.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// address of the TCP listener, empty disables it
+    pub bind: String,
+    /// path of the Unix socket listener, None disables it
+    pub unixsocket: Option<PathBuf>,
+    /// permissions of the Unix socket file
+    pub unixsocketperm: u32,
+    /// connections served at once over every listener, 0 for no limit
+    pub maxclients: usize,
+    /// event loop threads, each with its own reactor, TCP listeners are bound by each of them
+    /// with SO_REUSEPORT
+    pub event_loops: usize,
+    /// backend the event loops poll with
+    pub poller: PollerBackend,
+    /// listeners declared with `listen`, served along with the ones above
+    pub listen: Vec<ListenerConfig>,
+    pub dir: PathBuf,
+    pub dbfilename: String,
+    /// automatic snapshot rules, `(seconds, changes)`: save once at least `changes` writes
+    /// happened and `seconds` elapsed since the last save
+    pub save: Vec<(u64, u64)>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: FsyncPolicy,
+    /// growth over the size after the last rewrite, in percent, that triggers a rewrite
+    pub auto_aof_rewrite_percentage: u64,
+    /// no automatic rewrite happens below this size
+    pub auto_aof_rewrite_min_size: u64,
+    /// primary to follow at startup, `(host, port)`
+    pub replicaof: Option<(String, u16)>,
+    /// bytes of the replication stream kept for replicas resuming after a disconnect
+    pub repl_backlog_size: u64,
+    /// bytes of the stream waiting for a replica before it is disconnected
+    pub repl_output_buffer_limit: u64,
+    /// replicas reject write commands from clients
+    pub replica_read_only: bool,
+    /// password clients have to send with AUTH, None leaves the server open
+    pub requirepass: Option<String>,
+    /// password sent to the primary before syncing
+    pub masterauth: Option<String>,
+    /// user authenticating with `masterauth`, the default user when None
+    pub masteruser: Option<String>,
+    /// file the users are loaded from at startup and written to by ACL SAVE
+    pub aclfile: Option<PathBuf>,
+    /// port of the TLS listener, on the host of `bind`, 0 disables it
+    pub tls_port: u16,
+    /// PEM certificate chain and private key of the TLS listener
+    pub tls_cert_file: Option<PathBuf>,
+    pub tls_key_file: Option<PathBuf>,
+    /// PEM certificates of the authorities client certificates are verified against
+    pub tls_ca_cert_file: Option<PathBuf>,
+    pub tls_auth_clients: TlsAuthClients,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            bind: "127.0.0.1:7878".to_string(),
+            unixsocket: None,
+            unixsocketperm: 0o700,
+            maxclients: 10000,
+            event_loops: 1,
+            poller: PollerBackend::Mio,
+            listen: vec![],
+            dir: PathBuf::from("."),
+            dbfilename: "dump.snap".to_string(),
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: FsyncPolicy::EverySec,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+            replicaof: None,
+            repl_backlog_size: 1024 * 1024,
+            repl_output_buffer_limit: 256 * 1024 * 1024,
+            replica_read_only: true,
+            requirepass: None,
+            masterauth: None,
+            masteruser: None,
+            aclfile: None,
+            tls_port: 0,
+            tls_cert_file: None,
+            tls_key_file: None,
+            tls_ca_cert_file: None,
+            tls_auth_clients: TlsAuthClients::No,
+        }
+    }
+}
+
+pub(super) fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn parse_bool(key: &str, value: &str) -> Result<bool> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(invalid(format!("'{}' must be yes or no", key))),
+    }
+}
+
+pub(super) fn parse_number(key: &str, value: &str) -> Result<u64> {
+    value
+        .parse::<u64>()
+        .map_err(|_| invalid(format!("'{}' must be a positive number", key)))
+}
+
+/// Parses an octal file mode such as `770`.
+pub(super) fn parse_mode(key: &str, value: &str) -> Result<u32> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|perm| *perm <= 0o777)
+        .ok_or_else(|| invalid(format!("'{}' must be an octal mode", key)))
+}
+
+/// Parses a size such as `64mb`, `512kb` or a plain number of bytes.
+fn parse_memory(key: &str, value: &str) -> Result<u64> {
+    let lower = value.to_lowercase();
+    let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(idx) => lower.split_at(idx),
+        None => (lower.as_str(), ""),
+    };
+    let unit = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return Err(invalid(format!("'{}' has an invalid unit", key))),
+    };
+    Ok(parse_number(key, digits)? * unit)
+}
+
+/// Parses save rules given as `seconds changes` pairs, an empty value disables them.
+fn parse_save_rules(key: &str, value: &str) -> Result<Vec<(u64, u64)>> {
+    let value = value.trim_matches('"');
+    let numbers = value
+        .split_whitespace()
+        .map(|number| parse_number(key, number))
+        .collect::<Result<Vec<u64>>>()?;
+    if !numbers.len().is_multiple_of(2) {
+        return Err(invalid(format!(
+            "'{}' expects pairs of seconds and changes",
+            key
+        )));
+    }
+    Ok(numbers.chunks(2).map(|rule| (rule[0], rule[1])).collect())
+}
+
+/// Parses an optional value such as a password, an empty value unsets it.
+fn parse_optional(value: &str) -> Option<String> {
+    Some(value.trim_matches('"').to_string()).filter(|value| !value.is_empty())
+}
+
+/// Parses a `host port` pair.
+fn parse_host_port(key: &str, value: &str) -> Result<(String, u16)> {
+    let invalid_value = || invalid(format!("'{}' expects a host and a port", key));
+    let (host, port) = value
+        .trim_matches('"')
+        .split_once(char::is_whitespace)
+        .ok_or_else(invalid_value)?;
+    let port = port.trim().parse::<u16>().map_err(|_| invalid_value())?;
+    Ok((host.to_string(), port))
+}
+
+impl Config {
+    /// Builds the configuration from the process arguments (without the binary name).
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the config file can not be read or a setting is
+    /// unknown or invalid.
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> Result<Config> {
+        let mut config = Config::default();
+        let mut args = args.into_iter().peekable();
+
+        if let Some(path) = args.next_if(|arg| !arg.starts_with("--")) {
+            config.load_file(&path)?;
+        }
+        while let Some(arg) = args.next() {
+            let key = arg
+                .strip_prefix("--")
+                .ok_or_else(|| invalid(format!("unexpected argument '{}'", arg)))?;
+            let value = args
+                .next()
+                .ok_or_else(|| invalid(format!("missing value for '--{}'", key)))?;
+            config.set(key, &value)?;
+        }
+        Ok(config)
+    }
+
+    /// Applies every `key value` line of a config file, empty lines and `#` comments are skipped.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can not be read or holds an invalid line.
+    pub fn load_file(&mut self, path: &str) -> Result<()> {
+        for (n, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, value) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| invalid(format!("{}:{}: missing value", path, n + 1)))?;
+            self.set(key, value.trim())
+                .map_err(|err| invalid(format!("{}:{}: {}", path, n + 1, err)))?;
+        }
+        Ok(())
+    }
+
+    /// Updates a single setting.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the key is unknown or the value is invalid.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        match key.to_lowercase().as_str() {
+            "bind" => self.bind = value.trim_matches('"').to_string(),
+            "unixsocket" => self.unixsocket = parse_optional(value).map(PathBuf::from),
+            "unixsocketperm" => self.unixsocketperm = parse_mode(key, value)?,
+            "maxclients" => {
+                self.maxclients = parse_number(key, value)?
+                    .try_into()
+                    .map_err(|_| invalid(format!("'{}' is too large", key)))?
+            }
+            "poller" => {
+                self.poller = PollerBackend::parse(value)
+                    .ok_or_else(|| invalid("'poller' must be mio or io_uring".to_string()))?
+            }
+            "event-loops" => {
+                self.event_loops = parse_number(key, value)?
+                    .try_into()
+                    .ok()
+                    .filter(|threads| *threads > 0)
+                    .ok_or_else(|| invalid(format!("'{}' must be at least 1", key)))?
+            }
+            "listen" => self.listen.push(ListenerConfig::parse(value)?),
+            "dir" => self.dir = PathBuf::from(value),
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "save" => self.save = parse_save_rules(key, value)?,
+            "appendonly" => self.appendonly = parse_bool(key, value)?,
+            "appendfilename" => self.appendfilename = value.to_string(),
+            "appendfsync" => {
+                self.appendfsync = FsyncPolicy::parse(value).ok_or_else(|| {
+                    invalid("'appendfsync' must be always, everysec or no".to_string())
+                })?
+            }
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = parse_number(key, value)?
+            }
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size = parse_memory(key, value)?
+            }
+            "replicaof" => self.replicaof = Some(parse_host_port(key, value)?),
+            "repl-backlog-size" => self.repl_backlog_size = parse_memory(key, value)?,
+            "repl-output-buffer-limit" => self.repl_output_buffer_limit = parse_memory(key, value)?,
+            "replica-read-only" => self.replica_read_only = parse_bool(key, value)?,
+            "requirepass" => self.requirepass = parse_optional(value),
+            "masterauth" => self.masterauth = parse_optional(value),
+            "masteruser" => self.masteruser = parse_optional(value),
+            "aclfile" => self.aclfile = parse_optional(value).map(PathBuf::from),
+            "tls-port" => {
+                self.tls_port = parse_number(key, value)?
+                    .try_into()
+                    .map_err(|_| invalid(format!("'{}' must be a valid port", key)))?
+            }
+            "tls-cert-file" => self.tls_cert_file = parse_optional(value).map(PathBuf::from),
+            "tls-key-file" => self.tls_key_file = parse_optional(value).map(PathBuf::from),
+            "tls-ca-cert-file" => self.tls_ca_cert_file = parse_optional(value).map(PathBuf::from),
+            "tls-auth-clients" => {
+                self.tls_auth_clients = TlsAuthClients::parse(value).ok_or_else(|| {
+                    invalid("'tls-auth-clients' must be yes, no or optional".to_string())
+                })?
+            }
+            _ => return Err(invalid(format!("unknown setting '{}'", key))),
+        }
+        Ok(())
+    }
+
+    /// Every listener to serve: `bind`, `tls-port` and `unixsocket` followed by the declared
+    /// ones.
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        let perm = self.unixsocketperm;
+        let tcp = Some(self.bind.as_str())
+            .filter(|bind| !bind.is_empty())
+            .map(|bind| ListenAddr::Tcp(bind.to_string()));
+        let tls = self.tls_addr().map(ListenAddr::Tls);
+        let unix = self.unixsocket.clone().map(ListenAddr::Unix);
+        [tcp, tls, unix]
+            .into_iter()
+            .flatten()
+            .map(|addr| ListenerConfig::new(addr, perm))
+            .chain(self.listen.iter().cloned())
+            .collect()
+    }
+
+    /// Address of the TLS listener, None when it is disabled.
+    pub fn tls_addr(&self) -> Option<String> {
+        if self.tls_port == 0 {
+            return None;
+        }
+        let host = self
+            .bind
+            .rsplit_once(':')
+            .map_or("127.0.0.1", |(host, _)| host);
+        Some(format!("{}:{}", host, self.tls_port))
+    }
+
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.dir.join(&self.dbfilename)
+    }
+
+    pub fn aof_path(&self) -> PathBuf {
+        self.dir.join(&self.appendfilename)
+    }
+}
@@ -0,0 +1,105 @@
+use std::{io::Result, net::SocketAddr, path::PathBuf};
+
+use super::core::{invalid, parse_mode, parse_number};
+
+/// Address a listener binds.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ListenAddr {
+    Tcp(String),
+    /// TCP served over TLS with the `tls-*` certificate settings
+    Tls(String),
+    Unix(PathBuf),
+}
+
+/// How connections accepted by a listener authenticate.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthPolicy {
+    /// authenticated as the default user unless it has a password
+    Default,
+    /// always start unauthenticated, even when the default user has no password
+    Required,
+    /// trusted connections, authenticated as the user from the start
+    As(String),
+}
+
+/// A listener declared with `listen <url> [option=value ...]`, e.g.
+/// `listen unix:///run/server.sock perm=700 user=admin maxclients=4`.
+///
+/// Urls are `tcp://host:port`, `tls://host:port` and `unix:///path`, IPv6 hosts are written
+/// in brackets. Options are `maxclients` (0 for no limit), `auth` (`default` or `required`),
+/// `user` and `perm`, the mode of a Unix socket file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListenerConfig {
+    pub addr: ListenAddr,
+    /// connections served at once, 0 for no limit
+    pub maxclients: usize,
+    pub auth: AuthPolicy,
+    pub perm: u32,
+}
+
+impl ListenerConfig {
+    pub fn new(addr: ListenAddr, perm: u32) -> ListenerConfig {
+        ListenerConfig {
+            addr,
+            maxclients: 0,
+            auth: AuthPolicy::Default,
+            perm,
+        }
+    }
+
+    /// Parses the value of a `listen` setting.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the url or an option is invalid.
+    pub fn parse(value: &str) -> Result<ListenerConfig> {
+        let mut words = value.trim_matches('"').split_whitespace();
+        let url = words
+            .next()
+            .ok_or_else(|| invalid("'listen' expects an url".to_string()))?;
+        let mut listener = ListenerConfig::new(parse_url(url)?, 0o700);
+        for option in words {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| invalid(format!("'listen' option '{}' has no value", option)))?;
+            match key.to_lowercase().as_str() {
+                "maxclients" => {
+                    listener.maxclients = parse_number(key, value)?
+                        .try_into()
+                        .map_err(|_| invalid(format!("'{}' is too large", key)))?
+                }
+                "auth" => {
+                    listener.auth = match value.to_lowercase().as_str() {
+                        "default" => AuthPolicy::Default,
+                        "required" => AuthPolicy::Required,
+                        _ => return Err(invalid("'auth' must be default or required".to_string())),
+                    }
+                }
+                "user" => listener.auth = AuthPolicy::As(value.to_string()),
+                "perm" => listener.perm = parse_mode(key, value)?,
+                _ => return Err(invalid(format!("unknown 'listen' option '{}'", key))),
+            }
+        }
+        Ok(listener)
+    }
+}
+
+fn parse_url(url: &str) -> Result<ListenAddr> {
+    let (scheme, addr) = url
+        .split_once("://")
+        .ok_or_else(|| invalid(format!("'{}' is not a tcp://, tls:// or unix:// url", url)))?;
+    let socket_addr = || {
+        addr.parse::<SocketAddr>()
+            .map(|_| addr.to_string())
+            .map_err(|_| invalid(format!("invalid address '{}'", addr)))
+    };
+    match scheme.to_lowercase().as_str() {
+        "tcp" => Ok(ListenAddr::Tcp(socket_addr()?)),
+        "tls" => Ok(ListenAddr::Tls(socket_addr()?)),
+        "unix" if !addr.is_empty() => Ok(ListenAddr::Unix(PathBuf::from(addr))),
+        _ => Err(invalid(format!(
+            "'{}' is not a tcp://, tls:// or unix:// url",
+            url
+        ))),
+    }
+}
@@ -1,62 +1,82 @@
 use std::{
     collections::HashMap,
     io::Result,
+    mem,
     sync::{Arc, RwLock},
 };
 
-use mio::Events;
-
-use crate::reactor::{core::Reactor, event_listener::EventListener};
+use crate::reactor::{
+    completion::CompletionQueue, core::Reactor, event::Event, event_listener::EventListener,
+};
 
 pub struct EventLoop {
     pub connection_handler_map: HashMap<usize, Box<dyn EventListener>>,
     pub reactor: Arc<RwLock<Reactor>>,
+    completions: Arc<CompletionQueue>,
+    /// reused by each wait
+    events: Vec<Event>,
 }
 
 impl EventLoop {
     pub fn new(reactor: Arc<RwLock<Reactor>>) -> EventLoop {
+        let completions = reactor.read().unwrap().completions();
         EventLoop {
             connection_handler_map: HashMap::new(),
             reactor,
+            completions,
+            events: Vec::with_capacity(1024),
         }
     }
 
     pub fn run(&mut self) -> Result<()> {
         loop {
-            // get all the scheduled tasks and poll for them
-            self.poll_for_scheduled_tasks()?;
-            // handle new connections
-            self.handle_new_connections()?;
-            // handle old connections
-            self.handle_dead_connections()?;
-            // wait for io events and run events for them
-            self.wait_for_events()?;
+            self.run_once()?;
+        }
+    }
+
+    /// Runs one iteration of the loop, the simulation drives the loop with it.
+    pub fn run_once(&mut self) -> Result<()> {
+        // poll the handlers other threads completed work for
+        self.poll_completions();
+        // get all the scheduled tasks and poll for them
+        self.poll_for_scheduled_tasks()?;
+        // handle new connections
+        self.handle_new_connections()?;
+        // handle old connections
+        self.handle_dead_connections()?;
+        // wait for io events and run events for them
+        self.wait_for_events()
+    }
+
+    fn poll_completions(&mut self) {
+        for id in self.completions.drain() {
+            println!("completion for Fd {}", id);
+            if let Some(handler) = self.connection_handler_map.get_mut(&id) {
+                if let Err(err) = handler.poll() {
+                    println!("FD {} has failed with error: {}", id, err);
+                }
+            }
         }
     }
 
+    /// Polls the handlers scheduled so far, in the order they were scheduled. Tasks they
+    /// schedule in turn run on the next iteration, after the sockets that became ready, so a
+    /// handler with more work than it does per poll can't starve the others.
     fn poll_for_scheduled_tasks(&mut self) -> Result<()> {
-        loop {
-            if let Some(id) = {
-                let mut reactor = self.reactor.write().unwrap();
-                reactor.tasks.pop()
-            } {
-                println!("scheduled task for Fd {}", id);
-                if let Some(handler) = self.connection_handler_map.get_mut(&id) {
-                    match handler.poll() {
-                        Ok(_) => {}
-                        Err(err) => {
-                            println!("FD {} has failed with error: {}", id, err);
-                        }
+        let tasks = {
+            let mut reactor = self.reactor.write().unwrap();
+            mem::take(&mut reactor.tasks)
+        };
+        for id in tasks {
+            println!("scheduled task for Fd {}", id);
+            if let Some(handler) = self.connection_handler_map.get_mut(&id) {
+                match handler.poll() {
+                    Ok(_) => {}
+                    Err(err) => {
+                        println!("FD {} has failed with error: {}", id, err);
                     }
                 }
             }
-            let res = {
-                let reactor = self.reactor.read().unwrap();
-                reactor.tasks.is_empty()
-            };
-            if res {
-                break;
-            }
         }
 
         Ok(())
@@ -87,18 +107,22 @@ impl EventLoop {
 
     fn wait_for_events(&mut self) -> Result<()> {
         println!("waiting for i/o");
-        let mut events = Events::with_capacity(1024);
-        {
-            let mut reactor = self.reactor.write().unwrap();
-            reactor.wait(&mut events).unwrap();
-        }
+        let (poller, timeout) = {
+            let reactor = self.reactor.read().unwrap();
+            (reactor.poller(), reactor.poll_timeout())
+        };
+        // the reactor isn't held while waiting, the threads waking the loop may need it
+        let mut events = mem::take(&mut self.events);
+        events.clear();
+        poller.wait(&mut events, timeout)?;
         for ev in events.iter() {
             println!("events {:?}", ev);
 
-            if let Some(handler) = self.connection_handler_map.get_mut(&ev.token().0) {
+            if let Some(handler) = self.connection_handler_map.get_mut(&ev.token()) {
                 handler.handle_event(ev);
             }
         }
+        self.events = events;
 
         Ok(())
     }
@@ -0,0 +1,255 @@
+use std::{
+    io::Result,
+    os::fd::{FromRawFd, IntoRawFd},
+    sync::{Arc, RwLock},
+    thread::{self, JoinHandle},
+};
+
+use mio::net::{TcpListener, UnixListener};
+use rustls::ServerConfig;
+
+use crate::{
+    async_server::{
+        core::AsyncTcpCommandServer,
+        listener::{ListenerPolicy, ServerListener},
+    },
+    config::core::PollerBackend,
+    reactor::{core::Reactor, event_listener::EventListener, poller::core::new_poller},
+    store::core::Store,
+};
+
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::reactor::poller::uring::UringListener;
+
+use super::core::EventLoop;
+
+/// A bound listener waiting for the shard that serves it to start.
+pub enum BoundListener {
+    Tcp(TcpListener),
+    Tls(TcpListener, Arc<ServerConfig>),
+    Unix(UnixListener),
+}
+
+impl BoundListener {
+    /// The server accepting the connections of the listener, registered with `reactor`.
+    pub fn serve(
+        self,
+        policy: Arc<ListenerPolicy>,
+        reactor: Arc<RwLock<Reactor>>,
+        db: Arc<RwLock<Store>>,
+    ) -> Box<dyn EventListener> {
+        match self {
+            BoundListener::Tcp(listener) => accept_on(listener, None, policy, reactor, db),
+            BoundListener::Tls(listener, tls) => {
+                accept_on(listener, Some(tls), policy, reactor, db)
+            }
+            BoundListener::Unix(listener) => accept_on(listener, None, policy, reactor, db),
+        }
+    }
+}
+
+/// Serves a listener, through the ring of the reactor when its poller has one.
+fn accept_on<L>(
+    listener: L,
+    tls: Option<Arc<ServerConfig>>,
+    policy: Arc<ListenerPolicy>,
+    reactor: Arc<RwLock<Reactor>>,
+    db: Arc<RwLock<Store>>,
+) -> Box<dyn EventListener>
+where
+    L: ServerListener,
+    L::Stream: FromRawFd + IntoRawFd,
+{
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    {
+        let ring = reactor.read().unwrap().poller.ring();
+        if let Some(ring) = ring {
+            let listener = UringListener::new(listener, ring);
+            return Box::new(AsyncTcpCommandServer::new(
+                listener, tls, policy, reactor, db,
+            ));
+        }
+    }
+    Box::new(AsyncTcpCommandServer::new(
+        listener, tls, policy, reactor, db,
+    ))
+}
+
+/// One event loop with its own reactor, serving the connections accepted by its listeners.
+///
+/// Shards only share the store, so the reactor lock is only contended by the shard's own
+/// handlers and the workers running their commands. Handlers aren't Send, they are created on
+/// the thread running the shard.
+pub struct Shard {
+    id: usize,
+    reactor: Arc<RwLock<Reactor>>,
+    listeners: Vec<(BoundListener, Arc<ListenerPolicy>)>,
+}
+
+impl Shard {
+    /// # Errors
+    ///
+    /// This function will return an error if the poller of the reactor can't be created.
+    pub fn new(id: usize, backend: PollerBackend) -> Result<Shard> {
+        Ok(Shard {
+            id,
+            reactor: Arc::new(RwLock::new(Reactor::new(new_poller(backend)?))),
+            listeners: vec![],
+        })
+    }
+
+    pub fn reactor(&self) -> Arc<RwLock<Reactor>> {
+        self.reactor.clone()
+    }
+
+    pub fn listen(&mut self, listener: BoundListener, policy: Arc<ListenerPolicy>) {
+        self.listeners.push((listener, policy));
+    }
+
+    /// Registers the listeners and runs the event loop on the current thread.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the event loop fails.
+    pub fn run(self, db: Arc<RwLock<Store>>) -> Result<()> {
+        let mut event_loop = EventLoop::new(self.reactor.clone());
+        for (listener, policy) in self.listeners {
+            let server = listener.serve(policy, self.reactor.clone(), db.clone());
+            println!(
+                "event loop {} listening with {} over {}",
+                self.id,
+                server.name(),
+                self.reactor.read().unwrap().poller.name()
+            );
+            event_loop
+                .connection_handler_map
+                .insert(server.id(), server);
+        }
+        event_loop.run()
+    }
+
+    /// Runs the shard on a thread of its own.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the thread can not be spawned.
+    pub fn spawn(self, db: Arc<RwLock<Store>>) -> Result<JoinHandle<()>> {
+        let id = self.id;
+        thread::Builder::new()
+            .name(format!("event-loop-{}", id))
+            .spawn(move || {
+                if let Err(err) = self.run(db) {
+                    println!("event loop {} has failed with error: {}", id, err);
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpStream,
+    };
+
+    use super::*;
+    use crate::{
+        async_server::listener::{bind_tcp, ClientLimit},
+        config::listener::{ListenAddr, ListenerConfig},
+        harness::core::TIMEOUT,
+        stats::core::Stats,
+    };
+
+    fn policy() -> Arc<ListenerPolicy> {
+        let config = ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        Arc::new(ListenerPolicy::new(
+            &config,
+            Arc::new(ClientLimit::new(0)),
+            Arc::new(Stats::default()),
+        ))
+    }
+
+    fn connect(addr: &str) -> TcpStream {
+        let conn = TcpStream::connect(addr).unwrap();
+        conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+        conn
+    }
+
+    fn request(conn: &mut TcpStream, command: &str, expected: &str) {
+        conn.write_all(command.as_bytes()).unwrap();
+        let mut reply = vec![0; expected.len()];
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(String::from_utf8(reply).unwrap(), expected, "{:?}", command);
+    }
+
+    #[test]
+    fn shards_share_the_connections_of_a_port() {
+        let policy = policy();
+        let db = Arc::new(RwLock::new(Store::default()));
+        let mut addr = "127.0.0.1:0".to_string();
+        let mut reactors = vec![];
+        // the event loops run until the test process exits
+        for id in 0..2 {
+            let mut shard = Shard::new(id, PollerBackend::Mio).unwrap();
+            let listener = bind_tcp(&addr, true).unwrap();
+            addr = listener.local_addr().unwrap().to_string();
+            shard.listen(BoundListener::Tcp(listener), policy.clone());
+            reactors.push(shard.reactor());
+            shard.spawn(db.clone()).unwrap();
+        }
+
+        let conns: Vec<_> = (0..32)
+            .map(|_| {
+                let mut conn = connect(&addr);
+                conn.write_all(b"PING\r\n").unwrap();
+                let mut reply = [0; 7];
+                conn.read_exact(&mut reply).unwrap();
+                assert_eq!(&reply, b"+PONG\r\n");
+                conn
+            })
+            .collect();
+        // the kernel hashes connections over the listeners, each shard serves some of them
+        let served: Vec<_> = reactors
+            .iter()
+            .map(|reactor| reactor.read().unwrap().existing_tokens.len() - 1)
+            .collect();
+        assert_eq!(served.iter().sum::<usize>(), conns.len());
+        assert!(served.iter().all(|&served| served > 0), "{:?}", served);
+    }
+
+    #[test]
+    fn watches_of_clients_on_different_shards_are_apart() {
+        let (policy, db) = (policy(), Arc::new(RwLock::new(Store::default())));
+        // the first client of each shard gets the same token from its reactor
+        let addrs: Vec<_> = (0..2)
+            .map(|id| {
+                let mut shard = Shard::new(id, PollerBackend::Mio).unwrap();
+                let listener = bind_tcp("127.0.0.1:0", false).unwrap();
+                let addr = listener.local_addr().unwrap().to_string();
+                shard.listen(BoundListener::Tcp(listener), policy.clone());
+                shard.spawn(db.clone()).unwrap();
+                addr
+            })
+            .collect();
+        let mut first = connect(&addrs[0]);
+        let mut second = connect(&addrs[1]);
+
+        // a write to a key the first client watches leaves the second one alone
+        request(&mut first, "WATCH a\r\n", "+OK\r\n");
+        request(&mut first, "SET a 1\r\n", "+OK\r\n");
+        request(&mut second, "WATCH b\r\n", "+OK\r\n");
+        request(&mut second, "MULTI\r\n", "+OK\r\n");
+        request(&mut second, "SET b 1\r\n", "+QUEUED\r\n");
+        request(&mut second, "EXEC\r\n", "*1\r\n+OK\r\n");
+
+        // and the first client ending its transaction doesn't clear the second one's
+        request(&mut second, "WATCH b\r\n", "+OK\r\n");
+        request(&mut first, "SET b 2\r\n", "+OK\r\n");
+        request(&mut first, "MULTI\r\n", "+OK\r\n");
+        request(&mut first, "EXEC\r\n", "*-1\r\n");
+        request(&mut second, "MULTI\r\n", "+OK\r\n");
+        request(&mut second, "SET b 3\r\n", "+QUEUED\r\n");
+        request(&mut second, "EXEC\r\n", "*-1\r\n");
+        request(&mut second, "GET b\r\n", "$1\r\n2\r\n");
+    }
+}
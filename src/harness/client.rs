@@ -0,0 +1,192 @@
+//! End-to-end tests of the client library against a [`TestServer`](super::core::TestServer).
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::ErrorKind,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use async_tcp_command_client::{
+        Connection, NonBlockingConnection, Pipeline, Pool, PoolConfig, Value,
+    };
+    use mio::{Events, Interest, Poll, Token};
+
+    use crate::harness::core::{TestServer, TIMEOUT};
+
+    fn bulk(text: &str) -> Value {
+        Value::Bulk(text.to_string())
+    }
+
+    fn connect(server: &TestServer) -> Connection {
+        Connection::connect_timeout(&server.addr(), TIMEOUT).unwrap()
+    }
+
+    #[test]
+    fn decodes_typed_replies() {
+        let server = TestServer::start();
+        let mut conn = connect(&server);
+        conn.ping().unwrap();
+        assert_eq!(
+            conn.command(&["SET", "key", "value"]).unwrap(),
+            Value::Simple("OK".to_string())
+        );
+        assert_eq!(conn.command(&["GET", "key"]).unwrap(), bulk("value"));
+        assert_eq!(conn.command(&["GET", "missing"]).unwrap(), Value::Nil);
+        assert_eq!(conn.command(&["INCR", "n"]).unwrap(), Value::Integer(1));
+        assert_eq!(
+            conn.command(&["ECHO", "hi"]).unwrap(),
+            Value::Bulk("hi".to_string())
+        );
+        conn.command(&["ZADD", "z", "2", "b", "1", "a"]).unwrap();
+        assert_eq!(
+            conn.command(&["ZRANGE", "z", "0", "-1"]).unwrap(),
+            Value::Array(vec![bulk("a"), bulk("b")])
+        );
+
+        let reply = conn.command(&["NOPE"]).unwrap();
+        assert_eq!(reply, Value::Error("ERR unknown command".to_string()));
+        assert_eq!(reply.into_result().unwrap_err().kind(), ErrorKind::Other);
+        // an argument the server would split in two is refused before anything is sent
+        let err = conn.command(&["SET", "key", "two words"]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(!conn.is_broken());
+        conn.ping().unwrap();
+    }
+
+    #[test]
+    fn pipelines_commands() {
+        let server = TestServer::start();
+        let mut conn = connect(&server);
+        let mut pipeline = Pipeline::new();
+        let mut expected = vec![];
+        for i in 0..1000 {
+            let (key, value) = (format!("key{i}"), i.to_string());
+            pipeline
+                .add(&["SET", &key, &value])
+                .unwrap()
+                .add(&["GET", &key])
+                .unwrap();
+            expected.push(Value::Simple("OK".to_string()));
+            expected.push(bulk(&value));
+        }
+        assert_eq!(conn.pipeline(&pipeline).unwrap(), expected);
+        assert_eq!(conn.command(&["GET", "key999"]).unwrap(), bulk("999"));
+    }
+
+    #[test]
+    fn breaks_when_the_server_closes() {
+        let server = TestServer::start();
+        let mut conn = connect(&server);
+        assert_eq!(
+            conn.command(&["QUIT"]).unwrap(),
+            Value::Simple("OK".to_string())
+        );
+        assert!(conn.ping().is_err());
+        assert!(conn.is_broken());
+        assert_eq!(conn.ping().unwrap_err().kind(), ErrorKind::NotConnected);
+    }
+
+    #[test]
+    fn polls_a_non_blocking_connection() {
+        const CONN: Token = Token(0);
+        let server = TestServer::start();
+        let mut poll = Poll::new().unwrap();
+        let mut events = Events::with_capacity(16);
+        let mut conn = NonBlockingConnection::connect(server.addr()).unwrap();
+        poll.registry()
+            .register(&mut conn, CONN, Interest::READABLE | Interest::WRITABLE)
+            .unwrap();
+
+        let mut pipeline = Pipeline::new();
+        for i in 0..2000 {
+            pipeline.add(&["SET", &format!("key{i}"), "value"]).unwrap();
+        }
+        conn.send_pipeline(&pipeline);
+        conn.send(&["GET", "key1999"]).unwrap();
+        assert_eq!(conn.pending(), 2001);
+
+        let mut replies = vec![];
+        let deadline = Instant::now() + TIMEOUT;
+        while conn.pending() > 0 {
+            assert!(Instant::now() < deadline, "replies didn't come in");
+            poll.poll(&mut events, Some(Duration::from_millis(100)))
+                .unwrap();
+            conn.flush().unwrap();
+            while let Some(reply) = conn.try_recv().unwrap() {
+                replies.push(reply);
+            }
+        }
+        assert!(!conn.wants_write());
+        assert_eq!(replies.len(), 2001);
+        assert!(replies[..2000]
+            .iter()
+            .all(|reply| *reply == Value::Simple("OK".to_string())));
+        assert_eq!(replies[2000], bulk("value"));
+    }
+
+    #[test]
+    fn pools_connections_between_threads() {
+        let server = TestServer::start();
+        let config = PoolConfig {
+            max_size: 4,
+            ..PoolConfig::default()
+        };
+        let pool = Pool::new(server.addr(), config).unwrap();
+        thread::scope(|scope| {
+            for t in 0..16 {
+                let pool = &pool;
+                scope.spawn(move || {
+                    for i in 0..50 {
+                        let mut conn = pool.get().unwrap();
+                        let key = format!("t{t}:{i}");
+                        conn.command(&["SET", &key, "v"]).unwrap();
+                        assert_eq!(conn.command(&["GET", &key]).unwrap(), bulk("v"));
+                    }
+                });
+            }
+        });
+        let (open, idle) = pool.status();
+        assert!(open <= 4 && open == idle, "{} open, {} idle", open, idle);
+        assert!(server.wait_for_clients(open));
+    }
+
+    #[test]
+    fn pool_waits_for_a_connection() {
+        let server = TestServer::start();
+        let config = PoolConfig {
+            max_size: 1,
+            wait_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        };
+        let pool = Pool::new(server.addr(), config).unwrap();
+        let conn = pool.get().unwrap();
+        assert_eq!(pool.get().err().unwrap().kind(), ErrorKind::TimedOut);
+        drop(conn);
+        pool.get().unwrap().ping().unwrap();
+    }
+
+    #[test]
+    fn pool_replaces_unhealthy_connections() {
+        let server = TestServer::start();
+        let config = PoolConfig {
+            max_size: 1,
+            check_after: Duration::ZERO,
+            ..PoolConfig::default()
+        };
+        let pool = Pool::new(server.addr(), config).unwrap();
+        {
+            let mut conn = pool.get().unwrap();
+            // the server closes the connection, which goes back to the pool looking fine
+            conn.command(&["QUIT"]).unwrap();
+        }
+        assert_eq!(pool.status(), (1, 1));
+        assert!(server.wait_for_clients(0));
+
+        let mut conn = pool.get().unwrap();
+        assert_eq!(conn.command(&["ECHO", "fresh"]).unwrap(), bulk("fresh"));
+        drop(conn);
+        assert_eq!(pool.status(), (1, 1));
+    }
+}
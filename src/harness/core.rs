@@ -0,0 +1,951 @@
+use std::{
+    io::Result,
+    net::{SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, RwLock,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use rustls::ServerConfig;
+
+use crate::{
+    async_server::listener::{bind_tcp, bind_unix, ClientLimit, ListenerPolicy},
+    config::listener::{ListenAddr, ListenerConfig},
+    event_loop::{core::EventLoop, shard::BoundListener},
+    reactor::{
+        core::Reactor,
+        poller::{core::Poller, mio_poller::MioPoller},
+    },
+    stats::core::Stats,
+    store::core::Store,
+};
+
+/// How long helpers wait for the server before failing the test.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Stops a [`TestServer`] from any thread.
+#[derive(Clone)]
+pub struct Shutdown {
+    stop: Arc<AtomicBool>,
+    poller: Arc<dyn Poller>,
+}
+
+impl Shutdown {
+    /// Ends the event loop after its current iteration, it is woken if it waits.
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.poller.wake();
+    }
+}
+
+/// The whole server stack, reactor, event loop and command servers, serving one or more
+/// listeners from a background thread. TCP and TLS listeners get a port picked by the kernel.
+///
+/// The event loop stops when the server is dropped, the connections it served are closed then.
+pub struct TestServer {
+    /// address of each listener, None for a Unix socket
+    addrs: Vec<Option<SocketAddr>>,
+    /// policy of the first listener, the client limit of the server and the stats are shared
+    policy: Arc<ListenerPolicy>,
+    db: Arc<RwLock<Store>>,
+    shutdown: Shutdown,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl TestServer {
+    /// Starts a server on `127.0.0.1:0` with an empty store.
+    pub fn start() -> TestServer {
+        let config = ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        TestServer::start_with(config, Store::default())
+    }
+
+    /// Starts a server with the limits and auth policy of `config`, on its TCP address or Unix
+    /// socket.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` is a TLS listener or the server can't be set up.
+    pub fn start_with(config: ListenerConfig, store: Store) -> TestServer {
+        TestServer::start_on(Arc::new(MioPoller::new().unwrap()), config, store)
+    }
+
+    /// Starts a server like [`TestServer::start_with`], its reactor polling with `poller`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` is a TLS listener or the server can't be set up.
+    pub fn start_on(poller: Arc<dyn Poller>, config: ListenerConfig, store: Store) -> TestServer {
+        let (listener, addr) = TestServer::bind(&config);
+        TestServer::serve(poller, vec![(listener, addr, config)], store)
+    }
+
+    /// Starts a server serving every listener of `configs` from one event loop, like the
+    /// `listen` settings of a single process.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `configs` is empty, holds a TLS listener or the server can't be set up.
+    pub fn start_listeners(configs: Vec<ListenerConfig>, store: Store) -> TestServer {
+        assert!(!configs.is_empty(), "a server needs a listener");
+        let listeners = configs
+            .into_iter()
+            .map(|config| {
+                let (listener, addr) = TestServer::bind(&config);
+                (listener, addr, config)
+            })
+            .collect();
+        TestServer::serve(Arc::new(MioPoller::new().unwrap()), listeners, store)
+    }
+
+    fn bind(config: &ListenerConfig) -> (BoundListener, Option<SocketAddr>) {
+        match &config.addr {
+            ListenAddr::Tcp(addr) => {
+                let listener = bind_tcp(addr, false).unwrap();
+                let addr = listener.local_addr().unwrap();
+                (BoundListener::Tcp(listener), Some(addr))
+            }
+            ListenAddr::Unix(path) => {
+                let listener = bind_unix(path, config.perm).unwrap();
+                (BoundListener::Unix(listener), None)
+            }
+            addr => panic!("TLS listeners need a certificate, not {:?}", addr),
+        }
+    }
+
+    /// Starts a server on the TLS address of `config`, the real listener and handshake as
+    /// `tls-port` sets them up.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config` isn't a TLS listener or the server can't be set up.
+    pub fn start_tls(config: ListenerConfig, store: Store, tls: Arc<ServerConfig>) -> TestServer {
+        let addr = match &config.addr {
+            ListenAddr::Tls(addr) => addr,
+            addr => panic!("expected a TLS listener, not {:?}", addr),
+        };
+        let listener = bind_tcp(addr, false).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let poller = Arc::new(MioPoller::new().unwrap());
+        let listener = BoundListener::Tls(listener, tls);
+        TestServer::serve(poller, vec![(listener, Some(addr), config)], store)
+    }
+
+    fn serve(
+        poller: Arc<dyn Poller>,
+        listeners: Vec<(BoundListener, Option<SocketAddr>, ListenerConfig)>,
+        store: Store,
+    ) -> TestServer {
+        let reactor = Arc::new(RwLock::new(Reactor::new(poller.clone())));
+        let db = Arc::new(RwLock::new(store));
+        let (clients, stats) = (Arc::new(ClientLimit::new(0)), Arc::new(Stats::default()));
+        let mut addrs = vec![];
+        let listeners: Vec<_> = listeners
+            .into_iter()
+            .map(|(listener, addr, config)| {
+                addrs.push(addr);
+                let policy = ListenerPolicy::new(&config, clients.clone(), stats.clone());
+                (listener, Arc::new(policy))
+            })
+            .collect();
+        let policy = listeners[0].1.clone();
+        let shutdown = Shutdown {
+            stop: Arc::new(AtomicBool::new(false)),
+            poller,
+        };
+
+        let (stop, server_db) = (shutdown.stop.clone(), db.clone());
+        // handlers aren't Send, they are created on the thread running the loop
+        let thread = thread::spawn(move || {
+            let mut event_loop = EventLoop::new(reactor.clone());
+            for (listener, policy) in listeners {
+                let server = listener.serve(policy, reactor.clone(), server_db.clone());
+                event_loop
+                    .connection_handler_map
+                    .insert(server.id(), server);
+            }
+            while !stop.load(Ordering::SeqCst) {
+                event_loop.run_once()?;
+            }
+            Ok(())
+        });
+
+        TestServer {
+            addrs,
+            policy,
+            db,
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+
+    /// The address of the first listener.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server listens on a Unix socket.
+    pub fn addr(&self) -> SocketAddr {
+        self.addr_of(0)
+    }
+
+    /// The address of the listener at `idx` in the order they were given.
+    ///
+    /// # Panics
+    ///
+    /// Panics if that listener is a Unix socket or there is no such listener.
+    pub fn addr_of(&self, idx: usize) -> SocketAddr {
+        self.addrs[idx].expect("a Unix socket has no address")
+    }
+
+    /// The keyspace the server serves, for checks that no command can make.
+    pub fn db(&self) -> Arc<RwLock<Store>> {
+        self.db.clone()
+    }
+
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Opens a blocking connection whose reads fail after `TIMEOUT`.
+    pub fn connect(&self) -> TcpStream {
+        let stream = TcpStream::connect(self.addr()).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        stream
+    }
+
+    /// Connections the server is serving, over all of its listeners.
+    pub fn clients(&self) -> usize {
+        self.policy.clients().1
+    }
+
+    /// Waits until the server serves `clients` connections, returns false on timeout.
+    pub fn wait_for_clients(&self, clients: usize) -> bool {
+        let deadline = Instant::now() + TIMEOUT;
+        while self.clients() != clients {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    /// Stops the event loop and waits for its thread.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the event loop failed.
+    pub fn stop(mut self) -> Result<()> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<()> {
+        self.shutdown.shutdown();
+        match self.thread.take() {
+            Some(thread) => thread.join().unwrap(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Read, Write},
+        sync::atomic::AtomicUsize,
+    };
+
+    use super::*;
+    use crate::{
+        async_client::core::{MAX_QUERY_BUFFER, READ_BUDGET},
+        config::listener::AuthPolicy,
+        stats::core as stats,
+    };
+
+    /// Sends `command` and checks the server answers exactly `expected`.
+    fn request(conn: &mut (impl Read + Write), command: &str, expected: &str) {
+        conn.write_all(command.as_bytes()).unwrap();
+        assert_eq!(
+            read_reply(conn, expected.len()),
+            expected,
+            "reply to {:?}",
+            command
+        );
+    }
+
+    fn read_reply(conn: &mut impl Read, len: usize) -> String {
+        let mut reply = vec![0; len];
+        conn.read_exact(&mut reply).unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    fn read_line(conn: &mut TcpStream) -> String {
+        let mut line = vec![];
+        let mut byte = [0];
+        while !line.ends_with(b"\n") {
+            conn.read_exact(&mut byte).unwrap();
+            line.push(byte[0]);
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    /// Sends a command replying with an array of bulk strings, returns them sorted since sets
+    /// reply in no particular order.
+    fn members(conn: &mut TcpStream, command: &str) -> Vec<String> {
+        conn.write_all(command.as_bytes()).unwrap();
+        let header = read_line(conn);
+        let len = match header.strip_prefix('*') {
+            Some(len) => len.trim_end().parse::<usize>().unwrap(),
+            None => panic!("reply to {:?}: {:?}", command, header),
+        };
+        let mut members: Vec<String> = (0..len)
+            .map(|_| {
+                read_line(conn);
+                read_line(conn).trim_end().to_string()
+            })
+            .collect();
+        members.sort();
+        members
+    }
+
+    /// Whether the server closed the connection, pending replies are skipped.
+    fn is_closed(conn: &mut TcpStream) -> bool {
+        let mut buf = [0; 64];
+        loop {
+            match conn.read(&mut buf) {
+                Ok(0) => return true,
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::ConnectionReset => return true,
+                Err(_) => return false,
+            }
+        }
+    }
+
+    #[test]
+    fn ping() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "PING\r\n", "+PONG\r\n");
+        request(&mut conn, "ping\r\n", "+PONG\r\n");
+    }
+
+    #[test]
+    fn echo() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "ECHO hello world\r\n", "$11\r\nhello world\r\n");
+        request(
+            &mut conn,
+            "ECHO\r\n",
+            "-ERR wrong number of arguments for 'echo' command\r\n",
+        );
+    }
+
+    #[test]
+    fn invalid_commands() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "NOPE\r\n", "-ERR unknown command\r\n");
+        request(
+            &mut conn,
+            "GET\r\n",
+            "-ERR wrong number of arguments for 'get' command\r\n",
+        );
+        request(
+            &mut conn,
+            "SET a\r\n",
+            "-ERR wrong number of arguments for 'set' command\r\n",
+        );
+        request(
+            &mut conn,
+            "INCR a b\r\n",
+            "-ERR wrong number of arguments for 'incr' command\r\n",
+        );
+        // the connection is still served
+        request(&mut conn, "PING\r\n", "+PONG\r\n");
+    }
+
+    #[test]
+    fn huge_counts() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "SADD s a\r\n", ":1\r\n");
+        request(
+            &mut conn,
+            "SRANDMEMBER s 9223372036854775807\r\n",
+            "*1\r\n$1\r\na\r\n",
+        );
+        request(
+            &mut conn,
+            "SRANDMEMBER s -9223372036854775807\r\n",
+            "-ERR value is out of range\r\n",
+        );
+        request(
+            &mut conn,
+            "SPOP s 9223372036854775807\r\n",
+            "*1\r\n$1\r\na\r\n",
+        );
+        // the store is still usable
+        request(&mut conn, "SCARD s\r\n", ":0\r\n");
+    }
+
+    #[test]
+    fn watch_ignores_writes_that_change_nothing() {
+        let server = TestServer::start();
+        let (mut watcher, mut other) = (server.connect(), server.connect());
+        request(&mut other, "SADD s a\r\n", ":1\r\n");
+        request(&mut other, "ZADD z 1 a\r\n", ":1\r\n");
+        request(&mut watcher, "WATCH s z missing\r\n", "+OK\r\n");
+        for (command, reply) in [
+            ("SADD s a\r\n", ":0\r\n"),
+            ("SREM s b\r\n", ":0\r\n"),
+            ("SREM missing b\r\n", ":0\r\n"),
+            ("ZADD z 1 a\r\n", ":0\r\n"),
+            ("ZADD z XX 1 b\r\n", ":0\r\n"),
+            ("ZADD missing XX 1 b\r\n", ":0\r\n"),
+            ("ZREM z b\r\n", ":0\r\n"),
+            ("DEL missing\r\n", ":0\r\n"),
+        ] {
+            request(&mut other, command, reply);
+        }
+        request(&mut watcher, "MULTI\r\n", "+OK\r\n");
+        request(&mut watcher, "GET missing\r\n", "+QUEUED\r\n");
+        request(&mut watcher, "EXEC\r\n", "*1\r\n$-1\r\n");
+
+        request(&mut watcher, "WATCH z\r\n", "+OK\r\n");
+        request(&mut other, "ZADD z 2 a\r\n", ":0\r\n");
+        request(&mut watcher, "MULTI\r\n", "+OK\r\n");
+        request(&mut watcher, "GET missing\r\n", "+QUEUED\r\n");
+        request(&mut watcher, "EXEC\r\n", "*-1\r\n");
+    }
+
+    #[test]
+    fn string_ranges_keep_text() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        // "é" is two bytes
+        request(&mut conn, "SET s aébc\r\n", "+OK\r\n");
+        request(&mut conn, "GETRANGE s 1 2\r\n", "$2\r\né\r\n");
+        request(
+            &mut conn,
+            "GETRANGE s 0 1\r\n",
+            "-ERR offset splits a multi-byte character\r\n",
+        );
+        request(
+            &mut conn,
+            "SETRANGE s 2 x\r\n",
+            "-ERR offset splits a multi-byte character\r\n",
+        );
+        request(
+            &mut conn,
+            "SETRANGE s 0 xy\r\n",
+            "-ERR offset splits a multi-byte character\r\n",
+        );
+        request(&mut conn, "GET s\r\n", "$5\r\naébc\r\n");
+        request(&mut conn, "SETRANGE s 1 xy\r\n", ":5\r\n");
+        request(&mut conn, "SETRANGE s 4 ü\r\n", ":6\r\n");
+        request(&mut conn, "GET s\r\n", "$6\r\naxybü\r\n");
+    }
+
+    #[test]
+    fn counters_stay_in_range() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        let not_an_integer = "-ERR value is not an integer or out of range\r\n";
+        let overflow = "-ERR increment or decrement would overflow\r\n";
+        request(&mut conn, "INCR n\r\n", ":1\r\n");
+        request(&mut conn, "DECRBY n 3\r\n", ":-2\r\n");
+        request(
+            &mut conn,
+            "INCRBY n -9223372036854775806\r\n",
+            ":-9223372036854775808\r\n",
+        );
+        request(&mut conn, "DECR n\r\n", overflow);
+        request(&mut conn, "INCRBY n -1\r\n", overflow);
+        request(&mut conn, "GET n\r\n", "$20\r\n-9223372036854775808\r\n");
+        request(&mut conn, "SET n 9223372036854775807\r\n", "+OK\r\n");
+        request(&mut conn, "INCR n\r\n", overflow);
+        request(&mut conn, "DECRBY n -1\r\n", overflow);
+        // the negation of the smallest integer does not fit
+        request(&mut conn, "DECRBY m -9223372036854775808\r\n", overflow);
+        request(&mut conn, "GET m\r\n", "$-1\r\n");
+        request(
+            &mut conn,
+            "DECRBY m 9223372036854775807\r\n",
+            ":-9223372036854775807\r\n",
+        );
+
+        for command in [
+            "INCRBY n 1.5\r\n",
+            "INCRBY n x\r\n",
+            "DECRBY n 9223372036854775808\r\n",
+        ] {
+            request(&mut conn, command, not_an_integer);
+        }
+        request(&mut conn, "GET n\r\n", "$19\r\n9223372036854775807\r\n");
+        for value in ["abc", "1.0", "9223372036854775808", "\"\""] {
+            request(&mut conn, &format!("SET s {}\r\n", value), "+OK\r\n");
+            request(&mut conn, "INCR s\r\n", not_an_integer);
+        }
+        request(&mut conn, "SADD set a\r\n", ":1\r\n");
+        request(
+            &mut conn,
+            "INCR set\r\n",
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+        );
+    }
+
+    #[test]
+    fn float_counters_stay_finite() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        let not_a_float = "-ERR value is not a valid float\r\n";
+        request(&mut conn, "INCRBYFLOAT f 10.5\r\n", "$4\r\n10.5\r\n");
+        request(&mut conn, "INCRBYFLOAT f 0.5\r\n", "$2\r\n11\r\n");
+        request(&mut conn, "INCRBYFLOAT f -12.25\r\n", "$5\r\n-1.25\r\n");
+        request(&mut conn, "INCRBYFLOAT f 5e3\r\n", "$7\r\n4998.75\r\n");
+        request(&mut conn, "SET n 3\r\n", "+OK\r\n");
+        request(&mut conn, "INCRBYFLOAT n 1.5\r\n", "$3\r\n4.5\r\n");
+        // an integer that became a float is no longer one
+        request(
+            &mut conn,
+            "INCR n\r\n",
+            "-ERR value is not an integer or out of range\r\n",
+        );
+
+        for delta in ["abc", "nan", "inf", "-inf", "infinity"] {
+            request(
+                &mut conn,
+                &format!("INCRBYFLOAT f {}\r\n", delta),
+                not_a_float,
+            );
+        }
+        request(&mut conn, "SET g 1e308\r\n", "+OK\r\n");
+        request(
+            &mut conn,
+            "INCRBYFLOAT g 1e308\r\n",
+            "-ERR increment would produce NaN or Infinity\r\n",
+        );
+        request(&mut conn, "GET g\r\n", "$5\r\n1e308\r\n");
+        for value in ["abc", "inf"] {
+            request(&mut conn, &format!("SET s {}\r\n", value), "+OK\r\n");
+            request(&mut conn, "INCRBYFLOAT s 1\r\n", not_a_float);
+        }
+        request(&mut conn, "GET f\r\n", "$7\r\n4998.75\r\n");
+    }
+
+    #[test]
+    fn set_algebra() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "SADD a 1 2 3 4\r\n", ":4\r\n");
+        request(&mut conn, "SADD b 3 4 5\r\n", ":3\r\n");
+        request(&mut conn, "SADD c 4 6\r\n", ":2\r\n");
+        for (command, expected) in [
+            ("SINTER a b\r\n", &["3", "4"][..]),
+            ("SINTER a b c\r\n", &["4"]),
+            ("SINTER a missing\r\n", &[]),
+            ("SINTER missing a\r\n", &[]),
+            ("SUNION a c\r\n", &["1", "2", "3", "4", "6"]),
+            ("SUNION missing c\r\n", &["4", "6"]),
+            ("SDIFF a b\r\n", &["1", "2"]),
+            ("SDIFF a b c\r\n", &["1", "2"]),
+            ("SDIFF a missing\r\n", &["1", "2", "3", "4"]),
+            ("SDIFF missing a\r\n", &[]),
+            ("SDIFF a a\r\n", &[]),
+        ] {
+            assert_eq!(members(&mut conn, command), expected, "{:?}", command);
+        }
+
+        request(&mut conn, "SINTERSTORE d a b\r\n", ":2\r\n");
+        assert_eq!(members(&mut conn, "SMEMBERS d\r\n"), ["3", "4"]);
+        // the destination replaces whatever was there, even when it is also a source
+        request(&mut conn, "SUNIONSTORE d d c\r\n", ":3\r\n");
+        assert_eq!(members(&mut conn, "SMEMBERS d\r\n"), ["3", "4", "6"]);
+        request(&mut conn, "SDIFFSTORE a a d\r\n", ":2\r\n");
+        assert_eq!(members(&mut conn, "SMEMBERS a\r\n"), ["1", "2"]);
+        request(&mut conn, "SET s x\r\n", "+OK\r\n");
+        request(&mut conn, "SUNIONSTORE s b\r\n", ":3\r\n");
+        request(&mut conn, "SCARD s\r\n", ":3\r\n");
+
+        // an empty result deletes the destination
+        request(&mut conn, "SINTERSTORE d a c\r\n", ":0\r\n");
+        request(&mut conn, "EXISTS d\r\n", ":0\r\n");
+        request(&mut conn, "SDIFFSTORE s s b\r\n", ":0\r\n");
+        request(&mut conn, "EXISTS s\r\n", ":0\r\n");
+        request(&mut conn, "SUNIONSTORE d missing\r\n", ":0\r\n");
+        request(&mut conn, "EXISTS d\r\n", ":0\r\n");
+
+        // a key of another type is refused, even when the result is known to be empty
+        let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        request(&mut conn, "SET str x\r\n", "+OK\r\n");
+        for command in [
+            "SINTER str a\r\n",
+            "SINTER missing str\r\n",
+            "SUNION a str\r\n",
+            "SDIFF missing str\r\n",
+            "SINTERSTORE d missing str\r\n",
+            "SDIFFSTORE a a str\r\n",
+        ] {
+            request(&mut conn, command, wrong_type);
+        }
+        // and the destination is left untouched
+        assert_eq!(members(&mut conn, "SMEMBERS a\r\n"), ["1", "2"]);
+        request(&mut conn, "GET str\r\n", "$1\r\nx\r\n");
+        request(&mut conn, "SUNIONSTORE str a\r\n", ":2\r\n");
+        request(&mut conn, "TYPE str\r\n", "+set\r\n");
+    }
+
+    #[test]
+    fn sorted_set_ranges() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "ZADD z 1 a 2 b 3 c 4 d 5 e\r\n", ":5\r\n");
+        request(&mut conn, "ZRANK z d\r\n", ":3\r\n");
+        request(&mut conn, "ZREVRANK z d\r\n", ":1\r\n");
+        request(&mut conn, "ZCOUNT z (1 4\r\n", ":3\r\n");
+        request(&mut conn, "ZCOUNT z 4 1\r\n", ":0\r\n");
+        request(
+            &mut conn,
+            "ZRANGE z 2 +inf BYSCORE LIMIT 1 2\r\n",
+            "*2\r\n$1\r\nc\r\n$1\r\nd\r\n",
+        );
+        request(
+            &mut conn,
+            "ZRANGE z +inf (2 BYSCORE REV LIMIT 1 -1\r\n",
+            "*2\r\n$1\r\nd\r\n$1\r\nc\r\n",
+        );
+        request(&mut conn, "ZRANGE z 0 -1 BYSCORE LIMIT 9 1\r\n", "*0\r\n");
+        request(
+            &mut conn,
+            "ZRANGE z -2 -1 REV WITHSCORES\r\n",
+            "*4\r\n$1\r\nb\r\n$1\r\n2\r\n$1\r\na\r\n$1\r\n1\r\n",
+        );
+        request(&mut conn, "DEL z\r\n", ":1\r\n");
+        request(&mut conn, "ZADD z 0 a 0 b 0 c 0 d\r\n", ":4\r\n");
+        request(
+            &mut conn,
+            "ZRANGE z [b + BYLEX LIMIT 0 2\r\n",
+            "*2\r\n$1\r\nb\r\n$1\r\nc\r\n",
+        );
+        request(
+            &mut conn,
+            "ZRANGE z (d - BYLEX REV\r\n",
+            "*3\r\n$1\r\nc\r\n$1\r\nb\r\n$1\r\na\r\n",
+        );
+    }
+
+    #[test]
+    fn pipelining() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        let (mut commands, mut expected) = (String::new(), String::new());
+        for i in 0..500 {
+            commands += &format!("SET key{i} {i}\r\nGET key{i}\r\nPING\r\n");
+            expected += &format!("+OK\r\n${}\r\n{i}\r\n+PONG\r\n", i.to_string().len());
+        }
+        request(&mut conn, &commands, &expected);
+    }
+
+    #[test]
+    fn pipelining_past_the_read_budget() {
+        let server = TestServer::start();
+        let mut busy = server.connect();
+        let pipeline: String = (0..20_000).map(|i| format!("SET key{i} {i}\r\n")).collect();
+        let commands = pipeline.lines().count();
+        assert!(pipeline.len() > 4 * READ_BUDGET);
+
+        // sent at once, the socket holds more than one budget and no new data wakes the reader
+        let mut writer = busy.try_clone().unwrap();
+        let sender = thread::spawn(move || writer.write_all(pipeline.as_bytes()).unwrap());
+        let answered = Arc::new(AtomicUsize::new(0));
+        let counter = answered.clone();
+        let receiver = thread::spawn(move || {
+            for _ in 0..commands {
+                assert_eq!(read_reply(&mut busy, 5), "+OK\r\n");
+                counter.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+
+        // another client is served in between the commands of the busy one
+        let mut other = server.connect();
+        let deadline = Instant::now() + TIMEOUT;
+        while answered.load(Ordering::SeqCst) == 0 {
+            assert!(Instant::now() < deadline, "the pipeline is not served");
+            thread::sleep(Duration::from_millis(1));
+        }
+        request(&mut other, "PING\r\n", "+PONG\r\n");
+        request(&mut other, "GET key0\r\n", "$1\r\n0\r\n");
+        assert!(answered.load(Ordering::SeqCst) < commands);
+
+        sender.join().unwrap();
+        receiver.join().unwrap();
+        request(&mut other, "GET key19999\r\n", "$5\r\n19999\r\n");
+    }
+
+    #[test]
+    fn pipelining_across_writes() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        // commands split in the middle of a line
+        for part in ["SET a 1\r\nGE", "T a\r", "\nPI", "NG\r\n"] {
+            conn.write_all(part.as_bytes()).unwrap();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(read_reply(&mut conn, 19), "+OK\r\n$1\r\n1\r\n+PONG\r\n");
+    }
+
+    #[test]
+    fn quit_closes_the_connection() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "QUIT\r\n", "+OK\r\n");
+        assert!(is_closed(&mut conn));
+        assert!(server.wait_for_clients(0));
+    }
+
+    #[test]
+    fn disconnects_release_the_connection() {
+        let config = ListenerConfig {
+            maxclients: 1,
+            ..ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0)
+        };
+        let server = TestServer::start_with(config, Store::default());
+
+        // closes with a partial command
+        let mut conn = server.connect();
+        request(&mut conn, "SET a 1\r\n", "+OK\r\n");
+        conn.write_all(b"GET").unwrap();
+        drop(conn);
+        assert!(server.wait_for_clients(0));
+
+        // closes with replies still to be read
+        let mut conn = server.connect();
+        assert!(server.wait_for_clients(1));
+        conn.write_all("PING\r\n".repeat(10_000).as_bytes())
+            .unwrap();
+        drop(conn);
+        assert!(server.wait_for_clients(0));
+
+        // the only slot is free again
+        let mut conn = server.connect();
+        request(&mut conn, "GET a\r\n", "$1\r\n1\r\n");
+        let mut rejected = server.connect();
+        assert_eq!(
+            read_reply(&mut rejected, 36),
+            "-ERR max number of clients reached\r\n"
+        );
+    }
+
+    #[test]
+    fn counts_refusals() {
+        let config = ListenerConfig {
+            maxclients: 1,
+            auth: AuthPolicy::Required,
+            ..ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0)
+        };
+        let server = TestServer::start_with(config, Store::default());
+        let mut conn = server.connect();
+        request(
+            &mut conn,
+            "GET a\r\n",
+            "-NOAUTH Authentication required.\r\n",
+        );
+        request(
+            &mut conn,
+            "AUTH nobody secret\r\n",
+            "-WRONGPASS invalid username-password pair or user is disabled.\r\n",
+        );
+        let mut rejected = server.connect();
+        assert_eq!(
+            read_reply(&mut rejected, 36),
+            "-ERR max number of clients reached\r\n"
+        );
+
+        let stats = server.policy.stats();
+        assert_eq!(stats::read(&stats.noauth_rejections), 1);
+        assert_eq!(stats::read(&stats.auth_failures), 1);
+        assert_eq!(stats::read(&stats.rejected_connections), 1);
+        request(&mut conn, "AUTH default x\r\n", "+OK\r\n");
+        let info = "INFO stats\r\n";
+        conn.write_all(info.as_bytes()).unwrap();
+        let header = read_line(&mut conn);
+        let len: usize = header[1..header.len() - 2].parse().unwrap();
+        let body = read_reply(&mut conn, len + 2);
+        assert!(body.contains("rejected_connections:1\r\n"), "{}", body);
+        assert!(body.contains("total_noauth_rejections:1\r\n"), "{}", body);
+    }
+
+    #[test]
+    fn authenticates_each_listener_its_own_way() {
+        let mut store = Store::default();
+        let rules: Vec<String> = ["on", "nopass", "~*", "+@read", "+acl|whoami"]
+            .map(str::to_string)
+            .to_vec();
+        store.auth_mut().set_user("reader", &rules).unwrap();
+        let tcp = || ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        let server = TestServer::start_listeners(
+            vec![
+                ListenerConfig {
+                    auth: AuthPolicy::Required,
+                    ..tcp()
+                },
+                ListenerConfig {
+                    auth: AuthPolicy::As("reader".to_string()),
+                    ..tcp()
+                },
+                tcp(),
+            ],
+            store,
+        );
+        let connect = |idx| {
+            let conn = TcpStream::connect(server.addr_of(idx)).unwrap();
+            conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+            conn
+        };
+        let (mut required, mut trusted, mut open) = (connect(0), connect(1), connect(2));
+        assert!(server.wait_for_clients(3));
+
+        // the default user has no password, only the listener requiring auth asks for it
+        request(&mut open, "ACL WHOAMI\r\n", "$7\r\ndefault\r\n");
+        request(&mut open, "SET a 1\r\n", "+OK\r\n");
+        request(
+            &mut required,
+            "GET a\r\n",
+            "-NOAUTH Authentication required.\r\n",
+        );
+        request(&mut required, "AUTH reader x\r\n", "+OK\r\n");
+        request(&mut required, "ACL WHOAMI\r\n", "$6\r\nreader\r\n");
+        request(&mut required, "GET a\r\n", "$1\r\n1\r\n");
+
+        // connections of the trusted listener start as its user, with that user's rights
+        request(&mut trusted, "ACL WHOAMI\r\n", "$6\r\nreader\r\n");
+        request(&mut trusted, "GET a\r\n", "$1\r\n1\r\n");
+        request(
+            &mut trusted,
+            "SET a 2\r\n",
+            "-NOPERM User reader has no permissions to run the 'set' command\r\n",
+        );
+        request(&mut trusted, "AUTH default x\r\n", "+OK\r\n");
+        request(&mut trusted, "SET a 2\r\n", "+OK\r\n");
+        request(&mut required, "GET a\r\n", "$1\r\n2\r\n");
+
+        // each new connection follows the policy of the listener it came through
+        let mut again = connect(0);
+        request(
+            &mut again,
+            "GET a\r\n",
+            "-NOAUTH Authentication required.\r\n",
+        );
+        let stats = server.policy.stats();
+        assert_eq!(stats::read(&stats.noauth_rejections), 2);
+        assert_eq!(stats::read(&stats.acl_denied_commands), 1);
+        drop((required, trusted, open));
+        assert!(server.wait_for_clients(1));
+    }
+
+    #[test]
+    fn serves_a_unix_socket_with_its_permissions() {
+        use std::{
+            fs,
+            os::unix::{fs::PermissionsExt, net::UnixStream},
+        };
+
+        let dir = std::env::temp_dir().join(format!("unix-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+        let config = ListenerConfig::new(ListenAddr::Unix(path.clone()), 0o600);
+        let server = TestServer::start_with(config, Store::default());
+
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        // only the socket is left, the directory it was bound in is gone
+        let entries: Vec<_> = fs::read_dir(&dir).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        let mut conn = UnixStream::connect(&path).unwrap();
+        conn.set_read_timeout(Some(TIMEOUT)).unwrap();
+        request(&mut conn, "PING\r\n", "+PONG\r\n");
+        drop(conn);
+        server.stop().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn reads_share_the_store_lock() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "SET a 1\r\n", "+OK\r\n");
+        let db = server.db();
+        let reader = db.read().unwrap();
+        // served while the store is read elsewhere, a write would wait for it
+        request(&mut conn, "GET a\r\n", "$1\r\n1\r\n");
+        request(&mut conn, "ZCARD z\r\n", ":0\r\n");
+        drop(reader);
+        request(&mut conn, "SET a 2\r\n", "+OK\r\n");
+    }
+
+    #[test]
+    fn closes_a_client_past_the_query_buffer_limit() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        conn.write_all(&vec![b'a'; MAX_QUERY_BUFFER + 1]).unwrap();
+        let reply = "-ERR Protocol error: too big inline request\r\n";
+        assert_eq!(read_reply(&mut conn, reply.len()), reply);
+        assert!(is_closed(&mut conn));
+        assert!(server.wait_for_clients(0));
+
+        // a command of the largest size is still served
+        let mut conn = server.connect();
+        let value = "v".repeat(MAX_QUERY_BUFFER - "SET a \r\n".len());
+        request(&mut conn, &format!("SET a {}\r\n", value), "+OK\r\n");
+        request(&mut conn, "STRLEN a\r\n", &format!(":{}\r\n", value.len()));
+    }
+
+    #[test]
+    fn concurrent_clients() {
+        let server = TestServer::start();
+        let clients: Vec<_> = (0..16)
+            .map(|client| {
+                let mut conn = server.connect();
+                thread::spawn(move || {
+                    for i in 0..200 {
+                        request(&mut conn, &format!("SET c{client}:{i} v{i}\r\n"), "+OK\r\n");
+                        let value = format!("v{i}");
+                        request(
+                            &mut conn,
+                            &format!("GET c{client}:{i}\r\n"),
+                            &format!("${}\r\n{}\r\n", value.len(), value),
+                        );
+                        conn.write_all(b"INCR counter\r\n").unwrap();
+                        assert!(read_line(&mut conn).starts_with(':'));
+                    }
+                })
+            })
+            .collect();
+        for client in clients {
+            client.join().unwrap();
+        }
+        let mut conn = server.connect();
+        request(&mut conn, "GET c15:199\r\n", "$4\r\nv199\r\n");
+        request(&mut conn, "GET counter\r\n", "$4\r\n3200\r\n");
+    }
+
+    #[test]
+    fn shutdown_closes_connections() {
+        let server = TestServer::start();
+        let mut conn = server.connect();
+        request(&mut conn, "PING\r\n", "+PONG\r\n");
+        server.shutdown_handle().shutdown();
+        server.stop().unwrap();
+        assert!(is_closed(&mut conn));
+    }
+}
@@ -0,0 +1,4 @@
+pub mod client;
+pub mod core;
+pub mod persistence;
+pub mod replication;
@@ -0,0 +1,189 @@
+//! End-to-end tests of persistence, a [`TestServer`](super::core::TestServer) restarted from
+//! what it wrote.
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        path::PathBuf,
+        sync::{Arc, RwLock},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use async_tcp_command_client::{Connection, Value};
+
+    use crate::{
+        config::{
+            core::Config,
+            listener::{ListenAddr, ListenerConfig},
+        },
+        harness::core::{TestServer, TIMEOUT},
+        persistence::{aof::Aof, cron::spawn_cron},
+        store::{core::Store, value::Value as StoreValue},
+    };
+
+    /// A fresh directory for the files of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("persist-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Starts a server on the AOF of `config`, replaying what the file holds first.
+    fn start(config: &Config) -> TestServer {
+        let mut store = Store::default();
+        Aof::load(&config.aof_path(), &mut store).unwrap();
+        store.set_aof(Aof::open(config).unwrap());
+        let listener = ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        TestServer::start_with(listener, store)
+    }
+
+    fn connect(server: &TestServer) -> Connection {
+        Connection::connect_timeout(&server.addr(), TIMEOUT).unwrap()
+    }
+
+    fn bulk(text: &str) -> Value {
+        Value::Bulk(text.to_string())
+    }
+
+    /// Polls `check` until it holds, fails the test after `TIMEOUT`.
+    fn wait_until(what: &str, mut check: impl FnMut() -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !check() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn rewriting(db: &Arc<RwLock<Store>>) -> bool {
+        db.read().unwrap().aof().unwrap().is_rewriting()
+    }
+
+    /// Adds keys without going through the AOF, only a rewrite saves them.
+    fn insert_unlogged(db: &Arc<RwLock<Store>>, keys: usize) {
+        let mut store = db.write().unwrap();
+        for i in 0..keys {
+            store.insert(format!("bulk{}", i), StoreValue::String(i.to_string()));
+        }
+    }
+
+    #[test]
+    fn restarts_from_the_aof() {
+        let config = Config {
+            dir: temp_dir("restart"),
+            appendonly: true,
+            ..Config::default()
+        };
+        let server = start(&config);
+        let mut conn = connect(&server);
+        conn.command(&["SET", "a", "1"]).unwrap();
+        conn.command(&["ZADD", "z", "1", "one", "2", "two"])
+            .unwrap();
+        conn.command(&["MULTI"]).unwrap();
+        conn.command(&["INCR", "n"]).unwrap();
+        conn.command(&["SADD", "s", "x"]).unwrap();
+        conn.command(&["EXEC"]).unwrap();
+        conn.command(&["SET", "gone", "1"]).unwrap();
+        conn.command(&["DEL", "gone"]).unwrap();
+        // a failed command is not written
+        assert!(conn.command(&["INCR", "a", "b"]).unwrap().is_error());
+        drop(conn);
+        server.stop().unwrap();
+
+        let server = start(&config);
+        let mut conn = connect(&server);
+        assert_eq!(conn.command(&["GET", "a"]).unwrap(), bulk("1"));
+        assert_eq!(conn.command(&["GET", "n"]).unwrap(), bulk("1"));
+        assert_eq!(conn.command(&["ZSCORE", "z", "two"]).unwrap(), bulk("2"));
+        assert_eq!(
+            conn.command(&["SISMEMBER", "s", "x"]).unwrap(),
+            Value::Integer(1)
+        );
+        assert_eq!(
+            conn.command(&["EXISTS", "gone"]).unwrap(),
+            Value::Integer(0)
+        );
+    }
+
+    #[test]
+    fn keeps_the_writes_made_during_a_rewrite() {
+        let config = Config {
+            dir: temp_dir("rewrite"),
+            appendonly: true,
+            ..Config::default()
+        };
+        let server = start(&config);
+        let db = server.db();
+        // enough keys for the rewrite to take a while
+        insert_unlogged(&db, 50_000);
+        let mut conn = connect(&server);
+        let mut incrs = 0;
+        for _ in 0..100 {
+            conn.command(&["INCR", "n"]).unwrap();
+            incrs += 1;
+        }
+        conn.command(&["SET", "b", "1"]).unwrap();
+
+        let started = Value::Simple("Background append only file rewriting started".to_string());
+        let mut during = false;
+        for _ in 0..10 {
+            assert_eq!(conn.command(&["BGREWRITEAOF"]).unwrap(), started);
+            conn.command(&["INCR", "n"]).unwrap();
+            incrs += 1;
+            conn.command(&["DEL", "b"]).unwrap();
+            conn.command(&["SET", "during", "1"]).unwrap();
+            // the writes were answered before the rewrite finished, they went to its buffer
+            during = rewriting(&db);
+            wait_until("the rewrite", || !rewriting(&db));
+            if during {
+                break;
+            }
+        }
+        assert!(during, "every rewrite finished before the writes");
+        drop((conn, db));
+        server.stop().unwrap();
+
+        let server = start(&config);
+        let mut conn = connect(&server);
+        assert_eq!(
+            conn.command(&["GET", "n"]).unwrap(),
+            bulk(&incrs.to_string())
+        );
+        assert_eq!(conn.command(&["GET", "during"]).unwrap(), bulk("1"));
+        assert_eq!(conn.command(&["EXISTS", "b"]).unwrap(), Value::Integer(0));
+        assert_eq!(conn.command(&["GET", "bulk49999"]).unwrap(), bulk("49999"));
+    }
+
+    #[test]
+    fn rewrites_once_the_aof_grew() {
+        let config = Config {
+            dir: temp_dir("growth"),
+            appendonly: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 1024,
+            ..Config::default()
+        };
+        let server = start(&config);
+        let db = server.db();
+        insert_unlogged(&db, 10);
+        spawn_cron(db.clone());
+        let mut conn = connect(&server);
+        // the same key over and over, the rewrite keeps a single SET
+        let value = "v".repeat(100);
+        for _ in 0..20 {
+            conn.command(&["SET", "a", &value]).unwrap();
+        }
+        wait_until("the automatic rewrite", || {
+            let store = db.read().unwrap();
+            let aof = store.aof().unwrap();
+            !aof.is_rewriting() && aof.current_size() < 1024
+        });
+        let size = fs::metadata(config.aof_path()).unwrap().len();
+        assert_eq!(size, db.read().unwrap().aof().unwrap().current_size());
+
+        let mut store = Store::default();
+        assert_eq!(Aof::load(&config.aof_path(), &mut store).unwrap(), 11);
+    }
+}
@@ -0,0 +1,339 @@
+//! End-to-end tests of replication between two [`TestServer`](super::core::TestServer)s.
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::Arc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use async_tcp_command_client::{Connection, Value};
+
+    use crate::{
+        config::{
+            core::Config,
+            listener::{ListenAddr, ListenerConfig},
+        },
+        harness::core::{TestServer, TIMEOUT},
+        replication::core::Replication,
+        store::{core::Store, value::Value as StoreValue},
+    };
+
+    fn start() -> TestServer {
+        let mut store = Store::default();
+        store.set_replication(Replication::new(&Config::default()));
+        let config = ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        TestServer::start_with(config, store)
+    }
+
+    fn connect(server: &TestServer) -> Connection {
+        Connection::connect_timeout(&server.addr(), TIMEOUT).unwrap()
+    }
+
+    fn bulk(text: &str) -> Value {
+        Value::Bulk(text.to_string())
+    }
+
+    /// Polls `check` until it holds, fails the test after `TIMEOUT`.
+    fn wait_until(what: &str, mut check: impl FnMut() -> bool) {
+        let deadline = Instant::now() + TIMEOUT;
+        while !check() {
+            assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn simple(text: &str) -> Value {
+        Value::Simple(text.to_string())
+    }
+
+    /// The `field:value` lines of `INFO replication`.
+    fn replication_info(conn: &mut Connection) -> Vec<String> {
+        match conn.command(&["INFO", "replication"]).unwrap() {
+            Value::Bulk(info) => info.lines().map(str::to_string).collect(),
+            other => panic!("INFO replied {:?}", other),
+        }
+    }
+
+    fn info_field(conn: &mut Connection, name: &str) -> Option<String> {
+        let prefix = format!("{}:", name);
+        replication_info(conn)
+            .into_iter()
+            .find_map(|line| line.strip_prefix(&prefix).map(str::to_string))
+    }
+
+    fn offset(server: &TestServer) -> u64 {
+        let db = server.db();
+        let store = db.read().unwrap();
+        store.replication().unwrap().offset()
+    }
+
+    #[test]
+    fn syncs_and_resumes_after_a_disconnect() {
+        let (primary, replica) = (start(), start());
+        let (mut to_primary, mut to_replica) = (connect(&primary), connect(&replica));
+        to_primary.command(&["SET", "a", "1"]).unwrap();
+        to_primary.command(&["SADD", "s", "x", "y"]).unwrap();
+
+        // full sync of what the primary holds, then the stream
+        let port = primary.addr().port().to_string();
+        assert_eq!(
+            to_replica
+                .command(&["REPLICAOF", "127.0.0.1", &port])
+                .unwrap(),
+            Value::Simple("OK".to_string())
+        );
+        wait_until("the full sync", || {
+            to_replica.command(&["GET", "a"]).unwrap() == bulk("1")
+        });
+        assert_eq!(
+            to_replica.command(&["SCARD", "s"]).unwrap(),
+            Value::Integer(2)
+        );
+        to_primary.command(&["SET", "b", "2"]).unwrap();
+        wait_until("the stream", || {
+            to_replica.command(&["GET", "b"]).unwrap() == bulk("2")
+        });
+        assert!(offset(&primary) > 0);
+        wait_until("the replica to catch up", || {
+            offset(&replica) == offset(&primary)
+        });
+        let err = to_replica.command(&["SET", "b", "3"]).unwrap();
+        assert!(err.is_error(), "{:?}", err);
+
+        // a key only the replica has survives a partial resync, a full sync would drop it
+        replica
+            .db()
+            .write()
+            .unwrap()
+            .insert("marker".to_string(), StoreValue::String("kept".to_string()));
+        let first_link = {
+            let db = primary.db();
+            let store = db.read().unwrap();
+            store.replication().unwrap().replicas()[0].clone()
+        };
+        first_link.disconnect();
+        to_primary.command(&["SET", "c", "3"]).unwrap();
+        for _ in 0..3 {
+            to_primary.command(&["INCR", "n"]).unwrap();
+        }
+        wait_until("the resync", || {
+            to_replica.command(&["GET", "n"]).unwrap() == bulk("3")
+        });
+        assert_eq!(to_replica.command(&["GET", "c"]).unwrap(), bulk("3"));
+        assert_eq!(
+            to_replica.command(&["GET", "marker"]).unwrap(),
+            bulk("kept")
+        );
+
+        // both ends agree on where the stream is, and the backlog covers it
+        wait_until("the replica to catch up", || {
+            offset(&replica) == offset(&primary)
+        });
+        let db = primary.db();
+        let store = db.read().unwrap();
+        let replication = store.replication().unwrap();
+        assert_eq!(replication.backlog().end_offset(), replication.offset());
+        assert_eq!(replication.replicas().len(), 1);
+        assert!(!Arc::ptr_eq(&replication.replicas()[0], &first_link));
+        let db = replica.db();
+        let replica_store = db.read().unwrap();
+        assert_eq!(
+            replica_store.replication().unwrap().replid(),
+            replication.replid()
+        );
+    }
+
+    #[test]
+    fn skips_empty_records_of_the_stream() {
+        let (primary, replica) = (start(), start());
+        let (mut to_primary, mut to_replica) = (connect(&primary), connect(&replica));
+        let port = primary.addr().port().to_string();
+        to_replica
+            .command(&["REPLICAOF", "127.0.0.1", &port])
+            .unwrap();
+        to_primary.command(&["SET", "a", "1"]).unwrap();
+        wait_until("the sync", || {
+            to_replica.command(&["GET", "a"]).unwrap() == bulk("1")
+        });
+
+        // a record without a command name used to panic the replica with the store locked
+        {
+            let db = primary.db();
+            let mut store = db.write().unwrap();
+            store.replication_mut().unwrap().feed(b"*0\r\n");
+        }
+        to_primary.command(&["SET", "b", "2"]).unwrap();
+        wait_until("the stream", || {
+            to_replica.command(&["GET", "b"]).unwrap() == bulk("2")
+        });
+        wait_until("the replica to catch up", || {
+            offset(&replica) == offset(&primary)
+        });
+    }
+
+    #[test]
+    fn disconnects_a_replica_that_stops_reading() {
+        let config = Config {
+            repl_output_buffer_limit: 64 * 1024,
+            ..Config::default()
+        };
+        let mut store = Store::default();
+        store.set_replication(Replication::new(&config));
+        let listener = ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        let primary = TestServer::start_with(listener, store);
+        let replicas = || {
+            let db = primary.db();
+            let store = db.read().unwrap();
+            store.replication().unwrap().replicas().len()
+        };
+
+        // a replica that asks for the stream and never reads it
+        let mut stalled = primary.connect();
+        stalled.write_all(b"PSYNC ? -1\r\n").unwrap();
+        wait_until("the replica", || replicas() == 1);
+        let mut to_primary = connect(&primary);
+        let value = "v".repeat(64 * 1024);
+        for i in 0..1_000 {
+            to_primary
+                .command(&["SET", &format!("key{}", i), &value])
+                .unwrap();
+            if replicas() == 0 {
+                break;
+            }
+        }
+        wait_until("the replica to be dropped", || replicas() == 0);
+        assert_eq!(
+            to_primary.command(&["PING"]).unwrap(),
+            Value::Simple("PONG".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_roles_and_fails_over() {
+        let (primary, replica) = (start(), start());
+        let (mut to_primary, mut to_replica) = (connect(&primary), connect(&replica));
+        assert_eq!(
+            to_replica.command(&["ROLE"]).unwrap(),
+            Value::Array(vec![
+                bulk("master"),
+                Value::Integer(0),
+                Value::Array(vec![])
+            ])
+        );
+        let port = primary.addr().port();
+        to_replica
+            .command(&["REPLICAOF", "127.0.0.1", &port.to_string()])
+            .unwrap();
+        to_primary.command(&["SET", "a", "1"]).unwrap();
+        wait_until("the sync", || {
+            to_replica.command(&["GET", "a"]).unwrap() == bulk("1")
+        });
+        wait_until("the replica to catch up", || {
+            offset(&replica) == offset(&primary)
+        });
+
+        // a replica serves reads and refuses writes, inside transactions too
+        let read_only =
+            Value::Error("READONLY You can't write against a read only replica.".to_string());
+        assert_eq!(to_replica.command(&["SET", "a", "2"]).unwrap(), read_only);
+        assert_eq!(to_replica.command(&["SADD", "s", "x"]).unwrap(), read_only);
+        assert_eq!(to_replica.command(&["MULTI"]).unwrap(), simple("OK"));
+        assert_eq!(to_replica.command(&["SET", "a", "2"]).unwrap(), read_only);
+        assert_eq!(
+            to_replica.command(&["EXEC"]).unwrap(),
+            Value::Error("EXECABORT Transaction discarded because of previous errors.".to_string())
+        );
+        assert_eq!(to_replica.command(&["GET", "a"]).unwrap(), bulk("1"));
+
+        let replica_offset = offset(&replica) as i64;
+        assert_eq!(
+            to_replica.command(&["ROLE"]).unwrap(),
+            Value::Array(vec![
+                bulk("slave"),
+                bulk("127.0.0.1"),
+                Value::Integer(port as i64),
+                bulk("connected"),
+                Value::Integer(replica_offset),
+            ])
+        );
+        let info = replication_info(&mut to_replica);
+        for line in [
+            "role:slave".to_string(),
+            "master_host:127.0.0.1".to_string(),
+            format!("master_port:{}", port),
+            "master_link_status:up".to_string(),
+            "master_sync_in_progress:0".to_string(),
+            format!("slave_repl_offset:{}", replica_offset),
+            "slave_read_only:1".to_string(),
+            "connected_slaves:0".to_string(),
+        ] {
+            assert!(info.contains(&line), "{:?} not in {:?}", line, info);
+        }
+
+        let role = to_primary.command(&["ROLE"]).unwrap();
+        let replicas = match role {
+            Value::Array(ref fields)
+                if fields[..2] == [bulk("master"), Value::Integer(replica_offset)] =>
+            {
+                fields[2].clone()
+            }
+            other => panic!("ROLE replied {:?}", other),
+        };
+        match replicas {
+            Value::Array(replicas) => match &replicas[..] {
+                [Value::Array(replica)] => assert_eq!(replica[0], bulk("127.0.0.1")),
+                other => panic!("replicas {:?}", other),
+            },
+            other => panic!("replicas {:?}", other),
+        }
+        assert_eq!(info_field(&mut to_primary, "role").unwrap(), "master");
+        assert_eq!(
+            info_field(&mut to_primary, "connected_slaves").unwrap(),
+            "1"
+        );
+        let slave = info_field(&mut to_primary, "slave0").unwrap();
+        assert!(
+            slave.starts_with("ip=127.0.0.1,") && slave.contains(",state=online,"),
+            "{}",
+            slave
+        );
+        let primary_replid = info_field(&mut to_primary, "master_replid").unwrap();
+
+        // promoted, the replica keeps its data, takes writes and no longer follows the primary
+        assert_eq!(
+            to_replica.command(&["REPLICAOF", "NO", "ONE"]).unwrap(),
+            simple("OK")
+        );
+        assert_eq!(
+            to_replica.command(&["ROLE"]).unwrap(),
+            Value::Array(vec![
+                bulk("master"),
+                Value::Integer(replica_offset),
+                Value::Array(vec![])
+            ])
+        );
+        assert_eq!(to_replica.command(&["GET", "a"]).unwrap(), bulk("1"));
+        assert_eq!(
+            to_replica.command(&["SET", "a", "2"]).unwrap(),
+            simple("OK")
+        );
+        assert_eq!(info_field(&mut to_replica, "role").unwrap(), "master");
+        assert_eq!(
+            info_field(&mut to_replica, "master_replid2").unwrap(),
+            primary_replid
+        );
+        assert_ne!(
+            info_field(&mut to_replica, "master_replid").unwrap(),
+            primary_replid
+        );
+        wait_until("the primary to drop the replica", || {
+            info_field(&mut to_primary, "connected_slaves").unwrap() == "0"
+        });
+        to_primary.command(&["SET", "b", "1"]).unwrap();
+        assert_eq!(to_primary.command(&["GET", "a"]).unwrap(), bulk("1"));
+        assert_eq!(to_replica.command(&["GET", "b"]).unwrap(), Value::Nil);
+    }
+}
@@ -1,23 +1,134 @@
 use std::{
-    io::Result,
-    sync::{Arc, Mutex, RwLock},
+    env,
+    io::{self, Result},
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 
-use async_server::core::AsyncTcpCommandServer;
-use event_loop::core::EventLoop;
-use reactor::{core::Reactor, event_listener::EventListener};
+use async_server::listener::{self, ClientLimit, ListenerPolicy};
+use auth::core::Auth;
+use config::{core::Config, listener::ListenAddr};
+use event_loop::shard::{BoundListener, Shard};
+use persistence::{
+    aof::Aof,
+    cron::spawn_cron,
+    snapshot::{self, SnapshotState},
+};
+use replication::{core::Replication, upstream::spawn_connect};
+use stats::core::Stats;
+use store::core::Store;
 
 pub mod async_client;
 pub mod async_server;
+pub mod auth;
 pub mod command;
+pub mod config;
 pub mod event_loop;
+#[cfg(test)]
+pub mod harness;
+pub mod persistence;
+pub mod protocol;
 pub mod reactor;
+pub mod replication;
+#[cfg(test)]
+pub mod sim;
+pub mod stats;
+pub mod store;
+pub mod tls;
 fn main() -> Result<()> {
-    let mut reactor = Arc::new(RwLock::new(Reactor::default()));
-    let server = AsyncTcpCommandServer::new("127.0.0.1:7878".to_string(), reactor.clone());
-    let mut event_loop = EventLoop::new(reactor.clone());
-    event_loop
-        .connection_handler_map
-        .insert(server.id(), Box::new(server));
-    event_loop.run()
+    let config = Config::from_args(env::args().skip(1))?;
+    let mut shards = (0..config.event_loops)
+        .map(|id| Shard::new(id, config.poller))
+        .collect::<Result<Vec<Shard>>>()?;
+
+    let mut store = Store::default();
+    if config.appendonly {
+        let path = config.aof_path();
+        let applied = Aof::load(&path, &mut store)?;
+        println!("loaded {} commands from {}", applied, path.display());
+        store.set_aof(Aof::open(&config)?);
+    } else {
+        let path = config.snapshot_path();
+        let loaded = snapshot::load(&path, &mut store)?;
+        println!("loaded {} keys from {}", loaded, path.display());
+    }
+    store.set_snapshot_state(SnapshotState::new(&config));
+    let mut auth = Auth::new(&config);
+    auth.load()?;
+    store.set_auth(auth);
+    let mut replication = Replication::new(&config);
+    let upstream = config
+        .replicaof
+        .as_ref()
+        .map(|(host, port)| replication.follow(host, *port).0);
+    store.set_replication(replication);
+    let db = Arc::new(RwLock::new(store));
+    spawn_cron(db.clone());
+    if let Some(link_id) = upstream {
+        spawn_connect(link_id, Duration::ZERO, shards[0].reactor(), db.clone());
+    }
+
+    let listeners = config.listeners();
+    if listeners.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no listener configured, set 'bind', 'unixsocket' or 'listen'",
+        ));
+    }
+    let tls = listeners
+        .iter()
+        .any(|listener| matches!(listener.addr, ListenAddr::Tls(_)))
+        .then(|| tls::core::server_config(&config))
+        .transpose()?;
+    let maxclients = listener::fit_open_files(config.maxclients)?;
+    if maxclients != config.maxclients {
+        println!(
+            "open files limit too low for maxclients {}, serving at most {} clients",
+            config.maxclients, maxclients
+        );
+    }
+    let clients = Arc::new(ClientLimit::new(maxclients));
+    let stats = Arc::new(Stats::default());
+    let reuse_port = shards.len() > 1;
+    for listener in listeners {
+        let policy = Arc::new(ListenerPolicy::new(
+            &listener,
+            clients.clone(),
+            stats.clone(),
+        ));
+        match &listener.addr {
+            ListenAddr::Tcp(addr) | ListenAddr::Tls(addr) => {
+                let tls = match listener.addr {
+                    ListenAddr::Tls(_) => tls.clone(),
+                    _ => None,
+                };
+                // every shard binds the port the first one got, in case it was picked by the
+                // kernel
+                let mut addr = addr.to_string();
+                for shard in shards.iter_mut() {
+                    let bound = listener::bind_tcp(&addr, reuse_port)?;
+                    addr = bound.local_addr()?.to_string();
+                    let bound = match &tls {
+                        Some(tls) => BoundListener::Tls(bound, tls.clone()),
+                        None => BoundListener::Tcp(bound),
+                    };
+                    shard.listen(bound, policy.clone());
+                }
+            }
+            ListenAddr::Unix(path) => {
+                let bound = listener::bind_unix(path, listener.perm)?;
+                for shard in shards.iter_mut() {
+                    let shared = listener::share_unix(&bound)?;
+                    shard.listen(BoundListener::Unix(shared), policy.clone());
+                }
+            }
+        }
+    }
+
+    let mut shards = shards.into_iter();
+    let main_shard = shards.next().unwrap();
+    for shard in shards {
+        shard.spawn(db.clone())?;
+    }
+    main_shard.run(db)
 }
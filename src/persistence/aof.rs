@@ -0,0 +1,395 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Result, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Weak,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    command::core::execute_keyspace,
+    config::core::{Config, FsyncPolicy},
+    protocol::parser::{encode_multibulk, parse_multibulk},
+    store::core::Store,
+};
+
+/// Append only file holding every write command in multibulk form.
+///
+/// Records are written while the store lock is held so the order in the file is the order in
+/// which the commands were applied.
+#[derive(Debug)]
+pub struct Aof {
+    path: PathBuf,
+    file: Arc<File>,
+    policy: FsyncPolicy,
+    /// set when a record was written since the last fsync, the everysec thread clears it
+    pending_fsync: Arc<AtomicBool>,
+    /// size of the file right after the last rewrite (or at startup)
+    base_size: u64,
+    current_size: u64,
+    auto_rewrite_percentage: u64,
+    auto_rewrite_min_size: u64,
+    /// records appended while a rewrite is in progress, they are added to the rewritten file
+    /// before it replaces the current one
+    rewrite_buffer: Option<Vec<u8>>,
+}
+
+impl Aof {
+    /// Opens the configured file for appending, creating it if needed.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can not be opened.
+    pub fn open(config: &Config) -> Result<Aof> {
+        let path = config.aof_path();
+        let (file, pending_fsync) = open_file(&path, config.appendfsync)?;
+        let size = file.metadata()?.len();
+
+        Ok(Aof {
+            path,
+            file,
+            policy: config.appendfsync,
+            pending_fsync,
+            base_size: size,
+            current_size: size,
+            auto_rewrite_percentage: config.auto_aof_rewrite_percentage,
+            auto_rewrite_min_size: config.auto_aof_rewrite_min_size,
+            rewrite_buffer: None,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn current_size(&self) -> u64 {
+        self.current_size
+    }
+
+    /// Appends one command to the file and flushes it according to the fsync policy.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the write or the fsync fails.
+    pub fn append(&mut self, args: &[String]) -> Result<()> {
+        let record = encode_multibulk(args);
+        if let Some(buffer) = self.rewrite_buffer.as_mut() {
+            buffer.extend_from_slice(record.as_bytes());
+        }
+        (&*self.file).write_all(record.as_bytes())?;
+        self.current_size += record.len() as u64;
+        match self.policy {
+            FsyncPolicy::Always => self.file.sync_data()?,
+            FsyncPolicy::EverySec => self.pending_fsync.store(true, Ordering::Release),
+            FsyncPolicy::No => {}
+        }
+        Ok(())
+    }
+
+    pub fn is_rewriting(&self) -> bool {
+        self.rewrite_buffer.is_some()
+    }
+
+    /// Returns true once the file grew past the configured percentage over its size after the
+    /// last rewrite, a percentage of 0 disables automatic rewrites.
+    pub fn rewrite_due(&self) -> bool {
+        if self.is_rewriting()
+            || self.auto_rewrite_percentage == 0
+            || self.current_size < self.auto_rewrite_min_size
+        {
+            return false;
+        }
+        let base = self.base_size.max(1);
+        let growth = self.current_size.saturating_sub(base) * 100 / base;
+        growth >= self.auto_rewrite_percentage
+    }
+
+    /// Starts buffering appended records for a rewrite, returns false if one is already running.
+    pub fn begin_rewrite(&mut self) -> bool {
+        if self.is_rewriting() {
+            return false;
+        }
+        self.rewrite_buffer.replace(vec![]);
+        true
+    }
+
+    pub fn abort_rewrite(&mut self) {
+        self.rewrite_buffer.take();
+    }
+
+    /// Completes a rewrite: the records buffered since it started are appended to the rewritten
+    /// file which then atomically replaces the current one.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the rewritten file can not be written, synced or
+    /// renamed, the current file is left untouched in that case.
+    pub fn finish_rewrite(&mut self, rewritten: &Path) -> Result<()> {
+        let buffer = self.rewrite_buffer.take().unwrap_or_default();
+        let mut tmp = OpenOptions::new().append(true).open(rewritten)?;
+        tmp.write_all(&buffer)?;
+        tmp.sync_all()?;
+        drop(tmp);
+
+        fs::rename(rewritten, &self.path)?;
+        let (file, pending_fsync) = open_file(&self.path, self.policy)?;
+        self.file = file;
+        self.pending_fsync = pending_fsync;
+        self.current_size = self.file.metadata()?.len();
+        self.base_size = self.current_size;
+        Ok(())
+    }
+
+    /// Replays the file into the store and returns the number of commands applied.
+    ///
+    /// A record cut short by a crash at the end of the file is dropped and the file is truncated
+    /// back to the last complete record, the same goes for a MULTI block that never reached its
+    /// EXEC.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the file can not be read or holds a corrupt or
+    /// unknown record before its end.
+    pub fn load(path: &Path, store: &mut Store) -> Result<usize> {
+        let data = match fs::read(path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let mut pos = 0;
+        // end of the last record that was fully applied
+        let mut valid_len = 0;
+        let mut applied = 0;
+        let mut transaction: Option<Vec<Vec<String>>> = None;
+        while let Some((args, used)) = parse_multibulk(&data[pos..])? {
+            pos += used;
+            let name = args
+                .first()
+                .map(|name| name.to_uppercase())
+                .unwrap_or_default();
+            match name.as_str() {
+                "MULTI" => transaction = Some(vec![]),
+                "EXEC" => {
+                    for args in transaction.take().unwrap_or_default() {
+                        replay(store, &args)?;
+                        applied += 1;
+                    }
+                    valid_len = pos;
+                }
+                _ => match transaction.as_mut() {
+                    Some(queued) => queued.push(args),
+                    None => {
+                        replay(store, &args)?;
+                        applied += 1;
+                        valid_len = pos;
+                    }
+                },
+            }
+        }
+
+        if valid_len < data.len() {
+            println!(
+                "AOF {} ends with an incomplete record, dropping the last {} bytes",
+                path.display(),
+                data.len() - valid_len
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(valid_len as u64)?;
+        }
+        Ok(applied)
+    }
+}
+
+fn replay(store: &mut Store, args: &[String]) -> Result<()> {
+    match execute_keyspace(store, args) {
+        Some(_) => Ok(()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown command in AOF: {:?}", args),
+        )),
+    }
+}
+
+fn open_file(path: &Path, policy: FsyncPolicy) -> Result<(Arc<File>, Arc<AtomicBool>)> {
+    let file = Arc::new(OpenOptions::new().create(true).append(true).open(path)?);
+    let pending_fsync = Arc::new(AtomicBool::new(false));
+    if policy == FsyncPolicy::EverySec {
+        spawn_fsync_thread(Arc::downgrade(&file), pending_fsync.clone());
+    }
+    Ok((file, pending_fsync))
+}
+
+/// Flushes the file once per second while records are pending, the thread exits once the file
+/// has been dropped.
+fn spawn_fsync_thread(file: Weak<File>, pending_fsync: Arc<AtomicBool>) {
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        let file = match file.upgrade() {
+            Some(file) => file,
+            None => break,
+        };
+        if pending_fsync.swap(false, Ordering::AcqRel) {
+            if let Err(err) = file.sync_data() {
+                println!("failed to fsync the AOF: {}", err);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::store::value::Value;
+
+    /// A fresh directory for the AOF of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn records(commands: &[&[&str]]) -> String {
+        commands
+            .iter()
+            .map(|args| {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                encode_multibulk(&args)
+            })
+            .collect()
+    }
+
+    fn get(store: &Store, key: &str) -> Option<Value> {
+        store
+            .snapshot()
+            .into_iter()
+            .find_map(|(other, value, _)| (other == key).then_some(value))
+    }
+
+    fn string(value: &str) -> Option<Value> {
+        Some(Value::String(value.to_string()))
+    }
+
+    #[test]
+    fn replays_the_commands_of_the_file() {
+        let path = temp_dir("replay").join("appendonly.aof");
+        let data = records(&[
+            &["SET", "a", "1"],
+            &["SADD", "s", "x", "y"],
+            &["MULTI"],
+            &["INCR", "n"],
+            &["INCRBY", "n", "41"],
+            &["EXEC"],
+            &["SET", "gone", "1"],
+            &["DEL", "gone"],
+        ]);
+        fs::write(&path, &data).unwrap();
+
+        let mut store = Store::default();
+        assert_eq!(Aof::load(&path, &mut store).unwrap(), 6);
+        assert_eq!(get(&store, "a"), string("1"));
+        assert_eq!(get(&store, "n"), string("42"));
+        assert_eq!(get(&store, "gone"), None);
+        assert_eq!(store.len(), 3);
+        // a complete file is left as it is
+        assert_eq!(fs::read(&path).unwrap(), data.as_bytes());
+        assert_eq!(
+            Aof::load(&path.with_extension("missing"), &mut store).unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn drops_a_truncated_final_record() {
+        let path = temp_dir("truncated").join("appendonly.aof");
+        let complete = records(&[&["SET", "a", "1"], &["SET", "b", "2"]]);
+        let cut = &records(&[&["SET", "c", "3"]])[..15];
+        fs::write(&path, format!("{}{}", complete, cut)).unwrap();
+
+        let mut store = Store::default();
+        assert_eq!(Aof::load(&path, &mut store).unwrap(), 2);
+        assert_eq!(get(&store, "c"), None);
+        assert_eq!(fs::read(&path).unwrap(), complete.as_bytes());
+
+        // the next load finds nothing to drop
+        let mut store = Store::default();
+        assert_eq!(Aof::load(&path, &mut store).unwrap(), 2);
+        assert_eq!(fs::read(&path).unwrap(), complete.as_bytes());
+    }
+
+    #[test]
+    fn drops_an_unfinished_multi_block() {
+        let path = temp_dir("multi").join("appendonly.aof");
+        let complete = records(&[&["SET", "a", "1"]]);
+        let unfinished = records(&[&["MULTI"], &["SET", "a", "2"], &["SET", "b", "2"]]);
+        fs::write(&path, format!("{}{}", complete, unfinished)).unwrap();
+
+        let mut store = Store::default();
+        assert_eq!(Aof::load(&path, &mut store).unwrap(), 1);
+        assert_eq!(get(&store, "a"), string("1"));
+        assert_eq!(get(&store, "b"), None);
+        assert_eq!(fs::read(&path).unwrap(), complete.as_bytes());
+    }
+
+    #[test]
+    fn rejects_bad_records_before_the_end() {
+        let dir = temp_dir("bad");
+        let tail = records(&[&["SET", "a", "1"]]);
+        for (name, head) in [
+            ("unknown", records(&[&["NOSUCHCOMMAND", "a"]])),
+            ("corrupt", "hello\r\n".to_string()),
+        ] {
+            let path = dir.join(name);
+            let data = format!("{}{}", head, tail);
+            fs::write(&path, &data).unwrap();
+            let err = Aof::load(&path, &mut Store::default()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{}", name);
+            // nothing is truncated when the file can't be trusted
+            assert_eq!(fs::read(&path).unwrap(), data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn appends_with_every_fsync_policy() {
+        let dir = temp_dir("fsync");
+        let args = ["SET".to_string(), "a".to_string(), "1".to_string()];
+        let record = encode_multibulk(&args);
+        for policy in [FsyncPolicy::Always, FsyncPolicy::EverySec, FsyncPolicy::No] {
+            let config = Config {
+                dir: dir.clone(),
+                appendfilename: format!("{:?}.aof", policy),
+                appendfsync: policy,
+                ..Config::default()
+            };
+            let mut aof = Aof::open(&config).unwrap();
+            aof.append(&args).unwrap();
+            aof.append(&args).unwrap();
+            assert_eq!(aof.current_size(), 2 * record.len() as u64);
+            assert_eq!(fs::read_to_string(aof.path()).unwrap(), record.repeat(2));
+
+            // only everysec leaves the fsync to its thread, which clears the flag
+            let pending = aof.pending_fsync.load(Ordering::Acquire);
+            assert_eq!(pending, policy == FsyncPolicy::EverySec, "{:?}", policy);
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while aof.pending_fsync.load(Ordering::Acquire) {
+                assert!(Instant::now() < deadline, "the fsync thread didn't run");
+                thread::sleep(Duration::from_millis(50));
+            }
+
+            // reopening appends after what is there
+            drop(aof);
+            let mut aof = Aof::open(&config).unwrap();
+            aof.append(&args).unwrap();
+            assert_eq!(fs::read_to_string(aof.path()).unwrap(), record.repeat(3));
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::{
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use crate::store::core::Store;
+
+use super::{rewrite::start_rewrite, snapshot::start_bgsave};
+
+const CRON_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Expired keys removed per run at most, the rest are left for the next runs.
+const EXPIRED_PER_RUN: usize = 1000;
+
+/// Runs the periodic housekeeping on a background thread: removing expired keys, snapshots
+/// once a save rule is met and the automatic AOF rewrite once the file grew past its configured
+/// threshold.
+pub fn spawn_cron(db: Arc<RwLock<Store>>) {
+    thread::spawn(move || loop {
+        thread::sleep(CRON_INTERVAL);
+        let (save_due, rewrite_due) = {
+            let mut store = db.write().unwrap();
+            store.purge_expired(EXPIRED_PER_RUN);
+            (
+                store.snapshot_state().is_some_and(|state| state.save_due()),
+                store.aof().is_some_and(|aof| aof.rewrite_due()),
+            )
+        };
+        if save_due {
+            println!("save rule met, starting background save");
+            if let Err(err) = start_bgsave(db.clone()) {
+                println!("background save not started: {:?}", err);
+            }
+        }
+        if rewrite_due {
+            println!("starting automatic AOF rewrite");
+            if let Err(err) = start_rewrite(db.clone()) {
+                println!("automatic AOF rewrite not started: {:?}", err);
+            }
+        }
+    });
+}
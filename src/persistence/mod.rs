@@ -0,0 +1,4 @@
+pub mod aof;
+pub mod cron;
+pub mod rewrite;
+pub mod snapshot;
@@ -0,0 +1,116 @@
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Result, Write},
+    path::Path,
+    process,
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use crate::{
+    command::zset::format_score,
+    protocol::{parser::encode_multibulk, reply::Reply},
+    store::{
+        core::{Entry, Store},
+        value::Value,
+    },
+};
+
+/// Maximum number of elements added by a single command of the rewritten file.
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// The commands rebuilding a key from scratch, followed by a PEXPIREAT when the key has a ttl.
+pub fn rewrite_commands(key: &str, value: &Value, expiry: Option<u64>) -> Vec<Vec<String>> {
+    let command = |name: &str, items: &[String]| {
+        let mut args = vec![name.to_string(), key.to_string()];
+        args.extend_from_slice(items);
+        args
+    };
+
+    let mut commands = match value {
+        Value::String(val) => vec![command("SET", &[val.to_string()])],
+        Value::Set(set) => {
+            let members: Vec<String> = set.iter().cloned().collect();
+            members
+                .chunks(ITEMS_PER_COMMAND)
+                .map(|chunk| command("SADD", chunk))
+                .collect()
+        }
+        Value::SortedSet(zset) => {
+            let pairs: Vec<String> = zset
+                .iter()
+                .flat_map(|(member, score)| [format_score(score), member.to_string()])
+                .collect();
+            pairs
+                .chunks(ITEMS_PER_COMMAND * 2)
+                .map(|chunk| command("ZADD", chunk))
+                .collect()
+        }
+    };
+    if let Some(at) = expiry {
+        commands.push(command("PEXPIREAT", &[at.to_string()]));
+    }
+    commands
+}
+
+fn write_snapshot(path: &Path, snapshot: &[Entry]) -> Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    for (key, value, expiry) in snapshot {
+        for args in rewrite_commands(key, value, *expiry) {
+            out.write_all(encode_multibulk(&args).as_bytes())?;
+        }
+    }
+    out.into_inner()?.sync_all()
+}
+
+/// Rewrites the AOF in the background into the minimal set of commands rebuilding the current
+/// keyspace.
+///
+/// The keyspace is copied while holding the store lock, the copy is then written to a temporary
+/// file on a separate thread while the server keeps serving. Writes arriving in the meantime are
+/// buffered by the AOF and appended to the temporary file before it is renamed over the current
+/// one.
+///
+/// # Errors
+///
+/// This function will return an error reply if the AOF is disabled or a rewrite is already
+/// running.
+pub fn start_rewrite(db: Arc<RwLock<Store>>) -> std::result::Result<(), Reply> {
+    let (snapshot, tmp_path) = {
+        let mut store = db.write().unwrap();
+        let aof = store
+            .aof_mut()
+            .ok_or_else(|| Reply::error("ERR append only file is not enabled"))?;
+        if !aof.begin_rewrite() {
+            return Err(Reply::error(
+                "ERR Background append only file rewriting already in progress",
+            ));
+        }
+        let tmp_path = aof
+            .path()
+            .with_file_name(format!("temp-rewriteaof-{}.aof", process::id()));
+        (store.snapshot(), tmp_path)
+    };
+
+    thread::spawn(move || {
+        let result = write_snapshot(&tmp_path, &snapshot).and_then(|_| {
+            let mut store = db.write().unwrap();
+            match store.aof_mut() {
+                Some(aof) => aof.finish_rewrite(&tmp_path),
+                None => Ok(()),
+            }
+        });
+
+        match result {
+            Ok(_) => println!("background AOF rewrite finished successfully"),
+            Err(err) => {
+                println!("background AOF rewrite failed: {}", err);
+                if let Some(aof) = db.write().unwrap().aof_mut() {
+                    aof.abort_rewrite();
+                }
+                let _ = fs::remove_file(&tmp_path);
+            }
+        }
+    });
+    Ok(())
+}
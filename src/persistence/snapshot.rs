@@ -0,0 +1,597 @@
+use std::{
+    collections::HashSet,
+    fs::{self, File},
+    io::{self, Result, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, RwLock},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    config::core::Config,
+    protocol::reply::Reply,
+    store::{
+        core::{Entry, Store},
+        sorted_set::SortedSet,
+        value::Value,
+    },
+};
+
+/// Snapshot files start with this magic followed by the format version.
+const MAGIC: &[u8; 8] = b"ATCSSNAP";
+const VERSION: u16 = 1;
+
+const OP_ENTRY: u8 = 0x01;
+const OP_ENTRY_WITH_EXPIRY: u8 = 0x02;
+const OP_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_SET: u8 = 1;
+const TYPE_SORTED_SET: u8 = 2;
+
+/// Seconds the save rules wait after a failed background save before trying again.
+const SAVE_RETRY_DELAY: u64 = 5;
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Bookkeeping for snapshots: where they go, when the last one succeeded and how many writes
+/// happened since, which drives the automatic save rules.
+#[derive(Debug)]
+pub struct SnapshotState {
+    path: PathBuf,
+    rules: Vec<(u64, u64)>,
+    /// unix time in seconds of the last successful save
+    last_save: u64,
+    changes_since_save: u64,
+    /// changes counted when the running BGSAVE took its copy of the keyspace
+    changes_at_bgsave: Option<u64>,
+    /// unix time in seconds of the last failed background save, if none succeeded since
+    last_failure: Option<u64>,
+}
+
+impl SnapshotState {
+    pub fn new(config: &Config) -> SnapshotState {
+        SnapshotState {
+            path: config.snapshot_path(),
+            rules: config.save.clone(),
+            last_save: now_secs(),
+            changes_since_save: 0,
+            changes_at_bgsave: None,
+            last_failure: None,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn last_save(&self) -> u64 {
+        self.last_save
+    }
+
+    pub fn changes_since_save(&self) -> u64 {
+        self.changes_since_save
+    }
+
+    pub fn is_saving(&self) -> bool {
+        self.changes_at_bgsave.is_some()
+    }
+
+    pub fn record_change(&mut self) {
+        self.changes_since_save += 1;
+    }
+
+    /// Returns true when one of the "N changes in M seconds" rules is met, and a failed save
+    /// isn't too recent.
+    pub fn save_due(&self) -> bool {
+        let now = now_secs();
+        let elapsed = now.saturating_sub(self.last_save);
+        let retry = self
+            .last_failure
+            .is_none_or(|at| now.saturating_sub(at) >= SAVE_RETRY_DELAY);
+        !self.is_saving()
+            && retry
+            && self.rules.iter().any(|(seconds, changes)| {
+                self.changes_since_save >= *changes && elapsed >= *seconds
+            })
+    }
+
+    fn saved(&mut self, changes: u64) {
+        self.last_save = now_secs();
+        self.last_failure = None;
+        self.changes_since_save = self.changes_since_save.saturating_sub(changes);
+    }
+}
+
+fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    out.extend_from_slice(bytes);
+}
+
+/// Encodes entries in the snapshot format:
+///
+/// ```text
+/// "ATCSSNAP" | version u16
+/// per key: opcode u8 | [expiry u64 when opcode is 0x02] | type u8 | key | value
+/// 0xFF | crc32 u32 of every preceding byte
+/// ```
+///
+/// Strings are a u32 length followed by the bytes, sets a u32 count followed by the members,
+/// sorted sets a u32 count followed by member and f64 score pairs. Integers are little endian.
+pub fn encode(entries: &[Entry]) -> Vec<u8> {
+    let mut out = vec![];
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+
+    for (key, value, expiry) in entries {
+        match expiry {
+            Some(at) => {
+                out.push(OP_ENTRY_WITH_EXPIRY);
+                out.extend_from_slice(&at.to_le_bytes());
+            }
+            None => out.push(OP_ENTRY),
+        }
+        match value {
+            Value::String(val) => {
+                out.push(TYPE_STRING);
+                put_bytes(&mut out, key.as_bytes());
+                put_bytes(&mut out, val.as_bytes());
+            }
+            Value::Set(set) => {
+                out.push(TYPE_SET);
+                put_bytes(&mut out, key.as_bytes());
+                out.extend_from_slice(&(set.len() as u32).to_le_bytes());
+                for member in set {
+                    put_bytes(&mut out, member.as_bytes());
+                }
+            }
+            Value::SortedSet(zset) => {
+                out.push(TYPE_SORTED_SET);
+                put_bytes(&mut out, key.as_bytes());
+                out.extend_from_slice(&(zset.len() as u32).to_le_bytes());
+                for (member, score) in zset.iter() {
+                    put_bytes(&mut out, member.as_bytes());
+                    out.extend_from_slice(&score.to_le_bytes());
+                }
+            }
+        }
+    }
+
+    out.push(OP_EOF);
+    let checksum = crc32fast::hash(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    out
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt snapshot: {}", msg),
+    )
+}
+
+/// Cursor over the bytes of a snapshot.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8]> {
+        if self.data.len() - self.pos < len {
+            return Err(corrupt("unexpected end of file"));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).map_err(|_| corrupt("invalid string"))
+    }
+}
+
+/// Decodes a snapshot produced by `encode`.
+///
+/// # Errors
+///
+/// This function will return an error if the magic, version or checksum do not match or the
+/// content is malformed.
+pub fn decode(data: &[u8]) -> Result<Vec<Entry>> {
+    if data.len() < MAGIC.len() + 2 + 1 + 4 || &data[..MAGIC.len()] != MAGIC {
+        return Err(corrupt("not a snapshot file"));
+    }
+    let (body, checksum) = data.split_at(data.len() - 4);
+    if crc32fast::hash(body) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(corrupt("checksum mismatch"));
+    }
+
+    let mut reader = Reader {
+        data: body,
+        pos: MAGIC.len(),
+    };
+    let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+    if version != VERSION {
+        return Err(corrupt(&format!("unsupported version {}", version)));
+    }
+
+    let mut entries = vec![];
+    loop {
+        let expiry = match reader.u8()? {
+            OP_EOF => break,
+            OP_ENTRY => None,
+            OP_ENTRY_WITH_EXPIRY => Some(reader.u64()?),
+            _ => return Err(corrupt("unknown opcode")),
+        };
+        let kind = reader.u8()?;
+        let key = reader.string()?;
+        let value = match kind {
+            TYPE_STRING => Value::String(reader.string()?),
+            TYPE_SET => {
+                let len = reader.u32()?;
+                let mut set = HashSet::new();
+                for _ in 0..len {
+                    set.insert(reader.string()?);
+                }
+                Value::Set(set)
+            }
+            TYPE_SORTED_SET => {
+                let len = reader.u32()?;
+                let mut zset = SortedSet::default();
+                for _ in 0..len {
+                    let member = reader.string()?;
+                    zset.insert(member, reader.f64()?);
+                }
+                Value::SortedSet(zset)
+            }
+            _ => return Err(corrupt("unknown value type")),
+        };
+        entries.push((key, value, expiry));
+    }
+    if reader.pos != body.len() {
+        return Err(corrupt("trailing data after end of file marker"));
+    }
+    Ok(entries)
+}
+
+/// Writes the entries to a temporary file and renames it over `path`, so a crash mid-save
+/// never leaves a partial snapshot behind.
+///
+/// # Errors
+///
+/// This function will return an error if the file can not be written or renamed.
+pub fn save(path: &Path, entries: &[Entry]) -> Result<()> {
+    let tmp_path = path.with_file_name(format!("temp-{}.snap", process::id()));
+    let result = File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(&encode(entries))?;
+        file.sync_all()
+    });
+    if let Err(err) = result.and_then(|_| fs::rename(&tmp_path, path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    Ok(())
+}
+
+/// Loads a snapshot into the store, returns the number of keys loaded.
+///
+/// # Errors
+///
+/// This function will return an error if the file exists but can not be read or decoded.
+pub fn load(path: &Path, store: &mut Store) -> Result<usize> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(err) => return Err(err),
+    };
+    let entries = decode(&data)?;
+    let len = entries.len();
+    for entry in entries {
+        store.restore(entry);
+    }
+    Ok(len)
+}
+
+/// Saves the keyspace on the calling thread while holding the store lock (SAVE).
+///
+/// # Errors
+///
+/// This function will return an error reply if snapshots are not configured, a background save
+/// is running or the file can not be written.
+pub fn save_blocking(store: &mut Store) -> std::result::Result<(), Reply> {
+    let state = store
+        .snapshot_state()
+        .ok_or_else(|| Reply::error("ERR snapshots are not configured"))?;
+    if state.is_saving() {
+        return Err(Reply::error("ERR Background save already in progress"));
+    }
+    let (path, changes) = (state.path.to_path_buf(), state.changes_since_save);
+    save(&path, &store.snapshot()).map_err(|err| Reply::error(format!("ERR {}", err)))?;
+    if let Some(state) = store.snapshot_state_mut() {
+        state.saved(changes);
+    }
+    Ok(())
+}
+
+/// Saves the keyspace on a background thread (BGSAVE).
+///
+/// The keyspace is copied while holding the store lock, which gives the background thread a
+/// consistent point in time view while the server keeps serving writes. It is only copied once
+/// the save is sure to start.
+///
+/// # Errors
+///
+/// This function will return an error reply if snapshots are not configured or a background
+/// save is already running.
+pub fn start_bgsave(db: Arc<RwLock<Store>>) -> std::result::Result<(), Reply> {
+    let (entries, path) = {
+        let mut store = db.write().unwrap();
+        let state = store
+            .snapshot_state_mut()
+            .ok_or_else(|| Reply::error("ERR snapshots are not configured"))?;
+        if state.is_saving() {
+            return Err(Reply::error("ERR Background save already in progress"));
+        }
+        state.changes_at_bgsave = Some(state.changes_since_save);
+        let path = state.path.to_path_buf();
+        (store.snapshot(), path)
+    };
+
+    thread::spawn(move || {
+        let result = save(&path, &entries);
+        let mut store = db.write().unwrap();
+        if let Some(state) = store.snapshot_state_mut() {
+            let changes = state.changes_at_bgsave.take().unwrap_or_default();
+            match result {
+                Ok(_) => {
+                    state.saved(changes);
+                    println!("background saving finished successfully");
+                }
+                Err(err) => {
+                    state.last_failure = Some(now_secs());
+                    println!(
+                        "background saving failed, retrying in {}s at the earliest: {}",
+                        SAVE_RETRY_DELAY, err
+                    );
+                }
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+    use crate::{
+        config::listener::{ListenAddr, ListenerConfig},
+        harness::core::{TestServer, TIMEOUT},
+    };
+
+    /// A fresh directory for the snapshots of one test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("snapshot-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn config(dir: &Path, save: Vec<(u64, u64)>) -> Config {
+        Config {
+            dir: dir.to_path_buf(),
+            save,
+            ..Config::default()
+        }
+    }
+
+    fn entries() -> Vec<Entry> {
+        let mut zset = SortedSet::default();
+        zset.insert("low".to_string(), -1.5);
+        zset.insert("high".to_string(), f64::INFINITY);
+        vec![
+            (
+                "s".to_string(),
+                Value::String("héllo\r\n".to_string()),
+                None,
+            ),
+            (
+                "set".to_string(),
+                Value::Set(HashSet::from(["a".to_string(), "".to_string()])),
+                Some(u64::MAX),
+            ),
+            ("z".to_string(), Value::SortedSet(zset), None),
+        ]
+    }
+
+    fn sorted(mut entries: Vec<Entry>) -> Vec<Entry> {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        entries
+    }
+
+    #[test]
+    fn round_trips_every_type() {
+        let entries = entries();
+        assert_eq!(decode(&encode(&entries)).unwrap(), entries);
+        assert_eq!(decode(&encode(&[])).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn rejects_corrupt_snapshots() {
+        let data = encode(&entries());
+        let reject = |data: &[u8], reason: &str| {
+            let err = decode(data).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(reason), "{}", err);
+        };
+
+        let mut flipped = data.clone();
+        flipped[20] ^= 1;
+        reject(&flipped, "checksum mismatch");
+        reject(&data[..data.len() - 1], "checksum mismatch");
+        reject(&data[..10], "not a snapshot file");
+        reject(b"NOTASNAPSHOT-NOTASNAPSHOT", "not a snapshot file");
+
+        // a valid checksum over a version this build doesn't read
+        let mut newer = data[..data.len() - 4].to_vec();
+        newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        let checksum = crc32fast::hash(&newer);
+        newer.extend_from_slice(&checksum.to_le_bytes());
+        reject(&newer, "unsupported version 2");
+    }
+
+    #[test]
+    fn follows_the_save_rules() {
+        let dir = temp_dir("rules");
+        let mut state = SnapshotState::new(&config(&dir, vec![(3600, 1), (0, 2)]));
+        assert!(!state.save_due());
+        state.record_change();
+        assert!(!state.save_due(), "an hour hasn't passed");
+        state.record_change();
+        assert!(state.save_due());
+
+        state.changes_at_bgsave = Some(2);
+        assert!(!state.save_due(), "a save is running");
+        state.changes_at_bgsave = None;
+        state.last_failure = Some(now_secs());
+        assert!(!state.save_due(), "the last save just failed");
+        state.last_failure = Some(now_secs() - SAVE_RETRY_DELAY);
+        assert!(state.save_due());
+
+        // changes made while the save ran are left for the next one
+        state.record_change();
+        state.saved(2);
+        assert_eq!(state.changes_since_save(), 1);
+        assert!(state.last_failure.is_none() && !state.save_due());
+    }
+
+    #[test]
+    fn saves_and_loads_the_keyspace() {
+        let dir = temp_dir("save");
+        let mut store = Store::default();
+        for (key, value, _) in entries() {
+            store.insert(key, value);
+        }
+        assert!(
+            save_blocking(&mut store).is_err(),
+            "snapshots aren't configured"
+        );
+
+        store.set_snapshot_state(SnapshotState::new(&config(&dir, vec![])));
+        store.record_change();
+        store.snapshot_state_mut().unwrap().changes_at_bgsave = Some(0);
+        assert!(
+            save_blocking(&mut store).is_err(),
+            "a background save is running"
+        );
+        store.snapshot_state_mut().unwrap().changes_at_bgsave = None;
+        save_blocking(&mut store).unwrap();
+        assert_eq!(store.snapshot_state().unwrap().changes_since_save(), 0);
+
+        let mut loaded = Store::default();
+        let path = dir.join(&Config::default().dbfilename);
+        assert_eq!(load(&path, &mut loaded).unwrap(), 3);
+        assert_eq!(sorted(loaded.snapshot()), sorted(store.snapshot()));
+        // only the snapshot is left, no temporary file
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        assert_eq!(load(&dir.join("missing.snap"), &mut loaded).unwrap(), 0);
+    }
+
+    #[test]
+    fn saves_in_the_background_once_at_a_time() {
+        let dir = temp_dir("bgsave");
+        let mut store = Store::default();
+        store.insert("a".to_string(), Value::String("1".to_string()));
+        store.set_snapshot_state(SnapshotState::new(&config(&dir, vec![])));
+        let db = Arc::new(RwLock::new(store));
+
+        db.write()
+            .unwrap()
+            .snapshot_state_mut()
+            .unwrap()
+            .changes_at_bgsave = Some(0);
+        assert!(start_bgsave(db.clone()).is_err());
+        db.write()
+            .unwrap()
+            .snapshot_state_mut()
+            .unwrap()
+            .changes_at_bgsave = None;
+
+        start_bgsave(db.clone()).unwrap();
+        let deadline = Instant::now() + TIMEOUT;
+        while db.read().unwrap().snapshot_state().unwrap().is_saving() {
+            assert!(
+                Instant::now() < deadline,
+                "the background save didn't finish"
+            );
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut loaded = Store::default();
+        let path = dir.join(&Config::default().dbfilename);
+        assert_eq!(load(&path, &mut loaded).unwrap(), 1);
+    }
+
+    #[test]
+    fn answers_save_bgsave_and_lastsave() {
+        let dir = temp_dir("commands");
+        let mut store = Store::default();
+        let mut state = SnapshotState::new(&config(&dir, vec![]));
+        state.last_save = 1;
+        store.set_snapshot_state(state);
+        let config = ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        let server = TestServer::start_with(config, store);
+        let mut conn = server.connect();
+        let mut request = |command: &str, expected: &str| {
+            conn.write_all(command.as_bytes()).unwrap();
+            let mut reply = vec![0; expected.len()];
+            conn.read_exact(&mut reply).unwrap();
+            assert_eq!(String::from_utf8(reply).unwrap(), expected, "{:?}", command);
+        };
+
+        request("LASTSAVE\r\n", ":1\r\n");
+        request("SET a 1\r\n", "+OK\r\n");
+        request("SAVE\r\n", "+OK\r\n");
+        let last_save = server
+            .db()
+            .read()
+            .unwrap()
+            .snapshot_state()
+            .unwrap()
+            .last_save();
+        assert!(last_save >= now_secs() - 1);
+        request("LASTSAVE\r\n", &format!(":{}\r\n", last_save));
+        request("BGSAVE\r\n", "+Background saving started\r\n");
+
+        let mut loaded = Store::default();
+        let path = dir.join(&Config::default().dbfilename);
+        assert_eq!(load(&path, &mut loaded).unwrap(), 1);
+    }
+}
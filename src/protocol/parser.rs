@@ -0,0 +1,119 @@
+use std::io::{self, Result};
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", msg),
+    )
+}
+
+/// Reads a `\r\n` terminated line starting at `pos`, returning it along with the position right
+/// after the terminator. Returns None when the line is not complete yet.
+fn read_line(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let end = buf[pos..].windows(2).position(|window| window == b"\r\n")?;
+    Some((&buf[pos..pos + end], pos + end + 2))
+}
+
+fn read_len(line: &[u8], prefix: u8) -> Result<i64> {
+    if line.first() != Some(&prefix) {
+        return Err(invalid(&format!("expected '{}'", prefix as char)));
+    }
+    std::str::from_utf8(&line[1..])
+        .ok()
+        .and_then(|len| len.parse::<i64>().ok())
+        .ok_or_else(|| invalid("invalid length"))
+}
+
+/// Parses one command in multibulk form (`*<n>\r\n$<len>\r\n<arg>\r\n...`) from the start of
+/// `buf`.
+///
+/// Returns the arguments along with the number of bytes consumed, or None if the buffer does not
+/// hold a complete command yet.
+///
+/// # Errors
+///
+/// This function will return an error if the bytes are not a valid multibulk command.
+pub fn parse_multibulk(buf: &[u8]) -> Result<Option<(Vec<String>, usize)>> {
+    let (line, mut pos) = match read_line(buf, 0) {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    let count = read_len(line, b'*')?;
+    if count < 0 {
+        return Err(invalid("invalid multibulk length"));
+    }
+
+    // the count comes from the peer, the arguments are only allocated once they arrived
+    let mut args = Vec::new();
+    for _ in 0..count {
+        let (line, next) = match read_line(buf, pos) {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+        let len = read_len(line, b'$')?;
+        if len < 0 {
+            return Err(invalid("invalid bulk length"));
+        }
+        let end = match (len as u64)
+            .checked_add(next as u64 + 2)
+            .and_then(|end| usize::try_from(end).ok())
+        {
+            Some(end) => end,
+            None => return Err(invalid("invalid bulk length")),
+        };
+        if buf.len() < end {
+            return Ok(None);
+        }
+        let len = len as usize;
+        if &buf[next + len..end] != b"\r\n" {
+            return Err(invalid("bulk string is not terminated"));
+        }
+        args.push(String::from_utf8_lossy(&buf[next..next + len]).to_string());
+        pos = end;
+    }
+    Ok(Some((args, pos)))
+}
+
+/// Encodes a command in multibulk form.
+pub fn encode_multibulk(args: &[String]) -> String {
+    let mut out = format!("*{}\r\n", args.len());
+    for arg in args {
+        out.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_commands() {
+        let buf = b"*2\r\n$3\r\nGET\r\n$3\r\na\r\n\r\n*1\r\n";
+        let (args, used) = parse_multibulk(buf).unwrap().unwrap();
+        assert_eq!(args, ["GET", "a\r\n"]);
+        assert_eq!(used, 22);
+        assert_eq!(parse_multibulk(&buf[used..]).unwrap(), None);
+        assert_eq!(parse_multibulk(&buf[..21]).unwrap(), None);
+    }
+
+    #[test]
+    fn trusts_no_length() {
+        let max = i64::MAX;
+        // waits for the arguments rather than reserving room for them
+        let buf = format!("*{max}\r\n$1\r\na\r\n");
+        assert_eq!(parse_multibulk(buf.as_bytes()).unwrap(), None);
+        let buf = format!("*1\r\n${max}\r\nabc\r\n");
+        assert_eq!(parse_multibulk(buf.as_bytes()).unwrap(), None);
+
+        for buf in [
+            "*-1\r\n",
+            "*1\r\n$-1\r\n",
+            "*1\r\n$1\r\nab\r\n",
+            "$1\r\na\r\n",
+        ] {
+            let err = parse_multibulk(buf.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData, "{:?}", buf);
+        }
+    }
+}
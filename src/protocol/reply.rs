@@ -0,0 +1,95 @@
+/// A reply produced by a command, encoded in the RESP wire format before it is written back to
+/// the client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(String),
+    Nil,
+    Array(Vec<Reply>),
+    NilArray,
+}
+
+impl Reply {
+    pub fn ok() -> Reply {
+        Reply::Simple("OK".to_string())
+    }
+
+    /// Builds an error reply, the message should already carry its prefix (ERR, WRONGTYPE, ...).
+    pub fn error(msg: impl Into<String>) -> Reply {
+        Reply::Error(msg.into())
+    }
+
+    pub fn wrong_type() -> Reply {
+        Reply::error("WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
+
+    pub fn wrong_arity(cmd: &str) -> Reply {
+        Reply::error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            cmd.to_lowercase()
+        ))
+    }
+
+    pub fn not_an_integer() -> Reply {
+        Reply::error("ERR value is not an integer or out of range")
+    }
+
+    pub fn syntax_error() -> Reply {
+        Reply::error("ERR syntax error")
+    }
+
+    pub fn read_only() -> Reply {
+        Reply::error("READONLY You can't write against a read only replica.")
+    }
+
+    pub fn bulk_array<I>(items: I) -> Reply
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Reply::Array(items.into_iter().map(Reply::Bulk).collect())
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, Reply::Error(_))
+    }
+
+    /// Encodes the reply using RESP framing.
+    pub fn encode(&self) -> String {
+        let mut out = String::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    fn encode_into(&self, out: &mut String) {
+        match self {
+            Reply::Simple(val) => {
+                out.push('+');
+                out.push_str(val);
+                out.push_str("\r\n");
+            }
+            Reply::Error(msg) => {
+                out.push('-');
+                out.push_str(msg);
+                out.push_str("\r\n");
+            }
+            Reply::Integer(val) => {
+                out.push_str(&format!(":{}\r\n", val));
+            }
+            Reply::Bulk(val) => {
+                out.push_str(&format!("${}\r\n", val.len()));
+                out.push_str(val);
+                out.push_str("\r\n");
+            }
+            Reply::Nil => out.push_str("$-1\r\n"),
+            Reply::Array(items) => {
+                out.push_str(&format!("*{}\r\n", items.len()));
+                for item in items {
+                    item.encode_into(out);
+                }
+            }
+            Reply::NilArray => out.push_str("*-1\r\n"),
+        }
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    fmt,
+    io::Result,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use super::poller::core::Poller;
+
+/// Decides when the workers handing their output back through a [`CompletionQueue`] run.
+///
+/// Workers run as soon as they are spawned unless the poller of the reactor provides a gate,
+/// the simulation does to run them one at a time in an order it picks.
+pub trait WorkerGate: Send + Sync {
+    /// Called when a worker is spawned, before its thread starts, returns its ticket.
+    fn spawned(&self, fd: usize) -> u64;
+    /// Called by the worker thread, blocks until the worker may run.
+    fn enter(&self, ticket: u64);
+    /// Called by the worker thread once its output was handed back.
+    fn leave(&self, ticket: u64);
+}
+
+/// Handlers to poll again, handed to the reactor by other threads.
+///
+/// Workers push the fd of the handler whose output is ready and wake the reactor, the event
+/// loop drains the queue on each iteration. Neither side takes the reactor lock: producers send
+/// on a channel, only the event loop receives from it.
+pub struct CompletionQueue {
+    sender: Sender<usize>,
+    receiver: Mutex<Receiver<usize>>,
+    /// fds pushed and not drained yet, counted before they are sent so the event loop never
+    /// waits while one is on its way
+    pending: AtomicUsize,
+    poller: Arc<dyn Poller>,
+    gate: Option<Arc<dyn WorkerGate>>,
+}
+
+impl CompletionQueue {
+    pub fn new(poller: Arc<dyn Poller>) -> CompletionQueue {
+        let (sender, receiver) = mpsc::channel();
+        CompletionQueue {
+            sender,
+            receiver: Mutex::new(receiver),
+            pending: AtomicUsize::new(0),
+            gate: poller.worker_gate(),
+            poller,
+        }
+    }
+
+    /// Queues `fd` without waking the reactor.
+    pub fn push(&self, fd: usize) {
+        self.pending.fetch_add(1, Ordering::SeqCst);
+        // the receiver lives as long as the queue, sending can't fail
+        let _ = self.sender.send(fd);
+    }
+
+    /// Queues `fd` and wakes the reactor so it gets polled.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the waker fails.
+    pub fn complete(&self, fd: usize) -> Result<()> {
+        self.push(fd);
+        self.poller.wake()
+    }
+
+    /// Takes every queued fd, in the order they were queued.
+    pub fn drain(&self) -> Vec<usize> {
+        let fds: Vec<usize> = self.receiver.lock().unwrap().try_iter().collect();
+        self.pending.fetch_sub(fds.len(), Ordering::SeqCst);
+        fds
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pending.load(Ordering::SeqCst) == 0
+    }
+
+    /// Gate the workers of this queue go through, see [`WorkerGate`].
+    pub fn gate(&self) -> Option<Arc<dyn WorkerGate>> {
+        self.gate.clone()
+    }
+}
+
+impl fmt::Debug for CompletionQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompletionQueue")
+            .field("empty", &self.is_empty())
+            .finish()
+    }
+}
+
+/// Schedules one handler from any thread, whichever reactor it lives on.
+#[derive(Debug, Clone)]
+pub struct Notifier {
+    fd: usize,
+    completions: Arc<CompletionQueue>,
+}
+
+impl Notifier {
+    pub fn new(fd: usize, completions: Arc<CompletionQueue>) -> Notifier {
+        Notifier { fd, completions }
+    }
+
+    pub fn fd(&self) -> usize {
+        self.fd
+    }
+
+    /// Schedules the handler and wakes its reactor.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the waker fails.
+    pub fn notify(&self) -> Result<()> {
+        self.completions.complete(self.fd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, thread};
+
+    use super::*;
+    use crate::reactor::poller::mio_poller::MioPoller;
+
+    #[test]
+    fn drains_what_every_producer_pushed() {
+        const PRODUCERS: usize = 8;
+        const PUSHES: usize = 10_000;
+        let queue = Arc::new(CompletionQueue::new(Arc::new(MioPoller::new().unwrap())));
+        let producers: Vec<_> = (0..PRODUCERS)
+            .map(|producer| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..PUSHES {
+                        queue.complete(producer * PUSHES + i).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        // drained while the producers push, each producer's fds come out in its order
+        let mut seen = HashSet::new();
+        let mut last = [None; PRODUCERS];
+        let mut drain = |seen: &mut HashSet<usize>| {
+            for fd in queue.drain() {
+                let producer = fd / PUSHES;
+                assert!(
+                    last[producer] < Some(fd),
+                    "{} after {:?}",
+                    fd,
+                    last[producer]
+                );
+                last[producer] = Some(fd);
+                assert!(seen.insert(fd));
+            }
+        };
+        while !producers.iter().all(|producer| producer.is_finished()) {
+            drain(&mut seen);
+        }
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        drain(&mut seen);
+        assert_eq!(seen.len(), PRODUCERS * PUSHES);
+        assert!(queue.is_empty());
+    }
+}
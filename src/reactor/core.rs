@@ -1,73 +1,56 @@
-use std::{
-    collections::HashSet,
-    io::{self, Result},
-    sync::Arc,
-    time::Duration,
-};
+use std::{collections::HashSet, io::Result, os::fd::AsRawFd, sync::Arc, time::Duration};
 
-use mio::{event::Source, Events, Interest, Poll, Waker};
+use mio::Interest;
 
-use super::event_listener::EventListener;
+use super::{
+    completion::CompletionQueue, event_listener::EventListener, poller::core::Poller,
+    slab::TokenSlab,
+};
 
 pub struct Reactor {
+    /// tokens registered on the poller
     pub existing_tokens: HashSet<usize>,
-    pub poller: Poll,
+    /// tokens handed out to handlers, see [`TokenSlab`]
+    pub tokens: TokenSlab,
+    /// the event loop waits on it without holding the reactor, see [`Poller`]
+    pub poller: Arc<dyn Poller>,
     pub tasks: Vec<usize>,
     pub new_source: Vec<(usize, Box<dyn EventListener + Send + Sync>)>,
     pub old_source: Vec<usize>,
-    pub waker: Option<Arc<Waker>>,
+    /// handlers scheduled by other threads without taking the reactor lock
+    pub completions: Arc<CompletionQueue>,
 }
 
-impl Default for Reactor {
-    fn default() -> Reactor {
-        let poller = Poll::new().unwrap();
+impl Reactor {
+    pub fn new(poller: Arc<dyn Poller>) -> Reactor {
         Reactor {
             existing_tokens: HashSet::new(),
+            tokens: TokenSlab::default(),
+            completions: Arc::new(CompletionQueue::new(poller.clone())),
             poller,
             tasks: vec![],
             new_source: vec![],
             old_source: vec![],
-            waker: None,
         }
     }
-}
 
-impl Reactor {
-    /// Returns the events that has been recieved by the poller;
-    /// This method will poll for events and it will return Ok(events) only when events  is
-    /// present.
-    /// In case polling returns and empty event list it will stay inside the loop.
-    /// # Errors
-    ///
-    /// This function will return an error if .
-    /// mio::Poll::poll method returns any error except for Interrupted ;
-    pub fn wait(&mut self, events: &mut Events) -> Result<()> {
-        while events.is_empty() {
-            match self.poller.poll(events, Some(Duration::from_nanos(20))) {
-                Ok(_) => {
-                    if !self.tasks.is_empty() {
-                        break;
-                    }
-                }
-                Err(err) => {
-                    if err.kind() == io::ErrorKind::Interrupted {
-                        // in case notify is called we break out of the waiting loop and do one
-                        // iteration of event loop
-                        break;
-                    } else {
-                        return Err(err);
-                    }
-                }
-            }
-        }
-        Ok(())
+    /// How long the event loop may wait for events: not at all when work is already pending,
+    /// forever otherwise. Work handed over by other threads comes with a wake up.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        let pending = !self.tasks.is_empty()
+            || !self.new_source.is_empty()
+            || !self.old_source.is_empty()
+            || !self.completions.is_empty();
+        pending.then_some(Duration::ZERO)
     }
 
-    /// Takes a listener or TcpStream as source along with it;s Fd and Interest is None.
+    /// Takes a listener or TcpStream as source along with its token from `allocate_token` and
+    /// Interest is None.
     /// If no interest is passed explictly we will add READABLE and WRITABLE interest to the event
     /// so that it works for both.
     ///
-    /// Suggested: use READABLE interest for server and keep None for clients
+    /// Suggested: use READABLE interest for servers and clients, clients add WRITABLE with
+    /// `reregister` while they have output pending
     ///
     /// # Errors
     ///
@@ -75,67 +58,81 @@ impl Reactor {
     /// register register will throw error
     pub fn register(
         &mut self,
-        fd: usize,
-        source: &mut impl Source,
+        token: usize,
+        source: &impl AsRawFd,
         interest: Option<Interest>,
     ) -> Result<()> {
-        if !self.existing_tokens.contains(&fd) {
+        if !self.existing_tokens.contains(&token) {
             let interest = interest.unwrap_or(Interest::READABLE.add(Interest::WRITABLE));
-            self.existing_tokens.insert(fd);
+            self.existing_tokens.insert(token);
 
-            return self
-                .poller
-                .registry()
-                .register(source, mio::Token(fd), interest);
+            return self.poller.register(source.as_raw_fd(), token, interest);
         }
         Ok(())
     }
 
+    /// Changes the interest of a source registered with `register`.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if reregister fn return any error
+    pub fn reregister(
+        &mut self,
+        token: usize,
+        source: &impl AsRawFd,
+        interest: Interest,
+    ) -> Result<()> {
+        self.poller.reregister(source.as_raw_fd(), token, interest)
+    }
+
     /// It takes a source and then it removes from the poller instance;
     ///
     /// # Errors
     ///
     /// This function will return an error if deregister fn return any error
-    pub fn unregister(&mut self, source: &mut impl Source) -> Result<()> {
-        self.poller.registry().deregister(source)
+    pub fn unregister(&mut self, token: usize, source: &impl AsRawFd) -> Result<()> {
+        self.poller.deregister(source.as_raw_fd(), token)
     }
 
-    /// It takes an Fd and add it to schduler;
+    /// It takes a token and add it to schduler;
     pub fn schedule(&mut self, id: usize) {
         self.tasks.push(id);
     }
 
-    pub fn add_new_connection<E>(&mut self, fd: usize, handler: E)
+    /// Hands out the token identifying a new handler.
+    pub fn allocate_token(&mut self) -> usize {
+        self.tokens.allocate()
+    }
+
+    /// Gives back a token that never got registered.
+    pub fn release_token(&mut self, token: usize) {
+        self.tokens.release(token);
+    }
+
+    pub fn add_new_connection<E>(&mut self, token: usize, handler: E)
     where
         E: EventListener + Send + Sync + 'static,
     {
         self.new_source.push((handler.id(), Box::new(handler)));
-        self.schedule(fd);
+        self.schedule(token);
     }
 
-    pub fn remove_old_connection(&mut self, fd: usize, source: &mut impl Source) {
-        self.unregister(source).unwrap();
-        self.existing_tokens.remove(&fd);
-        self.old_source.push(fd);
+    /// Unregisters the source and releases its token, tasks still scheduled for it are
+    /// dropped by the event loop.
+    pub fn remove_old_connection(&mut self, token: usize, source: &impl AsRawFd) {
+        self.unregister(token, source).unwrap();
+        self.existing_tokens.remove(&token);
+        self.tokens.release(token);
+        self.old_source.push(token);
     }
 
-    fn init_waker(&mut self) {
-        match self.waker {
-            None => {
-                self.waker.replace(Arc::new(
-                    mio::Waker::new(self.poller.registry(), mio::Token(0)).unwrap(),
-                ));
-            }
-            _ => {}
-        };
+    /// Poller of the reactor, other threads wake the event loop through it.
+    pub fn poller(&self) -> Arc<dyn Poller> {
+        self.poller.clone()
     }
 
-    pub fn get_waker_for_fd(&mut self) -> Arc<Waker> {
-        if let Some(waker) = self.waker.clone() {
-            return waker;
-        } else {
-            self.init_waker();
-            return self.get_waker_for_fd();
-        }
+    /// Queue other threads schedule handlers through, see [`CompletionQueue`].
+    pub fn completions(&self) -> Arc<CompletionQueue> {
+        self.completions.clone()
     }
 }
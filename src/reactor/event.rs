@@ -0,0 +1,49 @@
+/// Readiness of a registered source, as reported by the poller of the reactor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Event {
+    pub token: usize,
+    pub readable: bool,
+    pub writable: bool,
+    pub error: bool,
+    pub read_closed: bool,
+    pub write_closed: bool,
+}
+
+impl Event {
+    pub fn token(&self) -> usize {
+        self.token
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.readable
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.writable
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.error
+    }
+
+    pub fn is_read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    pub fn is_write_closed(&self) -> bool {
+        self.write_closed
+    }
+}
+
+impl From<&mio::event::Event> for Event {
+    fn from(event: &mio::event::Event) -> Event {
+        Event {
+            token: event.token().0,
+            readable: event.is_readable(),
+            writable: event.is_writable(),
+            error: event.is_error(),
+            read_closed: event.is_read_closed(),
+            write_closed: event.is_write_closed(),
+        }
+    }
+}
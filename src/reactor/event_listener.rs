@@ -1,6 +1,6 @@
 use std::io::Result;
 
-use mio::event::Event;
+use super::event::Event;
 
 pub trait EventListener {
     fn id(&self) -> usize;
@@ -1,2 +1,6 @@
+pub mod completion;
 pub mod core;
+pub mod event;
 pub mod event_listener;
+pub mod poller;
+pub mod slab;
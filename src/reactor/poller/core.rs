@@ -0,0 +1,66 @@
+use std::{io::Result, os::fd::RawFd, sync::Arc, time::Duration};
+
+use mio::Interest;
+
+use crate::{
+    config::core::PollerBackend,
+    reactor::{completion::WorkerGate, event::Event},
+};
+
+use super::mio_poller::MioPoller;
+
+/// Token of the wake ups, never handed to a source.
+pub const WAKER_TOKEN: usize = 0;
+
+/// Readiness notifications for the sources of a reactor.
+///
+/// Events are edge triggered, a source is reported when it becomes readable or writable and
+/// handlers read or write until WouldBlock. Sources are registered and the poller is woken from
+/// any thread, only the event loop waits.
+pub trait Poller: Send + Sync {
+    /// Name of the backend, shown in the logs.
+    fn name(&self) -> &'static str;
+
+    fn register(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()>;
+
+    fn reregister(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()>;
+
+    fn deregister(&self, fd: RawFd, token: usize) -> Result<()>;
+
+    /// Ends the current wait, or the next one when the event loop isn't waiting.
+    fn wake(&self) -> Result<()>;
+
+    /// Waits for events for at most `timeout`, or until woken when None, and appends them to
+    /// `events`. Wake ups and interrupted waits return without events.
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> Result<()>;
+
+    /// Gate the workers of the reactor go through, they run right away when None.
+    fn worker_gate(&self) -> Option<Arc<dyn WorkerGate>> {
+        None
+    }
+
+    /// Ring the connections of the reactor do their I/O through, None when their handlers
+    /// read and write the sockets themselves.
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn ring(&self) -> Option<Arc<super::uring::Ring>> {
+        None
+    }
+}
+
+/// Creates the poller of a reactor.
+///
+/// # Errors
+///
+/// This function will return an error if the backend can't be set up, or isn't built in.
+pub fn new_poller(backend: PollerBackend) -> Result<Arc<dyn Poller>> {
+    match backend {
+        PollerBackend::Mio => Ok(Arc::new(MioPoller::new()?)),
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        PollerBackend::IoUring => Ok(Arc::new(super::uring::UringPoller::new()?)),
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        PollerBackend::IoUring => Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "io_uring support is not built in, build on Linux with the io-uring feature",
+        )),
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    io::{self, Result},
+    os::fd::RawFd,
+    sync::Mutex,
+    time::Duration,
+};
+
+use mio::{unix::SourceFd, Events, Interest, Poll, Registry, Token, Waker};
+
+use crate::reactor::event::Event;
+
+use super::core::{Poller, WAKER_TOKEN};
+
+/// Polls with mio, epoll on Linux and kqueue on the BSDs.
+pub struct MioPoller {
+    /// only locked by the event loop, while it waits
+    poll: Mutex<(Poll, Events)>,
+    /// registers sources while the event loop waits
+    registry: Registry,
+    waker: Waker,
+}
+
+impl MioPoller {
+    /// # Errors
+    ///
+    /// This function will return an error if the poll or its waker can't be created.
+    pub fn new() -> Result<MioPoller> {
+        let poll = Poll::new()?;
+        let waker = Waker::new(poll.registry(), Token(WAKER_TOKEN))?;
+        let registry = poll.registry().try_clone()?;
+        Ok(MioPoller {
+            poll: Mutex::new((poll, Events::with_capacity(1024))),
+            registry,
+            waker,
+        })
+    }
+}
+
+impl Poller for MioPoller {
+    fn name(&self) -> &'static str {
+        "mio"
+    }
+
+    fn register(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()> {
+        self.registry
+            .register(&mut SourceFd(&fd), Token(token), interest)
+    }
+
+    fn reregister(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()> {
+        self.registry
+            .reregister(&mut SourceFd(&fd), Token(token), interest)
+    }
+
+    fn deregister(&self, fd: RawFd, _token: usize) -> Result<()> {
+        self.registry.deregister(&mut SourceFd(&fd))
+    }
+
+    fn wake(&self) -> Result<()> {
+        self.waker.wake()
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> Result<()> {
+        let mut poll = self.poll.lock().unwrap();
+        let (poll, polled) = &mut *poll;
+        match poll.poll(polled, timeout) {
+            Ok(_) => {}
+            // a signal interrupted the wait, the loop just goes for another iteration
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        events.extend(
+            polled
+                .iter()
+                .filter(|event| event.token().0 != WAKER_TOKEN)
+                .map(Event::from),
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,4 @@
+pub mod core;
+pub mod mio_poller;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+pub mod uring;
@@ -0,0 +1,792 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fs::File,
+    io::{self, Read, Result, Write},
+    mem,
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd},
+    ptr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use io_uring::{
+    cqueue, opcode, squeue,
+    types::{Fd, SubmitArgs, Timespec},
+    IoUring,
+};
+use mio::Interest;
+
+use crate::{
+    async_client::stream::ClientStream, async_server::listener::ServerListener,
+    reactor::event::Event,
+};
+
+use super::core::{Poller, WAKER_TOKEN};
+
+/// Entries of the submission and completion rings.
+const RING_ENTRIES: u32 = 1024;
+/// Bytes a connection receives per request, the same as a handler reads at once.
+const RECV_BUFFER: usize = 4096;
+/// User data of removals and cancellations, their completions are ignored.
+const CANCEL: u64 = u64::MAX;
+
+/// What a request in flight is for.
+#[derive(Debug, Clone, Copy)]
+enum Request {
+    /// readiness of a polled source or of the waker, by token
+    Poll(usize),
+    Recv(u64),
+    Send(u64),
+    Accept(u64),
+}
+
+/// A source polled for readiness, the handler does its own I/O on it.
+#[derive(Debug, Clone, Copy)]
+struct Polled {
+    fd: RawFd,
+    mask: u32,
+    /// the multishot poll of the source, polls it replaced complete as stale
+    request: u64,
+}
+
+/// A socket whose I/O goes through the ring, see [`UringStream`] and [`UringListener`].
+struct Socket {
+    fd: RawFd,
+    /// closed when the socket goes, None for listeners as their fd belongs to the listener
+    owned: Option<OwnedFd>,
+    listening: bool,
+    /// token and interest while registered
+    registered: Option<(usize, Interest)>,
+    /// recv or accept in flight
+    reading: Option<u64>,
+    /// send in flight
+    sending: Option<u64>,
+    /// the kernel receives into it while a recv is in flight
+    buf: Box<[u8]>,
+    /// bytes received and not read yet
+    received: VecDeque<u8>,
+    /// connections accepted and not taken yet
+    accepted: VecDeque<OwnedFd>,
+    /// the peer closed its end
+    eof: bool,
+    /// errno of the last failed request, returned by the next read or write
+    error: Option<i32>,
+    /// bytes handed to the kernel, they stay in place until sent from `sent` on
+    out: Vec<u8>,
+    sent: usize,
+    /// the stream or listener was dropped, the socket goes once nothing is in flight
+    dropped: bool,
+}
+
+impl Socket {
+    fn readable(&self) -> bool {
+        !self.received.is_empty() || !self.accepted.is_empty() || self.eof || self.error.is_some()
+    }
+
+    fn writable(&self) -> bool {
+        self.sending.is_none()
+    }
+}
+
+#[derive(Default)]
+struct State {
+    next_id: u64,
+    requests: HashMap<u64, Request>,
+    polled: HashMap<usize, Polled>,
+    sockets: HashMap<u64, Socket>,
+    /// sockets by fd, a registration finds the socket it is for
+    by_fd: HashMap<RawFd, u64>,
+    /// requests submitted with the next wait
+    queued: Vec<squeue::Entry>,
+    /// events found outside of a wait, reported by the next one
+    ready: Vec<Event>,
+}
+
+impl State {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn submit(&mut self, request: Request, entry: squeue::Entry) -> u64 {
+        let id = self.next_id();
+        self.requests.insert(id, request);
+        self.queued.push(entry.user_data(id));
+        id
+    }
+
+    fn poll(&mut self, token: usize, fd: RawFd, mask: u32) {
+        let entry = opcode::PollAdd::new(Fd(fd), mask).multi(true).build();
+        let request = self.submit(Request::Poll(token), entry);
+        let replaced = self.polled.insert(token, Polled { fd, mask, request });
+        if let Some(replaced) = replaced {
+            self.remove_poll(replaced.request);
+        }
+    }
+
+    fn remove_poll(&mut self, request: u64) {
+        self.queued
+            .push(opcode::PollRemove::new(request).build().user_data(CANCEL));
+    }
+
+    /// Receives or accepts into the socket unless it already does or has something to read.
+    fn start_read(&mut self, id: u64) {
+        let socket = match self.sockets.get_mut(&id) {
+            Some(socket) if socket.reading.is_none() && !socket.readable() && !socket.dropped => {
+                socket
+            }
+            _ => return,
+        };
+        let (request, entry) = if socket.listening {
+            let entry = opcode::Accept::new(Fd(socket.fd), ptr::null_mut(), ptr::null_mut())
+                .flags(libc::SOCK_CLOEXEC)
+                .build();
+            (Request::Accept(id), entry)
+        } else {
+            // the buffer is boxed, it stays in place while the socket moves in the map
+            let entry = opcode::Recv::new(
+                Fd(socket.fd),
+                socket.buf.as_mut_ptr(),
+                socket.buf.len() as u32,
+            )
+            .build();
+            (Request::Recv(id), entry)
+        };
+        let request = self.submit(request, entry);
+        self.sockets.get_mut(&id).unwrap().reading = Some(request);
+    }
+
+    /// Sends what is left of the output of the socket.
+    fn start_send(&mut self, id: u64) {
+        let socket = &self.sockets[&id];
+        let rest = &socket.out[socket.sent..];
+        // `out` isn't touched until the send completes
+        let entry = opcode::Send::new(Fd(socket.fd), rest.as_ptr(), rest.len() as u32).build();
+        let request = self.submit(Request::Send(id), entry);
+        self.sockets.get_mut(&id).unwrap().sending = Some(request);
+    }
+
+    /// Reports what the socket is ready for among `readable` and `writable`, as far as its
+    /// handler waits for it.
+    fn report(&mut self, id: u64, readable: bool, writable: bool) {
+        let socket = match self.sockets.get(&id) {
+            Some(socket) => socket,
+            None => return,
+        };
+        let (token, interest) = match socket.registered {
+            Some(registered) => registered,
+            None => return,
+        };
+        let readable = readable && interest.is_readable() && socket.readable();
+        let writable = writable && interest.is_writable() && socket.writable();
+        if readable || writable {
+            self.ready.push(Event {
+                token,
+                readable,
+                writable,
+                error: socket.error.is_some(),
+                read_closed: readable && socket.eof,
+                write_closed: writable && socket.error.is_some(),
+            });
+        }
+    }
+
+    /// Picks up a socket for the interest it was registered with, what it is ready for is
+    /// reported and reading starts when it has nothing to read.
+    fn resume(&mut self, id: u64) {
+        self.report(id, true, true);
+        let reads = self.sockets[&id]
+            .registered
+            .is_some_and(|(_, interest)| interest.is_readable());
+        if reads {
+            self.start_read(id);
+        }
+    }
+
+    /// Drops a socket once it was dropped by its owner and has nothing in flight.
+    fn collect(&mut self, id: u64) {
+        let done = self.sockets.get(&id).is_some_and(|socket| {
+            socket.dropped && socket.reading.is_none() && socket.sending.is_none()
+        });
+        if done {
+            // closes the fd of a connection, nothing refers to it anymore
+            let socket = self.sockets.remove(&id).unwrap();
+            drop(socket.owned);
+        }
+    }
+
+    fn complete(&mut self, id: u64, result: i32, flags: u32, waker: &File) {
+        let request = match self.requests.get(&id) {
+            Some(request) => *request,
+            None => return,
+        };
+        let more = cqueue::more(flags);
+        if !more {
+            self.requests.remove(&id);
+        }
+        match request {
+            Request::Poll(token) => self.complete_poll(id, token, result, more, waker),
+            Request::Recv(socket_id) => {
+                let socket = match self.sockets.get_mut(&socket_id) {
+                    Some(socket) => socket,
+                    None => return,
+                };
+                socket.reading = None;
+                match result {
+                    n if n > 0 => socket.received.extend(&socket.buf[..n as usize]),
+                    0 => socket.eof = true,
+                    err if err == -libc::ECANCELED => {}
+                    err => socket.error = Some(-err),
+                }
+                self.report(socket_id, true, false);
+                self.collect(socket_id);
+            }
+            Request::Accept(socket_id) => {
+                let socket = match self.sockets.get_mut(&socket_id) {
+                    Some(socket) => socket,
+                    None => return,
+                };
+                socket.reading = None;
+                match result {
+                    // the kernel made the fd for us
+                    fd if fd >= 0 => socket
+                        .accepted
+                        .push_back(unsafe { OwnedFd::from_raw_fd(fd) }),
+                    err if err == -libc::ECANCELED => {}
+                    err => socket.error = Some(-err),
+                }
+                self.report(socket_id, true, false);
+                self.collect(socket_id);
+            }
+            Request::Send(socket_id) => {
+                let socket = match self.sockets.get_mut(&socket_id) {
+                    Some(socket) => socket,
+                    None => return,
+                };
+                socket.sending = None;
+                match result {
+                    n if n > 0 => socket.sent += n as usize,
+                    0 => socket.error = Some(libc::EPIPE),
+                    err => socket.error = Some(-err),
+                }
+                if socket.error.is_none() && socket.sent < socket.out.len() {
+                    self.start_send(socket_id);
+                    return;
+                }
+                socket.out.clear();
+                socket.sent = 0;
+                self.report(socket_id, false, true);
+                self.collect(socket_id);
+            }
+        }
+    }
+
+    fn complete_poll(&mut self, id: u64, token: usize, result: i32, more: bool, waker: &File) {
+        let polled = match self.polled.get(&token) {
+            Some(polled) if polled.request == id => *polled,
+            // replaced or deregistered, its removal may not have reached it yet
+            _ => {
+                if more {
+                    self.remove_poll(id);
+                }
+                return;
+            }
+        };
+        if !more {
+            // the kernel ended the poll
+            self.poll(token, polled.fd, polled.mask);
+        }
+        if token == WAKER_TOKEN {
+            let mut count = [0; 8];
+            let _ = (&*waker).read(&mut count);
+        } else if result >= 0 {
+            self.ready.push(poll_event(token, result as u32));
+        } else if result != -libc::ECANCELED {
+            self.ready.push(Event {
+                token,
+                error: true,
+                ..Event::default()
+            });
+        }
+    }
+}
+
+/// A ring along with the state of its requests, shared by the poller and the sockets doing
+/// their I/O through it.
+pub struct Ring {
+    /// only locked by the event loop, while it waits
+    uring: Mutex<IoUring>,
+    state: Mutex<State>,
+    /// eventfd polled with `WAKER_TOKEN`
+    waker: File,
+}
+
+impl Ring {
+    fn new() -> Result<Ring> {
+        let uring = IoUring::new(RING_ENTRIES)?;
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // the eventfd was just created and is owned by nobody else
+        let waker = unsafe { File::from_raw_fd(fd) };
+        let mut state = State::default();
+        state.poll(WAKER_TOKEN, waker.as_raw_fd(), libc::POLLIN as u32);
+        Ok(Ring {
+            uring: Mutex::new(uring),
+            state: Mutex::new(state),
+            waker,
+        })
+    }
+
+    /// Runs `change` on the state, the event loop is woken when it is waiting so the requests
+    /// and events it leaves don't wait for an unrelated event.
+    fn update<T>(&self, change: impl FnOnce(&mut State) -> T) -> T {
+        let result = change(&mut self.state.lock().unwrap());
+        // the event loop holds the ring only while waiting
+        if self.uring.try_lock().is_err() {
+            let _ = self.wake();
+        }
+        result
+    }
+
+    fn wake(&self) -> Result<()> {
+        (&self.waker).write_all(&1u64.to_ne_bytes())
+    }
+
+    fn attach(&self, fd: RawFd, owned: Option<OwnedFd>, listening: bool) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id();
+        state.sockets.insert(
+            id,
+            Socket {
+                fd,
+                owned,
+                listening,
+                registered: None,
+                reading: None,
+                sending: None,
+                buf: vec![0; if listening { 0 } else { RECV_BUFFER }].into_boxed_slice(),
+                received: VecDeque::new(),
+                accepted: VecDeque::new(),
+                eof: false,
+                error: None,
+                out: vec![],
+                sent: 0,
+                dropped: false,
+            },
+        );
+        state.by_fd.insert(fd, id);
+        id
+    }
+
+    /// Forgets a dropped socket. Its recv or accept is cancelled, a send in flight goes on so
+    /// a last reply reaches the peer, the fd is closed once both completed.
+    fn detach(&self, id: u64) {
+        self.update(|state| {
+            let socket = match state.sockets.get_mut(&id) {
+                Some(socket) => socket,
+                None => return,
+            };
+            socket.dropped = true;
+            socket.registered = None;
+            let (fd, reading) = (socket.fd, socket.reading);
+            state.by_fd.remove(&fd);
+            if let Some(reading) = reading {
+                state
+                    .queued
+                    .push(opcode::AsyncCancel::new(reading).build().user_data(CANCEL));
+            }
+            state.collect(id);
+        })
+    }
+
+    fn read(&self, id: u64, buf: &mut [u8]) -> Result<usize> {
+        self.update(|state| {
+            let socket = state.sockets.get_mut(&id).unwrap();
+            if !socket.received.is_empty() {
+                let n = buf.len().min(socket.received.len());
+                for (byte, received) in buf.iter_mut().zip(socket.received.drain(..n)) {
+                    *byte = received;
+                }
+                return Ok(n);
+            }
+            if let Some(errno) = socket.error {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            if socket.eof {
+                return Ok(0);
+            }
+            state.start_read(id);
+            Err(io::ErrorKind::WouldBlock.into())
+        })
+    }
+
+    fn write(&self, id: u64, buf: &[u8]) -> Result<usize> {
+        self.update(|state| {
+            let socket = state.sockets.get_mut(&id).unwrap();
+            if let Some(errno) = socket.error {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            if socket.sending.is_some() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            socket.out.extend_from_slice(buf);
+            state.start_send(id);
+            Ok(buf.len())
+        })
+    }
+
+    fn accept(&self, id: u64) -> Result<OwnedFd> {
+        self.update(|state| {
+            let socket = state.sockets.get_mut(&id).unwrap();
+            if let Some(fd) = socket.accepted.pop_front() {
+                return Ok(fd);
+            }
+            // the listener backs off and retries, the error doesn't stick
+            if let Some(errno) = socket.error.take() {
+                return Err(io::Error::from_raw_os_error(errno));
+            }
+            state.start_read(id);
+            Err(io::ErrorKind::WouldBlock.into())
+        })
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        let state = self.state.get_mut().unwrap();
+        if !state.requests.is_empty() {
+            // the kernel may still write into the buffers of the requests in flight
+            mem::forget(mem::take(&mut state.sockets));
+        }
+    }
+}
+
+/// Polls through io_uring, Linux 5.13 or later.
+///
+/// Connections and listeners served with [`UringStream`] and [`UringListener`] are completion
+/// based: their recv, send and accept are requests on the ring, the handler is told a socket is
+/// ready once a request completed. Other sources, such as the link to a primary, get a multishot
+/// poll request and do their own I/O, so events are edge triggered like with mio either way.
+pub struct UringPoller {
+    ring: Arc<Ring>,
+}
+
+impl UringPoller {
+    /// # Errors
+    ///
+    /// This function will return an error if the ring or the eventfd can't be created, for
+    /// instance when io_uring is disabled.
+    pub fn new() -> Result<UringPoller> {
+        Ok(UringPoller {
+            ring: Arc::new(Ring::new()?),
+        })
+    }
+}
+
+fn poll_mask(interest: Interest) -> u32 {
+    let mut mask = 0;
+    if interest.is_readable() {
+        mask |= (libc::POLLIN | libc::POLLRDHUP) as u32;
+    }
+    if interest.is_writable() {
+        mask |= libc::POLLOUT as u32;
+    }
+    mask
+}
+
+fn poll_event(token: usize, revents: u32) -> Event {
+    let has = |flags: libc::c_short| revents & flags as u32 != 0;
+    Event {
+        token,
+        readable: has(libc::POLLIN | libc::POLLPRI),
+        writable: has(libc::POLLOUT),
+        error: has(libc::POLLERR),
+        read_closed: has(libc::POLLRDHUP | libc::POLLHUP),
+        write_closed: has(libc::POLLHUP | libc::POLLERR),
+    }
+}
+
+impl Poller for UringPoller {
+    fn name(&self) -> &'static str {
+        "io_uring"
+    }
+
+    fn register(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()> {
+        self.ring.update(|state| match state.by_fd.get(&fd) {
+            Some(&id) => {
+                state.sockets.get_mut(&id).unwrap().registered = Some((token, interest));
+                state.resume(id);
+            }
+            None => state.poll(token, fd, poll_mask(interest)),
+        });
+        Ok(())
+    }
+
+    fn reregister(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()> {
+        // a replaced poll is removed, and ignored until the removal reaches it
+        self.register(fd, token, interest)
+    }
+
+    fn deregister(&self, fd: RawFd, token: usize) -> Result<()> {
+        self.ring.update(|state| match state.by_fd.get(&fd) {
+            Some(&id) => state.sockets.get_mut(&id).unwrap().registered = None,
+            None => {
+                if let Some(polled) = state.polled.remove(&token) {
+                    state.remove_poll(polled.request);
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn wake(&self) -> Result<()> {
+        self.ring.wake()
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, timeout: Option<Duration>) -> Result<()> {
+        let mut uring = self.ring.uring.lock().unwrap();
+        let (queued, ready) = {
+            let mut state = self.ring.state.lock().unwrap();
+            (mem::take(&mut state.queued), !state.ready.is_empty())
+        };
+        for entry in queued {
+            // the requests only point into buffers the ring keeps until they complete
+            while unsafe { uring.submission().push(&entry) }.is_err() {
+                uring.submit()?;
+            }
+        }
+        // events found since the last wait are reported right away
+        let timeout = if ready { Some(Duration::ZERO) } else { timeout };
+        let waited = match timeout {
+            None => uring.submit_and_wait(1),
+            Some(timeout) if timeout.is_zero() => uring.submit(),
+            Some(timeout) => {
+                let timespec = Timespec::from(timeout);
+                let args = SubmitArgs::new().timespec(&timespec);
+                uring.submitter().submit_with_args(1, &args)
+            }
+        };
+        match waited {
+            Ok(_) => {}
+            // a signal interrupted the wait or it timed out, the loop goes for another iteration
+            Err(err)
+                if err.kind() == io::ErrorKind::Interrupted
+                    || err.raw_os_error() == Some(libc::ETIME) => {}
+            Err(err) => return Err(err),
+        }
+
+        let mut state = self.ring.state.lock().unwrap();
+        for entry in uring.completion() {
+            state.complete(
+                entry.user_data(),
+                entry.result(),
+                entry.flags(),
+                &self.ring.waker,
+            );
+        }
+        events.append(&mut state.ready);
+        Ok(())
+    }
+
+    fn ring(&self) -> Option<Arc<Ring>> {
+        Some(self.ring.clone())
+    }
+}
+
+/// A connection doing its I/O through the ring of its reactor. Data is received ahead into a
+/// buffer reads are served from, writes are copied and sent while the handler goes on. A write
+/// would block while the previous one is still being sent.
+pub struct UringStream {
+    ring: Arc<Ring>,
+    id: u64,
+    fd: RawFd,
+    name: String,
+}
+
+impl UringStream {
+    fn new(ring: Arc<Ring>, fd: OwnedFd, name: String) -> UringStream {
+        let raw = fd.as_raw_fd();
+        let id = ring.attach(raw, Some(fd), false);
+        UringStream {
+            ring,
+            id,
+            fd: raw,
+            name,
+        }
+    }
+}
+
+impl Read for UringStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.ring.read(self.id, buf)
+    }
+}
+
+impl Write for UringStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.ring.write(self.id, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl AsRawFd for UringStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for UringStream {
+    fn drop(&mut self) {
+        self.ring.detach(self.id);
+    }
+}
+
+impl ClientStream for UringStream {
+    fn peer_name(&self) -> String {
+        self.name.to_string()
+    }
+}
+
+/// A listener accepting through the ring, the connections it hands out are [`UringStream`]s.
+pub struct UringListener<L> {
+    listener: L,
+    ring: Arc<Ring>,
+    id: u64,
+}
+
+impl<L: ServerListener> UringListener<L> {
+    pub fn new(listener: L, ring: Arc<Ring>) -> UringListener<L> {
+        let id = ring.attach(listener.as_raw_fd(), None, true);
+        UringListener { listener, ring, id }
+    }
+}
+
+impl<L> AsRawFd for UringListener<L>
+where
+    L: AsRawFd,
+{
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl<L> Drop for UringListener<L> {
+    fn drop(&mut self) {
+        self.ring.detach(self.id);
+    }
+}
+
+impl<L> ServerListener for UringListener<L>
+where
+    L: ServerListener,
+    L::Stream: FromRawFd + IntoRawFd,
+{
+    type Stream = UringStream;
+
+    fn accept_client(&self) -> Result<UringStream> {
+        let fd = self.ring.accept(self.id)?;
+        // the stream type of the listener names the peer, the fd is only borrowed for it
+        let stream = unsafe { L::Stream::from_raw_fd(fd.into_raw_fd()) };
+        let name = stream.peer_name();
+        let fd = unsafe { OwnedFd::from_raw_fd(stream.into_raw_fd()) };
+        Ok(UringStream::new(self.ring.clone(), fd, name))
+    }
+
+    fn local_name(&self) -> String {
+        self.listener.local_name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        os::unix::net::UnixStream,
+    };
+
+    use super::*;
+    use crate::{
+        config::listener::{ListenAddr, ListenerConfig},
+        harness::core::TestServer,
+        store::core::Store,
+    };
+
+    fn wait_for(poller: &UringPoller, timeout: Duration) -> Vec<Event> {
+        let mut events = vec![];
+        poller.wait(&mut events, Some(timeout)).unwrap();
+        events
+    }
+
+    #[test]
+    fn reports_a_reregistered_source_once() {
+        let poller = UringPoller::new().unwrap();
+        let (source, mut peer) = UnixStream::pair().unwrap();
+        poller
+            .register(source.as_raw_fd(), 7, Interest::READABLE)
+            .unwrap();
+        assert!(wait_for(&poller, Duration::ZERO).is_empty());
+        // each reregistration replaces the poll in flight, only the last one may report
+        for _ in 0..3 {
+            poller
+                .reregister(source.as_raw_fd(), 7, Interest::READABLE)
+                .unwrap();
+        }
+        peer.write_all(b"ping").unwrap();
+
+        let events = wait_for(&poller, Duration::from_secs(1));
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert_eq!(events[0].token, 7);
+        assert!(events[0].readable);
+        assert!(wait_for(&poller, Duration::from_millis(50)).is_empty());
+
+        poller.deregister(source.as_raw_fd(), 7).unwrap();
+        peer.write_all(b"ping").unwrap();
+        assert!(wait_for(&poller, Duration::from_millis(50)).is_empty());
+    }
+
+    #[test]
+    fn serves_connections_through_the_ring() {
+        let config = ListenerConfig::new(ListenAddr::Tcp("127.0.0.1:0".to_string()), 0);
+        let poller = Arc::new(UringPoller::new().unwrap());
+        let server = TestServer::start_on(poller, config, Store::default());
+        let mut conn = server.connect();
+
+        let mut reply = [0; 7];
+        conn.write_all(b"PING\r\n").unwrap();
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+
+        // a value spanning many receives and sends
+        let value = "v".repeat(300 * 1024);
+        conn.write_all(format!("SET k {}\r\n", value).as_bytes())
+            .unwrap();
+        let mut reply = [0; 5];
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+OK\r\n");
+        conn.write_all(b"GET k\r\n").unwrap();
+        let expected = format!("${}\r\n{}\r\n", value.len(), value);
+        let mut reply = vec![0; expected.len()];
+        conn.read_exact(&mut reply).unwrap();
+        assert!(reply == expected.as_bytes());
+
+        conn.write_all(&b"PING\r\n".repeat(100)).unwrap();
+        let mut reply = vec![0; 700];
+        conn.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, b"+PONG\r\n".repeat(100));
+
+        // the reply to QUIT is sent before the connection is closed
+        conn.write_all(b"QUIT\r\n").unwrap();
+        let mut reply = vec![];
+        conn.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"+OK\r\n");
+        assert!(server.wait_for_clients(0));
+    }
+}
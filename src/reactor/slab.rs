@@ -0,0 +1,135 @@
+/// Bits of a token holding the slot, the generation takes the others.
+const SLOT_BITS: u32 = usize::BITS / 2;
+const SLOT_MASK: usize = (1 << SLOT_BITS) - 1;
+
+/// Allocates the tokens identifying the sources registered on a reactor.
+///
+/// A token is a slot index along with the generation of the slot, which is bumped every time
+/// the slot is released. A token is thus never handed out twice (until a slot is reused
+/// 2^32 times), so events or scheduled tasks still in flight for a closed connection can't
+/// reach the connection reusing its slot, the way they could when tokens were raw fds.
+/// Token 0 is never allocated, it belongs to the waker.
+#[derive(Debug, Default)]
+pub struct TokenSlab {
+    slots: Vec<Slot>,
+    /// released slots, reused before the slab grows
+    free: Vec<usize>,
+    /// slots in use
+    used: usize,
+}
+
+#[derive(Debug)]
+struct Slot {
+    generation: usize,
+    in_use: bool,
+}
+
+impl TokenSlab {
+    /// # Panics
+    ///
+    /// Panics if every slot a token can address is in use.
+    pub fn allocate(&mut self) -> usize {
+        let slot = self.free.pop().unwrap_or_else(|| {
+            self.slots.push(Slot {
+                generation: 0,
+                in_use: false,
+            });
+            self.slots.len() - 1
+        });
+        self.slots[slot].in_use = true;
+        self.used += 1;
+        token(slot, self.slots[slot].generation)
+    }
+
+    /// Frees the slot of `token`, returns false if the token was already released.
+    pub fn release(&mut self, token: usize) -> bool {
+        if !self.contains(token) {
+            return false;
+        }
+        let (slot, _) = split(token);
+        let entry = &mut self.slots[slot];
+        entry.generation = (entry.generation + 1) & (usize::MAX >> SLOT_BITS);
+        entry.in_use = false;
+        self.free.push(slot);
+        self.used -= 1;
+        true
+    }
+
+    /// Returns true if `token` is allocated and was not released since.
+    pub fn contains(&self, token: usize) -> bool {
+        let (slot, generation) = split(token);
+        self.slots
+            .get(slot)
+            .is_some_and(|entry| entry.in_use && entry.generation == generation)
+    }
+
+    pub fn len(&self) -> usize {
+        self.used
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.used == 0
+    }
+}
+
+fn token(slot: usize, generation: usize) -> usize {
+    // slot 0 is offset by one so no token is 0, the last slot would spill into the generation
+    assert!(
+        slot < SLOT_MASK,
+        "out of tokens, {} slots are in use",
+        SLOT_MASK
+    );
+    (generation << SLOT_BITS) | (slot + 1)
+}
+
+fn split(token: usize) -> (usize, usize) {
+    ((token & SLOT_MASK).wrapping_sub(1), token >> SLOT_BITS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_distinct_tokens() {
+        let mut slab = TokenSlab::default();
+        let tokens: Vec<_> = (0..4).map(|_| slab.allocate()).collect();
+        assert!(!tokens.contains(&0));
+        assert_eq!(tokens, [1, 2, 3, 4]);
+        assert_eq!(slab.len(), 4);
+        assert!(tokens.iter().all(|&token| slab.contains(token)));
+        assert!(!slab.contains(0) && !slab.contains(5));
+    }
+
+    #[test]
+    fn stale_tokens_miss_the_reused_slot() {
+        let mut slab = TokenSlab::default();
+        let first = slab.allocate();
+        assert!(slab.release(first));
+        assert!(!slab.release(first));
+        assert!(slab.is_empty());
+
+        let second = slab.allocate();
+        assert_eq!(split(second).0, split(first).0);
+        assert_ne!(second, first);
+        assert!(slab.contains(second) && !slab.contains(first));
+        assert!(!slab.release(first));
+        assert_eq!(slab.len(), 1);
+    }
+
+    #[test]
+    fn generations_wrap() {
+        let mut slab = TokenSlab::default();
+        let first = slab.allocate();
+        slab.slots[0].generation = usize::MAX >> SLOT_BITS;
+        let last = token(0, usize::MAX >> SLOT_BITS);
+        assert!(slab.release(last));
+        assert_eq!(slab.allocate(), first);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of tokens")]
+    fn refuses_a_slot_past_the_mask() {
+        token(SLOT_MASK, 0);
+    }
+}
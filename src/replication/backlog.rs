@@ -0,0 +1,59 @@
+use std::collections::VecDeque;
+
+/// Fixed size window over the most recent bytes of the replication stream.
+///
+/// A replica that reconnects with an offset still covered by the window resumes from there
+/// instead of going through a full sync.
+#[derive(Debug)]
+pub struct Backlog {
+    buf: VecDeque<u8>,
+    capacity: usize,
+    /// replication offset right after the last byte in the window
+    end_offset: u64,
+}
+
+impl Backlog {
+    /// Creates an empty backlog whose stream continues from `offset`.
+    pub fn new(capacity: usize, offset: u64) -> Backlog {
+        Backlog {
+            buf: VecDeque::new(),
+            capacity,
+            end_offset: offset,
+        }
+    }
+
+    /// Replication offset of the first byte still held by the backlog.
+    pub fn start_offset(&self) -> u64 {
+        self.end_offset - self.buf.len() as u64
+    }
+
+    pub fn end_offset(&self) -> u64 {
+        self.end_offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Appends bytes of the stream, dropping the oldest ones once the capacity is exceeded.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.end_offset += bytes.len() as u64;
+        self.buf.extend(bytes);
+        let overflow = self.buf.len().saturating_sub(self.capacity);
+        self.buf.drain(..overflow);
+    }
+
+    /// Returns the stream from `offset` up to the end, or None if part of it was already
+    /// dropped.
+    pub fn since(&self, offset: u64) -> Option<Vec<u8>> {
+        if offset < self.start_offset() || offset > self.end_offset {
+            return None;
+        }
+        let skip = (offset - self.start_offset()) as usize;
+        Some(self.buf.range(skip..).copied().collect())
+    }
+}
@@ -0,0 +1,298 @@
+use std::{sync::Arc, time::Instant};
+
+use rand::Rng;
+
+use crate::{config::core::Config, reactor::completion::Notifier};
+
+use super::{backlog::Backlog, link::ReplicaLink};
+
+/// State of the link with the primary, named like the `master_link_status` values of ROLE.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LinkStatus {
+    /// waiting to (re)connect
+    Connect,
+    /// connected, waiting for the reply to PSYNC
+    Connecting,
+    /// receiving the snapshot of a full sync
+    Sync,
+    /// following the stream
+    Connected,
+}
+
+impl LinkStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LinkStatus::Connect => "connect",
+            LinkStatus::Connecting => "connecting",
+            LinkStatus::Sync => "sync",
+            LinkStatus::Connected => "connected",
+        }
+    }
+}
+
+/// The primary this server follows.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub host: String,
+    pub port: u16,
+    /// identifies the current attempt to follow the primary, connections and reconnect timers
+    /// belonging to an older one stop on their own
+    pub link_id: u64,
+    /// connection to the primary once it is established, along with the reactor owning it
+    pub conn: Option<Notifier>,
+    pub status: LinkStatus,
+    /// last time data was received from the primary
+    pub last_io: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Role {
+    Primary,
+    Replica(Upstream),
+}
+
+/// Replication state of the server.
+///
+/// The stream is identified by a replication id and the offset of its next byte. A primary
+/// feeds every write it applies to the stream, a replica adopts the id and offset of its primary
+/// and forwards the stream it receives, so its own replicas can resume against it.
+#[derive(Debug)]
+pub struct Replication {
+    replid: String,
+    /// id of the stream this server followed before being promoted, replicas of the former
+    /// primary can resume against it up to `second_offset`
+    replid2: Option<String>,
+    second_offset: u64,
+    backlog: Backlog,
+    backlog_size: usize,
+    /// stream bytes a replica may leave unread, see [`ReplicaLink::feed`]
+    output_limit: usize,
+    replicas: Vec<Arc<ReplicaLink>>,
+    role: Role,
+    next_link_id: u64,
+    /// port clients connect to, announced to the primary
+    listening_port: u16,
+    /// replicas reject writes from clients
+    read_only: bool,
+    /// password sent to the primary with AUTH before PSYNC
+    masterauth: Option<String>,
+    masteruser: Option<String>,
+}
+
+/// Generates a new 40 characters hexadecimal replication id.
+fn new_replid() -> String {
+    let mut rng = rand::thread_rng();
+    (0..40)
+        .map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap())
+        .collect()
+}
+
+impl Replication {
+    pub fn new(config: &Config) -> Replication {
+        Replication {
+            replid: new_replid(),
+            replid2: None,
+            second_offset: 0,
+            backlog: Backlog::new(config.repl_backlog_size as usize, 0),
+            backlog_size: config.repl_backlog_size as usize,
+            output_limit: config.repl_output_buffer_limit as usize,
+            replicas: vec![],
+            role: Role::Primary,
+            next_link_id: 0,
+            listening_port: config
+                .bind
+                .rsplit(':')
+                .next()
+                .and_then(|port| port.parse().ok())
+                .unwrap_or_default(),
+            read_only: config.replica_read_only,
+            masterauth: config.masterauth.clone(),
+            masteruser: config.masteruser.clone(),
+        }
+    }
+
+    pub fn replid(&self) -> &str {
+        &self.replid
+    }
+
+    pub fn replid2(&self) -> Option<&str> {
+        self.replid2.as_deref()
+    }
+
+    pub fn second_offset(&self) -> u64 {
+        self.second_offset
+    }
+
+    pub fn listening_port(&self) -> u16 {
+        self.listening_port
+    }
+
+    /// Returns true if write commands from clients have to be rejected.
+    pub fn rejects_writes(&self) -> bool {
+        self.read_only && !self.is_primary()
+    }
+
+    /// Offset of the next byte of the replication stream.
+    pub fn offset(&self) -> u64 {
+        self.backlog.end_offset()
+    }
+
+    pub fn backlog(&self) -> &Backlog {
+        &self.backlog
+    }
+
+    pub fn backlog_size(&self) -> usize {
+        self.backlog_size
+    }
+
+    /// Bytes of the stream a replica may leave unread before it is disconnected.
+    pub fn output_limit(&self) -> usize {
+        self.output_limit
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// The AUTH command to send to the primary, if it requires one.
+    pub fn masterauth(&self) -> Option<String> {
+        let password = self.masterauth.as_deref()?;
+        Some(match self.masteruser.as_deref() {
+            Some(user) => format!("AUTH {} {}\r\n", user, password),
+            None => format!("AUTH {}\r\n", password),
+        })
+    }
+
+    pub fn role(&self) -> &Role {
+        &self.role
+    }
+
+    pub fn is_primary(&self) -> bool {
+        matches!(self.role, Role::Primary)
+    }
+
+    pub fn replicas(&self) -> &[Arc<ReplicaLink>] {
+        &self.replicas
+    }
+
+    /// Appends bytes to the replication stream and forwards them to every replica.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.backlog.push(bytes);
+        for replica in self.replicas.iter() {
+            replica.feed(bytes);
+        }
+    }
+
+    pub fn add_replica(&mut self, link: Arc<ReplicaLink>) {
+        self.replicas.push(link);
+    }
+
+    pub fn remove_replica(&mut self, client: usize) {
+        self.replicas.retain(|replica| replica.client() != client);
+    }
+
+    /// Returns the stream from `offset` if a replica that stopped there can resume.
+    pub fn continuation(&self, replid: &str, offset: u64) -> Option<Vec<u8>> {
+        let same_stream = replid == self.replid
+            || (self.replid2.as_deref() == Some(replid) && offset <= self.second_offset);
+        if !same_stream {
+            return None;
+        }
+        self.backlog.since(offset)
+    }
+
+    /// Starts following a new primary, returns the new link id along with the connection to the
+    /// previous primary, which has to be closed.
+    pub fn follow(&mut self, host: &str, port: u16) -> (u64, Option<Notifier>) {
+        let previous = self.upstream().and_then(|upstream| upstream.conn.clone());
+        self.next_link_id += 1;
+        self.role = Role::Replica(Upstream {
+            host: host.to_string(),
+            port,
+            link_id: self.next_link_id,
+            conn: None,
+            status: LinkStatus::Connect,
+            last_io: None,
+        });
+        (self.next_link_id, previous)
+    }
+
+    /// Stops following the primary (REPLICAOF NO ONE), returns the connection to it, which has
+    /// to be closed.
+    ///
+    /// The stream continues under a new id, the former one is kept so the other replicas of
+    /// the former primary can resume against this server after a failover.
+    pub fn promote(&mut self) -> Option<Notifier> {
+        let previous = self.upstream().and_then(|upstream| upstream.conn.clone());
+        if self.is_primary() {
+            return previous;
+        }
+        self.role = Role::Primary;
+        self.replid2 = Some(std::mem::replace(&mut self.replid, new_replid()));
+        self.second_offset = self.offset();
+        previous
+    }
+
+    pub fn upstream(&self) -> Option<&Upstream> {
+        match &self.role {
+            Role::Replica(upstream) => Some(upstream),
+            Role::Primary => None,
+        }
+    }
+
+    /// Returns the upstream if `link_id` is still the current attempt to follow it.
+    pub fn current_upstream(&self, link_id: u64) -> Option<&Upstream> {
+        self.upstream()
+            .filter(|upstream| upstream.link_id == link_id)
+    }
+
+    /// Records the connection to the primary, returns false if `link_id` is no longer current.
+    pub fn set_upstream_conn(&mut self, link_id: u64, conn: Option<Notifier>) -> bool {
+        match &mut self.role {
+            Role::Replica(upstream) if upstream.link_id == link_id => {
+                upstream.status = match conn {
+                    Some(_) => LinkStatus::Connecting,
+                    None => LinkStatus::Connect,
+                };
+                upstream.conn = conn;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn set_upstream_status(&mut self, link_id: u64, status: LinkStatus) {
+        if let Role::Replica(upstream) = &mut self.role {
+            if upstream.link_id == link_id {
+                upstream.status = status;
+                upstream.last_io = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Records that data was received from the primary.
+    pub fn touch_upstream(&mut self, link_id: u64) {
+        if let Role::Replica(upstream) = &mut self.role {
+            if upstream.link_id == link_id {
+                upstream.last_io = Some(Instant::now());
+            }
+        }
+    }
+
+    /// Adopts the stream of the primary after a full sync. Replicas of this server followed
+    /// the discarded stream, they are disconnected and sync again.
+    pub fn reset_stream(&mut self, replid: &str, offset: u64) {
+        self.replid = replid.to_string();
+        self.replid2 = None;
+        self.second_offset = 0;
+        self.backlog = Backlog::new(self.backlog_size, offset);
+        for replica in self.replicas.drain(..) {
+            replica.disconnect();
+        }
+    }
+
+    /// Adopts a new replication id for the stream the primary continued with.
+    pub fn set_replid(&mut self, replid: &str) {
+        self.replid = replid.to_string();
+    }
+}
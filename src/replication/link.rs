@@ -0,0 +1,276 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicU16, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{async_client::core::MAX_QUERY_BUFFER, reactor::completion::CompletionQueue};
+
+#[derive(Debug, Default)]
+struct LinkOutput {
+    /// snapshot of a full sync, sent before the stream
+    snapshot: Vec<u8>,
+    /// stream the connection did not take yet
+    buf: Vec<u8>,
+    /// set while the snapshot for a full sync is being prepared, the stream fed in the meantime
+    /// is held back until the snapshot is in front of it
+    syncing: bool,
+}
+
+/// Primary side of a replica connection: the replication stream waiting to be written to it.
+///
+/// The stream is fed while holding the store lock and written out by the client handler owning
+/// the connection, which gets handed to its reactor whenever new bytes are queued.
+pub struct ReplicaLink {
+    /// token of the connection in its reactor
+    fd: usize,
+    /// id of the client the connection was, unique across the shards
+    client: usize,
+    addr: String,
+    /// stream bytes waiting for the replica before it is disconnected, 0 for no limit
+    output_limit: usize,
+    /// queue of the reactor owning the connection
+    completions: Arc<CompletionQueue>,
+    output: Mutex<LinkOutput>,
+    disconnected: AtomicBool,
+    /// partial line sent by the replica
+    input: Mutex<Vec<u8>>,
+    /// offset acknowledged by the replica with `REPLCONF ACK`
+    ack_offset: AtomicU64,
+    last_ack: Mutex<Instant>,
+    /// port announced with `REPLCONF listening-port`, 0 until then
+    listening_port: AtomicU16,
+}
+
+impl std::fmt::Debug for ReplicaLink {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ReplicaLink")
+            .field("fd", &self.fd)
+            .field("client", &self.client)
+            .field("addr", &self.addr)
+            .finish()
+    }
+}
+
+impl ReplicaLink {
+    pub fn new(
+        fd: usize,
+        client: usize,
+        addr: String,
+        output_limit: usize,
+        completions: Arc<CompletionQueue>,
+    ) -> ReplicaLink {
+        ReplicaLink {
+            fd,
+            client,
+            addr,
+            output_limit,
+            completions,
+            output: Mutex::new(LinkOutput::default()),
+            disconnected: AtomicBool::new(false),
+            input: Mutex::new(vec![]),
+            ack_offset: AtomicU64::new(0),
+            last_ack: Mutex::new(Instant::now()),
+            listening_port: AtomicU16::new(0),
+        }
+    }
+
+    pub fn fd(&self) -> usize {
+        self.fd
+    }
+
+    pub fn client(&self) -> usize {
+        self.client
+    }
+
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    /// Ip address of the replica.
+    pub fn ip(&self) -> &str {
+        self.addr
+            .rsplit_once(':')
+            .map_or(self.addr.as_str(), |(ip, _)| ip)
+    }
+
+    pub fn listening_port(&self) -> u16 {
+        self.listening_port.load(Ordering::Acquire)
+    }
+
+    pub fn ack_offset(&self) -> u64 {
+        self.ack_offset.load(Ordering::Acquire)
+    }
+
+    /// Time since the replica last acknowledged the stream.
+    pub fn lag(&self) -> Duration {
+        self.last_ack.lock().unwrap().elapsed()
+    }
+
+    /// Handles bytes sent by the replica: `REPLCONF ACK <offset>` and
+    /// `REPLCONF listening-port <port>` lines, anything else is ignored. A replica sending a
+    /// line longer than a client query may be is disconnected.
+    pub fn receive(&self, bytes: &[u8]) {
+        let mut input = self.input.lock().unwrap();
+        input.extend_from_slice(bytes);
+        while let Some(end) = input.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = input.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let args: Vec<&str> = line.split_whitespace().collect();
+            match args.as_slice() {
+                [replconf, option, value] if replconf.eq_ignore_ascii_case("REPLCONF") => {
+                    if option.eq_ignore_ascii_case("ACK") {
+                        if let Ok(offset) = value.parse() {
+                            self.ack_offset.store(offset, Ordering::Release);
+                            *self.last_ack.lock().unwrap() = Instant::now();
+                        }
+                    } else if option.eq_ignore_ascii_case("listening-port") {
+                        if let Ok(port) = value.parse() {
+                            self.listening_port.store(port, Ordering::Release);
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        // what is left is an unfinished line
+        if input.len() > MAX_QUERY_BUFFER {
+            println!(
+                "replica {} sent a line past the query buffer limit",
+                self.addr
+            );
+            input.clear();
+            self.disconnect();
+        }
+    }
+
+    /// Queues bytes of the replication stream. A replica leaving more than the output limit
+    /// unread is disconnected rather than having the whole stream buffered for it.
+    pub fn feed(&self, bytes: &[u8]) {
+        if self.is_disconnected() {
+            return;
+        }
+        let (notify, over_limit) = {
+            let mut output = self.output.lock().unwrap();
+            if self.output_limit > 0 && output.buf.len() + bytes.len() > self.output_limit {
+                output.buf = vec![];
+                (false, true)
+            } else {
+                output.buf.extend_from_slice(bytes);
+                (!output.syncing, false)
+            }
+        };
+        if over_limit {
+            println!(
+                "replica {} passed the output buffer limit, disconnecting",
+                self.addr
+            );
+            self.disconnect();
+        } else if notify {
+            self.notify();
+        }
+    }
+
+    /// Holds back the stream until `finish_sync` puts the snapshot in front of it.
+    pub fn begin_sync(&self) {
+        self.output.lock().unwrap().syncing = true;
+    }
+
+    pub fn finish_sync(&self, snapshot: Vec<u8>) {
+        {
+            let mut output = self.output.lock().unwrap();
+            output.snapshot = snapshot;
+            output.syncing = false;
+        }
+        self.notify();
+    }
+
+    /// Returns true while the snapshot of a full sync is being prepared.
+    pub fn is_syncing(&self) -> bool {
+        self.output.lock().unwrap().syncing
+    }
+
+    /// Takes everything that can be written to the replica right now.
+    pub fn take_pending(&self) -> Vec<u8> {
+        let mut output = self.output.lock().unwrap();
+        if output.syncing {
+            return vec![];
+        }
+        let mut pending = std::mem::take(&mut output.snapshot);
+        pending.append(&mut output.buf);
+        pending
+    }
+
+    /// Asks the owning connection to close, used when the stream it follows is discarded.
+    pub fn disconnect(&self) {
+        self.disconnected.store(true, Ordering::Release);
+        self.notify();
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Acquire)
+    }
+
+    fn notify(&self) {
+        self.completions.complete(self.fd).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reactor::poller::mio_poller::MioPoller;
+
+    fn link(output_limit: usize) -> ReplicaLink {
+        let completions = Arc::new(CompletionQueue::new(Arc::new(MioPoller::new().unwrap())));
+        ReplicaLink::new(
+            1,
+            1,
+            "127.0.0.1:6380".to_string(),
+            output_limit,
+            completions,
+        )
+    }
+
+    #[test]
+    fn disconnects_a_replica_past_the_output_limit() {
+        let link = link(10);
+        link.feed(b"123456");
+        assert_eq!(link.take_pending(), b"123456");
+        link.feed(b"123456");
+        assert!(!link.is_disconnected());
+        link.feed(b"123456");
+        assert!(link.is_disconnected());
+        // the buffered stream is dropped, nothing more is queued
+        link.feed(b"1");
+        assert!(link.take_pending().is_empty());
+    }
+
+    #[test]
+    fn leaves_the_snapshot_out_of_the_limit() {
+        let link = link(10);
+        link.begin_sync();
+        link.feed(b"*1\r\n");
+        assert!(link.take_pending().is_empty());
+        link.finish_sync(vec![b'x'; 100]);
+        link.feed(b"$4\r\n");
+        assert!(!link.is_disconnected());
+        let pending = link.take_pending();
+        assert_eq!(pending.len(), 108);
+        assert!(pending.starts_with(&[b'x'; 100]) && pending.ends_with(b"*1\r\n$4\r\n"));
+    }
+
+    #[test]
+    fn disconnects_a_replica_sending_an_endless_line() {
+        let link = link(0);
+        link.receive(b"REPLCONF ACK 42\r\nREPLCONF listening-port 6380\r\n");
+        assert_eq!(link.ack_offset(), 42);
+        assert_eq!(link.listening_port(), 6380);
+        link.receive(&vec![b'a'; MAX_QUERY_BUFFER]);
+        assert!(!link.is_disconnected());
+        link.receive(b"a");
+        assert!(link.is_disconnected());
+    }
+}
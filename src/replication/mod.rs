@@ -0,0 +1,6 @@
+pub mod backlog;
+pub mod core;
+pub mod link;
+pub mod primary;
+pub mod upstream;
+pub mod upstream_states;
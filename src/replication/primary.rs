@@ -0,0 +1,77 @@
+use std::{
+    sync::{Arc, RwLock},
+    thread,
+};
+
+use crate::{
+    command::core::{CommandSpec, ADMIN},
+    persistence::snapshot,
+    protocol::reply::Reply,
+    store::core::Store,
+};
+
+use super::link::ReplicaLink;
+
+pub const SPECS: &[CommandSpec] = &[CommandSpec::new("PSYNC", 3, ADMIN).no_keys()];
+
+/// Handles `PSYNC <replid> <offset>` sent by a replica, `PSYNC ? -1` asks for a full sync.
+///
+/// If the replica follows our stream and the backlog still covers its offset, it resumes with
+/// `+CONTINUE <replid>` followed by the missing part of the stream. Otherwise it gets
+/// `+FULLRESYNC <replid> <offset>` followed by a snapshot as `$<len>\r\n<bytes>`, then the
+/// stream from that offset.
+///
+/// The keyspace is copied while holding the store lock and the snapshot is encoded on a
+/// background thread, the stream fed in the meantime is held back by the link.
+///
+/// # Errors
+///
+/// This function will return an error reply if the arguments are invalid.
+pub fn psync(db: Arc<RwLock<Store>>, link: Arc<ReplicaLink>, args: &[String]) -> Result<(), Reply> {
+    if args.len() != 3 {
+        return Err(Reply::wrong_arity("psync"));
+    }
+    let offset = args[2]
+        .parse::<i64>()
+        .map_err(|_| Reply::not_an_integer())?;
+
+    let (entries, replid, offset) = {
+        let mut store = db.write().unwrap();
+        let replication = store
+            .replication_mut()
+            .ok_or_else(|| Reply::error("ERR replication is not enabled"))?;
+
+        let continuation = u64::try_from(offset)
+            .ok()
+            .and_then(|offset| replication.continuation(&args[1], offset));
+        if let Some(stream) = continuation {
+            println!("replica {} resumes at offset {}", link.addr(), args[2]);
+            let mut out = format!("+CONTINUE {}\r\n", replication.replid()).into_bytes();
+            out.extend(stream);
+            link.feed(&out);
+            replication.add_replica(link);
+            return Ok(());
+        }
+
+        link.begin_sync();
+        replication.add_replica(link.clone());
+        let replid = replication.replid().to_string();
+        let offset = replication.offset();
+        (store.snapshot(), replid, offset)
+    };
+
+    println!("full sync of replica {} at offset {}", link.addr(), offset);
+    thread::spawn(move || {
+        let payload = snapshot::encode(&entries);
+        let mut out = format!(
+            "+FULLRESYNC {} {}\r\n${}\r\n",
+            replid,
+            offset,
+            payload.len()
+        )
+        .into_bytes();
+        out.extend(payload);
+        link.finish_sync(out);
+    });
+    Ok(())
+}
@@ -0,0 +1,591 @@
+use std::{
+    io::{self, Read, Result, Write},
+    net::ToSocketAddrs,
+    sync::{Arc, RwLock},
+    thread,
+    time::Duration,
+};
+
+use mio::net::TcpStream;
+
+use crate::{
+    command::core::execute_keyspace,
+    persistence::{rewrite::start_rewrite, snapshot},
+    protocol::parser::parse_multibulk,
+    reactor::{completion::Notifier, core::Reactor, event::Event, event_listener::EventListener},
+    store::core::Store,
+};
+
+use super::{core::LinkStatus, upstream_states::UpstreamStates};
+
+/// Delay between two attempts to reach the primary.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Interval at which the replica acknowledges its offset to the primary.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replica side of the replication link: the connection to the primary.
+///
+/// It runs on the same reactor as the client connections. After sending PSYNC it either loads
+/// the snapshot of a full sync or resumes the stream, then applies every write command the
+/// primary sends.
+pub struct UpstreamHandler {
+    stream: TcpStream,
+    fd: usize,
+    name: String,
+    link_id: u64,
+    reactor: Arc<RwLock<Reactor>>,
+    db: Arc<RwLock<Store>>,
+    state: Option<UpstreamStates>,
+    input: Vec<u8>,
+    output: Vec<u8>,
+    /// PSYNC held back until the primary accepted AUTH, the primary reads one command at a
+    /// time so they can't be pipelined
+    psync: Option<String>,
+    /// commands of a MULTI block received from the primary, applied once its EXEC arrives
+    transaction: Option<Vec<Vec<String>>>,
+    /// raw bytes of the pending MULTI block, they only count towards the offset once complete
+    transaction_raw: Vec<u8>,
+    /// the heartbeat thread stops once this is dropped along with the handler
+    alive: Arc<()>,
+}
+
+impl UpstreamHandler {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        stream: TcpStream,
+        token: usize,
+        name: String,
+        link_id: u64,
+        auth: Option<String>,
+        psync: String,
+        reactor: Arc<RwLock<Reactor>>,
+        db: Arc<RwLock<Store>>,
+    ) -> UpstreamHandler {
+        let (output, psync) = match auth {
+            Some(auth) => (auth, Some(psync)),
+            None => (psync, None),
+        };
+        UpstreamHandler {
+            stream,
+            fd: token,
+            name,
+            link_id,
+            reactor,
+            db,
+            state: None,
+            input: vec![],
+            psync,
+            output: output.into_bytes(),
+            transaction: None,
+            transaction_raw: vec![],
+            alive: Arc::new(()),
+        }
+    }
+
+    fn schedule(&mut self) {
+        let mut reactor = self.reactor.write().unwrap();
+        reactor.schedule(self.fd);
+    }
+
+    /// Returns false once REPLICAOF pointed the server somewhere else.
+    fn is_current(&self) -> bool {
+        let store = self.db.read().unwrap();
+        store
+            .replication()
+            .is_some_and(|replication| replication.current_upstream(self.link_id).is_some())
+    }
+
+    fn initalize(&mut self) -> Result<()> {
+        println!("connecting to primary {}", self.name);
+        {
+            let mut reactor = self.reactor.write().unwrap();
+            reactor.register(self.fd, &self.stream, None)?;
+        }
+        self.state.replace(if self.psync.is_some() {
+            UpstreamStates::Authenticating
+        } else {
+            UpstreamStates::Handshake
+        });
+        self.spawn_heartbeat();
+        self.flush_output()
+    }
+
+    /// Schedules the handler once per second so it acknowledges its offset even when the
+    /// stream is idle, the thread exits once the handler is dropped.
+    fn spawn_heartbeat(&self) {
+        let alive = Arc::downgrade(&self.alive);
+        let conn = Notifier::new(self.fd, self.reactor.read().unwrap().completions());
+        thread::spawn(move || loop {
+            thread::sleep(HEARTBEAT_INTERVAL);
+            if alive.upgrade().is_none() {
+                break;
+            }
+            let _ = conn.notify();
+        });
+    }
+
+    /// Queues `REPLCONF ACK <offset>` to tell the primary how far the stream was applied.
+    fn queue_ack(&mut self) {
+        let offset = {
+            let store = self.db.read().unwrap();
+            store
+                .replication()
+                .map_or(0, |replication| replication.offset())
+        };
+        self.output
+            .extend_from_slice(format!("REPLCONF ACK {}\r\n", offset).as_bytes());
+    }
+
+    fn set_status(&mut self, status: LinkStatus) {
+        let mut store = self.db.write().unwrap();
+        if let Some(replication) = store.replication_mut() {
+            replication.set_upstream_status(self.link_id, status);
+        }
+    }
+
+    fn flush_output(&mut self) -> Result<()> {
+        while !self.output.is_empty() {
+            match self.stream.write(&self.output) {
+                Ok(n) => {
+                    self.output.drain(..n);
+                }
+                // the connection is still being established, retried on the writable event
+                Err(err)
+                    if err.kind() == io::ErrorKind::WouldBlock
+                        || err.kind() == io::ErrorKind::NotConnected =>
+                {
+                    break
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads everything available on the connection.
+    fn read_input(&mut self) -> Result<()> {
+        let mut buf = [0; 16 * 1024];
+        loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "primary closed the connection",
+                    ))
+                }
+                Ok(n) => self.input.extend_from_slice(&buf[..n]),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn process_input(&mut self) -> Result<()> {
+        loop {
+            match self.state.take() {
+                Some(UpstreamStates::Authenticating) => {
+                    let line = match take_line(&mut self.input) {
+                        Some(line) => line,
+                        None => {
+                            self.state.replace(UpstreamStates::Authenticating);
+                            return Ok(());
+                        }
+                    };
+                    if line != "+OK" {
+                        return Err(invalid(&format!(
+                            "primary {} refused AUTH '{}'",
+                            self.name, line
+                        )));
+                    }
+                    if let Some(psync) = self.psync.take() {
+                        self.output.extend_from_slice(psync.as_bytes());
+                    }
+                    self.state.replace(UpstreamStates::Handshake);
+                }
+                Some(UpstreamStates::Handshake) => {
+                    let line = match take_line(&mut self.input) {
+                        Some(line) => line,
+                        None => {
+                            self.state.replace(UpstreamStates::Handshake);
+                            return Ok(());
+                        }
+                    };
+                    let state = self.handshake_reply(&line)?;
+                    self.state.replace(state);
+                }
+                Some(UpstreamStates::ReceivingSnapshot { replid, offset }) => {
+                    if !self.load_snapshot(&replid, offset)? {
+                        self.state
+                            .replace(UpstreamStates::ReceivingSnapshot { replid, offset });
+                        return Ok(());
+                    }
+                    self.state.replace(UpstreamStates::Streaming);
+                }
+                Some(UpstreamStates::Streaming) => {
+                    self.state.replace(UpstreamStates::Streaming);
+                    return self.apply_stream();
+                }
+                state => {
+                    self.state = state;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn handshake_reply(&mut self, line: &str) -> Result<UpstreamStates> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words
+            .first()
+            .is_some_and(|word| *word == "+FULLRESYNC" || *word == "+CONTINUE")
+        {
+            let port = {
+                let store = self.db.read().unwrap();
+                store
+                    .replication()
+                    .map_or(0, |replication| replication.listening_port())
+            };
+            self.output
+                .extend_from_slice(format!("REPLCONF listening-port {}\r\n", port).as_bytes());
+        }
+        match words.as_slice() {
+            ["+FULLRESYNC", replid, offset] => {
+                let offset = offset
+                    .parse::<u64>()
+                    .map_err(|_| invalid(&format!("invalid offset in '{}'", line)))?;
+                println!("full sync from primary {} at offset {}", self.name, offset);
+                self.set_status(LinkStatus::Sync);
+                Ok(UpstreamStates::ReceivingSnapshot {
+                    replid: replid.to_string(),
+                    offset,
+                })
+            }
+            ["+CONTINUE", replid @ ..] => {
+                let mut store = self.db.write().unwrap();
+                if let Some(replication) = store.replication_mut() {
+                    if let Some(replid) = replid.first() {
+                        replication.set_replid(replid);
+                    }
+                    replication.set_upstream_status(self.link_id, LinkStatus::Connected);
+                    println!(
+                        "resuming the stream of primary {} at offset {}",
+                        self.name,
+                        replication.offset()
+                    );
+                }
+                Ok(UpstreamStates::Streaming)
+            }
+            _ => Err(invalid(&format!("unexpected reply to PSYNC '{}'", line))),
+        }
+    }
+
+    /// Loads the snapshot of a full sync in place of the current keyspace, returns false while
+    /// it has not been fully received.
+    fn load_snapshot(&mut self, replid: &str, offset: u64) -> Result<bool> {
+        let header_end = match self.input.windows(2).position(|window| window == b"\r\n") {
+            Some(end) => end,
+            None => return Ok(false),
+        };
+        let len = std::str::from_utf8(&self.input[..header_end])
+            .ok()
+            .and_then(|header| header.strip_prefix('$'))
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| invalid("invalid snapshot header"))?;
+        let start = header_end + 2;
+        let end = start
+            .checked_add(len)
+            .ok_or_else(|| invalid("invalid snapshot header"))?;
+        if self.input.len() < end {
+            return Ok(false);
+        }
+        let entries = snapshot::decode(&self.input[start..end])?;
+        self.input.drain(..end);
+
+        let keys = entries.len();
+        let has_aof = {
+            let mut store = self.db.write().unwrap();
+            store.clear();
+            for entry in entries {
+                store.restore(entry);
+            }
+            if let Some(replication) = store.replication_mut() {
+                replication.reset_stream(replid, offset);
+                replication.set_upstream_status(self.link_id, LinkStatus::Connected);
+            }
+            store.aof().is_some()
+        };
+        println!("loaded {} keys from primary {}", keys, self.name);
+        // the AOF holds the keyspace that was just replaced
+        if has_aof {
+            if let Err(err) = start_rewrite(self.db.clone()) {
+                println!("AOF rewrite after full sync not started: {:?}", err);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Applies the write commands received so far.
+    fn apply_stream(&mut self) -> Result<()> {
+        let mut store = self.db.write().unwrap();
+        let mut pos = 0;
+        while let Some((args, used)) = parse_multibulk(&self.input[pos..])? {
+            let raw = &self.input[pos..pos + used];
+            pos += used;
+            let name = args
+                .first()
+                .map(|name| name.to_uppercase())
+                .unwrap_or_default();
+            let complete = match name.as_str() {
+                // an empty record changes nothing, it still counts in the offset
+                "" => match self.transaction {
+                    Some(_) => {
+                        self.transaction_raw.extend_from_slice(raw);
+                        None
+                    }
+                    None => Some(raw.to_vec()),
+                },
+                "MULTI" => {
+                    self.transaction = Some(vec![]);
+                    self.transaction_raw = raw.to_vec();
+                    None
+                }
+                "EXEC" => {
+                    let queued = self.transaction.take().unwrap_or_default();
+                    store.propagate(&["MULTI".to_string()]);
+                    for args in queued.iter() {
+                        apply(&mut store, args);
+                    }
+                    store.propagate(&["EXEC".to_string()]);
+                    self.transaction_raw.extend_from_slice(raw);
+                    Some(std::mem::take(&mut self.transaction_raw))
+                }
+                _ => match self.transaction.as_mut() {
+                    Some(queued) => {
+                        queued.push(args);
+                        self.transaction_raw.extend_from_slice(raw);
+                        None
+                    }
+                    None => {
+                        apply(&mut store, &args);
+                        Some(raw.to_vec())
+                    }
+                },
+            };
+            // forwarded to our own replicas, the offset only moves past complete units
+            if let (Some(raw), Some(replication)) = (complete, store.replication_mut()) {
+                replication.feed(&raw);
+            }
+        }
+        if pos > 0 {
+            if let Some(replication) = store.replication_mut() {
+                replication.touch_upstream(self.link_id);
+            }
+            drop(store);
+            self.input.drain(..pos);
+            self.queue_ack();
+        }
+        Ok(())
+    }
+
+    fn begin_close(&mut self) {
+        self.state.replace(UpstreamStates::Close);
+        self.schedule();
+    }
+
+    fn close(&mut self) {
+        let reconnect = {
+            let mut store = self.db.write().unwrap();
+            store
+                .replication_mut()
+                .is_some_and(|replication| replication.set_upstream_conn(self.link_id, None))
+        };
+        {
+            let mut reactor = self.reactor.write().unwrap();
+            reactor.remove_old_connection(self.fd, &self.stream);
+        }
+        if reconnect {
+            println!("lost the connection to primary {}, reconnecting", self.name);
+            spawn_connect(
+                self.link_id,
+                RECONNECT_DELAY,
+                self.reactor.clone(),
+                self.db.clone(),
+            );
+        }
+    }
+
+    fn fail(&mut self, err: io::Error) {
+        println!("replication link with {} failed: {}", self.name, err);
+        self.state.replace(UpstreamStates::ToBeClosed);
+        self.schedule();
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+/// Removes the first `\r\n` terminated line from the buffer.
+fn take_line(buf: &mut Vec<u8>) -> Option<String> {
+    let end = buf.windows(2).position(|window| window == b"\r\n")?;
+    let line = String::from_utf8_lossy(&buf[..end]).to_string();
+    buf.drain(..end + 2);
+    Some(line)
+}
+
+fn apply(store: &mut Store, args: &[String]) {
+    if execute_keyspace(store, args).is_none() {
+        println!("ignoring unknown command from primary: {:?}", args);
+    }
+}
+
+impl EventListener for UpstreamHandler {
+    fn id(&self) -> usize {
+        self.fd
+    }
+
+    fn name(&self) -> String {
+        format!("UpstreamHandler tcp://{}", self.name)
+    }
+
+    fn poll(&mut self) -> Result<()> {
+        match self.state.take() {
+            None => {
+                if let Err(err) = self.initalize() {
+                    self.fail(err);
+                }
+            }
+            Some(UpstreamStates::ToBeClosed) => self.begin_close(),
+            Some(UpstreamStates::Close) => self.close(),
+            Some(state) => {
+                let streaming = matches!(state, UpstreamStates::Streaming);
+                self.state.replace(state);
+                if !self.is_current() {
+                    println!("dropping the link with former primary {}", self.name);
+                    self.begin_close();
+                } else if streaming {
+                    self.queue_ack();
+                    if let Err(err) = self.flush_output() {
+                        self.fail(err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_event(&mut self, event: &Event) {
+        if matches!(
+            self.state,
+            Some(UpstreamStates::ToBeClosed) | Some(UpstreamStates::Close)
+        ) {
+            return;
+        }
+        if event.is_error() {
+            let err = self
+                .stream
+                .take_error()
+                .ok()
+                .flatten()
+                .unwrap_or_else(|| io::Error::other("connection error"));
+            return self.fail(err);
+        }
+        if event.is_writable() {
+            if let Err(err) = self.flush_output() {
+                return self.fail(err);
+            }
+        }
+        if event.is_readable() {
+            // what was received before the connection dropped is still applied
+            let read = self.read_input();
+            let result = self
+                .process_input()
+                .and(read)
+                .and_then(|_| self.flush_output());
+            if let Err(err) = result {
+                self.fail(err);
+            }
+        }
+    }
+}
+
+fn connect(link_id: u64, reactor: Arc<RwLock<Reactor>>, db: Arc<RwLock<Store>>) -> Result<()> {
+    let (host, port, auth, psync) = {
+        let store = db.read().unwrap();
+        let replication = match store.replication() {
+            Some(replication) => replication,
+            None => return Ok(()),
+        };
+        let upstream = match replication.current_upstream(link_id) {
+            Some(upstream) => upstream,
+            None => return Ok(()),
+        };
+        (
+            upstream.host.to_string(),
+            upstream.port,
+            replication.masterauth(),
+            format!(
+                "PSYNC {} {}\r\n",
+                replication.replid(),
+                replication.offset()
+            ),
+        )
+    };
+    let addr = (host.as_str(), port)
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "primary address not found"))?;
+    let stream = TcpStream::connect(addr)?;
+    let (token, completions) = {
+        let mut reactor = reactor.write().unwrap();
+        (reactor.allocate_token(), reactor.completions())
+    };
+    let conn = Notifier::new(token, completions);
+    {
+        let mut store = db.write().unwrap();
+        let current = store
+            .replication_mut()
+            .is_some_and(|replication| replication.set_upstream_conn(link_id, Some(conn)));
+        if !current {
+            drop(store);
+            reactor.write().unwrap().release_token(token);
+            return Ok(());
+        }
+    }
+
+    let handler = UpstreamHandler::new(
+        stream,
+        token,
+        format!("{}:{}", host, port),
+        link_id,
+        auth,
+        psync,
+        reactor.clone(),
+        db,
+    );
+    let poller = {
+        let mut reactor = reactor.write().unwrap();
+        reactor.add_new_connection(token, handler);
+        reactor.poller()
+    };
+    poller.wake()
+}
+
+/// Connects to the primary of the given link after `delay` on a background thread, retrying
+/// until it succeeds or the link is replaced by a newer REPLICAOF.
+pub fn spawn_connect(
+    link_id: u64,
+    delay: Duration,
+    reactor: Arc<RwLock<Reactor>>,
+    db: Arc<RwLock<Store>>,
+) {
+    thread::spawn(move || {
+        thread::sleep(delay);
+        while let Err(err) = connect(link_id, reactor.clone(), db.clone()) {
+            println!("failed to connect to primary: {}", err);
+            thread::sleep(RECONNECT_DELAY);
+        }
+    });
+}
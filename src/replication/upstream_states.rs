@@ -0,0 +1,16 @@
+#[derive(Debug)]
+pub enum UpstreamStates {
+    /// AUTH was sent, waiting for its +OK before sending PSYNC
+    Authenticating,
+    /// PSYNC was sent, waiting for +FULLRESYNC or +CONTINUE
+    Handshake,
+    /// waiting for the snapshot of a full sync
+    ReceivingSnapshot {
+        replid: String,
+        offset: u64,
+    },
+    /// applying the command stream
+    Streaming,
+    ToBeClosed,
+    Close,
+}
@@ -0,0 +1,357 @@
+use std::{
+    collections::HashSet,
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex, RwLock},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+use crate::{
+    async_server::{
+        core::AsyncTcpCommandServer,
+        listener::{ClientLimit, ListenerPolicy, ServerListener},
+    },
+    config::listener::{ListenAddr, ListenerConfig},
+    event_loop::core::EventLoop,
+    reactor::{core::Reactor, event_listener::EventListener},
+    stats::core::Stats,
+    store::core::Store,
+};
+
+use super::{
+    net::{Net, SimListener},
+    poller::{SimPoller, Workers},
+};
+
+/// Virtual time an action of a client may be delayed by.
+const MAX_LATENCY: u64 = 5;
+/// Largest send buffer of the server for a connection, small ones make writes partial.
+const MAX_CAPACITY: usize = 64;
+/// Steps after which a simulation that still has work is considered stuck.
+const MAX_STEPS: usize = 100_000;
+
+/// Client of a simulation, returned by [`Simulation::connect`].
+pub type ClientId = usize;
+
+#[derive(Debug)]
+enum Action {
+    Connect,
+    Send(Vec<u8>),
+    Close,
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    at: u64,
+    client: ClientId,
+    action: Action,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    /// index in `scheduled`
+    Action(usize),
+    Worker(u64, usize),
+    Read(ClientId),
+    Loop,
+}
+
+/// Runs a server on a simulated network, one step at a time in an order picked by a seeded
+/// random generator.
+///
+/// Steps are the actions of the clients (connecting, sending a chunk of their input, closing),
+/// clients reading part of the replies, workers running and iterations of the event loop. Only
+/// one of them runs at a time, so a seed always replays the same interleaving, and
+/// [`trace`](Simulation::trace) tells which one it was. Actions are due at a virtual time and
+/// the actions of a client happen in order, like on a TCP connection.
+pub struct Simulation {
+    rng: StdRng,
+    /// virtual time, a step takes one unit
+    now: u64,
+    net: Arc<Mutex<Net>>,
+    workers: Arc<Workers>,
+    reactor: Arc<RwLock<Reactor>>,
+    event_loop: EventLoop,
+    listener: RawFd,
+    /// fd of each client and when its last action is due
+    clients: Vec<(RawFd, u64)>,
+    /// in the order they were scheduled
+    scheduled: Vec<Scheduled>,
+    trace: Vec<String>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Simulation {
+        let net = Arc::new(Mutex::new(Net::default()));
+        let workers = Arc::new(Workers::default());
+        let poller = Arc::new(SimPoller::new(net.clone(), workers.clone()));
+        let reactor = Arc::new(RwLock::new(Reactor::new(poller)));
+        let db = Arc::new(RwLock::new(Store::default()));
+
+        let listener = SimListener::bind(net.clone());
+        let fd = listener.as_raw_fd();
+        let config = ListenerConfig::new(ListenAddr::Tcp(listener.local_name()), 0);
+        let policy = Arc::new(ListenerPolicy::new(
+            &config,
+            Arc::new(ClientLimit::new(0)),
+            Arc::new(Stats::default()),
+        ));
+        let server = AsyncTcpCommandServer::new(listener, None, policy, reactor.clone(), db);
+        let mut event_loop = EventLoop::new(reactor.clone());
+        event_loop
+            .connection_handler_map
+            .insert(server.id(), Box::new(server));
+
+        Simulation {
+            rng: StdRng::seed_from_u64(seed),
+            now: 0,
+            net,
+            workers,
+            reactor,
+            event_loop,
+            listener: fd,
+            clients: vec![],
+            scheduled: vec![],
+            trace: vec![],
+        }
+    }
+
+    /// Adds a client, it connects after a random delay.
+    pub fn connect(&mut self) -> ClientId {
+        let capacity = self.rng.gen_range(1..=MAX_CAPACITY);
+        let fd = self.net.lock().unwrap().open(capacity);
+        self.clients.push((fd, self.now));
+        let client = self.clients.len() - 1;
+        self.schedule(client, Action::Connect);
+        client
+    }
+
+    /// Sends `bytes` after what the client sent before, split in chunks of random sizes.
+    pub fn send(&mut self, client: ClientId, bytes: &[u8]) {
+        let mut rest = bytes;
+        while !rest.is_empty() {
+            let n = self.rng.gen_range(1..=rest.len());
+            self.schedule(client, Action::Send(rest[..n].to_vec()));
+            rest = &rest[n..];
+        }
+    }
+
+    /// Closes the client once everything it sent before is delivered.
+    pub fn close(&mut self, client: ClientId) {
+        self.schedule(client, Action::Close);
+    }
+
+    fn schedule(&mut self, client: ClientId, action: Action) {
+        let at = self.clients[client].1.max(self.now) + self.rng.gen_range(0..=MAX_LATENCY);
+        self.clients[client].1 = at;
+        self.scheduled.push(Scheduled { at, client, action });
+    }
+
+    /// Runs steps until the clients have nothing left to do and the server has no work.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server still has work after `MAX_STEPS` steps, or if the event loop fails.
+    pub fn run(&mut self) {
+        for _ in 0..MAX_STEPS {
+            if !self.step() {
+                return;
+            }
+        }
+        panic!("the simulation didn't settle after {} steps", MAX_STEPS);
+    }
+
+    /// Runs one step picked at random among the ones that can run, or advances the clock to the
+    /// next action when none can. Returns false once there is nothing left to do.
+    fn step(&mut self) -> bool {
+        let steps = self.runnable();
+        if steps.is_empty() {
+            return match self.scheduled.iter().map(|scheduled| scheduled.at).min() {
+                Some(at) => {
+                    self.now = at;
+                    true
+                }
+                None => false,
+            };
+        }
+        let step = steps[self.rng.gen_range(0..steps.len())];
+        self.now += 1;
+        self.perform(step);
+        true
+    }
+
+    fn runnable(&self) -> Vec<Step> {
+        let mut steps = vec![];
+        // the first action of each client, once it is due
+        let mut seen = HashSet::new();
+        for (index, scheduled) in self.scheduled.iter().enumerate() {
+            if seen.insert(scheduled.client) && scheduled.at <= self.now {
+                steps.push(Step::Action(index));
+            }
+        }
+        for (ticket, token) in self.workers.pending() {
+            steps.push(Step::Worker(ticket, token));
+        }
+        let net = self.net.lock().unwrap();
+        for (client, (fd, _)) in self.clients.iter().enumerate() {
+            if net.unread(*fd) > 0 {
+                steps.push(Step::Read(client));
+            }
+        }
+        if net.has_events() || self.reactor.read().unwrap().poll_timeout().is_some() {
+            steps.push(Step::Loop);
+        }
+        steps
+    }
+
+    fn perform(&mut self, step: Step) {
+        let entry = match step {
+            Step::Action(index) => {
+                let Scheduled { client, action, .. } = self.scheduled.remove(index);
+                let fd = self.clients[client].0;
+                let mut net = self.net.lock().unwrap();
+                match action {
+                    Action::Connect => {
+                        net.connect(self.listener, fd);
+                        format!("client {} connects", client)
+                    }
+                    Action::Send(bytes) => {
+                        net.deliver(fd, &bytes);
+                        format!(
+                            "client {} sends {:?}",
+                            client,
+                            String::from_utf8_lossy(&bytes)
+                        )
+                    }
+                    Action::Close => {
+                        net.close(fd);
+                        format!("client {} closes", client)
+                    }
+                }
+            }
+            Step::Worker(ticket, token) => {
+                self.workers.run(ticket);
+                format!("worker {} of token {} runs", ticket, token)
+            }
+            Step::Read(client) => {
+                let fd = self.clients[client].0;
+                let mut net = self.net.lock().unwrap();
+                let max = self.rng.gen_range(1..=net.unread(fd));
+                format!("client {} reads {} bytes", client, net.client_read(fd, max))
+            }
+            Step::Loop => {
+                self.event_loop.run_once().unwrap();
+                "event loop runs".to_string()
+            }
+        };
+        self.trace.push(format!("{:>6} {}", self.now, entry));
+    }
+
+    /// Replies the client read so far.
+    pub fn received(&self, client: ClientId) -> String {
+        let net = self.net.lock().unwrap();
+        String::from_utf8_lossy(net.received(self.clients[client].0)).into_owned()
+    }
+
+    /// Whether the server closed the connection of the client.
+    pub fn is_closed(&self, client: ClientId) -> bool {
+        self.net.lock().unwrap().is_dropped(self.clients[client].0)
+    }
+
+    /// Steps run so far with the virtual time they ran at.
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    pub fn reactor(&self) -> &Arc<RwLock<Reactor>> {
+        &self.reactor
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEEDS: u64 = 200;
+
+    /// Clients pipelining their commands, returns the simulation and the expected replies.
+    fn pipelined(seed: u64, clients: usize) -> (Simulation, Vec<String>) {
+        let mut sim = Simulation::new(seed);
+        let mut expected = vec![];
+        for i in 0..clients {
+            let client = sim.connect();
+            sim.send(
+                client,
+                format!("SET k{i} v{i}\r\nGET k{i}\r\nPING\r\nECHO hi{i}\r\nGET missing\r\n")
+                    .as_bytes(),
+            );
+            expected.push(format!(
+                "+OK\r\n$2\r\nv{i}\r\n+PONG\r\n$3\r\nhi{i}\r\n$-1\r\n"
+            ));
+        }
+        (sim, expected)
+    }
+
+    #[test]
+    fn replays_a_seed() {
+        let (mut first, _) = pipelined(7, 3);
+        first.run();
+        let (mut second, _) = pipelined(7, 3);
+        second.run();
+        assert_eq!(first.trace(), second.trace());
+        for client in 0..3 {
+            assert_eq!(first.received(client), second.received(client));
+        }
+
+        let (mut other, _) = pipelined(8, 3);
+        other.run();
+        assert_ne!(first.trace(), other.trace());
+    }
+
+    #[test]
+    fn replies_in_order_under_any_interleaving() {
+        for seed in 0..SEEDS {
+            let (mut sim, expected) = pipelined(seed, 3);
+            sim.run();
+            for (client, expected) in expected.iter().enumerate() {
+                assert_eq!(
+                    &sim.received(client),
+                    expected,
+                    "seed {} client {}\n{}",
+                    seed,
+                    client,
+                    sim.trace().join("\n")
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn releases_clients_that_disconnect() {
+        for seed in 0..SEEDS {
+            let mut sim = Simulation::new(seed);
+            let quits = sim.connect();
+            sim.send(quits, b"SET a 1\r\nQUIT\r\n");
+            // closes in the middle of a command, or while replies are being written
+            let leaves = sim.connect();
+            sim.send(leaves, b"PING\r\nGET a\r\nPI");
+            sim.close(leaves);
+            sim.run();
+
+            let trace = sim.trace().join("\n");
+            assert_eq!(
+                sim.received(quits),
+                "+OK\r\n+OK\r\n",
+                "seed {}\n{}",
+                seed,
+                trace
+            );
+            assert!(sim.is_closed(quits), "seed {}\n{}", seed, trace);
+            assert!(sim.is_closed(leaves), "seed {}\n{}", seed, trace);
+            // only the listener is left
+            let reactor = sim.reactor().read().unwrap();
+            assert_eq!(reactor.existing_tokens.len(), 1, "seed {}\n{}", seed, trace);
+            assert_eq!(reactor.tokens.len(), 1, "seed {}\n{}", seed, trace);
+        }
+    }
+}
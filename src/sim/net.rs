@@ -0,0 +1,262 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Result, Write},
+    os::fd::{AsRawFd, RawFd},
+    sync::{Arc, Mutex},
+};
+
+use mio::Interest;
+
+use crate::{
+    async_client::stream::ClientStream, async_server::listener::ServerListener,
+    reactor::event::Event,
+};
+
+/// Fds of the simulated sockets start here, they are never passed to the kernel.
+const FIRST_FD: RawFd = 1000;
+
+#[derive(Debug, Default)]
+struct Conn {
+    /// sent by the client, not read by the server yet
+    inbound: VecDeque<u8>,
+    /// written by the server, not read by the client yet
+    outbound: VecDeque<u8>,
+    /// room of the send buffer of the server, writes past it would block
+    capacity: usize,
+    /// everything the client read
+    received: Vec<u8>,
+    /// the client closed the connection
+    closed: bool,
+    /// the server dropped its end
+    dropped: bool,
+}
+
+/// In memory sockets standing in for the network in the simulation.
+///
+/// The simulation acts for the clients while the server reaches the sockets through
+/// [`SimListener`] and [`SimStream`]. Readiness changes of registered sockets turn into events
+/// the way epoll reports them edge triggered: when data comes in, when room is made in the send
+/// buffer, when the peer closes, and when a socket is registered while already ready.
+#[derive(Debug, Default)]
+pub struct Net {
+    next_fd: RawFd,
+    conns: HashMap<RawFd, Conn>,
+    backlogs: HashMap<RawFd, VecDeque<RawFd>>,
+    registrations: HashMap<RawFd, (usize, Interest)>,
+    events: Vec<Event>,
+}
+
+impl Net {
+    fn allocate_fd(&mut self) -> RawFd {
+        self.next_fd += 1;
+        FIRST_FD + self.next_fd
+    }
+
+    pub fn listen(&mut self) -> RawFd {
+        let fd = self.allocate_fd();
+        self.backlogs.insert(fd, VecDeque::new());
+        fd
+    }
+
+    /// Creates the socket of a client whose send buffer on the server side holds `capacity`
+    /// bytes, it reaches the server with `connect`.
+    pub fn open(&mut self, capacity: usize) -> RawFd {
+        let fd = self.allocate_fd();
+        self.conns.insert(
+            fd,
+            Conn {
+                capacity,
+                ..Conn::default()
+            },
+        );
+        fd
+    }
+
+    pub fn connect(&mut self, listener: RawFd, fd: RawFd) {
+        self.backlogs.get_mut(&listener).unwrap().push_back(fd);
+        self.notify(listener);
+    }
+
+    pub fn deliver(&mut self, fd: RawFd, bytes: &[u8]) {
+        self.conns.get_mut(&fd).unwrap().inbound.extend(bytes);
+        self.notify(fd);
+    }
+
+    /// The client reads up to `max` bytes of what the server wrote, returns how many.
+    pub fn client_read(&mut self, fd: RawFd, max: usize) -> usize {
+        let conn = self.conns.get_mut(&fd).unwrap();
+        let n = max.min(conn.outbound.len());
+        conn.received.extend(conn.outbound.drain(..n));
+        if n > 0 {
+            self.notify(fd);
+        }
+        n
+    }
+
+    pub fn close(&mut self, fd: RawFd) {
+        self.conns.get_mut(&fd).unwrap().closed = true;
+        self.notify(fd);
+    }
+
+    pub fn received(&self, fd: RawFd) -> &[u8] {
+        &self.conns[&fd].received
+    }
+
+    /// Bytes written by the server the client didn't read yet.
+    pub fn unread(&self, fd: RawFd) -> usize {
+        self.conns[&fd].outbound.len()
+    }
+
+    pub fn is_dropped(&self, fd: RawFd) -> bool {
+        self.conns[&fd].dropped
+    }
+
+    pub fn has_events(&self) -> bool {
+        !self.events.is_empty()
+    }
+
+    pub fn take_events(&mut self, events: &mut Vec<Event>) {
+        events.append(&mut self.events);
+    }
+
+    pub fn register(&mut self, fd: RawFd, token: usize, interest: Interest) {
+        self.registrations.insert(fd, (token, interest));
+        self.notify(fd);
+    }
+
+    pub fn deregister(&mut self, fd: RawFd) {
+        self.registrations.remove(&fd);
+    }
+
+    /// Reports the readiness of `fd` if it is registered for it.
+    fn notify(&mut self, fd: RawFd) {
+        let (token, interest) = match self.registrations.get(&fd) {
+            Some(registration) => *registration,
+            None => return,
+        };
+        let event = match (self.conns.get(&fd), self.backlogs.get(&fd)) {
+            (Some(conn), _) => Event {
+                token,
+                readable: interest.is_readable() && (!conn.inbound.is_empty() || conn.closed),
+                writable: interest.is_writable() && conn.outbound.len() < conn.capacity,
+                error: false,
+                read_closed: conn.closed,
+                write_closed: conn.closed,
+            },
+            (None, Some(backlog)) => Event {
+                token,
+                readable: interest.is_readable() && !backlog.is_empty(),
+                ..Event::default()
+            },
+            (None, None) => return,
+        };
+        if event.readable || event.writable || event.read_closed {
+            self.events.push(event);
+        }
+    }
+}
+
+/// Listener of the simulated network.
+pub struct SimListener {
+    net: Arc<Mutex<Net>>,
+    fd: RawFd,
+}
+
+impl SimListener {
+    pub fn bind(net: Arc<Mutex<Net>>) -> SimListener {
+        let fd = net.lock().unwrap().listen();
+        SimListener { net, fd }
+    }
+}
+
+impl AsRawFd for SimListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl ServerListener for SimListener {
+    type Stream = SimStream;
+
+    fn accept_client(&self) -> Result<SimStream> {
+        let fd = self
+            .net
+            .lock()
+            .unwrap()
+            .backlogs
+            .get_mut(&self.fd)
+            .and_then(|backlog| backlog.pop_front())
+            .ok_or(io::ErrorKind::WouldBlock)?;
+        Ok(SimStream {
+            net: self.net.clone(),
+            fd,
+        })
+    }
+
+    fn local_name(&self) -> String {
+        format!("sim://{}", self.fd)
+    }
+}
+
+/// Server end of a simulated connection.
+pub struct SimStream {
+    net: Arc<Mutex<Net>>,
+    fd: RawFd,
+}
+
+impl Read for SimStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let mut net = self.net.lock().unwrap();
+        let conn = net.conns.get_mut(&self.fd).unwrap();
+        if conn.inbound.is_empty() {
+            return match conn.closed {
+                true => Ok(0),
+                false => Err(io::ErrorKind::WouldBlock.into()),
+            };
+        }
+        let n = buf.len().min(conn.inbound.len());
+        for (byte, read) in buf.iter_mut().zip(conn.inbound.drain(..n)) {
+            *byte = read;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for SimStream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let mut net = self.net.lock().unwrap();
+        let conn = net.conns.get_mut(&self.fd).unwrap();
+        if conn.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        let n = buf.len().min(conn.capacity - conn.outbound.len());
+        if n == 0 {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        conn.outbound.extend(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for SimStream {
+    fn drop(&mut self) {
+        let mut net = self.net.lock().unwrap();
+        net.conns.get_mut(&self.fd).unwrap().dropped = true;
+    }
+}
+
+impl AsRawFd for SimStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl ClientStream for SimStream {
+    fn peer_name(&self) -> String {
+        format!("sim:{}", self.fd)
+    }
+}
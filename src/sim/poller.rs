@@ -0,0 +1,116 @@
+use std::{
+    io::Result,
+    os::fd::RawFd,
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+use mio::Interest;
+
+use crate::reactor::{completion::WorkerGate, event::Event, poller::core::Poller};
+
+use super::net::Net;
+
+/// Poller of a simulated reactor, reporting the events of the simulated network.
+///
+/// Waits never block, the simulation only runs the event loop when it has work. Workers go
+/// through [`Workers`] so the simulation decides when they run.
+pub struct SimPoller {
+    net: Arc<Mutex<Net>>,
+    workers: Arc<Workers>,
+}
+
+impl SimPoller {
+    pub fn new(net: Arc<Mutex<Net>>, workers: Arc<Workers>) -> SimPoller {
+        SimPoller { net, workers }
+    }
+}
+
+impl Poller for SimPoller {
+    fn name(&self) -> &'static str {
+        "sim"
+    }
+
+    fn register(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()> {
+        self.net.lock().unwrap().register(fd, token, interest);
+        Ok(())
+    }
+
+    fn reregister(&self, fd: RawFd, token: usize, interest: Interest) -> Result<()> {
+        self.net.lock().unwrap().register(fd, token, interest);
+        Ok(())
+    }
+
+    fn deregister(&self, fd: RawFd, _token: usize) -> Result<()> {
+        self.net.lock().unwrap().deregister(fd);
+        Ok(())
+    }
+
+    fn wake(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn wait(&self, events: &mut Vec<Event>, _timeout: Option<Duration>) -> Result<()> {
+        self.net.lock().unwrap().take_events(events);
+        Ok(())
+    }
+
+    fn worker_gate(&self) -> Option<Arc<dyn WorkerGate>> {
+        Some(self.workers.clone())
+    }
+}
+
+/// Holds the workers of a simulated reactor back until the simulation runs them, one at a
+/// time.
+#[derive(Debug, Default)]
+pub struct Workers {
+    state: Mutex<WorkerState>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct WorkerState {
+    next: u64,
+    /// tickets of the spawned workers that didn't run yet, with the token of their client
+    pending: Vec<(u64, usize)>,
+    running: Option<u64>,
+}
+
+impl Workers {
+    pub fn pending(&self) -> Vec<(u64, usize)> {
+        self.state.lock().unwrap().pending.clone()
+    }
+
+    /// Lets the worker holding `ticket` run and waits until it handed its output back.
+    pub fn run(&self, ticket: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.pending.retain(|(pending, _)| *pending != ticket);
+        state.running = Some(ticket);
+        self.changed.notify_all();
+        while state.running.is_some() {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+}
+
+impl WorkerGate for Workers {
+    fn spawned(&self, fd: usize) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        let ticket = state.next;
+        state.next += 1;
+        state.pending.push((ticket, fd));
+        ticket
+    }
+
+    fn enter(&self, ticket: u64) {
+        let mut state = self.state.lock().unwrap();
+        while state.running != Some(ticket) {
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    fn leave(&self, _ticket: u64) {
+        self.state.lock().unwrap().running = None;
+        self.changed.notify_all();
+    }
+}
@@ -0,0 +1,31 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server wide counters reported by INFO stats.
+///
+/// Shared by every listener and connection through their [`ListenerPolicy`], counters are
+/// atomics so that counting a refused command or connection doesn't lock the store.
+///
+/// [`ListenerPolicy`]: crate::async_server::listener::ListenerPolicy
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// AUTH and HELLO AUTH attempts with a wrong password
+    pub auth_failures: AtomicU64,
+    /// commands refused because the connection was not authenticated
+    pub noauth_rejections: AtomicU64,
+    /// commands refused by the ACL rules of the user
+    pub acl_denied_commands: AtomicU64,
+    pub acl_denied_keys: AtomicU64,
+    pub acl_denied_channels: AtomicU64,
+    /// connections turned away by `maxclients` or the limit of their listener
+    pub rejected_connections: AtomicU64,
+}
+
+/// Adds one to a counter of [`Stats`].
+pub fn bump(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Current value of a counter of [`Stats`].
+pub fn read(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
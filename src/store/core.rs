@@ -0,0 +1,358 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    auth::core::Auth,
+    persistence::{aof::Aof, snapshot::SnapshotState},
+    protocol::parser::encode_multibulk,
+    replication::core::Replication,
+};
+
+use super::value::Value;
+
+/// A key along with its value and expiry, as persisted by snapshots and AOF rewrites.
+pub type Entry = (String, Value, Option<u64>);
+
+/// Current unix time in milliseconds, the unit key expiries are stored in.
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// The keyspace shared by every connection.
+///
+/// It is kept behind an `Arc<RwLock<Store>>` and command workers take the write lock for the
+/// whole duration of a command so that every command is applied atomically.
+#[derive(Debug, Default)]
+pub struct Store {
+    entries: HashMap<String, Value>,
+    /// unix time in milliseconds at which a key expires, keys without a ttl are absent
+    expires: HashMap<String, u64>,
+    /// the same expiries ordered by time, active expiry finds the expired keys without a scan
+    expiry_queue: BTreeSet<(u64, String)>,
+    /// clients watching a key, keyed by the watched key
+    watchers: HashMap<String, HashSet<usize>>,
+    /// clients for which one of the watched keys has been modified since WATCH
+    dirty_watchers: HashSet<usize>,
+    aof: Option<Aof>,
+    snapshot: Option<SnapshotState>,
+    replication: Option<Replication>,
+    auth: Auth,
+}
+
+impl Store {
+    /// Expired keys are treated as missing by every accessor, they are physically removed
+    /// on the next write access or by `purge_expired`.
+    fn is_expired(&self, key: &str) -> bool {
+        self.expires.get(key).is_some_and(|at| *at <= now_ms())
+    }
+
+    fn set_expiry_at(&mut self, key: String, at_ms: u64) {
+        if let Some(previous) = self.expires.insert(key.to_string(), at_ms) {
+            self.expiry_queue.remove(&(previous, key.to_string()));
+        }
+        self.expiry_queue.insert((at_ms, key));
+    }
+
+    fn clear_expiry(&mut self, key: &str) {
+        if let Some(at) = self.expires.remove(key) {
+            self.expiry_queue.remove(&(at, key.to_string()));
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        if self.is_expired(key) {
+            return None;
+        }
+        self.entries.get(key)
+    }
+
+    /// Changes made through the reference are not seen by watchers, the caller reports them
+    /// with `modified` so that a command leaving the value as it was doesn't abort transactions.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Value> {
+        if self.is_expired(key) {
+            self.remove(key);
+            return None;
+        }
+        self.entries.get_mut(key)
+    }
+
+    /// Like `get_mut`, a missing key is first inserted with the value of `default`, which is
+    /// not reported to watchers either.
+    pub fn get_or_insert_with(&mut self, key: &str, default: impl FnOnce() -> Value) -> &mut Value {
+        if self.is_expired(key) {
+            self.remove(key);
+        }
+        self.entries.entry(key.to_string()).or_insert_with(default)
+    }
+
+    /// Makes the transactions of the clients watching the key fail, for changes made through
+    /// `get_mut` and `get_or_insert_with`.
+    pub fn modified(&mut self, key: &str) {
+        if let Some(clients) = self.watchers.get(key) {
+            self.dirty_watchers.extend(clients.iter());
+        }
+    }
+
+    /// Inserts or overwrites a key, overwriting a key clears its ttl.
+    pub fn insert(&mut self, key: String, value: Value) -> Option<Value> {
+        self.modified(&key);
+        self.clear_expiry(&key);
+        self.entries.insert(key, value)
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<Value> {
+        self.clear_expiry(key);
+        let value = self.entries.remove(key)?;
+        self.modified(key);
+        Some(value)
+    }
+
+    /// Removes a key without reporting it to watchers, for a value left empty by a command that
+    /// either reported its change already or didn't change anything.
+    pub fn discard(&mut self, key: &str) {
+        self.clear_expiry(key);
+        self.entries.remove(key);
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        !self.is_expired(key) && self.entries.contains_key(key)
+    }
+
+    /// Unix time in milliseconds at which the key expires.
+    pub fn expiry(&self, key: &str) -> Option<u64> {
+        if !self.contains(key) {
+            return None;
+        }
+        self.expires.get(key).copied()
+    }
+
+    /// Sets the expiry of an existing key, returns false if the key does not exist. A time in
+    /// the past deletes the key right away.
+    pub fn set_expiry(&mut self, key: &str, at_ms: u64) -> bool {
+        if !self.contains(key) {
+            return false;
+        }
+        if at_ms <= now_ms() {
+            self.remove(key);
+        } else {
+            self.modified(key);
+            self.set_expiry_at(key.to_string(), at_ms);
+        }
+        true
+    }
+
+    /// Removes the expiry of a key, returns false if the key had none.
+    pub fn persist(&mut self, key: &str) -> bool {
+        if !self.contains(key) || !self.expires.contains_key(key) {
+            return false;
+        }
+        self.modified(key);
+        self.clear_expiry(key);
+        true
+    }
+
+    /// Removes the keys whose expiry has passed, the ones that expired first, at most `limit` of
+    /// them so that a burst of expiries doesn't hold the store for long. Returns the number of
+    /// keys removed.
+    pub fn purge_expired(&mut self, limit: usize) -> usize {
+        let now = now_ms();
+        let mut purged = 0;
+        while purged < limit {
+            let key = match self.expiry_queue.first() {
+                Some((at, key)) if *at <= now => key.to_string(),
+                _ => break,
+            };
+            self.remove(&key);
+            purged += 1;
+        }
+        purged
+    }
+
+    /// Removes every key.
+    pub fn clear(&mut self) {
+        let keys: Vec<String> = self.entries.keys().cloned().collect();
+        for key in keys.iter() {
+            self.modified(key);
+        }
+        self.entries.clear();
+        self.expires.clear();
+        self.expiry_queue.clear();
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Registers the client as watching the key, any later modification of the key makes the
+    /// client's transaction fail on EXEC.
+    pub fn watch(&mut self, client: usize, key: &str) {
+        self.watchers
+            .entry(key.to_string())
+            .or_default()
+            .insert(client);
+    }
+
+    /// Removes the client from the watchers of the given keys and clears its dirty flag.
+    pub fn unwatch(&mut self, client: usize, keys: &[String]) {
+        for key in keys {
+            if let Some(clients) = self.watchers.get_mut(key) {
+                clients.remove(&client);
+                if clients.is_empty() {
+                    self.watchers.remove(key);
+                }
+            }
+        }
+        self.dirty_watchers.remove(&client);
+    }
+
+    /// Returns true if a key watched by the client was modified since it was watched.
+    pub fn is_dirty(&self, client: usize) -> bool {
+        self.dirty_watchers.contains(&client)
+    }
+
+    /// Starts feeding write commands to the AOF.
+    pub fn set_aof(&mut self, aof: Aof) {
+        self.aof.replace(aof);
+    }
+
+    pub fn aof(&self) -> Option<&Aof> {
+        self.aof.as_ref()
+    }
+
+    pub fn aof_mut(&mut self) -> Option<&mut Aof> {
+        self.aof.as_mut()
+    }
+
+    /// Copies every live key along with its value and expiry, used to persist a consistent view
+    /// of the keyspace from another thread.
+    pub fn snapshot(&self) -> Vec<Entry> {
+        self.entries
+            .iter()
+            .filter(|(key, _)| !self.is_expired(key))
+            .map(|(key, value)| {
+                (
+                    key.to_string(),
+                    value.clone(),
+                    self.expires.get(key).copied(),
+                )
+            })
+            .collect()
+    }
+
+    /// Loads an entry read from a snapshot, entries that already expired are skipped.
+    pub fn restore(&mut self, entry: Entry) {
+        let (key, value, expiry) = entry;
+        if expiry.is_some_and(|at| at <= now_ms()) {
+            return;
+        }
+        self.insert(key.to_string(), value);
+        if let Some(at) = expiry {
+            self.set_expiry_at(key, at);
+        }
+    }
+
+    pub fn set_snapshot_state(&mut self, state: SnapshotState) {
+        self.snapshot.replace(state);
+    }
+
+    pub fn snapshot_state(&self) -> Option<&SnapshotState> {
+        self.snapshot.as_ref()
+    }
+
+    pub fn snapshot_state_mut(&mut self) -> Option<&mut SnapshotState> {
+        self.snapshot.as_mut()
+    }
+
+    /// Counts a write towards the automatic save rules.
+    pub fn record_change(&mut self) {
+        if let Some(snapshot) = self.snapshot.as_mut() {
+            snapshot.record_change();
+        }
+    }
+
+    pub fn set_replication(&mut self, replication: Replication) {
+        self.replication.replace(replication);
+    }
+
+    pub fn replication(&self) -> Option<&Replication> {
+        self.replication.as_ref()
+    }
+
+    pub fn replication_mut(&mut self) -> Option<&mut Replication> {
+        self.replication.as_mut()
+    }
+
+    pub fn set_auth(&mut self, auth: Auth) {
+        self.auth = auth;
+    }
+
+    pub fn auth(&self) -> &Auth {
+        &self.auth
+    }
+
+    pub fn auth_mut(&mut self) -> &mut Auth {
+        &mut self.auth
+    }
+
+    /// Feeds a write command that was applied to the store to the AOF and, on a primary, to the
+    /// replication stream. Replicas forward the stream of their primary as is instead.
+    pub fn propagate(&mut self, args: &[String]) {
+        if let Some(aof) = self.aof.as_mut() {
+            if let Err(err) = aof.append(args) {
+                println!(
+                    "failed to append to the AOF {}: {}",
+                    aof.path().display(),
+                    err
+                );
+            }
+        }
+        if let Some(replication) = self.replication.as_mut() {
+            if replication.is_primary() {
+                replication.feed(encode_multibulk(args).as_bytes());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn purges_a_bounded_number_of_expired_keys() {
+        let mut store = Store::default();
+        let at = now_ms() + 20;
+        for i in 0..10 {
+            let key = format!("key{i}");
+            store.insert(key.to_string(), Value::String("v".to_string()));
+            // the later keys expire first
+            store.set_expiry(&key, at - i);
+        }
+        store.insert("kept".to_string(), Value::String("v".to_string()));
+        store.set_expiry("kept", at + 60_000);
+        store.set_expiry("key0", at + 60_000);
+        assert_eq!(store.purge_expired(100), 0);
+
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(store.purge_expired(4), 4);
+        assert!(store.entries.contains_key("key1") && !store.entries.contains_key("key9"));
+        assert_eq!(store.purge_expired(100), 5);
+        assert_eq!(store.len(), 2);
+        assert_eq!(store.expiry_queue.len(), 2);
+
+        store.persist("kept");
+        store.remove("key0");
+        assert!(store.expiry_queue.is_empty() && store.expires.is_empty());
+    }
+}
@@ -0,0 +1,4 @@
+pub mod core;
+pub mod rank_tree;
+pub mod sorted_set;
+pub mod value;
@@ -0,0 +1,446 @@
+use std::{fmt, ops::Range};
+
+use rand::Rng;
+
+type Link<T> = Option<Box<Node<T>>>;
+
+struct Node<T> {
+    value: T,
+    priority: u64,
+    /// values in the subtree, the node included
+    size: usize,
+    left: Link<T>,
+    right: Link<T>,
+}
+
+impl<T> Node<T> {
+    fn update(&mut self) {
+        self.size = 1 + size(&self.left) + size(&self.right);
+    }
+}
+
+fn size<T>(link: &Link<T>) -> usize {
+    link.as_ref().map_or(0, |node| node.size)
+}
+
+// The tree walks below keep their path on the heap rather than recursing, the depth of a tree
+// is only logarithmic in expectation and a worker's stack is small.
+
+/// Links the nodes a split detached along its path: the nodes that went left hang off each
+/// other's right side, the ones that went right off each other's left side.
+fn join_spines<T>(
+    mut lefts: Vec<Box<Node<T>>>,
+    mut rights: Vec<Box<Node<T>>>,
+) -> (Link<T>, Link<T>) {
+    let mut left = None;
+    while let Some(mut node) = lefts.pop() {
+        node.right = left;
+        node.update();
+        left = Some(node);
+    }
+    let mut right = None;
+    while let Some(mut node) = rights.pop() {
+        node.left = right;
+        node.update();
+        right = Some(node);
+    }
+    (left, right)
+}
+
+/// Splits a subtree into the values `below` holds for and the others.
+fn split<T>(mut link: Link<T>, below: &impl Fn(&T) -> bool) -> (Link<T>, Link<T>) {
+    let (mut lefts, mut rights) = (vec![], vec![]);
+    while let Some(mut node) = link {
+        if below(&node.value) {
+            link = node.right.take();
+            lefts.push(node);
+        } else {
+            link = node.left.take();
+            rights.push(node);
+        }
+    }
+    join_spines(lefts, rights)
+}
+
+/// Splits a subtree into its first `n` values and the others.
+fn split_at<T>(mut link: Link<T>, mut n: usize) -> (Link<T>, Link<T>) {
+    let (mut lefts, mut rights) = (vec![], vec![]);
+    while let Some(mut node) = link {
+        let left_size = size(&node.left);
+        if n <= left_size {
+            link = node.left.take();
+            rights.push(node);
+        } else {
+            n -= left_size + 1;
+            link = node.right.take();
+            lefts.push(node);
+        }
+    }
+    join_spines(lefts, rights)
+}
+
+/// Joins two subtrees, the values of `left` all come before the ones of `right`.
+fn merge<T>(mut left: Link<T>, mut right: Link<T>) -> Link<T> {
+    // nodes taken along the way, with whether the rest of the merge goes to their right
+    let mut path = vec![];
+    let mut rest = loop {
+        match (left, right) {
+            (None, last) | (last, None) => break last,
+            (Some(mut l), Some(mut r)) => {
+                if l.priority > r.priority {
+                    left = l.right.take();
+                    right = Some(r);
+                    path.push((l, true));
+                } else {
+                    right = r.left.take();
+                    left = Some(l);
+                    path.push((r, false));
+                }
+            }
+        }
+    };
+    while let Some((mut node, to_right)) = path.pop() {
+        if to_right {
+            node.right = rest;
+        } else {
+            node.left = rest;
+        }
+        node.update();
+        rest = Some(node);
+    }
+    rest
+}
+
+/// An ordered set that also answers positional queries, the rank of a value and the value at a
+/// position, in O(log n).
+///
+/// A treap: a search tree on the values that is a heap on random priorities, which keeps it
+/// balanced in expectation. Each node counts the values of its subtree. Every tree seeds its
+/// priorities from the thread's random generator, clients can't pick values in priority order
+/// to make it degenerate.
+pub struct RankTree<T> {
+    root: Link<T>,
+    seed: u64,
+}
+
+impl<T> Default for RankTree<T> {
+    fn default() -> RankTree<T> {
+        RankTree {
+            root: None,
+            seed: rand::thread_rng().gen(),
+        }
+    }
+}
+
+impl<T: Clone> Clone for RankTree<T> {
+    fn clone(&self) -> RankTree<T> {
+        // post-order, the clones of both children are on `done` when their parent is cloned
+        let mut todo = vec![(self.root.as_deref(), false)];
+        let mut done: Vec<Link<T>> = vec![];
+        while let Some((link, children_done)) = todo.pop() {
+            match link {
+                None => done.push(None),
+                Some(node) if children_done => {
+                    let right = done.pop().unwrap();
+                    let left = done.pop().unwrap();
+                    done.push(Some(Box::new(Node {
+                        value: node.value.clone(),
+                        priority: node.priority,
+                        size: node.size,
+                        left,
+                        right,
+                    })));
+                }
+                Some(node) => {
+                    todo.push((Some(node), true));
+                    todo.push((node.right.as_deref(), false));
+                    todo.push((node.left.as_deref(), false));
+                }
+            }
+        }
+        RankTree {
+            root: done.pop().unwrap(),
+            seed: self.seed,
+        }
+    }
+}
+
+impl<T> Drop for RankTree<T> {
+    fn drop(&mut self) {
+        let mut nodes: Vec<_> = self.root.take().into_iter().collect();
+        while let Some(mut node) = nodes.pop() {
+            nodes.extend(node.left.take());
+            nodes.extend(node.right.take());
+        }
+    }
+}
+
+/// splitmix64
+fn next_priority(seed: &mut u64) -> u64 {
+    *seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *seed;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+impl<T: Ord> RankTree<T> {
+    pub fn len(&self) -> usize {
+        size(&self.root)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Inserts `value`, returns false if it was already there.
+    pub fn insert(&mut self, value: T) -> bool {
+        if self.contains(&value) {
+            return false;
+        }
+        let (left, right) = split(self.root.take(), &|other| *other < value);
+        let node = Box::new(Node {
+            value,
+            priority: next_priority(&mut self.seed),
+            size: 1,
+            left: None,
+            right: None,
+        });
+        self.root = merge(merge(left, Some(node)), right);
+        true
+    }
+
+    pub fn remove(&mut self, value: &T) -> Option<T> {
+        let (left, rest) = split(self.root.take(), &|other| other < value);
+        let (found, right) = split(rest, &|other| other <= value);
+        self.root = merge(left, right);
+        found.map(|node| node.value)
+    }
+
+    pub fn contains(&self, value: &T) -> bool {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            link = match value.cmp(&node.value) {
+                std::cmp::Ordering::Less => &node.left,
+                std::cmp::Ordering::Greater => &node.right,
+                std::cmp::Ordering::Equal => return true,
+            };
+        }
+        false
+    }
+
+    /// Number of values `below` holds for. It has to hold for the smallest values and then stop
+    /// holding, like a comparison with a bound.
+    pub fn count_below(&self, below: impl Fn(&T) -> bool) -> usize {
+        let mut count = 0;
+        let mut link = &self.root;
+        while let Some(node) = link {
+            if below(&node.value) {
+                count += size(&node.left) + 1;
+                link = &node.right;
+            } else {
+                link = &node.left;
+            }
+        }
+        count
+    }
+
+    /// Value at position `index` in ascending order.
+    pub fn get(&self, mut index: usize) -> Option<&T> {
+        let mut link = &self.root;
+        while let Some(node) = link {
+            let left_size = size(&node.left);
+            if index < left_size {
+                link = &node.left;
+            } else if index == left_size {
+                return Some(&node.value);
+            } else {
+                index -= left_size + 1;
+                link = &node.right;
+            }
+        }
+        None
+    }
+
+    pub fn pop_first(&mut self) -> Option<T> {
+        let (first, rest) = split_at(self.root.take(), 1);
+        self.root = rest;
+        first.map(|node| node.value)
+    }
+
+    pub fn pop_last(&mut self) -> Option<T> {
+        let len = self.len();
+        let (rest, last) = split_at(self.root.take(), len.saturating_sub(1));
+        self.root = rest;
+        last.map(|node| node.value)
+    }
+
+    /// Values at the positions of `range` in ascending order, each one is found in O(log n).
+    pub fn range(&self, range: Range<usize>) -> Iter<'_, T> {
+        Iter {
+            tree: self,
+            front: range.start,
+            back: range.end.min(self.len()).max(range.start),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_, T> {
+        self.range(0..self.len())
+    }
+}
+
+impl<T: Ord + PartialEq> PartialEq for RankTree<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl<T: Ord + fmt::Debug> fmt::Debug for RankTree<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.iter()).finish()
+    }
+}
+
+/// Iterator over a range of positions of a [`RankTree`].
+pub struct Iter<'a, T> {
+    tree: &'a RankTree<T>,
+    front: usize,
+    back: usize,
+}
+
+impl<'a, T: Ord> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        if self.front == self.back {
+            return None;
+        }
+        self.front += 1;
+        self.tree.get(self.front - 1)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.back - self.front, Some(self.back - self.front))
+    }
+}
+
+impl<T: Ord> DoubleEndedIterator for Iter<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        self.tree.get(self.back)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn depth<T>(link: &Link<T>) -> usize {
+        link.as_ref()
+            .map_or(0, |node| 1 + depth(&node.left).max(depth(&node.right)))
+    }
+
+    #[test]
+    fn matches_an_ordered_set() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut tree = RankTree::default();
+        let mut expected = BTreeSet::new();
+        for _ in 0..20_000 {
+            let value = rng.gen_range(0..2_000);
+            if rng.gen_bool(0.6) {
+                assert_eq!(tree.insert(value), expected.insert(value));
+            } else {
+                assert_eq!(tree.remove(&value), expected.take(&value));
+            }
+        }
+        assert_eq!(tree.len(), expected.len());
+        assert!(tree.iter().eq(expected.iter()));
+        assert!(tree.iter().rev().eq(expected.iter().rev()));
+        for (rank, value) in expected.iter().enumerate() {
+            assert_eq!(tree.count_below(|other| other < value), rank);
+            assert_eq!(tree.get(rank), Some(value));
+            assert!(tree.contains(value));
+        }
+        assert_eq!(tree.get(expected.len()), None);
+        assert!(tree.range(10..20).eq(expected.iter().skip(10).take(10)));
+        let len = tree.len();
+        assert_eq!(tree.range(len + 5..len + 10).count(), 0);
+    }
+
+    #[test]
+    fn pops_both_ends() {
+        let mut tree = RankTree::default();
+        for value in [5, 1, 9, 3, 7] {
+            tree.insert(value);
+        }
+        assert_eq!(tree.pop_first(), Some(1));
+        assert_eq!(tree.pop_last(), Some(9));
+        assert!(tree.iter().eq([3, 5, 7].iter()));
+        assert_eq!(tree.pop_last(), Some(7));
+        assert_eq!(tree.pop_last(), Some(5));
+        assert_eq!(tree.pop_first(), Some(3));
+        assert_eq!(tree.pop_first(), None);
+        assert_eq!(tree.pop_last(), None);
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn stays_balanced_on_sorted_inserts() {
+        let mut tree = RankTree::default();
+        for value in 0..100_000 {
+            tree.insert(value);
+        }
+        // about 2.5 log2(n) is expected from random priorities
+        assert!(depth(&tree.root) < 60, "depth {}", depth(&tree.root));
+        assert_eq!(tree.count_below(|value| *value < 77_777), 77_777);
+    }
+
+    #[test]
+    fn stays_balanced_on_inserts_in_priority_order() {
+        // the values in the order of the priorities a tree seeded with 0 would give them,
+        // which made that tree a chain
+        let mut seed = 0;
+        let priorities: Vec<_> = (0..100_000).map(|_| next_priority(&mut seed)).collect();
+        let mut sorted = priorities.clone();
+        sorted.sort_unstable();
+        let mut tree = RankTree::default();
+        for priority in priorities.iter() {
+            tree.insert(sorted.binary_search(priority).unwrap());
+        }
+        assert!(depth(&tree.root) < 60, "depth {}", depth(&tree.root));
+    }
+
+    #[test]
+    fn walks_a_chain_without_recursing() {
+        // a chain too deep for the stack of a thread, built by hand as no seed leads to it
+        let len = 1_000_000;
+        let mut tree = RankTree::default();
+        for value in (0..len).rev() {
+            let right = tree.root.take();
+            tree.root = Some(Box::new(Node {
+                value,
+                priority: (len - value) as u64,
+                size: len - value,
+                left: None,
+                right,
+            }));
+        }
+        let copy = tree.clone();
+        assert_eq!(tree.remove(&(len / 2)), Some(len / 2));
+        assert!(tree.insert(len / 2));
+        assert_eq!(tree.pop_last(), Some(len - 1));
+        assert_eq!(tree.pop_first(), Some(0));
+        assert_eq!(tree.len(), len - 2);
+        assert_eq!(copy.len(), len);
+        assert_eq!(copy.get(len - 1), Some(&(len - 1)));
+        drop(tree);
+        drop(copy);
+    }
+}
@@ -0,0 +1,109 @@
+use std::{cmp::Ordering, collections::HashMap, ops::Range};
+
+use super::rank_tree::RankTree;
+
+/// A score with a total ordering so that it can be used as a key in the ordered index.
+///
+/// NaN scores are rejected when parsing, so `total_cmp` gives the natural numeric order.
+#[derive(Debug, Clone, Copy)]
+pub struct Score(pub f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// A set of unique members ordered by score and then lexicographically by member.
+///
+/// Members are indexed twice: by name to look their score up, and in a [`RankTree`] ordered by
+/// `(score, member)` which gives ordered iteration, ranks and positions in O(log n).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    index: RankTree<(Score, String)>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Inserts or updates a member, returning its previous score.
+    pub fn insert(&mut self, member: String, score: f64) -> Option<f64> {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.index.remove(&(Score(previous), member.clone()));
+        }
+        self.index.insert((Score(score), member));
+        previous
+    }
+
+    pub fn remove(&mut self, member: &str) -> Option<f64> {
+        let score = self.scores.remove(member)?;
+        self.index.remove(&(Score(score), member.to_string()));
+        Some(score)
+    }
+
+    /// Zero based position of the member in ascending order.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = Score(self.score(member)?);
+        Some(
+            self.index
+                .count_below(|(other, name)| (*other, name.as_str()) < (score, member)),
+        )
+    }
+
+    /// Number of members `below` holds for, given `(member, score)`. It has to hold for the
+    /// first members in ascending order and then stop holding, like a comparison with a bound.
+    pub fn count_below(&self, below: impl Fn(&str, f64) -> bool) -> usize {
+        self.index
+            .count_below(|(score, member)| below(member, score.0))
+    }
+
+    /// Iterates over the `(member, score)` pairs at the positions of `range` in ascending order.
+    pub fn range(&self, range: Range<usize>) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        self.index
+            .range(range)
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+
+    /// Iterates over `(member, score)` pairs in ascending order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&str, f64)> {
+        self.range(0..self.len())
+    }
+
+    pub fn pop_min(&mut self) -> Option<(String, f64)> {
+        let (score, member) = self.index.pop_first()?;
+        self.scores.remove(&member);
+        Some((member, score.0))
+    }
+
+    pub fn pop_max(&mut self) -> Option<(String, f64)> {
+        let (score, member) = self.index.pop_last()?;
+        self.scores.remove(&member);
+        Some((member, score.0))
+    }
+}
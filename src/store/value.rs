@@ -0,0 +1,63 @@
+use std::collections::HashSet;
+
+use super::sorted_set::SortedSet;
+
+/// A value held by a key in the keyspace.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Set(HashSet<String>),
+    SortedSet(SortedSet),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+        }
+    }
+
+    pub fn as_string(&self) -> Option<&String> {
+        match self {
+            Value::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn as_string_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Value::String(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    pub fn as_set(&self) -> Option<&HashSet<String>> {
+        match self {
+            Value::Set(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    pub fn as_set_mut(&mut self) -> Option<&mut HashSet<String>> {
+        match self {
+            Value::Set(set) => Some(set),
+            _ => None,
+        }
+    }
+
+    pub fn as_sorted_set(&self) -> Option<&SortedSet> {
+        match self {
+            Value::SortedSet(zset) => Some(zset),
+            _ => None,
+        }
+    }
+
+    pub fn as_sorted_set_mut(&mut self) -> Option<&mut SortedSet> {
+        match self {
+            Value::SortedSet(zset) => Some(zset),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,79 @@
+use std::{
+    io::{self, Result},
+    path::Path,
+    sync::Arc,
+};
+
+use rustls::{
+    crypto::ring,
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+    server::WebPkiClientVerifier,
+    RootCertStore, ServerConfig,
+};
+
+use crate::config::core::{Config, TlsAuthClients};
+
+fn invalid(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|err| invalid(format!("{}: {}", path.display(), err)))?;
+    if certs.is_empty() {
+        return Err(invalid(format!("{}: no certificate found", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Builds the TLS configuration of the listener from the certificate, key and client
+/// verification settings.
+///
+/// # Errors
+///
+/// This function will return an error if a file can not be read or parsed, or a setting is
+/// missing.
+pub fn server_config(config: &Config) -> Result<Arc<ServerConfig>> {
+    let cert_file = config
+        .tls_cert_file
+        .as_deref()
+        .ok_or_else(|| invalid("'tls-port' requires 'tls-cert-file'".to_string()))?;
+    let key_file = config
+        .tls_key_file
+        .as_deref()
+        .ok_or_else(|| invalid("'tls-port' requires 'tls-key-file'".to_string()))?;
+    let certs = load_certs(cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(key_file)
+        .map_err(|err| invalid(format!("{}: {}", key_file.display(), err)))?;
+
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|err| invalid(err.to_string()))?;
+    let builder = match config.tls_auth_clients {
+        TlsAuthClients::No => builder.with_no_client_auth(),
+        auth_clients => {
+            let ca_file = config.tls_ca_cert_file.as_deref().ok_or_else(|| {
+                invalid("'tls-auth-clients' requires 'tls-ca-cert-file'".to_string())
+            })?;
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots
+                    .add(cert)
+                    .map_err(|err| invalid(format!("{}: {}", ca_file.display(), err)))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            let verifier = match auth_clients {
+                TlsAuthClients::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            let verifier = verifier.build().map_err(|err| invalid(err.to_string()))?;
+            builder.with_client_cert_verifier(verifier)
+        }
+    };
+    let server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(format!("{}: {}", cert_file.display(), err)))?;
+    Ok(Arc::new(server_config))
+}
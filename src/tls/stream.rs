@@ -0,0 +1,349 @@
+use std::{
+    io::{self, Read, Result, Write},
+    os::fd::{AsRawFd, RawFd},
+    sync::Arc,
+};
+
+use mio::{event::Source, Interest, Registry, Token};
+use rustls::{ServerConfig, ServerConnection};
+
+/// Server side of a TLS session over a non blocking socket.
+///
+/// Nothing blocks: reads pull whatever TLS records the socket holds, which drives the
+/// handshake forward, and return WouldBlock until application data is decrypted. Records that
+/// could not be sent right away stay queued in the session and go out with the next read,
+/// write or flush, which the owner calls on writable events.
+pub struct TlsStream<S> {
+    sock: S,
+    session: ServerConnection,
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    /// Starts the server side of a handshake on an accepted connection.
+    ///
+    /// # Errors
+    ///
+    /// This function will return an error if the session can not be created from the config.
+    pub fn new(sock: S, config: Arc<ServerConfig>) -> Result<TlsStream<S>> {
+        let session = ServerConnection::new(config)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+        Ok(TlsStream { sock, session })
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.sock
+    }
+
+    /// Returns true while TLS records are queued, they go out with the next flush.
+    pub fn wants_write(&self) -> bool {
+        self.session.wants_write()
+    }
+
+    /// Writes the queued TLS records until the socket would block.
+    fn write_records(&mut self) -> Result<()> {
+        while self.session.wants_write() {
+            match self.session.write_tls(&mut self.sock) {
+                Ok(_) => {}
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the TLS records available on the socket, returns false once the peer closed it.
+    fn read_records(&mut self) -> Result<bool> {
+        if self.session.read_tls(&mut self.sock)? == 0 {
+            return Ok(false);
+        }
+        let processed = self.session.process_new_packets();
+        // an alert describing the failure may be queued, it is sent before giving up
+        self.write_records()?;
+        processed.map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        Ok(true)
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        loop {
+            match self.session.reader().read(buf) {
+                Ok(n) => return Ok(n),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {}
+                Err(err) => return Err(err),
+            }
+            // no plaintext buffered, more records are needed
+            if !self.read_records()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.session.writer().write(buf)?;
+        self.write_records()?;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.session.writer().flush()?;
+        self.write_records()
+    }
+}
+
+impl<S: Source> Source for TlsStream<S> {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        self.sock.register(registry, token, interests)
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        self.sock.reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        self.sock.deregister(registry)
+    }
+}
+
+impl<S: AsRawFd> AsRawFd for TlsStream<S> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{BufRead, BufReader},
+        net::{self, SocketAddr},
+        path::PathBuf,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use mio::{net::TcpListener, Events, Poll};
+    use rcgen::{
+        BasicConstraints, CertificateParams, CertifiedKey, ExtendedKeyUsagePurpose, IsCa, KeyPair,
+    };
+    use rustls::{
+        pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    };
+
+    use super::*;
+    use crate::{
+        config::{
+            core::{Config, TlsAuthClients},
+            listener::{ListenAddr, ListenerConfig},
+        },
+        harness::core::TestServer,
+        store::core::Store,
+        tls::core::server_config,
+    };
+
+    const LISTENER: Token = Token(0);
+    const CLIENT: Token = Token(1);
+
+    /// Certificates generated for one test, written as PEM files in a fresh directory.
+    struct Pki {
+        dir: PathBuf,
+        ca: CertifiedKey,
+        server: CertifiedKey,
+    }
+
+    impl Pki {
+        fn generate(name: &str) -> Pki {
+            let dir = std::env::temp_dir().join(format!("tls-{}-{}", name, std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+
+            let ca_key = KeyPair::generate().unwrap();
+            let mut ca_params = CertificateParams::new(vec![]).unwrap();
+            ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            let ca = CertifiedKey {
+                cert: ca_params.self_signed(&ca_key).unwrap(),
+                key_pair: ca_key,
+            };
+            // the server certificate is self-signed, clients trust it directly
+            let server = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+
+            fs::write(dir.join("ca.crt"), ca.cert.pem()).unwrap();
+            fs::write(dir.join("server.crt"), server.cert.pem()).unwrap();
+            fs::write(dir.join("server.key"), server.key_pair.serialize_pem()).unwrap();
+            Pki { dir, ca, server }
+        }
+
+        fn config(&self, auth_clients: TlsAuthClients) -> Config {
+            Config {
+                tls_port: 1,
+                tls_cert_file: Some(self.dir.join("server.crt")),
+                tls_key_file: Some(self.dir.join("server.key")),
+                tls_ca_cert_file: Some(self.dir.join("ca.crt")),
+                tls_auth_clients: auth_clients,
+                ..Config::default()
+            }
+        }
+
+        /// A client certificate signed by the test authority.
+        fn client_cert(&self) -> CertifiedKey {
+            let key_pair = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(vec!["client".to_string()]).unwrap();
+            params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+            let cert = params
+                .signed_by(&key_pair, &self.ca.cert, &self.ca.key_pair)
+                .unwrap();
+            CertifiedKey { cert, key_pair }
+        }
+
+        fn client_config(&self, cert: Option<&CertifiedKey>) -> Arc<ClientConfig> {
+            let mut roots = RootCertStore::empty();
+            roots.add(self.server.cert.der().clone()).unwrap();
+            let provider = Arc::new(rustls::crypto::ring::default_provider());
+            let builder = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(roots);
+            let config = match cert {
+                Some(cert) => builder
+                    .with_client_auth_cert(
+                        vec![CertificateDer::from(cert.cert.der().to_vec())],
+                        PrivateKeyDer::try_from(cert.key_pair.serialize_der()).unwrap(),
+                    )
+                    .unwrap(),
+                None => builder.with_no_client_auth(),
+            };
+            Arc::new(config)
+        }
+    }
+
+    impl Drop for Pki {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// Sends `PING` over a blocking TLS client and returns the reply line.
+    fn ping(addr: SocketAddr, config: Arc<ClientConfig>) -> Result<String> {
+        let sock = net::TcpStream::connect(addr)?;
+        sock.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let name = ServerName::try_from("localhost").unwrap();
+        let session = ClientConnection::new(config, name)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        let mut stream = StreamOwned::new(session, sock);
+        stream.write_all(b"PING\r\n")?;
+        let mut line = String::new();
+        BufReader::new(stream).read_line(&mut line)?;
+        Ok(line)
+    }
+
+    /// Accepts one TLS connection on a non blocking listener and answers the first line it
+    /// receives, driving everything from readiness events. Returns what the server read along
+    /// with the client outcome.
+    fn serve_one(
+        config: Arc<ServerConfig>,
+        client: Arc<ClientConfig>,
+    ) -> (Result<String>, Result<String>) {
+        let mut poll = Poll::new().unwrap();
+        let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        poll.registry()
+            .register(&mut listener, LISTENER, Interest::READABLE)
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let handle = thread::spawn(move || ping(addr, client));
+
+        let mut events = Events::with_capacity(16);
+        let mut stream: Option<TlsStream<mio::net::TcpStream>> = None;
+        let mut received = vec![];
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let outcome = 'serve: loop {
+            assert!(Instant::now() < deadline, "the exchange timed out");
+            poll.poll(&mut events, Some(Duration::from_millis(50)))
+                .unwrap();
+            for event in events.iter() {
+                if event.token() == LISTENER {
+                    let (sock, _) = listener.accept().unwrap();
+                    let mut tls = TlsStream::new(sock, config.clone()).unwrap();
+                    poll.registry()
+                        .register(&mut tls, CLIENT, Interest::READABLE | Interest::WRITABLE)
+                        .unwrap();
+                    stream = Some(tls);
+                    continue;
+                }
+                let tls = stream.as_mut().unwrap();
+                if event.is_writable() {
+                    tls.flush().unwrap();
+                }
+                let mut buf = [0; 1024];
+                loop {
+                    match tls.read(&mut buf) {
+                        Ok(0) => break 'serve Err(io::ErrorKind::UnexpectedEof.into()),
+                        Ok(n) => received.extend_from_slice(&buf[..n]),
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => break 'serve Err(err),
+                    }
+                }
+                if received.ends_with(b"\r\n") {
+                    tls.write_all(b"+PONG\r\n").unwrap();
+                    tls.flush().unwrap();
+                    break 'serve Ok(String::from_utf8(received).unwrap());
+                }
+            }
+        };
+        let reply = handle.join().unwrap();
+        (outcome, reply)
+    }
+
+    #[test]
+    fn handshake_and_records_progress_through_readiness() {
+        let pki = Pki::generate("plain");
+        let config = server_config(&pki.config(TlsAuthClients::No)).unwrap();
+        let (received, reply) = serve_one(config, pki.client_config(None));
+        assert_eq!(received.unwrap(), "PING\r\n");
+        assert_eq!(reply.unwrap(), "+PONG\r\n");
+    }
+
+    #[test]
+    fn client_certificate_is_verified() {
+        let pki = Pki::generate("client-auth");
+        let config = server_config(&pki.config(TlsAuthClients::Yes)).unwrap();
+
+        let client_cert = pki.client_cert();
+        let (received, reply) = serve_one(config.clone(), pki.client_config(Some(&client_cert)));
+        assert_eq!(received.unwrap(), "PING\r\n");
+        assert_eq!(reply.unwrap(), "+PONG\r\n");
+
+        let (received, reply) = serve_one(config, pki.client_config(None));
+        assert_eq!(
+            received.unwrap_err().kind(),
+            io::ErrorKind::InvalidData,
+            "a client without certificate is refused"
+        );
+        assert!(reply.is_err());
+    }
+
+    #[test]
+    fn client_certificate_can_be_optional() {
+        let pki = Pki::generate("optional");
+        let config = server_config(&pki.config(TlsAuthClients::Optional)).unwrap();
+        let (received, reply) = serve_one(config, pki.client_config(None));
+        assert_eq!(received.unwrap(), "PING\r\n");
+        assert_eq!(reply.unwrap(), "+PONG\r\n");
+    }
+
+    #[test]
+    fn server_answers_over_its_tls_listener() {
+        let pki = Pki::generate("listener");
+        let config = server_config(&pki.config(TlsAuthClients::Yes)).unwrap();
+        let listener = ListenerConfig::new(ListenAddr::Tls("127.0.0.1:0".to_string()), 0);
+        let server = TestServer::start_tls(listener, Store::default(), config);
+
+        let client_cert = pki.client_cert();
+        let reply = ping(server.addr(), pki.client_config(Some(&client_cert)));
+        assert_eq!(reply.unwrap(), "+PONG\r\n");
+        assert!(ping(server.addr(), pki.client_config(None)).is_err());
+        assert!(server.wait_for_clients(0));
+    }
+}